@@ -0,0 +1,267 @@
+/*
+   Copyright (C) 2022  Kalka
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License, or (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::{Component, Path, PathBuf};
+
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use rlua::{Lua, Result, Table};
+use tar::{Archive, Builder, EntryType};
+
+use crate::compress::level_option;
+use crate::error;
+use crate::help::{self, FunctionDoc};
+
+const TAR_DOCS: &[FunctionDoc] = &[
+    FunctionDoc {
+        name: "extract",
+        signature: "archive.tar.extract(path, dest) -> paths",
+        description: "Extracts a tar archive into the directory dest, creating it if needed, and returns the paths of what it extracted, relative to dest. Archives compressed with gzip or Zstandard are recognised by their contents. Permissions and modification times are kept, existing files are replaced, and entries that would land outside dest, like ../etc/passwd, are skipped.",
+        example: "archive.tar.extract(\"release.tar.gz\", \"/opt/app\")",
+    },
+    FunctionDoc {
+        name: "create",
+        signature: "archive.tar.create(path, files[, options]) -> nil",
+        description: "Writes a tar archive of the listed files and directories, directories with everything in them, keeping permissions, modification times and symlinks. Entries are named by the paths as given, without a leading /. options.compression is \"gzip\", \"zstd\" or \"none\", by default gzip for paths ending in .gz or .tgz, Zstandard for .zst or .tzst and none otherwise, and options.level is as in compress.gzip and compress.zstd.",
+        example: "archive.tar.create(\"release.tar.zst\", { \"bin\", \"config\", \"README.md\" }, { compression = \"zstd\" })",
+    },
+];
+
+// Where an archive is being written, compressed or not
+enum Output {
+    Plain(File),
+    Gzip(GzEncoder<File>),
+    Zstd(zstd::Encoder<'static, File>),
+}
+
+impl Output {
+    fn finish(self) -> std::io::Result<()> {
+        match self {
+            Output::Plain(mut file) => file.flush(),
+            Output::Gzip(encoder) => encoder.finish().map(drop),
+            Output::Zstd(encoder) => encoder.finish().map(drop),
+        }
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Output::Plain(file) => file.write(data),
+            Output::Gzip(encoder) => encoder.write(data),
+            Output::Zstd(encoder) => encoder.write(data),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Output::Plain(file) => file.flush(),
+            Output::Gzip(encoder) => encoder.flush(),
+            Output::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+// tar's errors leave what caused them out of their messages
+fn with_causes(error: std::io::Error) -> std::io::Error {
+    let mut message = error.to_string();
+    let mut cause = error.get_ref().and_then(|inner| inner.source());
+    while let Some(inner) = cause {
+        message = format!("{}: {}", message, inner);
+        cause = inner.source();
+    }
+    std::io::Error::new(error.kind(), message)
+}
+
+// Opens an archive, decompressing it when it starts like gzip or Zstandard does
+fn open(path: &str) -> std::io::Result<Box<dyn Read>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let start = reader.fill_buf()?;
+    Ok(if start.starts_with(&[0x1f, 0x8b]) {
+        Box::new(MultiGzDecoder::new(reader))
+    } else if start.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+        Box::new(zstd::Decoder::with_buffer(reader)?)
+    } else {
+        Box::new(reader)
+    })
+}
+
+fn extract(path: &str, dest: &str) -> std::io::Result<Vec<String>> {
+    std::fs::create_dir_all(dest)?;
+    let dest = Path::new(dest).canonicalize()?;
+    let mut archive = Archive::new(open(path)?);
+    archive.set_preserve_permissions(true);
+    archive.set_preserve_mtime(true);
+    archive.set_overwrite(true);
+
+    // Directories come last, as tar's own unpack does it, so one that isn't
+    // writable doesn't stop what's in it from being extracted
+    let mut paths = Vec::new();
+    let mut directories = Vec::new();
+    let name = |path: std::borrow::Cow<Path>| {
+        let name = path.to_string_lossy();
+        name.trim_end_matches('/').to_string()
+    };
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if entry.header().entry_type() == EntryType::Directory {
+            directories.push(entry);
+        } else if entry.unpack_in(&dest)? {
+            paths.push(name(entry.path()?));
+        }
+    }
+    directories.sort_by(|a, b| b.path_bytes().cmp(&a.path_bytes()));
+    for mut directory in directories {
+        if directory.unpack_in(&dest)? {
+            paths.push(name(directory.path()?));
+        }
+    }
+    Ok(paths)
+}
+
+// The name a file is stored under, its path without a root or leading ./
+fn entry_name(path: &Path) -> PathBuf {
+    let name = path
+        .components()
+        .filter(|component| {
+            !matches!(
+                component,
+                Component::Prefix(_) | Component::RootDir | Component::CurDir
+            )
+        })
+        .collect::<PathBuf>();
+    match name.as_os_str().is_empty() {
+        true => PathBuf::from("."),
+        false => name,
+    }
+}
+
+fn compression_option(options: &Option<Table>, path: &str) -> Result<&'static str> {
+    let compression = match options {
+        Some(options) => options.get::<_, Option<String>>("compression")?,
+        None => None,
+    };
+    match compression.as_deref() {
+        Some("gzip") => Ok("gzip"),
+        Some("zstd") => Ok("zstd"),
+        Some("none") => Ok("none"),
+        Some(compression) => Err(rlua::Error::RuntimeError(format!(
+            "bad option 'compression' to 'create' (unknown compression '{}', expected gzip, zstd or none)",
+            compression
+        ))),
+        None if path.ends_with(".gz") || path.ends_with(".tgz") => Ok("gzip"),
+        None if path.ends_with(".zst") || path.ends_with(".tzst") => Ok("zstd"),
+        None => Ok("none"),
+    }
+}
+
+fn create(
+    path: &str,
+    files: &[String],
+    compression: &str,
+    level: Option<i32>,
+) -> std::io::Result<()> {
+    let file = File::create(path)?;
+    let output = match compression {
+        "gzip" => Output::Gzip(GzEncoder::new(
+            file,
+            flate2::Compression::new(level.unwrap_or(6) as u32),
+        )),
+        "zstd" => Output::Zstd(zstd::Encoder::new(
+            file,
+            level.unwrap_or(zstd::DEFAULT_COMPRESSION_LEVEL),
+        )?),
+        _ => Output::Plain(file),
+    };
+    let mut builder = Builder::new(output);
+    builder.follow_symlinks(false);
+    for file in files {
+        let name = entry_name(Path::new(file));
+        match std::fs::symlink_metadata(file)?.is_dir() {
+            true => builder.append_dir_all(name, file)?,
+            false => builder.append_path_with_name(file, name)?,
+        }
+    }
+    builder.into_inner()?.finish()
+}
+
+pub fn load_archive_library(lua: &Lua) -> Result<()> {
+    lua.context(|lua_ctx| {
+        let tar = lua_ctx.create_table()?;
+
+        tar.set(
+            "extract",
+            lua_ctx.create_function(|_, (path, dest): (String, String)| {
+                extract(&path, &dest).map_err(|source| {
+                    rlua::Error::external(error::Error::Extract {
+                        path,
+                        dest,
+                        source: with_causes(source),
+                    })
+                })
+            })?,
+        )?;
+        tar.set(
+            "create",
+            lua_ctx.create_function(
+                |_, (path, files, options): (String, Vec<String>, Option<Table>)| {
+                    let compression = compression_option(&options, &path)?;
+                    let level = match compression {
+                        "none" => None,
+                        compression => level_option(&options, "create", compression)?,
+                    };
+                    if let Some(file) = files.iter().find(|file| {
+                        Path::new(file)
+                            .components()
+                            .any(|component| component == Component::ParentDir)
+                    }) {
+                        return Err(rlua::Error::RuntimeError(format!(
+                            "bad argument #2 to 'create' ('{}' has a .. in it, which archives can't hold)",
+                            file
+                        )));
+                    }
+                    // Name a file that's missing rather than the archive
+                    if let Some((file, source)) = files.iter().find_map(|file| {
+                        std::fs::symlink_metadata(file)
+                            .err()
+                            .map(|source| (file, source))
+                    }) {
+                        return Err(rlua::Error::external(error::Error::Read {
+                            path: file.clone(),
+                            source,
+                        }));
+                    }
+                    create(&path, &files, compression, level).map_err(|source| {
+                        // Don't leave half an archive behind
+                        let _ = std::fs::remove_file(&path);
+                        rlua::Error::external(error::Error::Write {
+                            path,
+                            source: with_causes(source),
+                        })
+                    })
+                },
+            )?,
+        )?;
+
+        help::register_module_docs(lua_ctx, "archive.tar", "Tar archives", &tar, TAR_DOCS)?;
+        let module = lua_ctx.create_table()?;
+        module.set("tar", tar)?;
+        lua_ctx.globals().set("archive", module)?;
+        Ok(())
+    })
+}
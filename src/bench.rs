@@ -0,0 +1,196 @@
+/*
+   Copyright (C) 2022  Kalka
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License, or (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use rlua::{Function, Lua, Result, Table};
+
+use crate::help::{self, FunctionDoc};
+use crate::profiler;
+
+// Returns `run`. Each benchmark is warmed up, which also estimates how long a call takes, and
+// then timed in samples of enough calls for the samples to fill the measurement time, so the
+// clock's resolution and the loop don't dominate fast functions.
+const BENCH_SOURCE: &str = r#"
+local now, format_results = ...
+local pairs, type, tostring, error, format = pairs, type, tostring, error, string.format
+local sort, sqrt, ceil, floor, max = table.sort, math.sqrt, math.ceil, math.floor, math.max
+
+local defaults = { warmup = 0.1, time = 1, samples = 50 }
+
+-- Fewest samples taken of functions too slow to fill the measurement time with the default
+local MIN_SAMPLES = 5
+
+local function option(options, name)
+    local value = options[name]
+    if value == nil then
+        return defaults[name]
+    elseif type(value) ~= "number" or value <= 0 then
+        error(format("bad option '%s' to 'run' (positive number expected)", name), 3)
+    end
+    return value
+end
+
+-- Seconds per call of n calls in a row
+local function sample(fn, n)
+    local start = now()
+    for _ = 1, n do
+        fn()
+    end
+    return (now() - start) / n
+end
+
+local function measure(name, fn, warmup, time, samples)
+    local calls, batch, start = 0, 1, now()
+    repeat
+        sample(fn, batch)
+        calls = calls + batch
+        batch = batch * 2
+    until now() - start >= warmup
+    local per_call = (now() - start) / calls
+
+    samples = max(MIN_SAMPLES, floor(math.min(samples, time / per_call)))
+    local iterations = max(1, floor(time / samples / per_call))
+    local times, total = {}, 0
+    for i = 1, samples do
+        times[i] = sample(fn, iterations)
+        total = total + times[i]
+    end
+    local mean = total / samples
+    local variance = 0
+    for i = 1, samples do
+        variance = variance + (times[i] - mean) ^ 2
+    end
+    sort(times)
+    return {
+        name = name,
+        mean = mean,
+        stddev = sqrt(variance / (samples - 1)),
+        p99 = times[ceil(samples * 0.99)],
+        min = times[1],
+        max = times[samples],
+        iterations = iterations * samples,
+    }
+end
+
+return function(benchmarks, options)
+    if type(benchmarks) ~= "table" then
+        error(format("bad argument #1 to 'run' (table expected, got %s)", type(benchmarks)), 2)
+    end
+    options = options or {}
+    local warmup, time = option(options, "warmup"), option(options, "time")
+    local samples = max(2, floor(option(options, "samples")))
+
+    local names = {}
+    for name, fn in pairs(benchmarks) do
+        if type(fn) ~= "function" then
+            error(format("benchmark '%s' is a %s, not a function", tostring(name), type(fn)), 2)
+        end
+        names[#names + 1] = name
+    end
+    sort(names, function(a, b)
+        return tostring(a) < tostring(b)
+    end)
+
+    local results = {}
+    for i, name in ipairs(names) do
+        results[i] = measure(tostring(name), benchmarks[name], warmup, time, samples)
+    end
+    sort(results, function(a, b)
+        return a.mean < b.mean
+    end)
+    print(format_results(results))
+    return results
+end
+"#;
+
+const BENCH_DOCS: &[FunctionDoc] = &[FunctionDoc {
+    name: "run",
+    signature: "bench.run(benchmarks[, options]) -> results",
+    description: "Times each function of the table after warming it up, prints the mean, 99th percentile and standard deviation of a call, fastest first, and returns them as a list of {name, mean, p99, stddev, min, max, iterations} in seconds. options can set warmup and time, the seconds spent on each, and the number of samples",
+    example: "bench.run({\n    concat = function() return \"a\" .. \"b\" .. \"c\" end,\n    format = function() return string.format(\"%s%s%s\", \"a\", \"b\", \"c\") end,\n}, { time = 2 })",
+}];
+
+// Seconds as the largest unit that keeps them above 1
+fn format_time(seconds: f64) -> String {
+    if seconds >= 1.0 {
+        format!("{:.2} s", seconds)
+    } else if seconds >= 1e-3 {
+        format!("{:.2} ms", seconds * 1e3)
+    } else if seconds >= 1e-6 {
+        format!("{:.2} µs", seconds * 1e6)
+    } else {
+        format!("{:.2} ns", seconds * 1e9)
+    }
+}
+
+/// The table `bench.run` prints, with how many times slower than the fastest each benchmark is.
+pub fn format_results(results: &Table) -> Result<String> {
+    let mut rows = Vec::new();
+    for row in results.clone().sequence_values::<Table>() {
+        let row = row?;
+        rows.push((
+            row.get::<_, String>("name")?,
+            row.get::<_, f64>("mean")?,
+            row.get::<_, f64>("p99")?,
+            row.get::<_, f64>("stddev")?,
+        ));
+    }
+    let fastest = rows.first().map_or(0.0, |row| row.1);
+    let width = rows
+        .iter()
+        .map(|row| row.0.chars().count())
+        .chain(std::iter::once("name".len()))
+        .max()
+        .unwrap_or(0);
+    let mut table = format!(
+        "{:<width$} {:>12} {:>12} {:>12} {:>9}\n",
+        "name", "mean", "p99", "stddev", "relative"
+    );
+    for (name, mean, p99, stddev) in rows {
+        table.push_str(&format!(
+            "{:<width$} {:>12} {:>12} {:>12} {:>8.2}x\n",
+            name,
+            format_time(mean),
+            format_time(p99),
+            format_time(stddev),
+            if fastest > 0.0 { mean / fastest } else { 1.0 },
+        ));
+    }
+    Ok(table.trim_end().to_string())
+}
+
+pub fn load_bench_library(lua: &Lua) -> Result<()> {
+    lua.context(|lua_ctx| {
+        let now = lua_ctx.create_function(|_, ()| Ok(profiler::now()))?;
+        let format_results =
+            lua_ctx.create_function(|_, results: Table| format_results(&results))?;
+        let run = lua_ctx
+            .load(BENCH_SOURCE)
+            .set_name("=bench")?
+            .call::<_, Function>((now, format_results))?;
+
+        let bench = lua_ctx.create_table()?;
+        bench.set("run", run)?;
+        help::register_module_docs(
+            lua_ctx,
+            "bench",
+            "Comparing how fast functions are",
+            &bench,
+            BENCH_DOCS,
+        )?;
+        lua_ctx.globals().set("bench", bench)?;
+        Ok(())
+    })
+}
@@ -0,0 +1,126 @@
+/*
+   Copyright (C) 2022  Kalka
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License, or (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use rlua::{Lua, Result, Table};
+
+use crate::help::{self, FunctionDoc};
+
+const STRUCT_DOCS: &[FunctionDoc] = &[
+    FunctionDoc {
+        name: "pack",
+        signature: "struct.pack(format, ...) -> bytes",
+        description: "Packs the values into bytes as the format says, with the format string.pack uses: < little-endian, > big-endian and = native, b/B a signed or unsigned byte, h/H 2 bytes, i[n]/I[n] n-byte integers (4 by default), j/J 8 bytes, f and d 4 and 8-byte floats, s[n] a string after its length in n bytes (8 by default), z a string ending in a zero byte, c[n] a string of exactly n bytes, and x a zero byte of padding. Spaces are ignored. Raises an error for values that don't fit.",
+        example: "local header = struct.pack(\">c4 I2 I2 I4\", \"RIFF\", 1, flags, #body)",
+    },
+    FunctionDoc {
+        name: "unpack",
+        signature: "struct.unpack(format, data[, position]) -> ..., next",
+        description: "Reads the values the format describes out of the data, a string or a list of bytes like memory.read returns, starting at position (1 by default), and returns them with the position just after them. Raises an error when the data is too short.",
+        example: "local magic, version, count, length = struct.unpack(\">c4 I2 I2 I4\", header)",
+    },
+    FunctionDoc {
+        name: "size",
+        signature: "struct.size(format) -> integer",
+        description: "The number of bytes struct.pack makes for the format. Raises an error for formats with s or z, whose size depends on the strings.",
+        example: "local header = file:read(struct.size(\">c4 I2 I2 I4\"))",
+    },
+    FunctionDoc {
+        name: "bytes",
+        signature: "struct.bytes(data) -> table",
+        description: "The bytes of a string as a list of numbers from 0 to 255, as memory.write takes them.",
+        example: "memory.write(p, struct.bytes(struct.pack(\"<d\", 3.14)))",
+    },
+];
+
+// Returns the struct table. The functions hand their work to string.pack, string.unpack and
+// string.packsize, with errors naming the struct function and pointing at the script's line.
+const STRUCT_SOURCE: &str = r#"
+local char, byte = string.char, string.byte
+local concat, pack_results, unpack_results = table.concat, table.pack, table.unpack
+local type, math_type, tostring, error, format = type, math.type, tostring, error, string.format
+local pcall = pcall
+
+local function checked(name, fn, ...)
+    local results = pack_results(pcall(fn, ...))
+    if not results[1] then
+        local message = tostring(results[2])
+        message = message:match("bad argument .*") or message
+        error((message:gsub("'string%.?%a*'", "'" .. name .. "'", 1)), 3)
+    end
+    return unpack_results(results, 2, results.n)
+end
+
+local function to_string(data)
+    if type(data) ~= "table" then
+        return data
+    end
+    local chars = {}
+    for i = 1, #data do
+        local value = data[i]
+        if math_type(value) ~= "integer" or value < 0 or value > 255 then
+            error(format("bad argument #2 to 'unpack' (byte expected at index %d, got %s)", i, tostring(value)), 3)
+        end
+        chars[i] = char(value)
+    end
+    return concat(chars)
+end
+
+local struct = {}
+
+function struct.pack(fmt, ...)
+    return checked("pack", string.pack, fmt, ...)
+end
+
+function struct.unpack(fmt, data, position)
+    return checked("unpack", string.unpack, fmt, to_string(data), position)
+end
+
+function struct.size(fmt)
+    return checked("size", string.packsize, fmt)
+end
+
+function struct.bytes(data)
+    if type(data) ~= "string" then
+        error(format("bad argument #1 to 'bytes' (string expected, got %s)", type(data)), 2)
+    end
+    local bytes = {}
+    for i = 1, #data do
+        bytes[i] = byte(data, i)
+    end
+    return bytes
+end
+
+return struct
+"#;
+
+pub fn load_struct_library(lua: &Lua) -> Result<()> {
+    lua.context(|lua_ctx| {
+        let structs = lua_ctx
+            .load(STRUCT_SOURCE)
+            .set_name("=struct")?
+            .call::<_, Table>(())?;
+
+        help::register_module_docs(
+            lua_ctx,
+            "struct",
+            "Packing values into binary data and back",
+            &structs,
+            STRUCT_DOCS,
+        )?;
+        lua_ctx.globals().set("struct", structs)?;
+        Ok(())
+    })
+}
@@ -0,0 +1,184 @@
+/*
+   Copyright (C) 2022  Kalka
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License, or (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use cumulus::logger;
+use rlua::{Function, Lua, Result, Table};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use crate::require;
+
+/// Where `rluaterm bundle` writes to without `-o`, e.g. `tool.bundle.lua` for `tool.lua`.
+pub fn default_output(entry: &Path) -> PathBuf {
+    let stem = entry.file_stem().unwrap_or_default().to_string_lossy();
+    entry.with_file_name(format!("{}.bundle.lua", stem))
+}
+
+// Names of the modules required with a literal name, as in `require("a.b")` or `require "a.b"`
+fn find_requires(source: &str) -> Vec<String> {
+    let mut names = Vec::new();
+    let mut rest = source;
+    while let Some(index) = rest.find("require") {
+        let preceded_by_name = rest[..index]
+            .chars()
+            .next_back()
+            .is_some_and(|c| c.is_alphanumeric() || c == '_' || c == '.' || c == ':');
+        rest = &rest[index + "require".len()..];
+        if preceded_by_name {
+            continue;
+        }
+        let call = rest.trim_start();
+        let call = call.strip_prefix('(').map_or(call, str::trim_start);
+        let quote = match call.chars().next() {
+            Some(quote @ ('"' | '\'')) => quote,
+            _ => continue,
+        };
+        if let Some(end) = call[1..].find(quote) {
+            names.push(call[1..end + 1].to_string());
+        }
+    }
+    names
+}
+
+// Quote the source as a long string that can't be closed by anything inside it. The newline
+// after the opening bracket is dropped by Lua, so line numbers stay the same, and the one before
+// the closing bracket keeps a `]` at the very end of the source from closing it early.
+fn long_string(source: &str) -> String {
+    let mut level = 0;
+    while source.contains(&format!("]{}]", "=".repeat(level))) {
+        level += 1;
+    }
+    let equals = "=".repeat(level);
+    format!("[{}[\n{}\n]{}]", equals, source, equals)
+}
+
+// A module's source loaded under its original chunk name, so errors point at the right file
+fn load_expression(source: &str, path: &Path) -> String {
+    format!(
+        "load({}, {:?})",
+        long_string(source),
+        format!("@{}", path.display())
+    )
+}
+
+fn read_source(path: &Path) -> Result<String> {
+    let mut contents = std::fs::read(path).map_err(rlua::Error::external)?;
+    crate::strip_shebang(&mut contents);
+    String::from_utf8(contents).map_err(rlua::Error::external)
+}
+
+/// Writes the entry script and every module it requires, directly or through other modules, into
+/// one file that registers the modules in `package.preload`. Modules are found the way `require`
+/// finds them, those that can't be found are left for `require` to find at runtime. Returns the
+/// number of modules bundled.
+pub fn bundle(entry: &Path, output: &Path) -> Result<usize> {
+    let entry_source = read_source(entry)?;
+    let lua = Lua::new();
+    require::install_searcher(&lua, &require::module_dirs(entry, None))?;
+    let modules = lua.context(|lua_ctx| {
+        let package = lua_ctx.globals().get::<_, Table>("package")?;
+        let search_path = require::search_path(lua_ctx)?;
+        let searchpath = package.get::<_, Function>("searchpath")?;
+
+        let mut modules = Vec::new();
+        let mut seen = HashSet::new();
+        let mut pending = find_requires(&entry_source);
+        while !pending.is_empty() {
+            let name = pending.remove(0);
+            if !seen.insert(name.clone()) {
+                continue;
+            }
+            let path =
+                searchpath.call::<_, Option<String>>((name.as_str(), search_path.as_str()))?;
+            let path = match path {
+                Some(path) => PathBuf::from(path),
+                None => {
+                    logger::warn(&format!(
+                        "Module {} not found, leaving it to be required at runtime",
+                        name
+                    ));
+                    continue;
+                }
+            };
+            let source = read_source(&path)?;
+            pending.extend(find_requires(&source));
+            modules.push((name, path, source));
+        }
+        Ok(modules)
+    })?;
+
+    let mut bundle = String::new();
+    let original = std::fs::read_to_string(entry).map_err(rlua::Error::external)?;
+    if original.starts_with("#!") {
+        bundle.push_str(original.lines().next().unwrap_or_default());
+        bundle.push('\n');
+    }
+    bundle.push_str(&format!("-- Bundled from {}\n", entry.display()));
+    for (name, path, source) in &modules {
+        bundle.push_str(&format!(
+            "package.preload[{:?}] = {}\n",
+            name,
+            load_expression(source, path)
+        ));
+    }
+    bundle.push_str(&format!(
+        "return {}(...)\n",
+        load_expression(&entry_source, entry)
+    ));
+    std::fs::write(output, bundle).map_err(rlua::Error::external)?;
+    Ok(modules.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // What Lua reads back from the long string
+    fn unquote(quoted: &str) -> String {
+        Lua::new().context(|lua_ctx| {
+            lua_ctx
+                .load(&format!("return {}", quoted))
+                .eval::<String>()
+                .unwrap()
+        })
+    }
+
+    #[test]
+    fn quotes_with_a_level_nothing_inside_closes() {
+        assert!(long_string("print(1)").starts_with("[[\n"));
+        assert!(long_string("x = t[u[1]]").starts_with("[=[\n"));
+        assert!(long_string("s = [==[a]]b]=]c]==]").starts_with("[===[\n"));
+    }
+
+    #[test]
+    fn keeps_the_source_as_is() {
+        for source in [
+            "print(1)",
+            "x = t[u[1]]",
+            "s = [==[a]]b]=]c]==]",
+            "t[1]",
+            "\nx = 1",
+        ] {
+            assert_eq!(unquote(&long_string(source)), format!("{}\n", source));
+        }
+    }
+
+    #[test]
+    fn finds_literal_requires() {
+        let source = r#"local a = require("a.b") local c = require 'c' x.require("d") require(e)"#;
+        assert_eq!(find_requires(source), vec!["a.b", "c"]);
+    }
+}
@@ -0,0 +1,259 @@
+/*
+   Copyright (C) 2022  Kalka
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License, or (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use rlua::{
+    AnyUserData, Context, LightUserData, Lua, MetaMethod, MultiValue, Result, UserData,
+    UserDataMethods, Value,
+};
+use std::io::Cursor;
+
+use crate::help::{self, FunctionDoc};
+use crate::{error, json};
+
+const CBOR_DOCS: &[FunctionDoc] = &[
+    FunctionDoc {
+        name: "encode",
+        signature: "cbor.encode(value) -> bytes",
+        description: "Turns a Lua value into CBOR. Tables with nothing but the keys 1 to n become arrays and other tables maps, empty ones included, and cbor.null becomes null. Strings of valid UTF-8 are written as text and others as byte strings, and cbor.bytes and cbor.tag write byte strings and tagged values. Raises an error for functions and other values CBOR can't hold, and for tables nested inside themselves.",
+        example: "local payload = cbor.encode({ device = \"sensor-7\", reading = 21.5 })",
+    },
+    FunctionDoc {
+        name: "decode",
+        signature: "cbor.decode(bytes[, position]) -> value, next",
+        description: "Reads a CBOR value from the bytes, starting at position (1 by default), and returns it with the position just after it. Arrays and maps become tables, text strings, byte strings cbor.bytes values, tagged values cbor.tag values, and null and undefined cbor.null, which is json.null. Raises an error for invalid CBOR.",
+        example: "local message = cbor.decode(packet)\nif message.tag == 18 then\n    local protected, unprotected, payload, signature = table.unpack(message.value)\nend",
+    },
+    FunctionDoc {
+        name: "bytes",
+        signature: "cbor.bytes(data) -> bytes",
+        description: "A string that cbor.encode writes as a byte string, even when it's valid UTF-8. bytes.data is the string, and so are tostring(bytes) and #bytes its length.",
+        example: "cbor.encode({ id = cbor.bytes(uuid) })",
+    },
+    FunctionDoc {
+        name: "tag",
+        signature: "cbor.tag(number, value) -> tagged",
+        description: "A value with a CBOR tag, which says how to read it, like 1 for seconds since 1970 or 32 for a URI. tagged.tag is the number and tagged.value the value.",
+        example: "cbor.encode(cbor.tag(1, os.time()))",
+    },
+];
+
+// Deeper tables are almost certainly cycles
+const MAX_DEPTH: usize = 100;
+
+// A CBOR byte string
+struct Bytes(Vec<u8>);
+
+impl UserData for Bytes {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_meta_method(MetaMethod::Index, |lua_ctx, bytes, key: String| {
+            Ok(match key.as_str() {
+                "data" => Value::String(lua_ctx.create_string(&bytes.0)?),
+                _ => Value::Nil,
+            })
+        });
+        methods.add_meta_method(MetaMethod::ToString, |lua_ctx, bytes, ()| {
+            lua_ctx.create_string(&bytes.0)
+        });
+        methods.add_meta_method(MetaMethod::Len, |_, bytes, ()| Ok(bytes.0.len()));
+        methods.add_meta_method(MetaMethod::Eq, |_, bytes, other: AnyUserData| {
+            Ok(other
+                .borrow::<Bytes>()
+                .is_ok_and(|other| other.0 == bytes.0))
+        });
+    }
+}
+
+// A value with a CBOR tag, kept in the registry while it's a Lua value
+struct Tag {
+    tag: u64,
+    value: rlua::RegistryKey,
+}
+
+impl UserData for Tag {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_meta_method(MetaMethod::Index, |lua_ctx, tagged, key: String| {
+            Ok(match key.as_str() {
+                "tag" => match i64::try_from(tagged.tag) {
+                    Ok(tag) => Value::Integer(tag),
+                    Err(_) => Value::Number(tagged.tag as f64),
+                },
+                "value" => lua_ctx.registry_value(&tagged.value)?,
+                _ => Value::Nil,
+            })
+        });
+        methods.add_meta_method(MetaMethod::ToString, |_, tagged, ()| {
+            Ok(format!("cbor.tag({})", tagged.tag))
+        });
+    }
+}
+
+fn encode_error(message: String) -> rlua::Error {
+    rlua::Error::RuntimeError(format!("cannot encode to CBOR: {}", message))
+}
+
+fn to_lua<'lua>(lua_ctx: Context<'lua>, value: ciborium::Value) -> Result<Value<'lua>> {
+    Ok(match value {
+        ciborium::Value::Null => json::null(),
+        ciborium::Value::Bool(boolean) => Value::Boolean(boolean),
+        ciborium::Value::Integer(integer) => match i64::try_from(integer) {
+            Ok(integer) => Value::Integer(integer),
+            Err(_) => Value::Number(i128::from(integer) as f64),
+        },
+        ciborium::Value::Float(float) => Value::Number(float),
+        ciborium::Value::Text(text) => Value::String(lua_ctx.create_string(&text)?),
+        ciborium::Value::Bytes(bytes) => Value::UserData(lua_ctx.create_userdata(Bytes(bytes))?),
+        ciborium::Value::Array(array) => {
+            let table = lua_ctx.create_table()?;
+            for (index, value) in array.into_iter().enumerate() {
+                table.raw_set(index as i64 + 1, to_lua(lua_ctx, value)?)?;
+            }
+            Value::Table(table)
+        }
+        ciborium::Value::Map(map) => {
+            let table = lua_ctx.create_table()?;
+            for (key, value) in map {
+                table.raw_set(to_lua(lua_ctx, key)?, to_lua(lua_ctx, value)?)?;
+            }
+            Value::Table(table)
+        }
+        ciborium::Value::Tag(tag, value) => {
+            let value = lua_ctx.create_registry_value(to_lua(lua_ctx, *value)?)?;
+            Value::UserData(lua_ctx.create_userdata(Tag { tag, value })?)
+        }
+        // ciborium has no other values, but its Value is non-exhaustive
+        _ => json::null(),
+    })
+}
+
+fn to_cbor<'lua>(
+    lua_ctx: Context<'lua>,
+    value: Value<'lua>,
+    depth: usize,
+) -> Result<ciborium::Value> {
+    if depth >= MAX_DEPTH {
+        return Err(encode_error("values are nested too deeply".to_string()));
+    }
+    Ok(match value {
+        Value::Nil => ciborium::Value::Null,
+        Value::LightUserData(LightUserData(pointer)) if pointer.is_null() => ciborium::Value::Null,
+        Value::Boolean(boolean) => ciborium::Value::Bool(boolean),
+        Value::Integer(integer) => ciborium::Value::Integer(integer.into()),
+        Value::Number(number) => ciborium::Value::Float(number),
+        Value::String(string) => match string.to_str() {
+            Ok(text) => ciborium::Value::Text(text.to_string()),
+            Err(_) => ciborium::Value::Bytes(string.as_bytes().to_vec()),
+        },
+        Value::Table(table) => {
+            let length = table.raw_len() as usize;
+            let pairs = table
+                .clone()
+                .pairs::<Value, Value>()
+                .collect::<Result<Vec<_>>>()?;
+            if length > 0 && pairs.len() == length {
+                let mut array = Vec::with_capacity(length);
+                for index in 1..=length {
+                    array.push(to_cbor(lua_ctx, table.raw_get(index as i64)?, depth + 1)?);
+                }
+                ciborium::Value::Array(array)
+            } else {
+                let mut map = Vec::with_capacity(pairs.len());
+                for (key, value) in pairs {
+                    map.push((
+                        to_cbor(lua_ctx, key, depth + 1)?,
+                        to_cbor(lua_ctx, value, depth + 1)?,
+                    ));
+                }
+                ciborium::Value::Map(map)
+            }
+        }
+        Value::UserData(userdata) if userdata.is::<Bytes>() => {
+            ciborium::Value::Bytes(userdata.borrow::<Bytes>()?.0.clone())
+        }
+        Value::UserData(userdata) if userdata.is::<Tag>() => {
+            let tagged = userdata.borrow::<Tag>()?;
+            let value = lua_ctx.registry_value(&tagged.value)?;
+            ciborium::Value::Tag(tagged.tag, Box::new(to_cbor(lua_ctx, value, depth + 1)?))
+        }
+        value => return Err(encode_error(format!("{} values", value.type_name()))),
+    })
+}
+
+fn decode<'lua>(
+    lua_ctx: Context<'lua>,
+    (bytes, position): (rlua::String<'lua>, Option<i64>),
+) -> Result<MultiValue<'lua>> {
+    let bytes = bytes.as_bytes();
+    let position = position.unwrap_or(1);
+    if position < 1 || position as usize > bytes.len() {
+        return Err(rlua::Error::RuntimeError(format!(
+            "bad argument #2 to 'decode' (position {} is outside of the {} bytes)",
+            position,
+            bytes.len()
+        )));
+    }
+    let mut reader = Cursor::new(&bytes[position as usize - 1..]);
+    let value = ciborium::de::from_reader::<ciborium::Value, _>(&mut reader)
+        .map_err(|source| rlua::Error::external(error::Error::Cbor { source }))?;
+    let next = position + reader.position() as i64;
+    Ok(MultiValue::from_vec(vec![
+        to_lua(lua_ctx, value)?,
+        Value::Integer(next),
+    ]))
+}
+
+pub fn load_cbor_library(lua: &Lua) -> Result<()> {
+    lua.context(|lua_ctx| {
+        let cbor = lua_ctx.create_table()?;
+
+        cbor.set(
+            "encode",
+            lua_ctx.create_function(|lua_ctx, value: Value| {
+                let mut bytes = Vec::new();
+                ciborium::ser::into_writer(&to_cbor(lua_ctx, value, 0)?, &mut bytes)
+                    .map_err(|error| encode_error(error.to_string()))?;
+                lua_ctx.create_string(&bytes)
+            })?,
+        )?;
+
+        cbor.set("decode", lua_ctx.create_function(decode)?)?;
+
+        cbor.set(
+            "bytes",
+            lua_ctx.create_function(|_, data: rlua::String| Ok(Bytes(data.as_bytes().to_vec())))?,
+        )?;
+
+        cbor.set(
+            "tag",
+            lua_ctx.create_function(|lua_ctx, (tag, value): (u64, Value)| {
+                let value = lua_ctx.create_registry_value(value)?;
+                Ok(Tag { tag, value })
+            })?,
+        )?;
+
+        cbor.set("null", json::null())?;
+
+        help::register_module_docs(
+            lua_ctx,
+            "cbor",
+            "CBOR encoding and decoding",
+            &cbor,
+            CBOR_DOCS,
+        )?;
+        lua_ctx.globals().set("cbor", cbor)?;
+
+        Ok(())
+    })
+}
@@ -0,0 +1,253 @@
+/*
+   Copyright (C) 2022  Kalka
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License, or (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use clap::{ArgMatches, Args, Parser, Subcommand, ValueHint};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::{config, output, timeout};
+
+const AFTER_HELP: &str = "\
+A script of `-`, or piped input, reads the script from stdin. A directory runs
+the project in it from its init.lua or main.lua.
+
+Options must come before the script, everything after it is passed to the script.";
+
+/// A terminal for Lua. Runs the script, or starts the REPL when there is no script or code to
+/// run.
+#[derive(Parser)]
+#[command(
+    name = "rluaterm",
+    args_conflicts_with_subcommands = true,
+    disable_version_flag = true,
+    after_help = AFTER_HELP
+)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    #[command(flatten)]
+    pub run: RunArgs,
+
+    /// Show the version and the available modules
+    #[arg(short = 'v', long)]
+    pub version: bool,
+
+    /// Color output
+    #[arg(long, global = true, value_name = "MODE",
+        value_parser = clap::builder::PossibleValuesParser::new(config::COLOR_MODES))]
+    pub color: Option<String>,
+
+    /// Lowest level of log messages shown
+    #[arg(long, global = true, value_name = "LEVEL",
+        value_parser = clap::builder::PossibleValuesParser::new(config::LOG_LEVELS))]
+    pub log_level: Option<String>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Run a script, or the project in a directory
+    Run(RunArgs),
+    /// Run a script, then the jobs it scheduled with schedule.cron, logging each run
+    Schedule(RunArgs),
+    /// Start the REPL
+    Repl(SessionArgs),
+    /// Report syntax errors without running anything
+    Check {
+        /// Lua files, or directories to look for them in
+        #[arg(required = true, value_name = "FILE OR DIR", value_hint = ValueHint::AnyPath)]
+        paths: Vec<String>,
+    },
+    /// Report unused and shadowed locals, undefined globals and unreachable code
+    Lint {
+        /// Lua files, or directories to look for them in
+        #[arg(required = true, value_name = "FILE OR DIR", value_hint = ValueHint::AnyPath)]
+        paths: Vec<String>,
+    },
+    /// Format Lua files in place
+    Fmt {
+        /// List the files that aren't formatted instead, failing if there are any
+        #[arg(long)]
+        check: bool,
+        /// Lua files, or directories to look for them in
+        #[arg(required = true, value_name = "FILE OR DIR", value_hint = ValueHint::AnyPath)]
+        paths: Vec<String>,
+    },
+    /// Compile a script to bytecode, which rluaterm runs like any other script
+    Compile {
+        #[arg(value_hint = ValueHint::FilePath)]
+        script: PathBuf,
+        /// Where to write the bytecode, the script path with a .luac extension by default
+        #[arg(short, long, value_hint = ValueHint::FilePath)]
+        output: Option<PathBuf>,
+        /// Leave out debug information such as line numbers
+        #[arg(long)]
+        strip: bool,
+    },
+    /// Pack a script and the modules it requires into one Lua file
+    Bundle {
+        #[arg(value_hint = ValueHint::FilePath)]
+        script: PathBuf,
+        /// Where to write the bundle, <script>.bundle.lua by default
+        #[arg(short, long, value_hint = ValueHint::FilePath)]
+        output: Option<PathBuf>,
+    },
+    /// Make an executable that runs the script with all of its arguments
+    Build {
+        #[arg(value_hint = ValueHint::FilePath)]
+        script: PathBuf,
+        /// Where to write the executable, named after the script by default
+        #[arg(short, long, value_hint = ValueHint::FilePath)]
+        output: Option<PathBuf>,
+    },
+    /// Run the tests in *_test.lua and test_*.lua files
+    Test {
+        /// Test files, or directories to look for them in, the current directory by default
+        #[arg(value_name = "FILE OR DIR", value_hint = ValueHint::AnyPath)]
+        paths: Vec<String>,
+        /// Only run the tests whose name contains the text
+        #[arg(short, long, value_name = "TEXT")]
+        filter: Option<String>,
+        /// Number of test files to run at once, the number of CPUs by default
+        #[arg(short, long, value_name = "N")]
+        jobs: Option<usize>,
+    },
+    /// Install pure-Lua packages from LuaRocks or git into lua_modules
+    Install {
+        /// Package names, like inspect or inspect@3.1.3, or git URLs, with a branch, tag or
+        /// commit after #. Without any, installs the project's dependencies as locked in
+        /// rluaterm.lock
+        #[arg(value_name = "PACKAGE")]
+        packages: Vec<String>,
+    },
+    /// Connect to a REPL served with --listen
+    Attach {
+        address: String,
+        /// Token to authenticate with
+        #[arg(long)]
+        token: Option<String>,
+    },
+    /// Print the completion script for a shell
+    Completions { shell: clap_complete::Shell },
+}
+
+/// Options for running Lua, with or without a script.
+#[derive(Args, Default)]
+pub struct SessionArgs {
+    /// Run a string of Lua, can be given several times
+    #[arg(short = 'e', value_name = "CODE")]
+    pub execute: Vec<String>,
+
+    /// Require a module into a global before running anything else
+    #[arg(short = 'l', value_name = "[NAME=]MODULE")]
+    pub library: Vec<String>,
+
+    /// Load modules from the directory too, can be given several times
+    #[arg(long, value_name = "DIR", value_hint = ValueHint::DirPath)]
+    pub path: Vec<PathBuf>,
+
+    /// Raise on reads of undefined globals, see help(strict)
+    #[arg(long)]
+    pub strict: bool,
+
+    /// Abort any chunk running longer than e.g. 30s, 500ms or 2m
+    #[arg(long, value_name = "TIME", value_parser = parse_timeout)]
+    pub timeout: Option<Duration>,
+
+    /// Disable HTTP, the memory library, processes and file access
+    #[arg(long)]
+    pub sandbox: bool,
+
+    /// Allow HTTP requests to the hosts in the sandbox
+    #[arg(long, value_name = "HOSTS", value_delimiter = ',')]
+    pub allow_net: Vec<String>,
+
+    /// Allow reading files under the directories in the sandbox
+    #[arg(long, value_name = "DIRS", value_delimiter = ',', value_hint = ValueHint::DirPath)]
+    pub allow_read: Vec<String>,
+
+    /// Serve the REPL to remote clients over TCP
+    #[arg(long, value_name = "ADDRESS")]
+    pub listen: Option<String>,
+
+    /// Token remote clients authenticate with
+    #[arg(long)]
+    pub token: Option<String>,
+}
+
+/// Options for running a script.
+#[derive(Args, Default)]
+pub struct RunArgs {
+    #[command(flatten)]
+    pub session: SessionArgs,
+
+    /// Start the REPL after running the script
+    #[arg(short)]
+    pub interactive: bool,
+
+    /// Rerun the script whenever it or a module it requires changes
+    #[arg(long)]
+    pub watch: bool,
+
+    /// Time every function the script calls and print the slowest ones once it's done
+    #[arg(long)]
+    pub profile: bool,
+
+    /// Write the profile as folded stacks for flamegraph tools, implies --profile
+    #[arg(long, value_name = "FILE", value_hint = ValueHint::FilePath)]
+    pub profile_output: Option<PathBuf>,
+
+    /// Print the values the script returns, or its error, on stdout, with everything else on
+    /// stderr
+    #[arg(long, value_name = "FORMAT",
+        value_parser = clap::builder::PossibleValuesParser::new(output::FORMATS))]
+    pub output_format: Option<String>,
+
+    /// The script and its arguments
+    #[arg(
+        value_name = "SCRIPT",
+        value_hint = ValueHint::FilePath,
+        trailing_var_arg = true,
+        allow_hyphen_values = true
+    )]
+    pub script: Vec<String>,
+}
+
+fn parse_timeout(text: &str) -> Result<Duration, String> {
+    timeout::parse_duration(text).ok_or_else(|| "use e.g. 30s, 500ms or 2m".to_string())
+}
+
+/// The code given with -e and the modules given with -l, as `("-e", code)` and `("-l", spec)`
+/// in the order they were given, like in the standalone lua interpreter.
+pub fn preloads(matches: &ArgMatches) -> Vec<(String, String)> {
+    let matches = match matches.subcommand() {
+        Some(("run" | "repl" | "schedule", matches)) => matches,
+        _ => matches,
+    };
+    let mut preloads = Vec::new();
+    for (id, flag) in [("execute", "-e"), ("library", "-l")] {
+        let values = matches.get_many::<String>(id).into_iter().flatten();
+        let indices = matches.indices_of(id).into_iter().flatten();
+        for (index, value) in indices.zip(values) {
+            preloads.push((index, flag.to_string(), value.clone()));
+        }
+    }
+    preloads.sort_by_key(|(index, _, _)| *index);
+    preloads
+        .into_iter()
+        .map(|(_, flag, value)| (flag, value))
+        .collect()
+}
@@ -0,0 +1,89 @@
+/*
+   Copyright (C) 2022  Kalka
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License, or (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use arboard::Clipboard;
+use rlua::{Lua, Result};
+use std::sync::{Mutex, OnceLock};
+
+use crate::error;
+use crate::help::{self, FunctionDoc};
+
+const CLIPBOARD_DOCS: &[FunctionDoc] = &[
+    FunctionDoc {
+        name: "get",
+        signature: "clipboard.get() -> string",
+        description: "The text on the system clipboard, or nil when there's none.",
+        example: "for line in clipboard.get():gmatch(\"[^\\n]+\") do\n    print(line)\nend",
+    },
+    FunctionDoc {
+        name: "set",
+        signature: "clipboard.set(text)",
+        description: "Puts the text on the system clipboard. On X11 it can be pasted for as long as rluaterm runs, unless a clipboard manager keeps it.",
+        example: "clipboard.set(response.token)",
+    },
+];
+
+// Kept open for the whole run, since on X11 the text set is served by the clipboard's own thread
+// and is gone once it's dropped
+static CLIPBOARD: OnceLock<Mutex<Option<Clipboard>>> = OnceLock::new();
+
+fn with_clipboard<T>(
+    f: impl FnOnce(&mut Clipboard) -> std::result::Result<T, arboard::Error>,
+) -> Result<T> {
+    let clipboard = CLIPBOARD.get_or_init(|| Mutex::new(None));
+    let mut clipboard = clipboard
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    let result = match clipboard.as_mut() {
+        Some(clipboard) => f(clipboard),
+        None => Clipboard::new().and_then(|opened| f(clipboard.insert(opened))),
+    };
+    result.map_err(|source| rlua::Error::external(error::Error::Clipboard { source }))
+}
+
+pub fn load_clipboard_library(lua: &Lua) -> Result<()> {
+    lua.context(|lua_ctx| {
+        let clipboard = lua_ctx.create_table()?;
+
+        clipboard.set(
+            "get",
+            lua_ctx.create_function(|_, ()| {
+                with_clipboard(|clipboard| match clipboard.get_text() {
+                    Ok(text) => Ok(Some(text)),
+                    Err(arboard::Error::ContentNotAvailable) => Ok(None),
+                    Err(err) => Err(err),
+                })
+            })?,
+        )?;
+
+        clipboard.set(
+            "set",
+            lua_ctx.create_function(|_, text: String| {
+                with_clipboard(|clipboard| clipboard.set_text(text))
+            })?,
+        )?;
+
+        help::register_module_docs(
+            lua_ctx,
+            "clipboard",
+            "Reading and writing the system clipboard",
+            &clipboard,
+            CLIPBOARD_DOCS,
+        )?;
+        lua_ctx.globals().set("clipboard", clipboard)?;
+        Ok(())
+    })
+}
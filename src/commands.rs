@@ -0,0 +1,217 @@
+/*
+   Copyright (C) 2022  Kalka
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License, or (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use colored::Colorize;
+use cumulus::logger;
+use rlua::{Context, Lua, Result, Table, Value};
+use std::path::Path;
+
+use crate::{help, pager, report, require, traceback, transcript};
+
+// Name of the registry table holding the names of the globals present before any user code ran
+const BUILTIN_GLOBALS_REGISTRY_KEY: &str = "rluaterm.builtin_globals";
+
+/// Records the current globals as built-in, so `:env` and `:who` only list what the user defined.
+pub fn snapshot_builtin_globals(lua: &Lua) -> Result<()> {
+    lua.context(|lua_ctx| {
+        let builtins = lua_ctx.create_table()?;
+        for pair in lua_ctx.globals().pairs::<Value, Value>() {
+            let (name, _) = pair?;
+            builtins.set(name, true)?;
+        }
+        lua_ctx.set_named_registry_value(BUILTIN_GLOBALS_REGISTRY_KEY, builtins)?;
+        Ok(())
+    })
+}
+
+// User-defined globals sorted by name
+fn user_globals(lua_ctx: Context) -> Result<Vec<(String, Value)>> {
+    let builtins = lua_ctx.named_registry_value::<_, Table>(BUILTIN_GLOBALS_REGISTRY_KEY)?;
+    let mut globals = Vec::new();
+    for pair in lua_ctx.globals().pairs::<Value, Value>() {
+        let (name, value) = pair?;
+        if builtins.contains_key(name.clone())? {
+            continue;
+        }
+        let name = match name {
+            Value::String(name) => name.to_str()?.to_string(),
+            Value::Integer(name) => name.to_string(),
+            Value::Number(name) => name.to_string(),
+            name => format!("<{}>", name.type_name()),
+        };
+        globals.push((name, value));
+    }
+    globals.sort_by(|(a, _), (b, _)| a.cmp(b));
+    Ok(globals)
+}
+
+// A rough size for the value: entries for tables, bytes for strings
+fn value_size(value: &Value) -> Result<String> {
+    Ok(match value {
+        Value::Table(table) => {
+            let mut entries = 0;
+            for pair in table.clone().pairs::<Value, Value>() {
+                pair?;
+                entries += 1;
+            }
+            format!("{} entries", entries)
+        }
+        Value::String(string) => format!("{} bytes", string.as_bytes().len()),
+        _ => String::new(),
+    })
+}
+
+fn print_env(lua_ctx: Context) -> Result<()> {
+    let globals = user_globals(lua_ctx)?;
+    if globals.is_empty() {
+        println!("No user-defined globals");
+        return Ok(());
+    }
+    for (name, value) in globals {
+        println!(
+            "  {:<24} {:<10} {}",
+            name.cyan(),
+            value.type_name().yellow(),
+            value_size(&value)?
+        );
+    }
+    Ok(())
+}
+
+fn print_who(lua_ctx: Context) -> Result<()> {
+    let names = user_globals(lua_ctx)?
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect::<Vec<_>>();
+    if names.is_empty() {
+        println!("No user-defined globals");
+    } else {
+        println!("{}", names.join("  "));
+    }
+    Ok(())
+}
+
+// :transcript <file> [--ansi] starts recording, :transcript stop finishes it
+fn run_transcript(argument: &str) {
+    let mut args = argument.split_whitespace();
+    let result = match args.next() {
+        None => {
+            match transcript::active_path() {
+                Some(path) => println!("Recording transcript to {}", path.display()),
+                None => println!("Usage: :transcript <file> [--ansi] | :transcript stop"),
+            }
+            return;
+        }
+        Some("stop") => transcript::stop().map(|path| match path {
+            Some(path) => println!("Saved transcript to {}", path.display().to_string().green()),
+            None => println!("No transcript is being recorded"),
+        }),
+        Some(path) => {
+            let keep_ansi = args.any(|arg| arg == "--ansi");
+            transcript::start(Path::new(path), !keep_ansi)
+                .map(|_| println!("Recording transcript to {}", path.green()))
+        }
+    };
+    if let Err(error) = result {
+        logger::error(&format!("Failed to write transcript [{}]", error));
+    }
+}
+
+fn print_commands() {
+    println!("{}", "REPL commands:".bold());
+    println!(
+        "  {:<16} List user-defined globals with their types and sizes",
+        ":env"
+    );
+    println!("  {:<16} List the names of user-defined globals", ":who");
+    println!("  {:<16} Show the documentation of a value", ":doc <name>");
+    println!(
+        "  {:<16} Record inputs and outputs into a Markdown file",
+        ":transcript <file>"
+    );
+    println!(
+        "  {:<16} Page through the full output of the last chunk",
+        ":page"
+    );
+    println!(
+        "  {:<16} Run a module's file again, or every module's",
+        ":reload [module]"
+    );
+    println!(
+        "  {:<16} Run a line of Lua and show how long it took",
+        ":time <code>"
+    );
+    println!("  {:<16} Show this list", ":help");
+}
+
+/// Runs a `:command` typed at the REPL prompt.
+pub fn run_command(lua: &Lua, line: &str) -> Result<()> {
+    let line = line.trim_start_matches(':');
+    let (command, argument) = line.split_once(' ').unwrap_or((line, ""));
+    let argument = argument.trim();
+    lua.context(|lua_ctx| match command {
+        "env" => print_env(lua_ctx),
+        "who" => print_who(lua_ctx),
+        "doc" if argument.is_empty() => {
+            println!("Usage: :doc <name>");
+            Ok(())
+        }
+        "doc" => help::print_help(lua_ctx, Value::String(lua_ctx.create_string(argument)?)),
+        "transcript" => {
+            run_transcript(argument);
+            Ok(())
+        }
+        "reload" if argument.is_empty() => {
+            match require::reload_all(lua_ctx) {
+                Ok(names) if names.is_empty() => println!("No modules were required from files"),
+                Ok(names) => println!("Reloaded {}", names.join(", ").green()),
+                Err(error) => report::report_error("Failed to reload", "", "", &error),
+            }
+            Ok(())
+        }
+        "reload" => {
+            let package = lua_ctx.globals().get::<_, Table>("package")?;
+            let reload = package.get::<_, rlua::Function>("reload")?;
+            match traceback::call_with_traceback::<_, Value>(lua_ctx, reload, argument) {
+                Ok(_) => println!("Reloaded {}", argument.green()),
+                Err(error) => {
+                    report::report_error(&format!("Failed to reload {}", argument), "", "", &error)
+                }
+            }
+            Ok(())
+        }
+        // The REPL runs `:time <code>` itself, so only a bare `:time` ends up here
+        "time" => {
+            println!("Usage: :time <code>");
+            Ok(())
+        }
+        "page" => {
+            pager::page_last_output();
+            Ok(())
+        }
+        "help" => {
+            print_commands();
+            Ok(())
+        }
+        command => {
+            println!(
+                "Unknown command {}, use :help to list commands",
+                format!(":{}", command).yellow()
+            );
+            Ok(())
+        }
+    })
+}
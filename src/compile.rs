@@ -0,0 +1,80 @@
+/*
+   Copyright (C) 2022  Kalka
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License, or (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use rlua::{Function, Lua, Result, Table};
+use std::path::{Path, PathBuf};
+
+use crate::report;
+
+// Compiled chunks start with "\x1bLua", which can never start Lua source
+const BYTECODE_SIGNATURE: &[u8] = b"\x1bLua";
+
+/// Whether the contents of a script are compiled bytecode rather than source.
+pub fn is_bytecode(contents: &[u8]) -> bool {
+    contents.starts_with(BYTECODE_SIGNATURE)
+}
+
+/// Where `rluaterm compile` writes to without `-o`: the script path with a `.luac` extension.
+pub fn default_output(script: &Path) -> PathBuf {
+    script.with_extension("luac")
+}
+
+/// Compiles the script into bytecode with `string.dump`, leaving out debug information such as
+/// line numbers and local names when `strip` is set. Returns None after reporting why the
+/// script didn't compile.
+pub fn compile_script(script: &Path, strip: bool) -> Result<Option<Vec<u8>>> {
+    let mut contents = std::fs::read(script).map_err(rlua::Error::external)?;
+    crate::strip_shebang(&mut contents);
+    let chunk_name = format!("@{}", script.display());
+    // Compiling needs nothing but the string library
+    let lua = Lua::new();
+    lua.context(|lua_ctx| {
+        let function = match lua_ctx
+            .load(&contents)
+            .set_name(&chunk_name)?
+            .into_function()
+        {
+            Ok(function) => function,
+            Err(error) => {
+                report::report_error(
+                    &format!("Failed to compile {}", script.display()),
+                    &chunk_name,
+                    &String::from_utf8_lossy(&contents),
+                    &error,
+                );
+                return Ok(None);
+            }
+        };
+        let dump = lua_ctx
+            .globals()
+            .get::<_, Table>("string")?
+            .get::<_, Function>("dump")?;
+        let bytecode = dump.call::<_, rlua::String>((function, strip))?;
+        Ok(Some(bytecode.as_bytes().to_vec()))
+    })
+}
+
+/// Compiles the script into a bytecode file. Returns whether the script compiled, after
+/// reporting why it didn't otherwise.
+pub fn compile(script: &Path, output: &Path, strip: bool) -> Result<bool> {
+    match compile_script(script, strip)? {
+        Some(bytecode) => {
+            std::fs::write(output, bytecode).map_err(rlua::Error::external)?;
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
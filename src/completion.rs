@@ -0,0 +1,88 @@
+/*
+   Copyright (C) 2022  Kalka
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License, or (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use colored::Colorize;
+use rlua::{Context, Result, Value};
+use rustyline::completion::Pair;
+
+use crate::help;
+
+/// A name the line can be completed with.
+pub struct Completion {
+    pub name: String,
+    // First line of the value's documentation, if it has any
+    pub summary: Option<String>,
+}
+
+fn is_path_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '.' || c == ':'
+}
+
+/// Completes the dotted name the line ends with against the globals, e.g. `http.ge` to
+/// `http.get`. Returns the byte offset the candidates replace the line from.
+pub fn complete(lua_ctx: Context, line: &str) -> Result<(usize, Vec<Completion>)> {
+    let start = line
+        .char_indices()
+        .rev()
+        .find(|(_, c)| !is_path_char(*c))
+        .map(|(i, c)| i + c.len_utf8())
+        .unwrap_or(0);
+    let word = &line[start..];
+    let (prefix, partial) = match word.rfind(['.', ':']) {
+        Some(i) => (&word[..=i], &word[i + 1..]),
+        None => ("", word),
+    };
+
+    let mut value = Value::Table(lua_ctx.globals());
+    for part in prefix.split(['.', ':']).filter(|part| !part.is_empty()) {
+        value = match value {
+            Value::Table(table) => table.get(part)?,
+            _ => return Ok((start, Vec::new())),
+        };
+    }
+    let table = match value {
+        Value::Table(table) => table,
+        _ => return Ok((start, Vec::new())),
+    };
+
+    let mut candidates = Vec::new();
+    for pair in table.pairs::<Value, Value>() {
+        let (key, value) = pair?;
+        if let Value::String(key) = key {
+            let key = key.to_str()?;
+            if key.starts_with(partial) {
+                candidates.push(Completion {
+                    name: format!("{}{}", prefix, key),
+                    summary: help::summary(lua_ctx, value)?,
+                });
+            }
+        }
+    }
+    candidates.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok((start, candidates))
+}
+
+/// A completion for the line editor, listed with its summary when there is one.
+pub fn candidate(name: &str, summary: Option<&str>) -> Pair {
+    let display = match summary {
+        Some(summary) => format!("{:<24} {}", name, summary.dimmed()),
+        None => name.to_string(),
+    };
+    Pair {
+        display,
+        replacement: name.to_string(),
+    }
+}
@@ -0,0 +1,549 @@
+/*
+   Copyright (C) 2022  Kalka
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License, or (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use std::io::{Read, Write};
+use std::path::Path;
+
+use flate2::write::{DeflateEncoder, GzEncoder, MultiGzDecoder, ZlibEncoder};
+use flate2::{Compression, Decompress, FlushDecompress, Status};
+use rlua::{Context, Function, Lua, Result, Table, UserData, UserDataMethods, Value};
+use zstd::stream::zio::Writer;
+
+use crate::error;
+use crate::hash::data_argument;
+use crate::help::{self, FunctionDoc};
+
+const COMPRESS_DOCS: &[FunctionDoc] = &[
+    FunctionDoc {
+        name: "gzip",
+        signature: "compress.gzip(data[, options]) -> bytes",
+        description: "Compresses a string or list of bytes as gzip, at options.level from 0 (none) to 9 (smallest), 6 by default.",
+        example: "fs.write(\"report.json.gz\", compress.gzip(json.encode(report)))",
+    },
+    FunctionDoc {
+        name: "gunzip",
+        signature: "compress.gunzip(data) -> bytes",
+        description: "Decompresses gzip, including several gzip files one after another, as concatenated logs are. Raises an error for data that isn't gzip.",
+        example: "local log = compress.gunzip(fs.read(\"access.log.1.gz\"))",
+    },
+    FunctionDoc {
+        name: "zstd",
+        signature: "compress.zstd(data[, options]) -> bytes",
+        description: "Compresses data as Zstandard, at options.level from 1 to 22, 3 by default, or negative levels for even faster compression.",
+        example: "fs.write(\"events.zst\", compress.zstd(table.concat(events, \"\\n\"), { level = 19 }))",
+    },
+    FunctionDoc {
+        name: "unzstd",
+        signature: "compress.unzstd(data) -> bytes",
+        description: "Decompresses Zstandard. Raises an error for data that isn't Zstandard.",
+        example: "local events = compress.unzstd(fs.read(\"events.zst\"))",
+    },
+    FunctionDoc {
+        name: "deflate",
+        signature: "compress.deflate(data[, options]) -> bytes",
+        description: "Compresses data as zlib, which is what HTTP's deflate encoding and PNG use, at options.level from 0 to 9, 6 by default. With options.raw, writes bare deflate without zlib's header and checksum, as zip files hold it.",
+        example: "local body = compress.deflate(payload)",
+    },
+    FunctionDoc {
+        name: "inflate",
+        signature: "compress.inflate(data[, options]) -> bytes",
+        description: "Decompresses zlib, or bare deflate with options.raw. Raises an error for data that isn't either.",
+        example: "local payload = compress.inflate(body)",
+    },
+    FunctionDoc {
+        name: "compressor",
+        signature: "compress.compressor(format[, options]) -> stream",
+        description: "Compresses data given a piece at a time, as \"gzip\", \"zstd\", \"deflate\" or \"raw\" deflate, with options.level as in compress.gzip. stream:update(data) compresses a string or list of bytes and returns whatever output is ready, maybe nothing yet, and stream:finish() returns the rest. Joined together, the outputs are the compressed data.",
+        example: "local stream = compress.compressor(\"gzip\")\nlocal out = fs.open(\"app.log.gz\", \"w\")\nfor line in io.lines(\"app.log\") do\n    out:write(stream:update(line .. \"\\n\"))\nend\nout:write(stream:finish())\nout:close()",
+    },
+    FunctionDoc {
+        name: "decompressor",
+        signature: "compress.decompressor(format) -> stream",
+        description: "Decompresses \"gzip\", \"zstd\", \"deflate\" or \"raw\" deflate given a piece at a time, with the same update and finish as compress.compressor. Raises an error from update or finish for data that isn't in the format.",
+        example: "local stream = compress.decompressor(\"zstd\")\nfor chunk in chunks do\n    io.write(stream:update(chunk))\nend\nio.write(stream:finish())",
+    },
+    FunctionDoc {
+        name: "stream_file",
+        signature: "compress.stream_file(src, dst, format[, options]) -> integer",
+        description: "Compresses the file src into dst as \"gzip\", \"zstd\", \"deflate\" or \"raw\" deflate, or with options.decompress decompresses it, a piece at a time so files of any size fit in memory, and returns how many bytes it wrote. options.level is as in compress.gzip, and options.progress is called with the bytes of src done so far and its size after each piece. dst is only replaced once it's all written.",
+        example: "compress.stream_file(\"app.log\", \"app.log.zst\", \"zstd\", {\n    progress = function(done, total)\n        io.write(string.format(\"\\r%d%%\", done * 100 // math.max(total, 1)))\n    end,\n})",
+    },
+];
+
+/// Compression or decompression writing its output to a buffer, which `take` empties.
+pub trait Codec {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<()>;
+    fn take(&mut self) -> Vec<u8>;
+    fn finish(self: Box<Self>) -> std::io::Result<Vec<u8>>;
+}
+
+macro_rules! codec {
+    ($($codec:ty),*) => {
+        $(
+            impl Codec for $codec {
+                fn write(&mut self, data: &[u8]) -> std::io::Result<()> {
+                    self.write_all(data)
+                }
+
+                fn take(&mut self) -> Vec<u8> {
+                    std::mem::take(self.get_mut())
+                }
+
+                fn finish(self: Box<Self>) -> std::io::Result<Vec<u8>> {
+                    (*self).finish()
+                }
+            }
+        )*
+    };
+}
+
+codec!(
+    GzEncoder<Vec<u8>>,
+    MultiGzDecoder<Vec<u8>>,
+    ZlibEncoder<Vec<u8>>,
+    DeflateEncoder<Vec<u8>>,
+    zstd::stream::write::Encoder<'static, Vec<u8>>
+);
+
+// zstd's own Decoder writer accepts a frame that's cut off, where this one
+// raises an error from finish
+type ZstdDecoder = Writer<Vec<u8>, zstd::stream::raw::Decoder<'static>>;
+
+impl Codec for ZstdDecoder {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<()> {
+        self.write_all(data)
+    }
+
+    fn take(&mut self) -> Vec<u8> {
+        std::mem::take(self.writer_mut())
+    }
+
+    fn finish(mut self: Box<Self>) -> std::io::Result<Vec<u8>> {
+        Writer::finish(&mut self)?;
+        Ok(self.into_inner().0)
+    }
+}
+
+// Inflates zlib or bare deflate, unlike flate2's writers raising an error from
+// finish when the data stops before the end of the stream
+struct Inflate {
+    decompress: Decompress,
+    output: Vec<u8>,
+    ended: bool,
+}
+
+impl Inflate {
+    fn new(zlib_header: bool) -> Inflate {
+        Inflate {
+            decompress: Decompress::new(zlib_header),
+            output: Vec::new(),
+            ended: false,
+        }
+    }
+
+    fn run(&mut self, mut input: &[u8]) -> std::io::Result<()> {
+        loop {
+            if self.ended {
+                return match input.is_empty() {
+                    true => Ok(()),
+                    false => Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        "there is more data after the end of the stream",
+                    )),
+                };
+            }
+            self.output.reserve(32 * 1024);
+            let (total_in, total_out) = (self.decompress.total_in(), self.decompress.total_out());
+            let status = self
+                .decompress
+                .decompress_vec(input, &mut self.output, FlushDecompress::None)
+                .map_err(|error| std::io::Error::new(std::io::ErrorKind::InvalidData, error))?;
+            let consumed = (self.decompress.total_in() - total_in) as usize;
+            input = &input[consumed..];
+            if status == Status::StreamEnd {
+                self.ended = true;
+            } else if consumed == 0 && self.decompress.total_out() == total_out {
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl Codec for Inflate {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<()> {
+        self.run(data)
+    }
+
+    fn take(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.output)
+    }
+
+    fn finish(mut self: Box<Self>) -> std::io::Result<Vec<u8>> {
+        self.run(&[])?;
+        match self.ended {
+            true => Ok(self.output),
+            false => Err(std::io::ErrorKind::UnexpectedEof.into()),
+        }
+    }
+}
+
+/// The name of a format for error messages, like "gzip data".
+pub fn format_name(format: &str) -> &'static str {
+    match format {
+        "gzip" => "gzip data",
+        "zstd" => "Zstandard data",
+        "deflate" => "zlib data",
+        _ => "deflate data",
+    }
+}
+
+/// options.level, checked against the levels the format supports.
+pub fn level_option(options: &Option<Table>, function: &str, format: &str) -> Result<Option<i32>> {
+    let level = match options {
+        Some(options) => options.get::<_, Option<i32>>("level")?,
+        None => None,
+    };
+    let range = match format {
+        "zstd" => zstd::compression_level_range(),
+        _ => 0..=9,
+    };
+    match level {
+        Some(level) if !range.contains(&level) => Err(rlua::Error::RuntimeError(format!(
+            "bad option 'level' to '{}' ({} to {} expected, got {})",
+            function,
+            range.start(),
+            range.end(),
+            level
+        ))),
+        level => Ok(level),
+    }
+}
+
+/// A compressor for the format, "gzip", "zstd", "deflate" or "raw".
+pub fn compressor(format: &str, level: Option<i32>) -> std::io::Result<Box<dyn Codec + Send>> {
+    let flate_level = || Compression::new(level.unwrap_or(6) as u32);
+    Ok(match format {
+        "gzip" => Box::new(GzEncoder::new(Vec::new(), flate_level())),
+        "zstd" => Box::new(zstd::stream::write::Encoder::new(
+            Vec::new(),
+            level.unwrap_or(zstd::DEFAULT_COMPRESSION_LEVEL),
+        )?),
+        "deflate" => Box::new(ZlibEncoder::new(Vec::new(), flate_level())),
+        _ => Box::new(DeflateEncoder::new(Vec::new(), flate_level())),
+    })
+}
+
+/// A decompressor for the format, "gzip", "zstd", "deflate" or "raw".
+pub fn decompressor(format: &str) -> std::io::Result<Box<dyn Codec + Send>> {
+    Ok(match format {
+        "gzip" => Box::new(MultiGzDecoder::new(Vec::new())),
+        "zstd" => Box::new(ZstdDecoder::new(
+            Vec::new(),
+            zstd::stream::raw::Decoder::new()?,
+        )),
+        "deflate" => Box::new(Inflate::new(true)),
+        _ => Box::new(Inflate::new(false)),
+    })
+}
+
+/// An error for data a decompressor couldn't make sense of.
+pub fn decompress_error(format: &str, source: std::io::Error) -> rlua::Error {
+    let message = match source.kind() {
+        std::io::ErrorKind::UnexpectedEof | std::io::ErrorKind::WriteZero => {
+            "the data ends in the middle of it".to_string()
+        }
+        _ => source.to_string(),
+    };
+    rlua::Error::external(error::Error::Decode {
+        format: format_name(format),
+        message,
+    })
+}
+
+fn compress_error(format: &str, source: std::io::Error) -> rlua::Error {
+    rlua::Error::RuntimeError(format!("cannot compress to {}: {}", format, source))
+}
+
+fn codec_error(format: &str, decompressing: bool, source: std::io::Error) -> rlua::Error {
+    match decompressing {
+        true => decompress_error(format, source),
+        false => compress_error(format, source),
+    }
+}
+
+// A compressor or decompressor for compress.compressor and compress.decompressor
+struct Stream {
+    format: &'static str,
+    decompressing: bool,
+    codec: Option<Box<dyn Codec + Send>>,
+}
+
+impl Stream {
+    fn error(&self, source: std::io::Error) -> rlua::Error {
+        codec_error(self.format, self.decompressing, source)
+    }
+}
+
+fn finished(method: &str) -> rlua::Error {
+    rlua::Error::RuntimeError(format!("cannot {} a stream that has finished", method))
+}
+
+impl UserData for Stream {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method_mut("update", |lua_ctx, stream, data: Value| {
+            let data = data_argument(data, "update", 1)?;
+            let codec = stream.codec.as_mut().ok_or_else(|| finished("update"))?;
+            let written = codec.write(&data);
+            let output = codec.take();
+            written.map_err(|source| stream.error(source))?;
+            lua_ctx.create_string(&output)
+        });
+        methods.add_method_mut("finish", |lua_ctx, stream, ()| {
+            let codec = stream.codec.take().ok_or_else(|| finished("finish"))?;
+            let output = codec.finish();
+            lua_ctx.create_string(&output.map_err(|source| stream.error(source))?)
+        });
+    }
+}
+
+fn format_argument(format: &str, function: &str, position: usize) -> Result<&'static str> {
+    match format {
+        "gzip" => Ok("gzip"),
+        "zstd" => Ok("zstd"),
+        "deflate" => Ok("deflate"),
+        "raw" => Ok("raw"),
+        format => Err(rlua::Error::RuntimeError(format!(
+            "bad argument #{} to '{}' (unknown format '{}', expected gzip, zstd, deflate or raw)",
+            position, function, format
+        ))),
+    }
+}
+
+fn compress(format: &str, data: &[u8], level: Option<i32>) -> Result<Vec<u8>> {
+    let mut codec = compressor(format, level).map_err(|source| compress_error(format, source))?;
+    codec
+        .write(data)
+        .and_then(|_| codec.finish())
+        .map_err(|source| compress_error(format, source))
+}
+
+fn decompress(format: &str, data: &[u8]) -> Result<Vec<u8>> {
+    let mut codec = decompressor(format).map_err(|source| decompress_error(format, source))?;
+    codec
+        .write(data)
+        .and_then(|_| codec.finish())
+        .map_err(|source| decompress_error(format, source))
+}
+
+// How much of a file compress.stream_file reads at a time
+const CHUNK_SIZE: usize = 64 * 1024;
+
+// Options for compress.stream_file
+struct StreamOptions<'lua> {
+    decompressing: bool,
+    level: Option<i32>,
+    progress: Option<Function<'lua>>,
+}
+
+fn stream_file<'lua>(
+    lua_ctx: Context<'lua>,
+    src: &str,
+    dst: &str,
+    format: &str,
+    options: StreamOptions<'lua>,
+) -> Result<u64> {
+    let read_error = |source| {
+        rlua::Error::external(error::Error::Read {
+            path: src.to_string(),
+            source,
+        })
+    };
+    let write_error = |source| {
+        rlua::Error::external(error::Error::Write {
+            path: dst.to_string(),
+            source,
+        })
+    };
+    let mut input = std::fs::File::open(src).map_err(read_error)?;
+    let total = input.metadata().map_err(read_error)?.len();
+    let mut codec = match options.decompressing {
+        true => decompressor(format),
+        false => compressor(format, options.level),
+    }
+    .map_err(|source| codec_error(format, options.decompressing, source))?;
+
+    // Like persist.save, the output goes next to dst and is renamed over it when it's done
+    let file_name = Path::new(dst)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let temporary = Path::new(dst).with_file_name(format!(".{}.tmp", file_name));
+    let mut output = std::fs::File::create(&temporary).map_err(write_error)?;
+    let stream = || -> Result<u64> {
+        let mut buffer = vec![0; CHUNK_SIZE];
+        let (mut done, mut written) = (0, 0);
+        loop {
+            let read = match input.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(read) => read,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(source) => return Err(read_error(source)),
+            };
+            codec
+                .write(&buffer[..read])
+                .map_err(|source| codec_error(format, options.decompressing, source))?;
+            let chunk = codec.take();
+            output.write_all(&chunk).map_err(write_error)?;
+            done += read as u64;
+            written += chunk.len() as u64;
+            if let Some(progress) = &options.progress {
+                progress.call::<_, ()>((done, total))?;
+            }
+            crate::check_interrupt(lua_ctx)?;
+        }
+        let chunk = codec
+            .finish()
+            .map_err(|source| codec_error(format, options.decompressing, source))?;
+        output.write_all(&chunk).map_err(write_error)?;
+        Ok(written + chunk.len() as u64)
+    };
+    match stream().and_then(|written| {
+        std::fs::rename(&temporary, dst).map_err(write_error)?;
+        Ok(written)
+    }) {
+        Ok(written) => Ok(written),
+        Err(error) => {
+            let _ = std::fs::remove_file(&temporary);
+            Err(error)
+        }
+    }
+}
+
+// deflate and inflate take options.raw for bare deflate
+fn deflate_format(options: &Option<Table>) -> Result<&'static str> {
+    let raw = match options {
+        Some(options) => options.get::<_, Option<bool>>("raw")?.unwrap_or(false),
+        None => false,
+    };
+    Ok(if raw { "raw" } else { "deflate" })
+}
+
+pub fn load_compress_library(lua: &Lua) -> Result<()> {
+    lua.context(|lua_ctx| {
+        let module = lua_ctx.create_table()?;
+
+        for (name, unname, format) in [
+            ("gzip", "gunzip", "gzip"),
+            ("zstd", "unzstd", "zstd"),
+            ("deflate", "inflate", "deflate"),
+        ] {
+            module.set(
+                name,
+                lua_ctx.create_function(
+                    move |lua_ctx, (data, options): (Value, Option<Table>)| {
+                        let data = data_argument(data, name, 1)?;
+                        let format = match format {
+                            "deflate" => deflate_format(&options)?,
+                            format => format,
+                        };
+                        let level = level_option(&options, name, format)?;
+                        lua_ctx.create_string(&compress(format, &data, level)?)
+                    },
+                )?,
+            )?;
+            module.set(
+                unname,
+                lua_ctx.create_function(
+                    move |lua_ctx, (data, options): (Value, Option<Table>)| {
+                        let data = data_argument(data, unname, 1)?;
+                        let format = match format {
+                            "deflate" => deflate_format(&options)?,
+                            format => format,
+                        };
+                        lua_ctx.create_string(&decompress(format, &data)?)
+                    },
+                )?,
+            )?;
+        }
+
+        module.set(
+            "compressor",
+            lua_ctx.create_function(|_, (format, options): (String, Option<Table>)| {
+                let format = format_argument(&format, "compressor", 1)?;
+                let level = level_option(&options, "compressor", format)?;
+                Ok(Stream {
+                    format,
+                    decompressing: false,
+                    codec: Some(
+                        compressor(format, level)
+                            .map_err(|source| compress_error(format, source))?,
+                    ),
+                })
+            })?,
+        )?;
+        module.set(
+            "decompressor",
+            lua_ctx.create_function(|_, format: String| {
+                let format = format_argument(&format, "decompressor", 1)?;
+                Ok(Stream {
+                    format,
+                    decompressing: true,
+                    codec: Some(
+                        decompressor(format).map_err(|source| decompress_error(format, source))?,
+                    ),
+                })
+            })?,
+        )?;
+
+        module.set(
+            "stream_file",
+            lua_ctx.create_function(
+                |lua_ctx, (src, dst, format, options): (String, String, String, Option<Table>)| {
+                    let format = format_argument(&format, "stream_file", 3)?;
+                    let (decompressing, progress) = match &options {
+                        Some(options) => (
+                            options
+                                .get::<_, Option<bool>>("decompress")?
+                                .unwrap_or(false),
+                            options.get::<_, Option<Function>>("progress")?,
+                        ),
+                        None => (false, None),
+                    };
+                    let level = match decompressing {
+                        true => None,
+                        false => level_option(&options, "stream_file", format)?,
+                    };
+                    let options = StreamOptions {
+                        decompressing,
+                        level,
+                        progress,
+                    };
+                    stream_file(lua_ctx, &src, &dst, format, options)
+                },
+            )?,
+        )?;
+
+        help::register_module_docs(
+            lua_ctx,
+            "compress",
+            "Compressing and decompressing data",
+            &module,
+            COMPRESS_DOCS,
+        )?;
+        lua_ctx.globals().set("compress", module)?;
+        Ok(())
+    })
+}
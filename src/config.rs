@@ -0,0 +1,204 @@
+/*
+   Copyright (C) 2022  Kalka
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License, or (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use cumulus::logger;
+use rlua::{Lua, Result, Table};
+use std::path::Path;
+use std::sync::OnceLock;
+
+use crate::config_dir;
+
+// Settings file in the user's config directory
+const USER_CONFIG_FILE: &str = "config.toml";
+// Settings file in the current directory, overriding the user's one
+const PROJECT_CONFIG_FILE: &str = "rluaterm.toml";
+
+pub const COLOR_MODES: &[&str] = &["auto", "always", "never"];
+// From the most to the least verbose
+pub const LOG_LEVELS: &[&str] = &["info", "warn", "error", "off"];
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+/// Interpreter defaults from `config.toml` and `rluaterm.toml`, which look like this:
+///
+/// ```toml
+/// color = "never"
+/// log_level = "warn"
+///
+/// [repl]
+/// history_size = 500
+/// prompt = "lua> "
+///
+/// [http.headers]
+/// User-Agent = "my-tool/1.0"
+///
+/// [modules]
+/// memory = false
+/// ```
+#[derive(Default)]
+pub struct Config {
+    pub color: Option<String>,
+    pub log_level: Option<String>,
+    pub history_size: Option<usize>,
+    pub prompt: Option<String>,
+    pub headers: Vec<(String, String)>,
+    // Globals removed before anything runs, by name
+    pub disabled_modules: Vec<String>,
+}
+
+fn string_setting(
+    table: &toml::Table,
+    key: &str,
+    allowed: &[&str],
+) -> std::result::Result<Option<String>, String> {
+    match table.get(key) {
+        None => Ok(None),
+        Some(toml::Value::String(value))
+            if allowed.is_empty() || allowed.contains(&value.as_str()) =>
+        {
+            Ok(Some(value.clone()))
+        }
+        Some(_) if allowed.is_empty() => Err(format!("{} must be a string", key)),
+        Some(_) => Err(format!("{} must be one of {}", key, allowed.join(", "))),
+    }
+}
+
+impl Config {
+    // Overrides the settings with the ones in the file
+    fn merge_file(&mut self, path: &Path) -> std::result::Result<(), String> {
+        let Ok(text) = std::fs::read_to_string(path) else {
+            return Ok(());
+        };
+        let table = text
+            .parse::<toml::Table>()
+            .map_err(|error| error.message().to_string())?;
+        if let Some(color) = string_setting(&table, "color", COLOR_MODES)? {
+            self.color = Some(color);
+        }
+        if let Some(level) = string_setting(&table, "log_level", LOG_LEVELS)? {
+            self.log_level = Some(level);
+        }
+        if let Some(repl) = table.get("repl").and_then(toml::Value::as_table) {
+            if let Some(size) = repl.get("history_size") {
+                let size = size
+                    .as_integer()
+                    .filter(|size| *size >= 0)
+                    .ok_or("repl.history_size must be a positive number")?;
+                self.history_size = Some(size as usize);
+            }
+            if let Some(prompt) = string_setting(repl, "prompt", &[])? {
+                self.prompt = Some(prompt);
+            }
+        }
+        let headers = table
+            .get("http")
+            .and_then(toml::Value::as_table)
+            .and_then(|http| http.get("headers"))
+            .and_then(toml::Value::as_table);
+        for (name, value) in headers.into_iter().flatten() {
+            let value = value
+                .as_str()
+                .ok_or_else(|| format!("http.headers.{} must be a string", name))?;
+            self.headers.push((name.clone(), value.to_string()));
+        }
+        for (name, enabled) in table
+            .get("modules")
+            .and_then(toml::Value::as_table)
+            .into_iter()
+            .flatten()
+        {
+            let enabled = enabled
+                .as_bool()
+                .ok_or_else(|| format!("modules.{} must be true or false", name))?;
+            self.disabled_modules.retain(|module| module != name);
+            if !enabled {
+                self.disabled_modules.push(name.clone());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reads the user's config file, then the one in the current directory. Files that can't be
+/// read are skipped with a warning.
+pub fn load() -> Config {
+    let mut config = Config::default();
+    let user_config = config_dir().map(|dir| dir.join(USER_CONFIG_FILE));
+    let paths = user_config
+        .into_iter()
+        .chain(std::iter::once(PROJECT_CONFIG_FILE.into()));
+    for path in paths {
+        if let Err(error) = config.merge_file(&path) {
+            logger::warn(&format!(
+                "Ignoring the rest of {} [{}]",
+                path.display(),
+                error
+            ));
+        }
+    }
+    config
+}
+
+/// Makes the config the one every interpreter is set up with, and applies its color mode.
+pub fn install(config: Config) {
+    match config.color.as_deref() {
+        Some("always") => colored::control::set_override(true),
+        Some("never") => colored::control::set_override(false),
+        _ => {}
+    }
+    let _ = CONFIG.set(config);
+}
+
+/// The installed config, or the defaults when there is none.
+pub fn get() -> &'static Config {
+    CONFIG.get_or_init(Config::default)
+}
+
+/// Whether Lua's `log.<level>` messages are shown at the configured log level.
+pub fn log_enabled(level: &str) -> bool {
+    let position = |level: &str| LOG_LEVELS.iter().position(|name| *name == level);
+    let configured = get().log_level.as_deref().unwrap_or(LOG_LEVELS[0]);
+    position(level) >= position(configured)
+}
+
+/// Applies the default headers, the prompt and the disabled modules to the interpreter.
+pub fn apply(lua: &Lua) -> Result<()> {
+    let config = get();
+    lua.context(|lua_ctx| {
+        let globals = lua_ctx.globals();
+        if let Ok(http) = globals.get::<_, Table>("http") {
+            let headers = http.get::<_, Table>("headers")?;
+            for (name, value) in &config.headers {
+                headers.set(name.as_str(), value.as_str())?;
+            }
+        }
+        if let (Some(prompt), Ok(repl)) = (&config.prompt, globals.get::<_, Table>("repl")) {
+            repl.set("prompt", prompt.as_str())?;
+        }
+        let loaded = match globals.get::<_, Option<Table>>("package")? {
+            Some(package) => Some(package.get::<_, Table>("loaded")?),
+            None => None,
+        };
+        for module in &config.disabled_modules {
+            globals.set(module.as_str(), rlua::Nil)?;
+            // Nor can they be required
+            if let Some(loaded) = &loaded {
+                loaded.set(module.as_str(), rlua::Nil)?;
+            }
+        }
+        Ok(())
+    })
+}
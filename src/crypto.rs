@@ -0,0 +1,269 @@
+/*
+   Copyright (C) 2022  Kalka
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License, or (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use ed25519_dalek::pkcs8::spki::der::pem::LineEnding;
+use ed25519_dalek::pkcs8::{DecodePrivateKey, DecodePublicKey, EncodePrivateKey, EncodePublicKey};
+use rlua::{Lua, MetaMethod, Result, Table, UserData, UserDataMethods, Value};
+use rsa::pkcs1::{DecodeRsaPrivateKey, DecodeRsaPublicKey};
+use rsa::rand_core::OsRng;
+use rsa::signature::{SignatureEncoding, Signer, Verifier};
+use rsa::{RsaPrivateKey, RsaPublicKey};
+
+use crate::error;
+use crate::hash::data_argument;
+use crate::help::{self, FunctionDoc};
+
+const CRYPTO_DOCS: &[FunctionDoc] = &[
+    FunctionDoc {
+        name: "generate",
+        signature: "crypto.generate(type[, options]) -> key",
+        description: "Generates a private key, of type \"ed25519\" or \"rsa\", with options.bits setting the size of RSA keys (2048 by default). key.type is the type, key.private whether it's private, key:public() the public key that goes with it, and key:pem() the key in PEM, PKCS#8 for private keys and SubjectPublicKeyInfo for public ones, as OpenSSL writes them.",
+        example: "local key = crypto.generate(\"ed25519\")\nfs.write(\"release.key\", key:pem())\nfs.write(\"release.pub\", key:public():pem())",
+    },
+    FunctionDoc {
+        name: "key",
+        signature: "crypto.key(pem) -> key",
+        description: "Reads an Ed25519 or RSA key from PEM, either a private key in PKCS#8 or PKCS#1 or a public key in SubjectPublicKeyInfo or PKCS#1. Raises an error for anything else, including encrypted private keys.",
+        example: "local key = crypto.key(fs.read(\"release.key\"))",
+    },
+    FunctionDoc {
+        name: "sign",
+        signature: "crypto.sign(key, message) -> signature",
+        description: "Signs the message, a string or list of bytes, with a private key, returning the signature as bytes. RSA keys sign with PKCS#1 v1.5 and SHA-256, as in RS256 JWTs.",
+        example: "fs.write(\"release.tar.gz.sig\", crypto.sign(key, fs.read(\"release.tar.gz\")))",
+    },
+    FunctionDoc {
+        name: "verify",
+        signature: "crypto.verify(key, message, signature) -> boolean",
+        description: "Whether the signature is the key's signature of the message. The key can be public or private.",
+        example: "local key = crypto.key(fs.read(\"release.pub\"))\nassert(crypto.verify(key, fs.read(\"release.tar.gz\"), fs.read(\"release.tar.gz.sig\")), \"bad signature\")",
+    },
+];
+
+/// An Ed25519 or RSA key from crypto.generate or crypto.key.
+#[derive(Clone)]
+pub enum Key {
+    Ed25519Private(ed25519_dalek::SigningKey),
+    Ed25519Public(ed25519_dalek::VerifyingKey),
+    RsaPrivate(RsaPrivateKey),
+    RsaPublic(RsaPublicKey),
+}
+
+fn key_error(message: impl ToString) -> rlua::Error {
+    rlua::Error::external(error::Error::Decode {
+        format: "key",
+        message: message.to_string(),
+    })
+}
+
+impl Key {
+    pub fn from_pem(pem: &str) -> Result<Key> {
+        let label = pem
+            .lines()
+            .find_map(|line| line.trim().strip_prefix("-----BEGIN "))
+            .and_then(|line| line.strip_suffix("-----"))
+            .ok_or_else(|| key_error("it isn't PEM"))?;
+        match label {
+            "PRIVATE KEY" => ed25519_dalek::SigningKey::from_pkcs8_pem(pem)
+                .map(Key::Ed25519Private)
+                .or_else(|_| RsaPrivateKey::from_pkcs8_pem(pem).map(Key::RsaPrivate))
+                .map_err(|_| key_error("it isn't an Ed25519 or RSA private key")),
+            "PUBLIC KEY" => ed25519_dalek::VerifyingKey::from_public_key_pem(pem)
+                .map(Key::Ed25519Public)
+                .or_else(|_| RsaPublicKey::from_public_key_pem(pem).map(Key::RsaPublic))
+                .map_err(|_| key_error("it isn't an Ed25519 or RSA public key")),
+            "RSA PRIVATE KEY" => RsaPrivateKey::from_pkcs1_pem(pem)
+                .map(Key::RsaPrivate)
+                .map_err(key_error),
+            "RSA PUBLIC KEY" => RsaPublicKey::from_pkcs1_pem(pem)
+                .map(Key::RsaPublic)
+                .map_err(key_error),
+            "ENCRYPTED PRIVATE KEY" => Err(key_error(
+                "it's encrypted, decrypt it first with openssl pkey",
+            )),
+            label => Err(key_error(format!("it's a {}, not a key", label))),
+        }
+    }
+
+    fn to_pem(&self) -> Result<String> {
+        let pem_error =
+            |error: &dyn std::fmt::Display| format!("cannot write key as PEM: {}", error);
+        match self {
+            Key::Ed25519Private(key) => key
+                .to_pkcs8_pem(LineEnding::LF)
+                .map(|pem| pem.to_string())
+                .map_err(|error| pem_error(&error)),
+            Key::Ed25519Public(key) => key
+                .to_public_key_pem(LineEnding::LF)
+                .map_err(|error| pem_error(&error)),
+            Key::RsaPrivate(key) => key
+                .to_pkcs8_pem(LineEnding::LF)
+                .map(|pem| pem.to_string())
+                .map_err(|error| pem_error(&error)),
+            Key::RsaPublic(key) => key
+                .to_public_key_pem(LineEnding::LF)
+                .map_err(|error| pem_error(&error)),
+        }
+        .map_err(rlua::Error::RuntimeError)
+    }
+
+    fn public(&self) -> Key {
+        match self {
+            Key::Ed25519Private(key) => Key::Ed25519Public(key.verifying_key()),
+            Key::RsaPrivate(key) => Key::RsaPublic(key.to_public_key()),
+            key => key.clone(),
+        }
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Key::Ed25519Private(_) | Key::Ed25519Public(_) => "ed25519",
+            Key::RsaPrivate(_) | Key::RsaPublic(_) => "rsa",
+        }
+    }
+
+    pub fn is_private(&self) -> bool {
+        matches!(self, Key::Ed25519Private(_) | Key::RsaPrivate(_))
+    }
+
+    /// The signature of the message, or None for public keys.
+    pub fn sign(&self, message: &[u8]) -> Option<Vec<u8>> {
+        match self {
+            Key::Ed25519Private(key) => Some(key.sign(message).to_vec()),
+            Key::RsaPrivate(key) => {
+                let key = rsa::pkcs1v15::SigningKey::<sha2::Sha256>::new(key.clone());
+                Some(key.sign(message).to_vec())
+            }
+            _ => None,
+        }
+    }
+
+    pub fn verify(&self, message: &[u8], signature: &[u8]) -> bool {
+        match self.public() {
+            Key::Ed25519Public(key) => ed25519_dalek::Signature::from_slice(signature)
+                .is_ok_and(|signature| key.verify(message, &signature).is_ok()),
+            Key::RsaPublic(key) => {
+                let key = rsa::pkcs1v15::VerifyingKey::<sha2::Sha256>::new(key);
+                rsa::pkcs1v15::Signature::try_from(signature)
+                    .is_ok_and(|signature| key.verify(message, &signature).is_ok())
+            }
+            _ => unreachable!("public() returns public keys"),
+        }
+    }
+}
+
+impl UserData for Key {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_meta_method(MetaMethod::Index, |lua_ctx, key, name: String| {
+            Ok(match name.as_str() {
+                "type" => Value::String(lua_ctx.create_string(key.kind())?),
+                "private" => Value::Boolean(key.is_private()),
+                _ => Value::Nil,
+            })
+        });
+        methods.add_method("public", |_, key, ()| Ok(key.public()));
+        methods.add_method("pem", |_, key, ()| key.to_pem());
+        methods.add_meta_method(MetaMethod::ToString, |_, key, ()| {
+            Ok(format!(
+                "crypto.key({} {})",
+                key.kind(),
+                if key.is_private() {
+                    "private"
+                } else {
+                    "public"
+                }
+            ))
+        });
+    }
+}
+
+fn generate(kind: &str, options: Option<Table>) -> Result<Key> {
+    match kind {
+        "ed25519" => Ok(Key::Ed25519Private(ed25519_dalek::SigningKey::generate(
+            &mut OsRng,
+        ))),
+        "rsa" => {
+            let bits = match &options {
+                Some(options) => options.get::<_, Option<usize>>("bits")?.unwrap_or(2048),
+                None => 2048,
+            };
+            if !(1024..=16384).contains(&bits) {
+                return Err(rlua::Error::RuntimeError(format!(
+                    "bad option 'bits' to 'generate' (1024 to 16384 expected, got {})",
+                    bits
+                )));
+            }
+            RsaPrivateKey::new(&mut OsRng, bits)
+                .map(Key::RsaPrivate)
+                .map_err(|error| {
+                    rlua::Error::RuntimeError(format!("could not generate RSA key: {}", error))
+                })
+        }
+        kind => Err(rlua::Error::RuntimeError(format!(
+            "bad argument #1 to 'generate' (unknown key type '{}', expected ed25519 or rsa)",
+            kind
+        ))),
+    }
+}
+
+pub fn load_crypto_library(lua: &Lua) -> Result<()> {
+    lua.context(|lua_ctx| {
+        let crypto = lua_ctx.create_table()?;
+
+        crypto.set(
+            "generate",
+            lua_ctx.create_function(|_, (kind, options): (String, Option<Table>)| {
+                generate(&kind, options)
+            })?,
+        )?;
+        crypto.set(
+            "key",
+            lua_ctx.create_function(|_, pem: String| Key::from_pem(&pem))?,
+        )?;
+        crypto.set(
+            "sign",
+            lua_ctx.create_function(|lua_ctx, (key, message): (Key, Value)| {
+                let message = data_argument(message, "sign", 2)?;
+                let signature = key.sign(&message).ok_or_else(|| {
+                    rlua::Error::RuntimeError(
+                        "bad argument #1 to 'sign' (private key expected, got a public key)"
+                            .to_string(),
+                    )
+                })?;
+                lua_ctx.create_string(&signature)
+            })?,
+        )?;
+        crypto.set(
+            "verify",
+            lua_ctx.create_function(
+                |_, (key, message, signature): (Key, Value, rlua::String)| {
+                    let message = data_argument(message, "verify", 2)?;
+                    Ok(key.verify(&message, signature.as_bytes()))
+                },
+            )?,
+        )?;
+
+        help::register_module_docs(
+            lua_ctx,
+            "crypto",
+            "Signing with Ed25519 and RSA keys",
+            &crypto,
+            CRYPTO_DOCS,
+        )?;
+        lua_ctx.globals().set("crypto", crypto)?;
+        Ok(())
+    })
+}
@@ -0,0 +1,247 @@
+/*
+   Copyright (C) 2022  Kalka
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License, or (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use rlua::{Context, Function, Lua, Result, Table, Value};
+use std::fs::File;
+use std::io::{Cursor, Read};
+use std::path::Path;
+
+use crate::error::Error;
+use crate::help::{self, FunctionDoc};
+
+const CSV_DOCS: &[FunctionDoc] = &[
+    FunctionDoc {
+        name: "read",
+        signature: "csv.read(source[, options]) -> iterator",
+        description: "Iterates over the rows of CSV, read from the file at source, or from source itself when it has a line break in it. Files are read as the loop goes, so they can be larger than memory. The first row names the columns and every row after it is a table from column names to values, or with options.headers set to false every row is a list of values. Values are strings. options.delimiter and options.quote change the characters separating and quoting values from , and \". Raises an error for rows with a different number of values than the first.",
+        example: "for row in csv.read(\"users.csv\") do\n    print(row.name, row.email)\nend",
+    },
+    FunctionDoc {
+        name: "write",
+        signature: "csv.write(path, rows[, options])",
+        description: "Writes rows to a CSV file, from a list or an iterator like csv.read's so large files can be written a row at a time. Rows that are lists are written as they are, and rows that are tables from column names to values are written in the columns of options.columns, or the first row's names sorted, under a row of those names unless options.headers is false. Numbers and booleans are written as text and nil as nothing. options.delimiter and options.quote work as for csv.read.",
+        example: "csv.write(\"report.csv\", {\n    { name = \"build\", seconds = 12.5 },\n    { name = \"test\", seconds = 40 },\n}, { columns = { \"name\", \"seconds\" } })",
+    },
+];
+
+/// Whether csv.read's source is CSV text rather than the path of a file.
+pub fn is_text(source: &str) -> bool {
+    source.contains('\n')
+}
+
+fn csv_error(source: csv::Error) -> rlua::Error {
+    rlua::Error::external(Error::Csv { source })
+}
+
+// The delimiter or quote in the options, which has to be a single byte
+fn byte_option(options: &Option<Table>, name: &str, default: u8, function: &str) -> Result<u8> {
+    let Some(options) = options else {
+        return Ok(default);
+    };
+    match options.get::<_, Option<String>>(name)? {
+        None => Ok(default),
+        Some(value) if value.len() == 1 => Ok(value.as_bytes()[0]),
+        Some(value) => Err(rlua::Error::RuntimeError(format!(
+            "bad argument #{} to '{}' ({} must be a single character, got \"{}\")",
+            if function == "read" { 2 } else { 3 },
+            function,
+            name,
+            value
+        ))),
+    }
+}
+
+fn headers_option(options: &Option<Table>) -> Result<bool> {
+    match options {
+        Some(options) => Ok(options.get::<_, Option<bool>>("headers")?.unwrap_or(true)),
+        None => Ok(true),
+    }
+}
+
+fn read<'lua>(
+    lua_ctx: Context<'lua>,
+    source: String,
+    options: Option<Table<'lua>>,
+) -> Result<Function<'lua>> {
+    let headers = headers_option(&options)?;
+    let input: Box<dyn Read + Send> = if is_text(&source) {
+        Box::new(Cursor::new(source.into_bytes()))
+    } else {
+        let file = File::open(&source).map_err(|error| {
+            rlua::Error::external(Error::Read {
+                path: source.clone(),
+                source: error,
+            })
+        })?;
+        Box::new(file)
+    };
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(headers)
+        .delimiter(byte_option(&options, "delimiter", b',', "read")?)
+        .quote(byte_option(&options, "quote", b'"', "read")?)
+        .from_reader(input);
+    let names = match headers {
+        true => Some(reader.headers().map_err(csv_error)?.clone()),
+        false => None,
+    };
+    let mut record = csv::StringRecord::new();
+    lua_ctx.create_function_mut(move |lua_ctx, ()| {
+        if !reader.read_record(&mut record).map_err(csv_error)? {
+            return Ok(None);
+        }
+        let row = lua_ctx.create_table()?;
+        match &names {
+            Some(names) => {
+                for (name, value) in names.iter().zip(record.iter()) {
+                    row.set(name, value)?;
+                }
+            }
+            None => {
+                for (index, value) in record.iter().enumerate() {
+                    row.set(index + 1, value)?;
+                }
+            }
+        }
+        Ok(Some(row))
+    })
+}
+
+// A value as it's written in a CSV file
+fn field(value: Value) -> Result<Vec<u8>> {
+    Ok(match value {
+        Value::Nil => Vec::new(),
+        Value::LightUserData(pointer) if pointer.0.is_null() => Vec::new(),
+        Value::Boolean(boolean) => boolean.to_string().into_bytes(),
+        Value::Integer(integer) => integer.to_string().into_bytes(),
+        Value::Number(number) => number.to_string().into_bytes(),
+        Value::String(string) => string.as_bytes().to_vec(),
+        value => {
+            return Err(rlua::Error::RuntimeError(format!(
+                "cannot write {} values to CSV",
+                value.type_name()
+            )))
+        }
+    })
+}
+
+// Whether a row is a list of values rather than a table from column names to values
+fn is_list(row: &Table) -> Result<bool> {
+    let length = row.raw_len();
+    let mut count = 0;
+    for pair in row.clone().pairs::<Value, Value>() {
+        pair?;
+        count += 1;
+    }
+    Ok(count == 0 || count == length)
+}
+
+fn write(path: String, rows: Value, options: Option<Table>) -> Result<()> {
+    let headers = headers_option(&options)?;
+    let mut columns = match &options {
+        Some(options) => options.get::<_, Option<Vec<String>>>("columns")?,
+        None => None,
+    };
+    let write_error = |source: std::io::Error| {
+        rlua::Error::external(Error::Write {
+            path: path.clone(),
+            source,
+        })
+    };
+    let file = File::create(Path::new(&path)).map_err(write_error)?;
+    let mut writer = csv::WriterBuilder::new()
+        .delimiter(byte_option(&options, "delimiter", b',', "write")?)
+        .quote(byte_option(&options, "quote", b'"', "write")?)
+        .flexible(true)
+        .from_writer(file);
+    let mut wrote_headers = !headers;
+    let mut write_row = |row: Table| -> Result<()> {
+        let record = if is_list(&row)? {
+            row.sequence_values::<Value>()
+                .map(|value| field(value?))
+                .collect::<Result<Vec<_>>>()?
+        } else {
+            if columns.is_none() {
+                let mut names = row
+                    .clone()
+                    .pairs::<String, Value>()
+                    .map(|pair| pair.map(|(name, _)| name))
+                    .collect::<Result<Vec<_>>>()?;
+                names.sort();
+                columns = Some(names);
+            }
+            if !wrote_headers {
+                writer
+                    .write_record(columns.iter().flatten())
+                    .map_err(|error| write_error(error.into()))?;
+                wrote_headers = true;
+            }
+            columns
+                .iter()
+                .flatten()
+                .map(|name| field(row.get(name.as_str())?))
+                .collect::<Result<Vec<_>>>()?
+        };
+        writer
+            .write_record(record)
+            .map_err(|error| write_error(error.into()))
+    };
+    match rows {
+        Value::Table(rows) => {
+            for row in rows.sequence_values::<Table>() {
+                write_row(row?)?;
+            }
+        }
+        Value::Function(next) => {
+            while let Some(row) = next.call::<_, Option<Table>>(())? {
+                write_row(row)?;
+            }
+        }
+        rows => {
+            return Err(rlua::Error::RuntimeError(format!(
+                "bad argument #2 to 'write' (table or iterator expected, got {})",
+                rows.type_name()
+            )))
+        }
+    }
+    writer.flush().map_err(write_error)
+}
+
+pub fn load_csv_library(lua: &Lua) -> Result<()> {
+    lua.context(|lua_ctx| {
+        let csv = lua_ctx.create_table()?;
+
+        csv.set(
+            "read",
+            lua_ctx.create_function(|lua_ctx, (source, options): (String, Option<Table>)| {
+                read(lua_ctx, source, options)
+            })?,
+        )?;
+
+        csv.set(
+            "write",
+            lua_ctx.create_function(
+                |_, (path, rows, options): (String, Value, Option<Table>)| {
+                    write(path, rows, options)
+                },
+            )?,
+        )?;
+
+        help::register_module_docs(lua_ctx, "csv", "CSV files", &csv, CSV_DOCS)?;
+        lua_ctx.globals().set("csv", csv)?;
+
+        Ok(())
+    })
+}
@@ -0,0 +1,252 @@
+/*
+   Copyright (C) 2022  Kalka
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License, or (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use base64::alphabet;
+use base64::engine::general_purpose::{GeneralPurpose, GeneralPurposeConfig};
+use base64::engine::DecodePaddingMode;
+use base64::Engine;
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC};
+use rlua::{Lua, Result, Table};
+
+use crate::error;
+use crate::help::{self, FunctionDoc};
+
+const ENCODE_DOCS: &[FunctionDoc] = &[
+    FunctionDoc {
+        name: "base64",
+        signature: "encode.base64(data) -> string",
+        description: "Encodes bytes as base64, with padding.",
+        example: "local auth = \"Basic \" .. encode.base64(user .. \":\" .. password)",
+    },
+    FunctionDoc {
+        name: "base64_decode",
+        signature: "encode.base64_decode(text) -> bytes",
+        description: "Decodes base64, with or without padding and ignoring whitespace, so PEM bodies can be decoded as they are. Raises an error for invalid base64.",
+        example: "local der = encode.base64_decode(pem:match(\"%-+BEGIN [^-]+%-+(.-)%-+END\"))",
+    },
+    FunctionDoc {
+        name: "base64url",
+        signature: "encode.base64url(data) -> string",
+        description: "Encodes bytes as URL-safe base64 without padding, as in JWTs.",
+        example: "local header = encode.base64url(json.encode({ alg = \"HS256\", typ = \"JWT\" }))",
+    },
+    FunctionDoc {
+        name: "base64url_decode",
+        signature: "encode.base64url_decode(text) -> bytes",
+        description: "Decodes URL-safe base64, with or without padding. Raises an error for invalid base64.",
+        example: "local claims = json.decode(encode.base64url_decode(token:match(\"^[^.]+%.([^.]+)\")))",
+    },
+    FunctionDoc {
+        name: "hex",
+        signature: "encode.hex(data) -> string",
+        description: "Encodes bytes as lowercase hexadecimal, two digits a byte.",
+        example: "print(encode.hex(\"\\0\\255\"))  --> 00ff",
+    },
+    FunctionDoc {
+        name: "hex_decode",
+        signature: "encode.hex_decode(text) -> bytes",
+        description: "Decodes hexadecimal in either case. Raises an error for anything but pairs of hex digits.",
+        example: "local key = encode.hex_decode(env.get(\"SIGNING_KEY\"))",
+    },
+    FunctionDoc {
+        name: "url",
+        signature: "encode.url(text[, options]) -> string",
+        description: "Percent-encodes text for a URL's path segment or query value, leaving only letters, digits and - . _ ~ as they are. With options.form, spaces become + as in HTML form bodies.",
+        example: "http.get(\"https://example.com/search?q=\" .. encode.url(\"rust & lua\"))",
+    },
+    FunctionDoc {
+        name: "url_decode",
+        signature: "encode.url_decode(text[, options]) -> bytes",
+        description: "Decodes percent-encoding, leaving malformed escapes as they are. With options.form, + becomes a space.",
+        example: "local query = encode.url_decode(\"rust%20%26%20lua\")",
+    },
+    FunctionDoc {
+        name: "punycode",
+        signature: "encode.punycode(domain) -> string",
+        description: "Converts an internationalized domain name to the ASCII form used in DNS, with punycode labels starting with xn--. Raises an error for names that aren't valid domains.",
+        example: "print(encode.punycode(\"bücher.example\"))  --> xn--bcher-kva.example",
+    },
+    FunctionDoc {
+        name: "punycode_decode",
+        signature: "encode.punycode_decode(domain) -> string",
+        description: "Converts a domain name's xn-- labels back to Unicode. Raises an error for names that aren't valid domains.",
+        example: "print(encode.punycode_decode(\"xn--bcher-kva.example\"))  --> bücher.example",
+    },
+];
+
+// Everything but the characters URLs never need escaped
+const URL_ESCAPED: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+// Decoders that take base64 with or without padding
+const BASE64: GeneralPurpose = GeneralPurpose::new(
+    &alphabet::STANDARD,
+    GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+const BASE64_URL: GeneralPurpose = GeneralPurpose::new(
+    &alphabet::URL_SAFE,
+    GeneralPurposeConfig::new()
+        .with_encode_padding(false)
+        .with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+fn decode_error(format: &'static str, message: impl ToString) -> rlua::Error {
+    rlua::Error::external(error::Error::Decode {
+        format,
+        message: message.to_string(),
+    })
+}
+
+fn hex_decode(text: &[u8]) -> Result<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return Err(decode_error("hex", "it has an odd number of digits"));
+    }
+    let digit = |byte: u8| {
+        (byte as char)
+            .to_digit(16)
+            .ok_or_else(|| decode_error("hex", format!("{:?} is not a hex digit", byte as char)))
+    };
+    text.chunks(2)
+        .map(|pair| Ok((digit(pair[0])? * 16 + digit(pair[1])?) as u8))
+        .collect()
+}
+
+fn form_option(options: Option<Table>) -> Result<bool> {
+    match options {
+        Some(options) => Ok(options.get::<_, Option<bool>>("form")?.unwrap_or(false)),
+        None => Ok(false),
+    }
+}
+
+pub fn load_encode_library(lua: &Lua) -> Result<()> {
+    lua.context(|lua_ctx| {
+        let encode = lua_ctx.create_table()?;
+
+        encode.set(
+            "base64",
+            lua_ctx.create_function(|_, data: rlua::String| Ok(BASE64.encode(data.as_bytes())))?,
+        )?;
+
+        encode.set(
+            "base64_decode",
+            lua_ctx.create_function(|lua_ctx, text: rlua::String| {
+                let text = text
+                    .as_bytes()
+                    .iter()
+                    .copied()
+                    .filter(|byte| !byte.is_ascii_whitespace())
+                    .collect::<Vec<_>>();
+                let bytes = BASE64
+                    .decode(text)
+                    .map_err(|error| decode_error("base64", error))?;
+                lua_ctx.create_string(&bytes)
+            })?,
+        )?;
+
+        encode.set(
+            "base64url",
+            lua_ctx
+                .create_function(|_, data: rlua::String| Ok(BASE64_URL.encode(data.as_bytes())))?,
+        )?;
+
+        encode.set(
+            "base64url_decode",
+            lua_ctx.create_function(|lua_ctx, text: rlua::String| {
+                let bytes = BASE64_URL
+                    .decode(text.as_bytes())
+                    .map_err(|error| decode_error("base64", error))?;
+                lua_ctx.create_string(&bytes)
+            })?,
+        )?;
+
+        encode.set(
+            "hex",
+            lua_ctx.create_function(|_, data: rlua::String| {
+                Ok(data
+                    .as_bytes()
+                    .iter()
+                    .map(|byte| format!("{:02x}", byte))
+                    .collect::<String>())
+            })?,
+        )?;
+
+        encode.set(
+            "hex_decode",
+            lua_ctx.create_function(|lua_ctx, text: rlua::String| {
+                lua_ctx.create_string(&hex_decode(text.as_bytes())?)
+            })?,
+        )?;
+
+        encode.set(
+            "url",
+            lua_ctx.create_function(|_, (text, options): (rlua::String, Option<Table>)| {
+                let form = form_option(options)?;
+                let encoded = text
+                    .as_bytes()
+                    .split(|byte| form && *byte == b' ')
+                    .map(|part| percent_encoding::percent_encode(part, URL_ESCAPED).to_string())
+                    .collect::<Vec<_>>();
+                Ok(encoded.join("+"))
+            })?,
+        )?;
+
+        encode.set(
+            "url_decode",
+            lua_ctx.create_function(
+                |lua_ctx, (text, options): (rlua::String, Option<Table>)| {
+                    let mut text = text.as_bytes().to_vec();
+                    if form_option(options)? {
+                        for byte in text.iter_mut().filter(|byte| **byte == b'+') {
+                            *byte = b' ';
+                        }
+                    }
+                    let bytes = percent_encoding::percent_decode(&text).collect::<Vec<_>>();
+                    lua_ctx.create_string(&bytes)
+                },
+            )?,
+        )?;
+
+        encode.set(
+            "punycode",
+            lua_ctx.create_function(|_, domain: String| {
+                idna::domain_to_ascii(&domain).map_err(|error| decode_error("domain", error))
+            })?,
+        )?;
+
+        encode.set(
+            "punycode_decode",
+            lua_ctx.create_function(|_, domain: String| {
+                let (unicode, result) = idna::domain_to_unicode(&domain);
+                result.map_err(|error| decode_error("domain", error))?;
+                Ok(unicode)
+            })?,
+        )?;
+
+        help::register_module_docs(
+            lua_ctx,
+            "encode",
+            "Base64, hex, URL and punycode encoding",
+            &encode,
+            ENCODE_DOCS,
+        )?;
+        lua_ctx.globals().set("encode", encode)?;
+
+        Ok(())
+    })
+}
@@ -0,0 +1,335 @@
+/*
+   Copyright (C) 2022  Kalka
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License, or (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use rlua::{Lua, Result, Table};
+
+use crate::error::Error;
+use crate::help::{self, FunctionDoc};
+
+const ENV_DOCS: &[FunctionDoc] = &[
+    FunctionDoc {
+        name: "get",
+        signature: "env.get(name[, default]) -> string",
+        description: "The value of the environment variable, or the default (nil unless given) when it isn't set.",
+        example: "local token = env.get(\"GITHUB_TOKEN\")",
+    },
+    FunctionDoc {
+        name: "set",
+        signature: "env.set(name, value)",
+        description: "Sets the environment variable for rluaterm and the processes it starts. Setting it to nil unsets it.",
+        example: "env.set(\"LANG\", \"C\")",
+    },
+    FunctionDoc {
+        name: "unset",
+        signature: "env.unset(name)",
+        description: "Removes the environment variable.",
+        example: "env.unset(\"http_proxy\")",
+    },
+    FunctionDoc {
+        name: "list",
+        signature: "env.list() -> table",
+        description: "All environment variables, as a table of names to values.",
+        example: "for name, value in pairs(env.list()) do print(name, value) end",
+    },
+    FunctionDoc {
+        name: "load",
+        signature: "env.load([path][, options]) -> table",
+        description: "Sets the variables from a .env file, \".env\" by default, and returns a table of the values the file gives them. Lines look like KEY=value, optionally starting with export, and # starts a comment. Values can be quoted, over several lines too, with escapes like \\n in double quotes and nothing special in single quotes. $NAME, ${NAME} and ${NAME:-default} in unquoted and double-quoted values are replaced with variables set earlier in the file or in the environment, unless options.interpolate is false. Variables that are already set are kept unless options.override is true. Raises an error saying why when the file can't be read or has a line it doesn't understand.",
+        example: "env.load()\nlocal response = http.get(\"https://api.example.com/me?token=\" .. env.get(\"API_TOKEN\"))",
+    },
+];
+
+// A variable's name and value from a .env file
+type Variable = (String, String);
+
+// set_var panics on names and values the platform can't store, so they're refused first
+fn check_name(name: &str) -> Result<()> {
+    if name.is_empty() || name.contains(['=', '\0']) {
+        return Err(rlua::Error::RuntimeError(format!(
+            "invalid environment variable name '{}'",
+            name
+        )));
+    }
+    Ok(())
+}
+
+fn set(name: &str, value: &str) -> Result<()> {
+    check_name(name)?;
+    if value.contains('\0') {
+        return Err(rlua::Error::RuntimeError(format!(
+            "value of environment variable {} contains a NUL byte",
+            name
+        )));
+    }
+    std::env::set_var(name, value);
+    Ok(())
+}
+
+fn parse_error(path: &str, line: usize, message: &str) -> rlua::Error {
+    rlua::Error::RuntimeError(format!("{}:{}: {}", path, line + 1, message))
+}
+
+// The byte offset of the quote ending a value that started with it. Double-quoted values can
+// contain it escaped with a backslash.
+fn closing_quote(text: &str, quote: char) -> Option<usize> {
+    let mut escaped = false;
+    for (offset, c) in text.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' && quote == '"' {
+            escaped = true;
+        } else if c == quote {
+            return Some(offset);
+        }
+    }
+    None
+}
+
+// Replaces escapes when `escapes` is set, and $NAME, ${NAME} and ${NAME:-default} with the
+// variable's value when `interpolate` is set
+fn expand(
+    text: &str,
+    escapes: bool,
+    interpolate: bool,
+    lookup: &dyn Fn(&str) -> Option<String>,
+) -> String {
+    let mut expanded = String::new();
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if escapes => match chars.next() {
+                Some('n') => expanded.push('\n'),
+                Some('r') => expanded.push('\r'),
+                Some('t') => expanded.push('\t'),
+                Some(c) => expanded.push(c),
+                None => expanded.push('\\'),
+            },
+            '$' if interpolate && chars.peek() == Some(&'{') => {
+                chars.next();
+                let mut inner = String::new();
+                for c in chars.by_ref() {
+                    if c == '}' {
+                        break;
+                    }
+                    inner.push(c);
+                }
+                let (name, default) = match inner.split_once(":-") {
+                    Some((name, default)) => (name, Some(default)),
+                    None => (inner.as_str(), None),
+                };
+                match lookup(name).filter(|value| !value.is_empty()) {
+                    Some(value) => expanded.push_str(&value),
+                    None => expanded.push_str(default.unwrap_or_default()),
+                }
+            }
+            '$' if interpolate
+                && chars
+                    .peek()
+                    .is_some_and(|c| c.is_ascii_alphabetic() || *c == '_') =>
+            {
+                let mut name = String::new();
+                while let Some(c) = chars.next_if(|c| c.is_ascii_alphanumeric() || *c == '_') {
+                    name.push(c);
+                }
+                expanded.push_str(&lookup(&name).unwrap_or_default());
+            }
+            c => expanded.push(c),
+        }
+    }
+    expanded
+}
+
+// The variables a .env file defines, in order, with the values they're interpolated with
+// looked up in `lookup`
+fn parse_dotenv(
+    path: &str,
+    text: &str,
+    interpolate: bool,
+    lookup: &dyn Fn(&str, &[Variable]) -> Option<String>,
+) -> Result<Vec<Variable>> {
+    let mut variables: Vec<Variable> = Vec::new();
+    let mut lines = text.lines().enumerate();
+    while let Some((index, line)) = lines.next() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line
+            .strip_prefix("export ")
+            .map(str::trim_start)
+            .unwrap_or(line);
+        let Some((name, value)) = line.split_once('=') else {
+            return Err(parse_error(path, index, "expected NAME=value"));
+        };
+        let name = name.trim();
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+        {
+            return Err(parse_error(
+                path,
+                index,
+                &format!("invalid variable name '{}'", name),
+            ));
+        }
+        let lookup = |name: &str| lookup(name, &variables);
+        let value = value.trim_start();
+        let value = match value.chars().next() {
+            Some(quote @ ('"' | '\'')) => {
+                // Quoted values go on over the following lines until the closing quote
+                let mut quoted = value[1..].to_string();
+                let end = loop {
+                    if let Some(end) = closing_quote(&quoted, quote) {
+                        break end;
+                    }
+                    match lines.next() {
+                        Some((_, line)) => {
+                            quoted.push('\n');
+                            quoted.push_str(line);
+                        }
+                        None => return Err(parse_error(path, index, "unterminated quote")),
+                    }
+                };
+                match quote {
+                    '"' => expand(&quoted[..end], true, interpolate, &lookup),
+                    _ => quoted[..end].to_string(),
+                }
+            }
+            _ => {
+                let value = match value.find(" #") {
+                    Some(comment) => &value[..comment],
+                    None => value,
+                };
+                expand(value.trim_end(), false, interpolate, &lookup)
+            }
+        };
+        variables.push((name.to_string(), value));
+    }
+    Ok(variables)
+}
+
+// Sets the variables from the .env file, keeping the ones already set unless overriding
+fn load_dotenv(path: &str, override_existing: bool, interpolate: bool) -> Result<Vec<Variable>> {
+    let text = std::fs::read_to_string(path).map_err(|source| {
+        rlua::Error::external(Error::Read {
+            path: path.to_string(),
+            source,
+        })
+    })?;
+    let existing = |name: &str| std::env::var(name).ok();
+    // A variable the file defined earlier is seen as it will be set
+    let lookup = |name: &str, defined: &[Variable]| {
+        let from_file = defined
+            .iter()
+            .rev()
+            .find(|(defined, _)| defined == name)
+            .map(|(_, value)| value.clone());
+        match (override_existing, existing(name)) {
+            (false, Some(value)) => Some(value),
+            (_, value) => from_file.or(value),
+        }
+    };
+    let variables = parse_dotenv(path, &text, interpolate, &lookup)?;
+    let kept = variables
+        .iter()
+        .filter(|(name, _)| !override_existing && existing(name).is_some())
+        .map(|(name, _)| name.clone())
+        .collect::<Vec<_>>();
+    for (name, value) in &variables {
+        if !kept.contains(name) {
+            set(name, value)?;
+        }
+    }
+    Ok(variables)
+}
+
+pub fn load_env_library(lua: &Lua) -> Result<()> {
+    lua.context(|lua_ctx| {
+        let env = lua_ctx.create_table()?;
+
+        env.set(
+            "get",
+            lua_ctx.create_function(|_, (name, default): (String, Option<String>)| {
+                Ok(std::env::var_os(name)
+                    .map(|value| value.to_string_lossy().into_owned())
+                    .or(default))
+            })?,
+        )?;
+
+        env.set(
+            "set",
+            lua_ctx.create_function(|_, (name, value): (String, Option<String>)| match value {
+                Some(value) => set(&name, &value),
+                None => {
+                    check_name(&name)?;
+                    std::env::remove_var(name);
+                    Ok(())
+                }
+            })?,
+        )?;
+
+        env.set(
+            "unset",
+            lua_ctx.create_function(|_, name: String| {
+                check_name(&name)?;
+                std::env::remove_var(name);
+                Ok(())
+            })?,
+        )?;
+
+        env.set(
+            "list",
+            lua_ctx.create_function(|lua_ctx, ()| {
+                let variables = lua_ctx.create_table()?;
+                for (name, value) in std::env::vars_os() {
+                    variables.set(
+                        name.to_string_lossy().into_owned(),
+                        value.to_string_lossy().into_owned(),
+                    )?;
+                }
+                Ok(variables)
+            })?,
+        )?;
+
+        env.set(
+            "load",
+            lua_ctx.create_function(
+                |lua_ctx, (path, options): (Option<String>, Option<Table>)| {
+                    let path = path.unwrap_or_else(|| ".env".to_string());
+                    let (override_existing, interpolate) = match options {
+                        Some(options) => (
+                            options.get::<_, Option<bool>>("override")?.unwrap_or(false),
+                            options
+                                .get::<_, Option<bool>>("interpolate")?
+                                .unwrap_or(true),
+                        ),
+                        None => (false, true),
+                    };
+                    let variables = lua_ctx.create_table()?;
+                    for (name, value) in load_dotenv(&path, override_existing, interpolate)? {
+                        variables.set(name, value)?;
+                    }
+                    Ok(variables)
+                },
+            )?,
+        )?;
+
+        help::register_module_docs(lua_ctx, "env", "Environment variables", &env, ENV_DOCS)?;
+        lua_ctx.globals().set("env", env)?;
+        Ok(())
+    })
+}
@@ -0,0 +1,262 @@
+/*
+   Copyright (C) 2022  Kalka
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License, or (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use std::fmt;
+
+/// Failures in the code rluaterm runs on behalf of scripts, like reading files and making HTTP
+/// requests. They're raised in Lua as errors scripts can catch with `pcall`, saying what was
+/// being done, instead of aborting the interpreter.
+#[derive(Debug)]
+pub enum Error {
+    Read {
+        path: String,
+        source: std::io::Error,
+    },
+    Write {
+        path: String,
+        source: std::io::Error,
+    },
+    Copy {
+        from: String,
+        to: String,
+        source: std::io::Error,
+    },
+    Move {
+        from: String,
+        to: String,
+        source: std::io::Error,
+    },
+    Remove {
+        path: String,
+        source: std::io::Error,
+    },
+    CreateDir {
+        path: String,
+        source: std::io::Error,
+    },
+    SetPermissions {
+        path: String,
+        source: std::io::Error,
+    },
+    Lock {
+        path: String,
+        source: std::io::Error,
+    },
+    Spawn {
+        command: String,
+        source: std::io::Error,
+    },
+    Signal {
+        signal: String,
+        pid: i64,
+        source: std::io::Error,
+    },
+    Clipboard {
+        source: arboard::Error,
+    },
+    Open {
+        target: String,
+        source: std::io::Error,
+    },
+    Http {
+        url: String,
+        source: reqwest::Error,
+    },
+    NotJson {
+        url: String,
+    },
+    Json {
+        source: serde_json::Error,
+    },
+    Yaml {
+        source: serde_yaml::Error,
+    },
+    Toml {
+        source: toml::de::Error,
+    },
+    Csv {
+        source: csv::Error,
+    },
+    MsgPack {
+        source: rmpv::decode::Error,
+    },
+    Cbor {
+        source: ciborium::de::Error<std::io::Error>,
+    },
+    Xml {
+        position: u64,
+        message: String,
+    },
+    Ini {
+        line: usize,
+        message: String,
+    },
+    Decode {
+        format: &'static str,
+        message: String,
+    },
+    ProtoSchema {
+        path: String,
+        source: protox::Error,
+    },
+    Tls {
+        target: String,
+        message: String,
+    },
+    Secrets {
+        service: String,
+        account: String,
+        source: keyring::Error,
+    },
+    Extract {
+        path: String,
+        dest: String,
+        source: std::io::Error,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Read { path, source } => write!(f, "could not read {}: {}", path, source),
+            Error::Write { path, source } => write!(f, "could not write {}: {}", path, source),
+            Error::Copy { from, to, source } => {
+                write!(f, "could not copy {} to {}: {}", from, to, source)
+            }
+            Error::Move { from, to, source } => {
+                write!(f, "could not move {} to {}: {}", from, to, source)
+            }
+            Error::Remove { path, source } => write!(f, "could not remove {}: {}", path, source),
+            Error::CreateDir { path, source } => {
+                write!(f, "could not create directory {}: {}", path, source)
+            }
+            Error::SetPermissions { path, source } => {
+                write!(f, "could not change permissions of {}: {}", path, source)
+            }
+            Error::Lock { path, source } => write!(f, "could not lock {}: {}", path, source),
+            Error::Spawn { command, source } => {
+                write!(f, "could not run {}: {}", command, source)
+            }
+            Error::Signal {
+                signal,
+                pid,
+                source,
+            } => write!(f, "could not send {} to {}: {}", signal, pid, source),
+            Error::Clipboard { source } => write!(f, "could not use the clipboard: {}", source),
+            Error::Open { target, source } => write!(f, "could not open {}: {}", target, source),
+            Error::Http { url, source } => write!(f, "request to {} failed: {}", url, source),
+            Error::NotJson { url } => write!(f, "response from {} is not JSON", url),
+            Error::Json { source } => write!(f, "invalid JSON: {}", source),
+            Error::Yaml { source } => write!(f, "invalid YAML: {}", source),
+            // The message ends with the line it's about and a blank line
+            Error::Toml { source } => {
+                write!(f, "invalid TOML: {}", source.to_string().trim_end())
+            }
+            Error::Csv { source } => write!(
+                f,
+                "invalid CSV: {}",
+                source.to_string().trim_start_matches("CSV error: ")
+            ),
+            Error::MsgPack { source } => match source {
+                rmpv::decode::Error::InvalidMarkerRead(error)
+                | rmpv::decode::Error::InvalidDataRead(error)
+                    if error.kind() == std::io::ErrorKind::UnexpectedEof =>
+                {
+                    write!(
+                        f,
+                        "invalid MessagePack: the data ends in the middle of a value"
+                    )
+                }
+                source => write!(f, "invalid MessagePack: {}", source),
+            },
+            // ciborium's errors only have Debug output
+            Error::Cbor { source } => match source {
+                ciborium::de::Error::Io(error)
+                    if error.kind() == std::io::ErrorKind::UnexpectedEof =>
+                {
+                    write!(f, "invalid CBOR: the data ends in the middle of a value")
+                }
+                ciborium::de::Error::Io(error) => write!(f, "invalid CBOR: {}", error),
+                ciborium::de::Error::Syntax(offset) => {
+                    write!(f, "invalid CBOR: unexpected byte at offset {}", offset)
+                }
+                ciborium::de::Error::Semantic(_, message) => {
+                    write!(f, "invalid CBOR: {}", message)
+                }
+                ciborium::de::Error::RecursionLimitExceeded => {
+                    write!(f, "invalid CBOR: values are nested too deeply")
+                }
+            },
+            Error::Xml { position, message } => {
+                write!(f, "invalid XML at byte {}: {}", position, message)
+            }
+            Error::Ini { line, message } => write!(f, "invalid INI at line {}: {}", line, message),
+            Error::Decode { format, message } => write!(f, "invalid {}: {}", format, message),
+            Error::ProtoSchema { path, source } => {
+                write!(f, "could not load {}: {}", path, source)
+            }
+            Error::Tls { target, message } => {
+                write!(f, "could not connect to {}: {}", target, message)
+            }
+            Error::Secrets {
+                service,
+                account,
+                source,
+            } => write!(
+                f,
+                "could not reach the secret for {} in {}: {}",
+                account, service, source
+            ),
+            Error::Extract { path, dest, source } => {
+                write!(f, "could not extract {} to {}: {}", path, dest, source)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Read { source, .. } => Some(source),
+            Error::Write { source, .. } => Some(source),
+            Error::Copy { source, .. } => Some(source),
+            Error::Move { source, .. } => Some(source),
+            Error::Remove { source, .. } => Some(source),
+            Error::CreateDir { source, .. } => Some(source),
+            Error::SetPermissions { source, .. } => Some(source),
+            Error::Lock { source, .. } => Some(source),
+            Error::Spawn { source, .. } => Some(source),
+            Error::Signal { source, .. } => Some(source),
+            Error::Clipboard { source } => Some(source),
+            Error::Open { source, .. } => Some(source),
+            Error::Http { source, .. } => Some(source),
+            Error::NotJson { .. } => None,
+            Error::Json { source } => Some(source),
+            Error::Yaml { source } => Some(source),
+            Error::Toml { source } => Some(source),
+            Error::Csv { source } => Some(source),
+            Error::MsgPack { source } => Some(source),
+            Error::Cbor { source } => Some(source),
+            Error::Xml { .. } => None,
+            Error::Ini { .. } => None,
+            Error::Decode { .. } => None,
+            Error::ProtoSchema { source, .. } => Some(source),
+            Error::Tls { .. } => None,
+            Error::Secrets { source, .. } => Some(source),
+            Error::Extract { source, .. } => Some(source),
+        }
+    }
+}
@@ -0,0 +1,376 @@
+/*
+   Copyright (C) 2022  Kalka
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License, or (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use cumulus::logger;
+use rlua::{Lua, Result};
+use std::path::PathBuf;
+
+use crate::lexer::{self, Token, TokenKind, BINARY_OPERATORS, UNARY_OPERATORS};
+use crate::lint;
+
+const INDENT: &str = "    ";
+const MAX_LINE_LENGTH: usize = 100;
+
+// Tokens that open an indented block, and the ones that close it. `else` does both
+const OPENERS: &[&str] = &["function", "do", "then", "repeat", "else", "(", "{", "["];
+const CLOSERS: &[&str] = &["end", "until", "else", "elseif", ")", "}", "]"];
+
+fn is_any(token: &Token, texts: &[&str]) -> bool {
+    texts.iter().any(|text| token.is(text))
+}
+
+// The line a token ends on, later than the one it starts on for long strings and comments
+fn end_line(token: &Token) -> usize {
+    token.line + token.text.matches('\n').count()
+}
+
+// Groups the tokens by the line they were on, noting whether blank lines came before each
+fn split_lines(tokens: Vec<Token>) -> Vec<(Vec<Token>, bool)> {
+    let mut lines: Vec<(Vec<Token>, bool)> = Vec::new();
+    let mut last_line = 0;
+    for token in tokens {
+        match lines.last_mut() {
+            Some((line, _)) if token.line == last_line => line.push(token.clone()),
+            _ => lines.push((
+                vec![token.clone()],
+                last_line > 0 && token.line > last_line + 1,
+            )),
+        }
+        last_line = end_line(&token);
+    }
+    lines
+}
+
+// Whether the operator at the index is unary, going by the token before it
+fn is_unary(tokens: &[Token], index: usize) -> bool {
+    if !is_any(&tokens[index], UNARY_OPERATORS) {
+        return false;
+    }
+    let Some(previous) = index.checked_sub(1).map(|index| &tokens[index]) else {
+        return true;
+    };
+    match previous.kind {
+        TokenKind::Symbol => !is_any(previous, &[")", "]", "}", "..."]),
+        TokenKind::Keyword => !is_any(previous, &["nil", "true", "false", "end"]),
+        _ => false,
+    }
+}
+
+// Whether the `<` or `>` at the index is part of an attribute such as `local x <const>`
+fn is_attribute(tokens: &[Token], index: usize) -> bool {
+    let is_name = |index: usize| {
+        tokens
+            .get(index)
+            .is_some_and(|token| token.kind == TokenKind::Name)
+    };
+    let is_local = tokens.first().is_some_and(|token| token.is("local"));
+    match tokens[index].text.as_str() {
+        "<" => is_local && is_name(index + 1) && tokens.get(index + 2).is_some_and(|t| t.is(">")),
+        ">" => is_local && index >= 2 && is_name(index - 1) && tokens[index - 2].is("<"),
+        _ => false,
+    }
+}
+
+fn needs_space(tokens: &[Token], index: usize, in_label: bool) -> bool {
+    let (before, after) = (&tokens[index - 1], &tokens[index]);
+    let ends_value = |token: &Token| {
+        matches!(token.kind, TokenKind::Name | TokenKind::String) || is_any(token, &[")", "]"])
+    };
+    if after.kind == TokenKind::Comment {
+        return true;
+    }
+    if before.is("::") {
+        return !in_label;
+    }
+    if after.is("::") {
+        return !in_label;
+    }
+    if is_any(before, &["(", "["]) || is_any(after, &[")", "]", ",", ";", ".", ":"]) {
+        return false;
+    }
+    if is_any(before, &[".", ":"]) {
+        return false;
+    }
+    if before.is("{") {
+        return !after.is("}");
+    }
+    if is_attribute(tokens, index - 1) && before.is("<")
+        || is_attribute(tokens, index) && after.is(">")
+    {
+        return false;
+    }
+    if after.is("(") {
+        return !(ends_value(before) || before.is("function"));
+    }
+    if after.is("[") {
+        return !ends_value(before);
+    }
+    if is_unary(tokens, index - 1) {
+        return before.is("not");
+    }
+    true
+}
+
+// Lays out the tokens of one line with a single space wherever one belongs
+fn render(tokens: &[Token]) -> String {
+    let mut text = String::new();
+    let mut in_label = false;
+    for (index, token) in tokens.iter().enumerate() {
+        if index > 0 && needs_space(tokens, index, in_label) {
+            text.push(' ');
+        }
+        if token.is("::") {
+            in_label = !in_label;
+        }
+        match token.kind {
+            TokenKind::Comment if !token.text.contains('\n') => {
+                text.push_str(token.text.trim_end())
+            }
+            _ => text.push_str(&token.text),
+        }
+    }
+    text
+}
+
+// The first bracket on the line that closes on it too and holds a comma-separated list, along
+// with the index of the closing bracket
+fn find_list(tokens: &[Token]) -> Option<(usize, usize)> {
+    for (open, token) in tokens.iter().enumerate() {
+        if !is_any(token, &["(", "{"]) {
+            continue;
+        }
+        let mut depth = 0;
+        let mut has_comma = false;
+        for (index, token) in tokens.iter().enumerate().skip(open) {
+            if is_any(token, &["(", "{", "["]) {
+                depth += 1;
+            } else if is_any(token, &[")", "}", "]"]) {
+                depth -= 1;
+                if depth == 0 {
+                    if has_comma {
+                        return Some((open, index));
+                    }
+                    break;
+                }
+            } else if depth == 1 && is_any(token, &[",", ";"]) {
+                has_comma = true;
+            }
+        }
+    }
+    None
+}
+
+// Renders a line at the indentation level, putting each item of a list on its own line when
+// the line is too long
+fn wrap(tokens: &[Token], indent: usize, lines: &mut Vec<String>) {
+    let text = format!("{}{}", INDENT.repeat(indent), render(tokens));
+    let multiline = tokens.iter().any(|token| token.text.contains('\n'));
+    let list = find_list(tokens).filter(|_| text.chars().count() > MAX_LINE_LENGTH && !multiline);
+    let Some((open, close)) = list else {
+        lines.push(text);
+        return;
+    };
+    wrap(&tokens[..=open], indent, lines);
+    let mut start = open + 1;
+    let mut depth = 0;
+    for index in open + 1..close {
+        let token = &tokens[index];
+        if is_any(token, &["(", "{", "["]) {
+            depth += 1;
+        } else if is_any(token, &[")", "}", "]"]) {
+            depth -= 1;
+        } else if depth == 0 && is_any(token, &[",", ";"]) {
+            wrap(&tokens[start..=index], indent + 1, lines);
+            start = index + 1;
+        }
+    }
+    if start < close {
+        wrap(&tokens[start..close], indent + 1, lines);
+    }
+    wrap(&tokens[close..], indent, lines);
+}
+
+// Whether the line carries on the expression of the line before it, which gets an extra level
+// of indentation
+fn is_continuation(previous: &[Token], line: &[Token]) -> bool {
+    let last = previous
+        .iter()
+        .rev()
+        .find(|token| token.kind != TokenKind::Comment);
+    let Some(last) = last else {
+        return false;
+    };
+    if is_any(last, BINARY_OPERATORS) || last.is("=") {
+        return true;
+    }
+    // No statement starts with an operator
+    let first = &line[0];
+    (is_any(first, BINARY_OPERATORS) || is_any(first, UNARY_OPERATORS))
+        && !is_any(last, &[",", ";", "(", "{", "["])
+        && !is_any(last, OPENERS)
+}
+
+/// Formats Lua source: four spaces of indentation per block, one space around operators and
+/// after commas, at most one blank line in a row, and lists split one item per line when a line
+/// goes over 100 characters. Line breaks and comments are kept.
+pub fn format_source(source: &str) -> std::result::Result<String, lexer::LexError> {
+    let mut output = String::new();
+    if source.starts_with('#') {
+        output.push_str(source.lines().next().unwrap_or("").trim_end());
+        output.push('\n');
+    }
+    let lines = split_lines(lexer::tokenize(source)?);
+    let mut stack: Vec<usize> = Vec::new();
+    let mut previous: &[Token] = &[];
+    let mut rendered = Vec::new();
+    for (line, blank_before) in &lines {
+        let leading = line
+            .iter()
+            .take_while(|token| is_any(token, CLOSERS))
+            .count();
+        for _ in 0..leading {
+            stack.pop();
+        }
+        let mut indent = stack.last().copied().unwrap_or(0);
+        for token in &line[..leading] {
+            if is_any(token, OPENERS) {
+                stack.push(indent + 1);
+            }
+        }
+        for token in &line[leading..] {
+            if is_any(token, CLOSERS) {
+                stack.pop();
+            }
+            if is_any(token, OPENERS) {
+                stack.push(indent + 1);
+            }
+        }
+        if leading == 0 && is_continuation(previous, line) {
+            indent += 1;
+        }
+        if *blank_before && !rendered.is_empty() {
+            rendered.push(String::new());
+        }
+        wrap(line, indent, &mut rendered);
+        previous = line;
+    }
+    for line in rendered {
+        output.push_str(line.trim_end());
+        output.push('\n');
+    }
+    Ok(output)
+}
+
+// The tokens that matter to Lua, to make sure formatting changed nothing but whitespace
+fn significant_tokens(source: &str) -> Option<Vec<String>> {
+    let tokens = lexer::tokenize(source).ok()?;
+    Some(
+        tokens
+            .into_iter()
+            .map(|token| match token.kind {
+                TokenKind::Comment => token.text.trim_end().to_string(),
+                _ => token.text,
+            })
+            .collect(),
+    )
+}
+
+/// Formats the files in place, or with `check` leaves them as they are. Returns the files that
+/// were, or would be, changed, and whether any couldn't be formatted, after logging why.
+pub fn format_files(lua: &Lua, paths: &[PathBuf], check: bool) -> Result<(Vec<PathBuf>, bool)> {
+    let mut changed = Vec::new();
+    let mut failed = false;
+    for path in paths {
+        let source = std::fs::read_to_string(path).map_err(rlua::Error::external)?;
+        let formatted = match lint::syntax_error(lua, path, &source)? {
+            Some(error) => Err(format!(
+                "{}:{}: {}",
+                error.line, error.column, error.message
+            )),
+            None => format_source(&source)
+                .map_err(|error| format!("{}:{}: {}", error.line, error.column, error.message)),
+        };
+        let formatted = match formatted {
+            Ok(formatted) if significant_tokens(&formatted) == significant_tokens(&source) => {
+                formatted
+            }
+            Ok(_) => {
+                logger::error(&format!(
+                    "Failed to format {} [formatting would change the code]",
+                    path.display()
+                ));
+                failed = true;
+                continue;
+            }
+            Err(message) => {
+                logger::error(&format!(
+                    "Failed to format {} [{}]",
+                    path.display(),
+                    message
+                ));
+                failed = true;
+                continue;
+            }
+        };
+        if formatted == source {
+            continue;
+        }
+        if !check {
+            std::fs::write(path, &formatted).map_err(rlua::Error::external)?;
+        }
+        changed.push(path.clone());
+    }
+    Ok((changed, failed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn format(source: &str) -> String {
+        format_source(source).unwrap()
+    }
+
+    #[test]
+    fn keeps_long_strings_as_they_are() {
+        assert_eq!(format("x=[==[a ]] b]=]c]==]\n"), "x = [==[a ]] b]=]c]==]\n");
+        let source = "if x then\ns = [[\n  keep\n\tthis  ]]\nend\n";
+        assert_eq!(
+            format(source),
+            "if x then\n    s = [[\n  keep\n\tthis  ]]\nend\n"
+        );
+    }
+
+    #[test]
+    fn keeps_long_comments_as_they_are() {
+        let source = "--[=[ a ]] b ]=]\nlocal  x=1\n";
+        assert_eq!(format(source), "--[=[ a ]] b ]=]\nlocal x = 1\n");
+    }
+
+    #[test]
+    fn keeps_escapes_in_quoted_strings() {
+        let source = "local s = \"a\\z\n   b\"\nlocal t=1\n";
+        assert_eq!(format(source), "local s = \"a\\z\n   b\"\nlocal t = 1\n");
+    }
+
+    #[test]
+    fn is_stable() {
+        let source = "function f(a,b)\nreturn a+b\nend\nlocal t={1,2,[[x]]}\n";
+        let formatted = format(source);
+        assert_eq!(format(&formatted), formatted);
+        assert_eq!(significant_tokens(source), significant_tokens(&formatted));
+    }
+}
@@ -0,0 +1,1487 @@
+/*
+   Copyright (C) 2022  Kalka
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License, or (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use notify::event::{ModifyKind, RenameMode};
+use notify::{EventKind, RecursiveMode, Watcher};
+use rlua::{
+    AnyUserData, Context, Function, Lua, MetaMethod, Result, Table, UserData, UserDataMethods,
+    Value, Variadic,
+};
+use std::collections::HashSet;
+use std::fs::{FileType, Metadata};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::error::Error;
+use crate::hash::{self, Hasher};
+use crate::help::{self, FunctionDoc};
+
+/// Functions that only look at files, which the sandbox allows in the directories it can read.
+pub const READERS: &[&str] = &[
+    "read",
+    "exists",
+    "list",
+    "walk",
+    "stat",
+    "is_dir",
+    "is_file",
+    "is_symlink",
+    "watch",
+    // The sandbox's check refuses modes that write
+    "open",
+    "checksum",
+];
+/// Functions that change files, which the sandbox disables.
+pub const WRITERS: &[&str] = &[
+    "write",
+    "append",
+    "copy",
+    "move",
+    "remove",
+    "remove_all",
+    "mkdirs",
+    "tempfile",
+    "tempdir",
+    "chmod",
+    "chown",
+    "set_readonly",
+    "lock",
+    "try_lock",
+];
+
+// Temporary files and directories that haven't been removed yet, removed when rluaterm exits
+static TEMP_PATHS: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+// Tries at picking an unused name for a temporary file before giving up
+const TEMP_NAME_ATTEMPTS: usize = 100;
+
+// How often fs.watch checks for Ctrl+C and the timeout while waiting for changes
+const WATCH_POLL_INTERVAL: Duration = Duration::from_millis(100);
+// Bytes a file handle collects before writing them to the file, and fs.checksum reads at once
+const BUFFER_SIZE: usize = 64 * 1024;
+
+// How often fs.lock tries again while another process holds the lock
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(50);
+// How long fs.watch waits for changes to settle by default, in seconds
+const DEFAULT_DEBOUNCE: f64 = 0.1;
+
+const FS_DOCS: &[FunctionDoc] = &[
+    FunctionDoc {
+        name: "read",
+        signature: "fs.read(path) -> string",
+        description: "Reads a whole file, text or binary. Raises an error saying why when the file can't be read.",
+        example: "local config = fs.read(\"config.json\")",
+    },
+    FunctionDoc {
+        name: "write",
+        signature: "fs.write(path, data)",
+        description: "Writes the data to the file, creating it or replacing what it held. Raises an error saying why when the file can't be written.",
+        example: "fs.write(\"out.txt\", \"hello\\n\")",
+    },
+    FunctionDoc {
+        name: "append",
+        signature: "fs.append(path, data)",
+        description: "Adds the data to the end of the file, creating it if it doesn't exist. Raises an error saying why when the file can't be written.",
+        example: "fs.append(\"log.txt\", os.date() .. \"\\n\")",
+    },
+    FunctionDoc {
+        name: "exists",
+        signature: "fs.exists(path) -> boolean",
+        description: "Whether there is a file or directory at the path.",
+        example: "if not fs.exists(\"cache\") then os.execute(\"mkdir cache\") end",
+    },
+    FunctionDoc {
+        name: "list",
+        signature: "fs.list(dir[, options]) -> entries",
+        description: "The entries of a directory sorted by name, as tables of name, path, type (\"file\", \"directory\", \"symlink\" or \"other\") and size in bytes. Set options.hidden to false to leave out names starting with a dot, and options.follow_symlinks to describe what symlinks point to instead.",
+        example: "for _, entry in ipairs(fs.list(\".\")) do\n    print(entry.type, entry.name)\nend",
+    },
+    FunctionDoc {
+        name: "walk",
+        signature: "fs.walk(dir[, options]) -> iterator",
+        description: "Iterates over every entry under a directory, each directory followed by its contents, with the same entries and options as fs.list. Symlinks to directories are only walked into with options.follow_symlinks, and never twice.",
+        example: "for entry in fs.walk(\"src\", { hidden = false }) do\n    if entry.name:match(\"%.lua$\") then print(entry.path) end\nend",
+    },
+    FunctionDoc {
+        name: "stat",
+        signature: "fs.stat(path[, options]) -> table",
+        description: "Describes a file: its type and size, when it was modified, accessed and created as seconds since 1970 (nil where the system doesn't keep the time), and whether it's readonly. On Unix, also its permissions as mode and as text like \"rwxr-xr-x\", and its owner's name, uid and gid. Follows symlinks unless options.follow_symlinks is false.",
+        example: "local info = fs.stat(\"build.lua\")\nif os.time() - info.modified > 3600 then print(\"stale\") end",
+    },
+    FunctionDoc {
+        name: "is_dir",
+        signature: "fs.is_dir(path) -> boolean",
+        description: "Whether the path is a directory, or a symlink to one.",
+        example: "if fs.is_dir(\"build\") then print(\"built\") end",
+    },
+    FunctionDoc {
+        name: "is_file",
+        signature: "fs.is_file(path) -> boolean",
+        description: "Whether the path is a regular file, or a symlink to one.",
+        example: "if not fs.is_file(\"config.toml\") then fs.write(\"config.toml\", \"\") end",
+    },
+    FunctionDoc {
+        name: "is_symlink",
+        signature: "fs.is_symlink(path) -> boolean",
+        description: "Whether the path is a symlink, even one pointing nowhere.",
+        example: "print(fs.is_symlink(\"current\"))",
+    },
+    FunctionDoc {
+        name: "copy",
+        signature: "fs.copy(from, to[, options])",
+        description: "Copies a file, or a directory and everything in it with options.recursive, into the directory at to if there is one and to the path otherwise. Symlinks inside a directory are copied as symlinks on Unix.",
+        example: "fs.copy(\"assets\", \"dist/assets\", { recursive = true })",
+    },
+    FunctionDoc {
+        name: "move",
+        signature: "fs.move(from, to)",
+        description: "Moves or renames a file or directory, into the directory at to if there is one, copying it when it moves to another filesystem.",
+        example: "fs.move(\"build/out.txt\", \"release/\")",
+    },
+    FunctionDoc {
+        name: "remove",
+        signature: "fs.remove(path)",
+        description: "Removes a file, symlink or empty directory.",
+        example: "fs.remove(\"out.txt\")",
+    },
+    FunctionDoc {
+        name: "remove_all",
+        signature: "fs.remove_all(path)",
+        description: "Removes a directory and everything in it, or a file. Does nothing if there's nothing at the path.",
+        example: "fs.remove_all(\"build\")",
+    },
+    FunctionDoc {
+        name: "mkdirs",
+        signature: "fs.mkdirs(path)",
+        description: "Creates the directory and any missing directories above it. Does nothing if it already exists.",
+        example: "fs.mkdirs(\"build/cache\")",
+    },
+    FunctionDoc {
+        name: "tempfile",
+        signature: "fs.tempfile([options]) -> handle",
+        description: "Creates an empty file in the system's temporary directory, named with options.prefix and options.suffix if given. handle.path is its path, and so is tostring(handle). The file is removed once the handle is garbage collected or rluaterm exits, unless handle:keep() is called, and right away with handle:remove().",
+        example: "local tmp = fs.tempfile({ suffix = \".json\" })\nfs.write(tmp.path, http.get(url).text)",
+    },
+    FunctionDoc {
+        name: "tempdir",
+        signature: "fs.tempdir([options]) -> handle",
+        description: "Creates an empty directory in the system's temporary directory, with the same options and handle as fs.tempfile. The directory is removed along with everything in it.",
+        example: "local dir = fs.tempdir()\nfs.write(path.join(dir.path, \"data.txt\"), \"...\")",
+    },
+    FunctionDoc {
+        name: "watch",
+        signature: "fs.watch(path, callback[, options])",
+        description: "Calls callback with {type, path} for every file created, modified or deleted at the path or under it, with type \"create\", \"modify\" or \"delete\", until the callback returns false or Ctrl+C. Changes are passed on once none have come for options.debounce seconds (0.1 by default), one event per file. Set options.recursive to false to leave out subdirectories.",
+        example: "fs.watch(\"src\", function(event)\n    print(event.type, event.path)\nend)",
+    },
+    FunctionDoc {
+        name: "chmod",
+        signature: "fs.chmod(path, mode)",
+        description: "Sets a file's permissions, from a mode number like tonumber(\"755\", 8), octal digits like \"755\", text like \"rwxr-xr-x\", or changes to the current ones like \"+x\" or \"u+x,go-w\". Windows only keeps whether a file can be written, which is taken from the write bits.",
+        example: "fs.write(\"run.sh\", script)\nfs.chmod(\"run.sh\", \"+x\")",
+    },
+    FunctionDoc {
+        name: "chown",
+        signature: "fs.chown(path, user[, group])",
+        description: "Sets the user and group owning a file, given as names or ids, leaving out either with nil. Only available on Unix.",
+        example: "fs.chown(\"/srv/app/data\", \"www-data\", \"www-data\")",
+    },
+    FunctionDoc {
+        name: "set_readonly",
+        signature: "fs.set_readonly(path, readonly)",
+        description: "Makes a file readonly, or writable again. On Unix, this takes away every write bit, or gives write permission to the owner.",
+        example: "fs.set_readonly(\"release/notes.txt\", true)",
+    },
+    FunctionDoc {
+        name: "lock",
+        signature: "fs.lock(path[, options]) -> guard",
+        description: "Waits until no other process holds a lock on the file, creating it if needed, and locks it until guard:close() is called or the guard is garbage collected. Set options.shared for a lock other shared locks can be held alongside, and options.timeout to give up after that many seconds and return nil. Locks are advisory: they only keep out processes that lock the file too.",
+        example: "local guard = fs.lock(\"state.json.lock\")\nlocal state = fs.read(\"state.json\")\n-- ...\nguard:close()",
+    },
+    FunctionDoc {
+        name: "try_lock",
+        signature: "fs.try_lock(path[, options]) -> guard",
+        description: "Locks the file like fs.lock, but returns nil right away when another process holds the lock.",
+        example: "local guard = fs.try_lock(\"/tmp/backup.lock\")\nif not guard then\n    print(\"another backup is running\")\n    return\nend",
+    },
+    FunctionDoc {
+        name: "open",
+        signature: "fs.open(path[, mode]) -> file",
+        description: "Opens a file to read or write it a piece at a time, with mode \"r\" to read (the default), \"w\" to replace it, \"a\" to add to its end, and \"r+\", \"w+\" or \"a+\" to do both. file:read(n) reads up to n bytes, file:read(\"l\") a line without its line ending, file:read(\"a\") the rest, all returning nil at the end. file:lines() iterates over the lines, file:write(...) writes strings and numbers, file:seek([\"set\"|\"cur\"|\"end\"[, offset]]) moves and returns the position, and file:flush() and file:close() write out what's buffered. Raises an error saying why when the file can't be opened, read or written.",
+        example: "local log = fs.open(\"huge.log\")\nfor line in log:lines() do\n    if line:find(\"ERROR\") then print(line) end\nend\nlog:close()",
+    },
+    FunctionDoc {
+        name: "checksum",
+        signature: "fs.checksum(path[, algorithm]) -> string",
+        description: "Hashes the file's contents a piece at a time with \"sha256\" (the default) or any other algorithm hash.new takes, like \"md5\" or \"crc32\", returning the checksum in lowercase hex. Raises an error saying why when the file can't be read.",
+        example: "if fs.checksum(\"release.tar.gz\") ~= expected then\n    error(\"download is corrupt\")\nend",
+    },
+];
+
+fn kind(file_type: FileType) -> &'static str {
+    if file_type.is_dir() {
+        "directory"
+    } else if file_type.is_file() {
+        "file"
+    } else if file_type.is_symlink() {
+        "symlink"
+    } else {
+        "other"
+    }
+}
+
+// Seconds since the Unix epoch, if the system keeps the time
+fn timestamp(time: std::io::Result<SystemTime>) -> Option<f64> {
+    Some(match time.ok()?.duration_since(UNIX_EPOCH) {
+        Ok(since) => since.as_secs_f64(),
+        Err(error) => -error.duration().as_secs_f64(),
+    })
+}
+
+fn stat_table<'lua>(lua_ctx: Context<'lua>, metadata: &Metadata) -> Result<Table<'lua>> {
+    let table = lua_ctx.create_table()?;
+    table.set("type", kind(metadata.file_type()))?;
+    table.set("size", metadata.len())?;
+    table.set("modified", timestamp(metadata.modified()))?;
+    table.set("accessed", timestamp(metadata.accessed()))?;
+    table.set("created", timestamp(metadata.created()))?;
+    table.set("readonly", metadata.permissions().readonly())?;
+    set_unix_fields(&table, metadata)?;
+    Ok(table)
+}
+
+#[cfg(unix)]
+fn set_unix_fields(table: &Table, metadata: &Metadata) -> Result<()> {
+    use std::os::unix::fs::MetadataExt;
+
+    let mode = metadata.mode() & 0o777;
+    let mut permissions = String::new();
+    for shift in [6, 3, 0] {
+        for (bit, letter) in [(4, 'r'), (2, 'w'), (1, 'x')] {
+            permissions.push(if (mode >> shift) & bit != 0 {
+                letter
+            } else {
+                '-'
+            });
+        }
+    }
+    table.set("mode", mode)?;
+    table.set("permissions", permissions)?;
+    table.set("uid", metadata.uid())?;
+    table.set("gid", metadata.gid())?;
+    table.set("owner", user_name(metadata.uid()))
+}
+
+#[cfg(not(unix))]
+fn set_unix_fields(_table: &Table, _metadata: &Metadata) -> Result<()> {
+    Ok(())
+}
+
+/// The name of the user with the id, from the password database.
+#[cfg(unix)]
+pub fn user_name(uid: u32) -> Option<String> {
+    let mut buffer = vec![0 as libc::c_char; 16384];
+    let mut passwd = unsafe { std::mem::zeroed::<libc::passwd>() };
+    let mut result = std::ptr::null_mut();
+    let status = unsafe {
+        libc::getpwuid_r(
+            uid,
+            &mut passwd,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut result,
+        )
+    };
+    if status != 0 || result.is_null() {
+        return None;
+    }
+    let name = unsafe { std::ffi::CStr::from_ptr(passwd.pw_name) };
+    Some(name.to_string_lossy().into_owned())
+}
+
+// Where copying or moving `from` to `to` puts it: inside `to` when it's a directory
+fn destination(from: &Path, to: &Path) -> PathBuf {
+    match from.file_name() {
+        Some(name) if to.is_dir() => to.join(name),
+        _ => to.to_path_buf(),
+    }
+}
+
+#[cfg(unix)]
+fn copy_symlink(from: &Path, to: &Path) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(std::fs::read_link(from)?, to)
+}
+
+// Symlinks can't always be made elsewhere, so what they point to is copied
+#[cfg(not(unix))]
+fn copy_symlink(from: &Path, to: &Path) -> std::io::Result<()> {
+    if from.is_dir() {
+        copy_tree(from, to)
+    } else {
+        std::fs::copy(from, to).map(|_| ())
+    }
+}
+
+fn copy_tree(from: &Path, to: &Path) -> std::io::Result<()> {
+    let file_type = std::fs::symlink_metadata(from)?.file_type();
+    if file_type.is_symlink() {
+        copy_symlink(from, to)
+    } else if file_type.is_dir() {
+        std::fs::create_dir_all(to)?;
+        for entry in std::fs::read_dir(from)? {
+            let entry = entry?;
+            copy_tree(&entry.path(), &to.join(entry.file_name()))?;
+        }
+        Ok(())
+    } else {
+        std::fs::copy(from, to).map(|_| ())
+    }
+}
+
+fn copy(from: &Path, to: &Path, recursive: bool) -> std::io::Result<()> {
+    let to = destination(from, to);
+    if !from.is_dir() {
+        return std::fs::copy(from, to).map(|_| ());
+    }
+    if !recursive {
+        return Err(std::io::Error::other(
+            "it is a directory, copy it with { recursive = true }",
+        ));
+    }
+    // Copying a directory into itself would never end
+    if let (Ok(from), Some(Ok(parent))) = (
+        from.canonicalize(),
+        to.parent().map(|parent| parent.canonicalize()),
+    ) {
+        if parent.starts_with(&from) {
+            return Err(std::io::Error::other("can't copy a directory into itself"));
+        }
+    }
+    copy_tree(from, &to)
+}
+
+fn move_path(from: &Path, to: &Path) -> std::io::Result<()> {
+    let to = destination(from, to);
+    match std::fs::rename(from, &to) {
+        // Renaming only works within a filesystem
+        Err(error) if error.kind() == std::io::ErrorKind::CrossesDevices => {
+            copy_tree(from, &to)?;
+            remove_all(from)
+        }
+        result => result,
+    }
+}
+
+fn remove(path: &Path) -> std::io::Result<()> {
+    if std::fs::symlink_metadata(path)?.is_dir() {
+        std::fs::remove_dir(path)
+    } else {
+        std::fs::remove_file(path)
+    }
+}
+
+fn remove_all(path: &Path) -> std::io::Result<()> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => std::fs::remove_dir_all(path),
+        Ok(_) => std::fs::remove_file(path),
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(error) => Err(error),
+    }
+}
+
+// A temporary file or directory, removed when dropped
+struct TempPath {
+    path: PathBuf,
+    kept: bool,
+}
+
+impl TempPath {
+    // Creates the file or directory named with the prefix and suffix given in the options
+    fn create(dir: bool, options: Option<Table>) -> Result<TempPath> {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let (prefix, suffix) = match options {
+            Some(options) => (
+                options.get::<_, Option<String>>("prefix")?,
+                options.get::<_, Option<String>>("suffix")?,
+            ),
+            None => (None, None),
+        };
+        let prefix = prefix.unwrap_or_else(|| "rluaterm-".to_string());
+        let suffix = suffix.unwrap_or_default();
+        let mut attempt = 0;
+        loop {
+            let nanos = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.subsec_nanos());
+            let name = format!(
+                "{}{}-{}-{}{}",
+                prefix,
+                std::process::id(),
+                COUNTER.fetch_add(1, Ordering::SeqCst),
+                nanos,
+                suffix
+            );
+            let path = std::env::temp_dir().join(name);
+            let created = if dir {
+                std::fs::create_dir(&path)
+            } else {
+                std::fs::OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .open(&path)
+                    .map(|_| ())
+            };
+            match created {
+                Ok(()) => {
+                    remove_temp_paths_on_exit();
+                    TEMP_PATHS.lock().unwrap().push(path.clone());
+                    return Ok(TempPath { path, kept: false });
+                }
+                Err(error)
+                    if error.kind() == std::io::ErrorKind::AlreadyExists
+                        && attempt < TEMP_NAME_ATTEMPTS =>
+                {
+                    attempt += 1;
+                }
+                Err(source) if dir => {
+                    return Err(rlua::Error::external(Error::CreateDir {
+                        path: path.display().to_string(),
+                        source,
+                    }))
+                }
+                Err(source) => return Err(write_error(&path.display().to_string(), source)),
+            }
+        }
+    }
+
+    // Stops removing the path, when kept or already removed
+    fn forget(&mut self) {
+        self.kept = true;
+        TEMP_PATHS.lock().unwrap().retain(|path| *path != self.path);
+    }
+}
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        if !self.kept {
+            let _ = remove_all(&self.path);
+            self.forget();
+        }
+    }
+}
+
+impl UserData for TempPath {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method_mut("keep", |_, temp, ()| {
+            temp.forget();
+            Ok(temp.path.to_string_lossy().into_owned())
+        });
+        methods.add_method_mut("remove", |_, temp, ()| {
+            if temp.kept {
+                return Ok(());
+            }
+            let result = remove_all(&temp.path).map_err(|source| {
+                rlua::Error::external(Error::Remove {
+                    path: temp.path.display().to_string(),
+                    source,
+                })
+            });
+            temp.forget();
+            result
+        });
+        methods.add_meta_method(MetaMethod::Index, |_, temp, key: String| {
+            Ok((key == "path").then(|| temp.path.to_string_lossy().into_owned()))
+        });
+        methods.add_meta_method(MetaMethod::ToString, |_, temp, ()| {
+            Ok(temp.path.to_string_lossy().into_owned())
+        });
+    }
+}
+
+// std::process::exit skips destructors, so what's left is removed by an exit handler. Elsewhere,
+// it's removed when the interpreter is closed on a clean exit.
+#[cfg(unix)]
+fn remove_temp_paths_on_exit() {
+    extern "C" fn remove_temp_paths() {
+        // Another thread may hold the lock while exiting
+        if let Ok(mut paths) = TEMP_PATHS.try_lock() {
+            for path in paths.drain(..) {
+                let _ = remove_all(&path);
+            }
+        }
+    }
+
+    static REGISTERED: std::sync::Once = std::sync::Once::new();
+    REGISTERED.call_once(|| unsafe {
+        libc::atexit(remove_temp_paths);
+    });
+}
+
+#[cfg(not(unix))]
+fn remove_temp_paths_on_exit() {}
+
+// The changes notify reports as what happened to each path, renames as a delete and a create
+fn changes(event: notify::Event) -> Vec<(PathBuf, &'static str)> {
+    let kind = match event.kind {
+        EventKind::Create(_) => "create",
+        EventKind::Remove(_) => "delete",
+        EventKind::Modify(ModifyKind::Name(RenameMode::From)) => "delete",
+        EventKind::Modify(ModifyKind::Name(RenameMode::To)) => "create",
+        EventKind::Modify(ModifyKind::Name(RenameMode::Both)) => {
+            let mut paths = event.paths.into_iter();
+            return paths
+                .next()
+                .map(|from| (from, "delete"))
+                .into_iter()
+                .chain(paths.next().map(|to| (to, "create")))
+                .collect();
+        }
+        // Only one side of the rename was seen
+        EventKind::Modify(ModifyKind::Name(_)) => {
+            return event
+                .paths
+                .into_iter()
+                .map(|path| {
+                    let kind = if path.exists() { "create" } else { "delete" };
+                    (path, kind)
+                })
+                .collect();
+        }
+        EventKind::Modify(_) => "modify",
+        _ => return Vec::new(),
+    };
+    event.paths.into_iter().map(|path| (path, kind)).collect()
+}
+
+// Adds a change to the pending ones, merging it with an earlier change to the same path
+fn add_change(pending: &mut Vec<(PathBuf, &'static str)>, path: PathBuf, kind: &'static str) {
+    let Some(index) = pending.iter().position(|(pending, _)| *pending == path) else {
+        pending.push((path, kind));
+        return;
+    };
+    let merged = match (pending[index].1, kind) {
+        ("create", "modify") => Some("create"),
+        // Gone before anyone saw it
+        ("create", "delete") => None,
+        // Replaced, as editors often save files
+        ("delete", "create") => Some("modify"),
+        (_, kind) => Some(kind),
+    };
+    match merged {
+        Some(kind) => pending[index].1 = kind,
+        None => {
+            pending.remove(index);
+        }
+    }
+}
+
+fn watch<'lua>(
+    lua_ctx: Context<'lua>,
+    path: &str,
+    callback: Function<'lua>,
+    options: Option<Table<'lua>>,
+) -> Result<()> {
+    let (recursive, debounce) = match &options {
+        Some(options) => (
+            options.get::<_, Option<bool>>("recursive")?.unwrap_or(true),
+            options
+                .get::<_, Option<f64>>("debounce")?
+                .unwrap_or(DEFAULT_DEBOUNCE),
+        ),
+        None => (true, DEFAULT_DEBOUNCE),
+    };
+    let debounce = Duration::from_secs_f64(debounce.max(0.0));
+    let mode = if recursive {
+        RecursiveMode::Recursive
+    } else {
+        RecursiveMode::NonRecursive
+    };
+    let (sender, receiver) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(sender).map_err(rlua::Error::external)?;
+    watcher
+        .watch(Path::new(path), mode)
+        .map_err(rlua::Error::external)?;
+
+    let mut pending = Vec::new();
+    let mut last_change = Instant::now();
+    loop {
+        crate::check_interrupt(lua_ctx)?;
+        let wait = if pending.is_empty() {
+            WATCH_POLL_INTERVAL
+        } else {
+            debounce
+                .saturating_sub(last_change.elapsed())
+                .min(WATCH_POLL_INTERVAL)
+        };
+        match receiver.recv_timeout(wait) {
+            Ok(Ok(event)) => {
+                for (path, kind) in changes(event) {
+                    add_change(&mut pending, path, kind);
+                    last_change = Instant::now();
+                }
+                continue;
+            }
+            Ok(Err(error)) => return Err(rlua::Error::external(error)),
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Err(mpsc::RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+        if pending.is_empty() || last_change.elapsed() < debounce {
+            continue;
+        }
+        for (path, kind) in pending.drain(..) {
+            let event = lua_ctx.create_table()?;
+            event.set("type", kind)?;
+            event.set("path", path.to_string_lossy().as_ref())?;
+            if let Value::Boolean(false) = callback.call::<_, Value>(event)? {
+                return Ok(());
+            }
+        }
+    }
+}
+
+// The mode a file with the current mode gets from fs.chmod's argument, if it makes sense
+fn parse_mode(current: u32, mode: &Value) -> Option<u32> {
+    let text = match mode {
+        Value::Integer(mode) => return u32::try_from(*mode).ok().filter(|mode| *mode <= 0o7777),
+        Value::String(text) => text.to_str().ok()?,
+        _ => return None,
+    };
+    if !text.is_empty() && text.len() <= 4 && text.chars().all(|c| ('0'..='7').contains(&c)) {
+        return u32::from_str_radix(text, 8).ok();
+    }
+    // As fs.stat shows it
+    if text.len() == 9 && text.chars().all(|c| "rwx-".contains(c)) {
+        let mut mode = 0;
+        for (index, c) in text.chars().enumerate() {
+            if c != '-' {
+                if c != "rwx".chars().nth(index % 3)? {
+                    return None;
+                }
+                mode |= 1 << (8 - index);
+            }
+        }
+        return Some(mode);
+    }
+    // Changes like "+x" or "u+x,go-w", to everyone when nobody is named
+    let mut mode = current;
+    for change in text.split(',') {
+        let operator = change.find(['+', '-', '='])?;
+        let (who, permissions) = change.split_at(operator);
+        let mut mask = if who.is_empty() { 0o777 } else { 0 };
+        for c in who.chars() {
+            mask |= match c {
+                'u' => 0o700,
+                'g' => 0o070,
+                'o' => 0o007,
+                'a' => 0o777,
+                _ => return None,
+            };
+        }
+        let mut bits = 0;
+        for c in permissions[1..].chars() {
+            bits |= match c {
+                'r' => 0o444,
+                'w' => 0o222,
+                'x' => 0o111,
+                _ => return None,
+            };
+        }
+        bits &= mask;
+        mode = match &permissions[..1] {
+            "+" => mode | bits,
+            "-" => mode & !bits,
+            _ => (mode & !mask) | bits,
+        };
+    }
+    Some(mode)
+}
+
+fn permissions_error(path: &str, source: std::io::Error) -> rlua::Error {
+    rlua::Error::external(Error::SetPermissions {
+        path: path.to_string(),
+        source,
+    })
+}
+
+fn chmod(path: &str, mode: &Value) -> Result<()> {
+    let permissions = std::fs::metadata(path)
+        .map_err(|source| permissions_error(path, source))?
+        .permissions();
+    let current = current_mode(&permissions);
+    let mode = parse_mode(current, mode).ok_or_else(|| {
+        rlua::Error::RuntimeError(
+            "bad argument #2 to 'chmod' (mode like 493, \"755\", \"rwxr-xr-x\" or \"+x\" expected)"
+                .to_string(),
+        )
+    })?;
+    std::fs::set_permissions(path, with_mode(permissions, mode))
+        .map_err(|source| permissions_error(path, source))
+}
+
+#[cfg(unix)]
+fn current_mode(permissions: &std::fs::Permissions) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    permissions.mode() & 0o7777
+}
+
+#[cfg(unix)]
+fn with_mode(_permissions: std::fs::Permissions, mode: u32) -> std::fs::Permissions {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::Permissions::from_mode(mode)
+}
+
+// Elsewhere, files are either readonly or not
+#[cfg(not(unix))]
+fn current_mode(permissions: &std::fs::Permissions) -> u32 {
+    if permissions.readonly() {
+        0o444
+    } else {
+        0o666
+    }
+}
+
+#[cfg(not(unix))]
+fn with_mode(mut permissions: std::fs::Permissions, mode: u32) -> std::fs::Permissions {
+    permissions.set_readonly(mode & 0o222 == 0);
+    permissions
+}
+
+fn set_readonly(path: &str, readonly: bool) -> Result<()> {
+    let permissions = std::fs::metadata(path)
+        .map_err(|source| permissions_error(path, source))?
+        .permissions();
+    // Permissions::set_readonly(false) would let everyone write on Unix
+    let mode = match current_mode(&permissions) {
+        mode if readonly => mode & !0o222,
+        mode => mode | 0o200,
+    };
+    std::fs::set_permissions(path, with_mode(permissions, mode))
+        .map_err(|source| permissions_error(path, source))
+}
+
+// The id of a user or group given by name or id, looked up with getpwnam_r or getgrnam_r
+#[cfg(unix)]
+fn owner_id(value: Value, group: bool) -> Result<Option<u32>> {
+    let name = match value {
+        Value::Nil => return Ok(None),
+        Value::Integer(id) => {
+            return u32::try_from(id)
+                .map(Some)
+                .map_err(|_| rlua::Error::RuntimeError(format!("invalid id {}", id)))
+        }
+        Value::String(name) => name.to_str()?.to_string(),
+        value => {
+            return Err(rlua::Error::RuntimeError(format!(
+                "bad argument to 'chown' (name or id expected, got {})",
+                value.type_name()
+            )))
+        }
+    };
+    let kind = if group { "group" } else { "user" };
+    let not_found = || rlua::Error::RuntimeError(format!("no {} named {}", kind, name));
+    let c_name = std::ffi::CString::new(name.as_str()).map_err(|_| not_found())?;
+    let mut buffer = vec![0 as libc::c_char; 16384];
+    let found = unsafe {
+        if group {
+            let mut entry = std::mem::zeroed::<libc::group>();
+            let mut result = std::ptr::null_mut();
+            let status = libc::getgrnam_r(
+                c_name.as_ptr(),
+                &mut entry,
+                buffer.as_mut_ptr(),
+                buffer.len(),
+                &mut result,
+            );
+            (status == 0 && !result.is_null()).then_some(entry.gr_gid)
+        } else {
+            let mut entry = std::mem::zeroed::<libc::passwd>();
+            let mut result = std::ptr::null_mut();
+            let status = libc::getpwnam_r(
+                c_name.as_ptr(),
+                &mut entry,
+                buffer.as_mut_ptr(),
+                buffer.len(),
+                &mut result,
+            );
+            (status == 0 && !result.is_null()).then_some(entry.pw_uid)
+        }
+    };
+    found.map(Some).ok_or_else(not_found)
+}
+
+#[cfg(unix)]
+fn chown(path: &str, user: Value, group: Value) -> Result<()> {
+    let uid = owner_id(user, false)?;
+    let gid = owner_id(group, true)?;
+    std::os::unix::fs::chown(path, uid, gid).map_err(|source| permissions_error(path, source))
+}
+
+#[cfg(not(unix))]
+fn chown(_path: &str, _user: Value, _group: Value) -> Result<()> {
+    Err(rlua::Error::RuntimeError(
+        "fs.chown is only available on Unix".to_string(),
+    ))
+}
+
+// An advisory lock on a file, released when closed or dropped along with the file
+struct FileLock {
+    file: Option<std::fs::File>,
+    path: String,
+}
+
+impl UserData for FileLock {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method_mut("close", |_, lock, ()| {
+            lock.file = None;
+            Ok(())
+        });
+        methods.add_meta_method(MetaMethod::Index, |lua_ctx, lock, key: String| {
+            Ok(match key.as_str() {
+                "path" => Some(Value::String(lua_ctx.create_string(&lock.path)?)),
+                "locked" => Some(Value::Boolean(lock.file.is_some())),
+                _ => None,
+            })
+        });
+    }
+}
+
+// Takes the lock if nobody else holds it
+fn try_lock_file(file: &std::fs::File, shared: bool) -> std::io::Result<bool> {
+    let result = if shared {
+        file.try_lock_shared()
+    } else {
+        file.try_lock()
+    };
+    match result {
+        Ok(()) => Ok(true),
+        Err(std::fs::TryLockError::WouldBlock) => Ok(false),
+        Err(std::fs::TryLockError::Error(error)) => Err(error),
+    }
+}
+
+// Locks the file, waiting for it up to the timeout in options when `wait` is set
+fn lock(
+    lua_ctx: Context,
+    path: String,
+    options: Option<Table>,
+    wait: bool,
+) -> Result<Option<FileLock>> {
+    let (shared, timeout) = match options {
+        Some(options) => (
+            options.get::<_, Option<bool>>("shared")?.unwrap_or(false),
+            options.get::<_, Option<f64>>("timeout")?,
+        ),
+        None => (false, None),
+    };
+    let lock_error = |source| {
+        rlua::Error::external(Error::Lock {
+            path: path.clone(),
+            source,
+        })
+    };
+    let file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&path)
+        .map_err(lock_error)?;
+    let deadline =
+        timeout.map(|timeout| Instant::now() + Duration::from_secs_f64(timeout.max(0.0)));
+    // Waiting in steps keeps Ctrl+C and the timeout working
+    while !try_lock_file(&file, shared).map_err(lock_error)? {
+        if !wait || deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return Ok(None);
+        }
+        crate::check_interrupt(lua_ctx)?;
+        std::thread::sleep(LOCK_POLL_INTERVAL);
+    }
+    Ok(Some(FileLock {
+        file: Some(file),
+        path,
+    }))
+}
+
+fn checksum(lua_ctx: Context, path: &str, algorithm: &str) -> Result<String> {
+    let mut hasher = Hasher::new(algorithm).ok_or_else(|| {
+        rlua::Error::RuntimeError(format!(
+            "bad argument #2 to 'checksum' (unknown algorithm '{}', expected {})",
+            algorithm,
+            hash::ALGORITHMS
+        ))
+    })?;
+    let mut file =
+        std::fs::File::open(path).map_err(|source| read_error(Path::new(path), source))?;
+    let mut buffer = vec![0; BUFFER_SIZE];
+    loop {
+        let read = match file.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(source) => return Err(read_error(Path::new(path), source)),
+        };
+        hasher.update(&buffer[..read]);
+        // Hashing a big file takes a while, so Ctrl+C and --timeout can stop it
+        crate::check_interrupt(lua_ctx)?;
+    }
+    Ok(hash::hex(&hasher.finish()))
+}
+
+// A file opened with fs.open. Reads are buffered by the reader, and writes collected in
+// `pending` until the buffer fills up or the handle reads, seeks, flushes or closes.
+struct FileHandle {
+    path: String,
+    reader: Option<BufReader<std::fs::File>>,
+    pending: Vec<u8>,
+}
+
+impl FileHandle {
+    fn open(path: String, mode: &str) -> Result<FileHandle> {
+        let mut options = std::fs::OpenOptions::new();
+        match mode.trim_end_matches('b') {
+            "r" => options.read(true),
+            "w" => options.write(true).create(true).truncate(true),
+            "a" => options.append(true).create(true),
+            "r+" => options.read(true).write(true),
+            "w+" => options.read(true).write(true).create(true).truncate(true),
+            "a+" => options.read(true).append(true).create(true),
+            _ => {
+                return Err(rlua::Error::RuntimeError(format!(
+                    "bad argument #2 to 'open' (invalid mode '{}')",
+                    mode
+                )))
+            }
+        };
+        let file = options.open(&path).map_err(|source| {
+            if mode.starts_with('r') {
+                read_error(Path::new(&path), source)
+            } else {
+                write_error(&path, source)
+            }
+        })?;
+        Ok(FileHandle {
+            path,
+            reader: Some(BufReader::new(file)),
+            pending: Vec::new(),
+        })
+    }
+
+    fn read_error(&self, source: std::io::Error) -> rlua::Error {
+        read_error(Path::new(&self.path), source)
+    }
+
+    fn write_error(&self, source: std::io::Error) -> rlua::Error {
+        write_error(&self.path, source)
+    }
+
+    fn flush_pending(&mut self) -> Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let pending = std::mem::take(&mut self.pending);
+        let reader = self.reader.as_mut().ok_or_else(closed_error)?;
+        reader
+            .get_mut()
+            .write_all(&pending)
+            .map_err(|source| self.write_error(source))
+    }
+
+    // The reader, with everything written before passed on to the file
+    fn reader(&mut self) -> Result<&mut BufReader<std::fs::File>> {
+        self.flush_pending()?;
+        self.reader.as_mut().ok_or_else(closed_error)
+    }
+
+    // A line, without its line ending unless `keep_ending` is set
+    fn read_line(&mut self, keep_ending: bool) -> Result<Option<Vec<u8>>> {
+        let mut line = Vec::new();
+        let read = self
+            .reader()?
+            .read_until(b'\n', &mut line)
+            .map_err(|source| self.read_error(source))?;
+        if read == 0 {
+            return Ok(None);
+        }
+        if !keep_ending && line.ends_with(b"\n") {
+            line.pop();
+            if line.ends_with(b"\r") {
+                line.pop();
+            }
+        }
+        Ok(Some(line))
+    }
+
+    fn read<'lua>(&mut self, lua_ctx: Context<'lua>, format: Value<'lua>) -> Result<Value<'lua>> {
+        let bytes = match format {
+            Value::Nil => self.read_line(false)?,
+            Value::Integer(count) => {
+                let mut bytes = Vec::new();
+                self.reader()?
+                    .take(count.max(0) as u64)
+                    .read_to_end(&mut bytes)
+                    .map_err(|source| self.read_error(source))?;
+                (count <= 0 || !bytes.is_empty()).then_some(bytes)
+            }
+            Value::String(format) => match format.to_str()?.trim_start_matches('*') {
+                "l" => self.read_line(false)?,
+                "L" => self.read_line(true)?,
+                "a" => {
+                    let mut bytes = Vec::new();
+                    self.reader()?
+                        .read_to_end(&mut bytes)
+                        .map_err(|source| self.read_error(source))?;
+                    Some(bytes)
+                }
+                format => {
+                    return Err(rlua::Error::RuntimeError(format!(
+                        "bad argument #1 to 'read' (invalid format '{}')",
+                        format
+                    )))
+                }
+            },
+            format => {
+                return Err(rlua::Error::RuntimeError(format!(
+                    "bad argument #1 to 'read' (number or format expected, got {})",
+                    format.type_name()
+                )))
+            }
+        };
+        match bytes {
+            Some(bytes) => Ok(Value::String(lua_ctx.create_string(&bytes)?)),
+            None => Ok(Value::Nil),
+        }
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<()> {
+        let reader = self.reader.as_mut().ok_or_else(closed_error)?;
+        // Reading ahead moved the file past where the handle is, so move it back
+        if !reader.buffer().is_empty() {
+            let position = reader
+                .stream_position()
+                .map_err(|source| read_error(Path::new(&self.path), source))?;
+            reader
+                .seek(SeekFrom::Start(position))
+                .map_err(|source| read_error(Path::new(&self.path), source))?;
+        }
+        self.pending.extend_from_slice(data);
+        if self.pending.len() >= BUFFER_SIZE {
+            self.flush_pending()?;
+        }
+        Ok(())
+    }
+
+    fn close(&mut self) -> Result<()> {
+        let result = self.flush_pending();
+        self.reader = None;
+        result
+    }
+}
+
+impl Drop for FileHandle {
+    fn drop(&mut self) {
+        let _ = self.flush_pending();
+    }
+}
+
+fn closed_error() -> rlua::Error {
+    rlua::Error::RuntimeError("attempt to use a closed file".to_string())
+}
+
+impl UserData for FileHandle {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method_mut("read", |lua_ctx, file, format: Value| {
+            file.read(lua_ctx, format)
+        });
+        methods.add_function("lines", |lua_ctx, file: AnyUserData| {
+            let next = lua_ctx.create_function(|lua_ctx, (file, _): (AnyUserData, Value)| {
+                let line = file.borrow_mut::<FileHandle>()?.read_line(false)?;
+                line.map(|line| lua_ctx.create_string(&line)).transpose()
+            })?;
+            Ok((next, file))
+        });
+        methods.add_function(
+            "write",
+            |_, (file, data): (AnyUserData, Variadic<rlua::String>)| {
+                {
+                    let mut handle = file.borrow_mut::<FileHandle>()?;
+                    for data in data.iter() {
+                        handle.write(data.as_bytes())?;
+                    }
+                }
+                Ok(file)
+            },
+        );
+        methods.add_method_mut(
+            "seek",
+            |_, file, (whence, offset): (Option<String>, Option<i64>)| {
+                let offset = offset.unwrap_or(0);
+                let position = match whence.as_deref().unwrap_or("cur") {
+                    "set" => SeekFrom::Start(offset.max(0) as u64),
+                    "cur" => SeekFrom::Current(offset),
+                    "end" => SeekFrom::End(offset),
+                    whence => {
+                        return Err(rlua::Error::RuntimeError(format!(
+                            "bad argument #1 to 'seek' (invalid option '{}')",
+                            whence
+                        )))
+                    }
+                };
+                let path = file.path.clone();
+                file.reader()?
+                    .seek(position)
+                    .map_err(|source| read_error(Path::new(&path), source))
+            },
+        );
+        methods.add_method_mut("flush", |_, file, ()| {
+            file.reader()?
+                .get_mut()
+                .flush()
+                .map_err(|source| write_error(&file.path, source))
+        });
+        methods.add_method_mut("close", |_, file, ()| file.close());
+        methods.add_meta_method(MetaMethod::ToString, |_, file, ()| {
+            let state = if file.reader.is_some() { "" } else { "closed " };
+            Ok(format!("{}file ({})", state, file.path))
+        });
+    }
+}
+
+// Options of fs.list and fs.walk
+struct ListOptions {
+    hidden: bool,
+    follow_symlinks: bool,
+}
+
+impl ListOptions {
+    fn from_table(options: Option<Table>) -> Result<ListOptions> {
+        let Some(options) = options else {
+            return Ok(ListOptions {
+                hidden: true,
+                follow_symlinks: false,
+            });
+        };
+        Ok(ListOptions {
+            hidden: options.get::<_, Option<bool>>("hidden")?.unwrap_or(true),
+            follow_symlinks: options
+                .get::<_, Option<bool>>("follow_symlinks")?
+                .unwrap_or(false),
+        })
+    }
+}
+
+struct Entry {
+    name: String,
+    path: PathBuf,
+    kind: &'static str,
+    size: u64,
+}
+
+impl Entry {
+    fn to_table<'lua>(&self, lua_ctx: Context<'lua>) -> Result<Table<'lua>> {
+        let table = lua_ctx.create_table()?;
+        table.set("name", self.name.as_str())?;
+        table.set("path", self.path.to_string_lossy().as_ref())?;
+        table.set("type", self.kind)?;
+        table.set("size", self.size)?;
+        Ok(table)
+    }
+}
+
+fn read_error(path: &Path, source: std::io::Error) -> rlua::Error {
+    rlua::Error::external(Error::Read {
+        path: path.display().to_string(),
+        source,
+    })
+}
+
+// The entries of the directory sorted by name
+fn read_entries(dir: &Path, options: &ListOptions) -> Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    for entry in std::fs::read_dir(dir).map_err(|source| read_error(dir, source))? {
+        let entry = entry.map_err(|source| read_error(dir, source))?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if !options.hidden && name.starts_with('.') {
+            continue;
+        }
+        let path = entry.path();
+        let mut metadata =
+            std::fs::symlink_metadata(&path).map_err(|source| read_error(&path, source))?;
+        // Broken symlinks stay symlinks
+        if options.follow_symlinks && metadata.file_type().is_symlink() {
+            if let Ok(target) = std::fs::metadata(&path) {
+                metadata = target;
+            }
+        }
+        entries.push(Entry {
+            name,
+            path,
+            kind: kind(metadata.file_type()),
+            size: metadata.len(),
+        });
+    }
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(entries)
+}
+
+fn write_error(path: &str, source: std::io::Error) -> rlua::Error {
+    rlua::Error::external(Error::Write {
+        path: path.to_string(),
+        source,
+    })
+}
+
+pub fn load_fs_library(lua: &Lua) -> Result<()> {
+    lua.context(|lua_ctx| {
+        let fs = lua_ctx.create_table()?;
+
+        fs.set(
+            "read",
+            lua_ctx.create_function(|lua_ctx, path: String| {
+                let contents = std::fs::read(&path).map_err(|source| {
+                    rlua::Error::external(Error::Read {
+                        path: path.clone(),
+                        source,
+                    })
+                })?;
+                lua_ctx.create_string(&contents)
+            })?,
+        )?;
+
+        fs.set(
+            "write",
+            lua_ctx.create_function(|_, (path, data): (String, rlua::String)| {
+                std::fs::write(&path, data.as_bytes()).map_err(|source| write_error(&path, source))
+            })?,
+        )?;
+
+        fs.set(
+            "append",
+            lua_ctx.create_function(|_, (path, data): (String, rlua::String)| {
+                std::fs::OpenOptions::new()
+                    .append(true)
+                    .create(true)
+                    .open(&path)
+                    .and_then(|mut file| file.write_all(data.as_bytes()))
+                    .map_err(|source| write_error(&path, source))
+            })?,
+        )?;
+
+        fs.set(
+            "exists",
+            lua_ctx.create_function(|_, path: String| Ok(Path::new(&path).exists()))?,
+        )?;
+
+        fs.set(
+            "list",
+            lua_ctx.create_function(|lua_ctx, (dir, options): (String, Option<Table>)| {
+                let options = ListOptions::from_table(options)?;
+                let entries = read_entries(Path::new(&dir), &options)?
+                    .iter()
+                    .map(|entry| entry.to_table(lua_ctx))
+                    .collect::<Result<Vec<_>>>()?;
+                lua_ctx.create_sequence_from(entries)
+            })?,
+        )?;
+
+        fs.set(
+            "walk",
+            lua_ctx.create_function(|lua_ctx, (dir, options): (String, Option<Table>)| {
+                let options = ListOptions::from_table(options)?;
+                let dir = PathBuf::from(dir);
+                // Entries still to visit, the next one last
+                let mut pending = read_entries(&dir, &options)?;
+                pending.reverse();
+                // Directories already walked into, so symlinks can't lead into a loop
+                let mut visited = HashSet::new();
+                visited.extend(dir.canonicalize().ok());
+                lua_ctx.create_function_mut(move |lua_ctx, ()| {
+                    let Some(entry) = pending.pop() else {
+                        return Ok(None);
+                    };
+                    // Without following symlinks, no directory can be reached twice
+                    let walk_into = entry.kind == "directory"
+                        && (!options.follow_symlinks
+                            || entry
+                                .path
+                                .canonicalize()
+                                .is_ok_and(|path| visited.insert(path)));
+                    if walk_into {
+                        let mut children = read_entries(&entry.path, &options)?;
+                        children.reverse();
+                        pending.extend(children);
+                    }
+                    entry.to_table(lua_ctx).map(Some)
+                })
+            })?,
+        )?;
+
+        fs.set(
+            "stat",
+            lua_ctx.create_function(|lua_ctx, (path, options): (String, Option<Table>)| {
+                let follow_symlinks = match options {
+                    Some(options) => options
+                        .get::<_, Option<bool>>("follow_symlinks")?
+                        .unwrap_or(true),
+                    None => true,
+                };
+                let metadata = if follow_symlinks {
+                    std::fs::metadata(&path)
+                } else {
+                    std::fs::symlink_metadata(&path)
+                };
+                let metadata = metadata.map_err(|source| read_error(Path::new(&path), source))?;
+                stat_table(lua_ctx, &metadata)
+            })?,
+        )?;
+
+        fs.set(
+            "is_dir",
+            lua_ctx.create_function(|_, path: String| Ok(Path::new(&path).is_dir()))?,
+        )?;
+
+        fs.set(
+            "is_file",
+            lua_ctx.create_function(|_, path: String| Ok(Path::new(&path).is_file()))?,
+        )?;
+
+        fs.set(
+            "is_symlink",
+            lua_ctx.create_function(|_, path: String| Ok(Path::new(&path).is_symlink()))?,
+        )?;
+
+        fs.set(
+            "copy",
+            lua_ctx.create_function(
+                |_, (from, to, options): (String, String, Option<Table>)| {
+                    let recursive = match options {
+                        Some(options) => options
+                            .get::<_, Option<bool>>("recursive")?
+                            .unwrap_or(false),
+                        None => false,
+                    };
+                    copy(Path::new(&from), Path::new(&to), recursive)
+                        .map_err(|source| rlua::Error::external(Error::Copy { from, to, source }))
+                },
+            )?,
+        )?;
+
+        fs.set(
+            "move",
+            lua_ctx.create_function(|_, (from, to): (String, String)| {
+                move_path(Path::new(&from), Path::new(&to))
+                    .map_err(|source| rlua::Error::external(Error::Move { from, to, source }))
+            })?,
+        )?;
+
+        fs.set(
+            "remove",
+            lua_ctx.create_function(|_, path: String| {
+                remove(Path::new(&path))
+                    .map_err(|source| rlua::Error::external(Error::Remove { path, source }))
+            })?,
+        )?;
+
+        fs.set(
+            "remove_all",
+            lua_ctx.create_function(|_, path: String| {
+                remove_all(Path::new(&path))
+                    .map_err(|source| rlua::Error::external(Error::Remove { path, source }))
+            })?,
+        )?;
+
+        fs.set(
+            "mkdirs",
+            lua_ctx.create_function(|_, path: String| {
+                std::fs::create_dir_all(&path)
+                    .map_err(|source| rlua::Error::external(Error::CreateDir { path, source }))
+            })?,
+        )?;
+
+        fs.set(
+            "tempfile",
+            lua_ctx
+                .create_function(|_, options: Option<Table>| TempPath::create(false, options))?,
+        )?;
+
+        fs.set(
+            "tempdir",
+            lua_ctx.create_function(|_, options: Option<Table>| TempPath::create(true, options))?,
+        )?;
+
+        fs.set(
+            "watch",
+            lua_ctx.create_function(
+                |lua_ctx, (path, callback, options): (String, Function, Option<Table>)| {
+                    watch(lua_ctx, &path, callback, options)
+                },
+            )?,
+        )?;
+
+        fs.set(
+            "chmod",
+            lua_ctx.create_function(|_, (path, mode): (String, Value)| chmod(&path, &mode))?,
+        )?;
+
+        fs.set(
+            "chown",
+            lua_ctx.create_function(|_, (path, user, group): (String, Value, Value)| {
+                chown(&path, user, group)
+            })?,
+        )?;
+
+        fs.set(
+            "set_readonly",
+            lua_ctx.create_function(|_, (path, readonly): (String, bool)| {
+                set_readonly(&path, readonly)
+            })?,
+        )?;
+
+        fs.set(
+            "lock",
+            lua_ctx.create_function(|lua_ctx, (path, options): (String, Option<Table>)| {
+                lock(lua_ctx, path, options, true)
+            })?,
+        )?;
+
+        fs.set(
+            "try_lock",
+            lua_ctx.create_function(|lua_ctx, (path, options): (String, Option<Table>)| {
+                lock(lua_ctx, path, options, false)
+            })?,
+        )?;
+
+        fs.set(
+            "open",
+            lua_ctx.create_function(|_, (path, mode): (String, Option<String>)| {
+                FileHandle::open(path, mode.as_deref().unwrap_or("r"))
+            })?,
+        )?;
+
+        fs.set(
+            "checksum",
+            lua_ctx.create_function(|lua_ctx, (path, algorithm): (String, Option<String>)| {
+                checksum(lua_ctx, &path, algorithm.as_deref().unwrap_or("sha256"))
+            })?,
+        )?;
+
+        help::register_module_docs(lua_ctx, "fs", "Files and directories", &fs, FS_DOCS)?;
+        lua_ctx.globals().set("fs", fs)?;
+        Ok(())
+    })
+}
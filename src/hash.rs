@@ -0,0 +1,246 @@
+/*
+   Copyright (C) 2022  Kalka
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License, or (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use base64::Engine;
+use rlua::{Context, Lua, MetaMethod, Result, UserData, UserDataMethods, Value};
+use sha2::Digest;
+
+use crate::help::{self, FunctionDoc};
+
+const HASH_DOCS: &[FunctionDoc] = &[
+    FunctionDoc {
+        name: "sha256",
+        signature: "hash.sha256(data[, encoding]) -> string",
+        description: "The SHA-256 hash of a string or a list of bytes like memory.read returns, in lowercase hex, or with encoding \"base64\" or \"raw\" as base64 or the bytes themselves. hash.sha1, hash.sha512, hash.md5, hash.blake3 and hash.crc32 work the same way.",
+        example: "if hash.sha256(download) ~= expected then\n    error(\"download is corrupt\")\nend",
+    },
+    FunctionDoc {
+        name: "sha1",
+        signature: "hash.sha1(data[, encoding]) -> string",
+        description: "The SHA-1 hash of the data, in hex, base64 or raw bytes like hash.sha256. SHA-1 is broken for signatures, but git and older APIs still use it.",
+        example: "local blob_id = hash.sha1(\"blob \" .. #contents .. \"\\0\" .. contents)",
+    },
+    FunctionDoc {
+        name: "sha512",
+        signature: "hash.sha512(data[, encoding]) -> string",
+        description: "The SHA-512 hash of the data, in hex, base64 or raw bytes like hash.sha256.",
+        example: "local integrity = \"sha512-\" .. hash.sha512(tarball, \"base64\")",
+    },
+    FunctionDoc {
+        name: "md5",
+        signature: "hash.md5(data[, encoding]) -> string",
+        description: "The MD5 hash of the data, in hex, base64 or raw bytes like hash.sha256. MD5 is only fit for checksums, like S3's Content-MD5, not for security.",
+        example: "local content_md5 = hash.md5(body, \"base64\")",
+    },
+    FunctionDoc {
+        name: "blake3",
+        signature: "hash.blake3(data[, encoding]) -> string",
+        description: "The 256-bit BLAKE3 hash of the data, in hex, base64 or raw bytes like hash.sha256. It's much faster than SHA-256 for big data.",
+        example: "local key = hash.blake3(fs.read(path))",
+    },
+    FunctionDoc {
+        name: "crc32",
+        signature: "hash.crc32(data[, encoding]) -> string",
+        description: "The CRC-32 checksum of the data as 4 big-endian bytes, in hex, base64 or raw bytes like hash.sha256.",
+        example: "print(hash.crc32(\"hello\"))  --> 3610a686",
+    },
+    FunctionDoc {
+        name: "new",
+        signature: "hash.new(algorithm) -> hasher",
+        description: "Starts hashing data given a piece at a time with \"sha256\", \"sha1\", \"sha512\", \"md5\", \"blake3\" or \"crc32\". hasher:update(data) adds a string or list of bytes and returns the hasher, and hasher:hex(), hasher:base64() and hasher:digest() return the hash so far in lowercase hex, base64 or raw bytes.",
+        example: "local hasher = hash.new(\"sha256\")\nfor chunk in chunks do\n    hasher:update(chunk)\nend\nprint(hasher:hex())",
+    },
+];
+
+/// The algorithms `Hasher::new` knows, for error messages.
+pub const ALGORITHMS: &str = "sha256, sha1, sha512, md5, blake3 or crc32";
+
+/// A hash being computed a piece at a time, by hash.new and fs.checksum.
+#[derive(Clone)]
+pub enum Hasher {
+    Sha256(sha2::Sha256),
+    Sha1(sha1::Sha1),
+    Sha512(sha2::Sha512),
+    Md5(md5::Md5),
+    Blake3(Box<blake3::Hasher>),
+    Crc32(crc32fast::Hasher),
+}
+
+impl Hasher {
+    pub fn new(algorithm: &str) -> Option<Hasher> {
+        Some(match algorithm.to_ascii_lowercase().as_str() {
+            "sha256" => Hasher::Sha256(sha2::Sha256::new()),
+            "sha1" => Hasher::Sha1(sha1::Sha1::new()),
+            "sha512" => Hasher::Sha512(sha2::Sha512::new()),
+            "md5" => Hasher::Md5(md5::Md5::new()),
+            "blake3" => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+            "crc32" => Hasher::Crc32(crc32fast::Hasher::new()),
+            _ => return None,
+        })
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha256(hasher) => hasher.update(data),
+            Hasher::Sha1(hasher) => hasher.update(data),
+            Hasher::Sha512(hasher) => hasher.update(data),
+            Hasher::Md5(hasher) => hasher.update(data),
+            Hasher::Blake3(hasher) => {
+                hasher.update(data);
+            }
+            Hasher::Crc32(hasher) => hasher.update(data),
+        }
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        match self {
+            Hasher::Sha256(hasher) => hasher.finalize().to_vec(),
+            Hasher::Sha1(hasher) => hasher.finalize().to_vec(),
+            Hasher::Sha512(hasher) => hasher.finalize().to_vec(),
+            Hasher::Md5(hasher) => hasher.finalize().to_vec(),
+            Hasher::Blake3(hasher) => hasher.finalize().as_bytes().to_vec(),
+            Hasher::Crc32(hasher) => hasher.finalize().to_be_bytes().to_vec(),
+        }
+    }
+}
+
+/// Lowercase hex, two digits a byte.
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// The bytes of a string, or of a list of bytes like memory.read returns, given to the function
+/// as the argument at the position.
+pub fn data_argument(value: Value, function: &str, position: usize) -> Result<Vec<u8>> {
+    match value {
+        Value::String(string) => Ok(string.as_bytes().to_vec()),
+        Value::Table(table) => table
+            .sequence_values::<Value>()
+            .enumerate()
+            .map(|(index, byte)| match byte? {
+                Value::Integer(byte @ 0..=255) => Ok(byte as u8),
+                byte => Err(rlua::Error::RuntimeError(format!(
+                    "bad argument #{} to '{}' (byte expected at index {}, got {})",
+                    position,
+                    function,
+                    index + 1,
+                    match byte {
+                        Value::Integer(integer) => integer.to_string(),
+                        byte => byte.type_name().to_string(),
+                    }
+                ))),
+            })
+            .collect(),
+        value => Err(rlua::Error::RuntimeError(format!(
+            "bad argument #{} to '{}' (string or list of bytes expected, got {})",
+            position,
+            function,
+            value.type_name()
+        ))),
+    }
+}
+
+/// A digest as hex (the default), base64 or the raw bytes, as the encoding argument at the
+/// position says.
+pub fn encode_digest<'lua>(
+    lua_ctx: Context<'lua>,
+    digest: &[u8],
+    encoding: Option<String>,
+    function: &str,
+    position: usize,
+) -> Result<rlua::String<'lua>> {
+    match encoding.as_deref().unwrap_or("hex") {
+        "hex" => lua_ctx.create_string(&hex(digest)),
+        "base64" => {
+            lua_ctx.create_string(&base64::engine::general_purpose::STANDARD.encode(digest))
+        }
+        "raw" => lua_ctx.create_string(digest),
+        encoding => Err(rlua::Error::RuntimeError(format!(
+            "bad argument #{} to '{}' (unknown encoding '{}', expected hex, base64 or raw)",
+            position, function, encoding
+        ))),
+    }
+}
+
+impl UserData for Hasher {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_function("update", |_, (hasher, data): (rlua::AnyUserData, Value)| {
+            let data = data_argument(data, "update", 1)?;
+            hasher.borrow_mut::<Hasher>()?.update(&data);
+            Ok(hasher)
+        });
+        // Finishing a copy leaves the hasher as it was, to take more data
+        methods.add_method("hex", |_, hasher, ()| Ok(hex(&hasher.clone().finish())));
+        methods.add_method("base64", |_, hasher, ()| {
+            Ok(base64::engine::general_purpose::STANDARD.encode(hasher.clone().finish()))
+        });
+        methods.add_method("digest", |lua_ctx, hasher, ()| {
+            lua_ctx.create_string(&hasher.clone().finish())
+        });
+        methods.add_meta_method(MetaMethod::ToString, |_, hasher, ()| {
+            let algorithm = match hasher {
+                Hasher::Sha256(_) => "sha256",
+                Hasher::Sha1(_) => "sha1",
+                Hasher::Sha512(_) => "sha512",
+                Hasher::Md5(_) => "md5",
+                Hasher::Blake3(_) => "blake3",
+                Hasher::Crc32(_) => "crc32",
+            };
+            Ok(format!("hash.new(\"{}\")", algorithm))
+        });
+    }
+}
+
+pub fn load_hash_library(lua: &Lua) -> Result<()> {
+    lua.context(|lua_ctx| {
+        let hash = lua_ctx.create_table()?;
+
+        for algorithm in ["sha256", "sha1", "sha512", "md5", "blake3", "crc32"] {
+            hash.set(
+                algorithm,
+                lua_ctx.create_function(
+                    move |lua_ctx, (data, encoding): (Value, Option<String>)| {
+                        let mut hasher = Hasher::new(algorithm).expect("known algorithm");
+                        hasher.update(&data_argument(data, algorithm, 1)?);
+                        encode_digest(lua_ctx, &hasher.finish(), encoding, algorithm, 2)
+                    },
+                )?,
+            )?;
+        }
+
+        hash.set(
+            "new",
+            lua_ctx.create_function(|_, algorithm: String| {
+                Hasher::new(&algorithm).ok_or_else(|| {
+                    rlua::Error::RuntimeError(format!(
+                        "bad argument #1 to 'new' (unknown algorithm '{}', expected {})",
+                        algorithm, ALGORITHMS
+                    ))
+                })
+            })?,
+        )?;
+
+        help::register_module_docs(
+            lua_ctx,
+            "hash",
+            "Hashes and checksums of data",
+            &hash,
+            HASH_DOCS,
+        )?;
+        lua_ctx.globals().set("hash", hash)?;
+        Ok(())
+    })
+}
@@ -0,0 +1,396 @@
+/*
+   Copyright (C) 2022  Kalka
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License, or (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use colored::Colorize;
+use rlua::{Context, Function, Lua, Result, Table, Value};
+
+use crate::traceback;
+
+// Name of the registry table mapping documented values (functions and module tables) to their docs
+const DOCS_REGISTRY_KEY: &str = "rluaterm.docs";
+// Name of the registry table listing every documented module, in registration order
+const MODULES_REGISTRY_KEY: &str = "rluaterm.docs.modules";
+
+/// Documentation for a single function injected into the Lua globals.
+pub struct FunctionDoc {
+    pub name: &'static str,
+    pub signature: &'static str,
+    pub description: &'static str,
+    pub example: &'static str,
+}
+
+fn docs_table(lua_ctx: Context) -> Result<Table> {
+    if let Ok(table) = lua_ctx.named_registry_value::<_, Table>(DOCS_REGISTRY_KEY) {
+        return Ok(table);
+    }
+    // Weak keys, so documenting a function doesn't keep it alive after it's redefined
+    let table = lua_ctx.create_table()?;
+    let metatable = lua_ctx.create_table()?;
+    metatable.set("__mode", "k")?;
+    table.set_metatable(Some(metatable));
+    lua_ctx.set_named_registry_value(DOCS_REGISTRY_KEY, table.clone())?;
+    Ok(table)
+}
+
+fn modules_table(lua_ctx: Context) -> Result<Table> {
+    if let Ok(table) = lua_ctx.named_registry_value::<_, Table>(MODULES_REGISTRY_KEY) {
+        return Ok(table);
+    }
+    let table = lua_ctx.create_table()?;
+    lua_ctx.set_named_registry_value(MODULES_REGISTRY_KEY, table.clone())?;
+    Ok(table)
+}
+
+/// Registers the documentation of a module and all of its functions, so `help` can find them
+/// either by name ("http.get") or by value (http.get).
+pub fn register_module_docs<'lua>(
+    lua_ctx: Context<'lua>,
+    module_name: &str,
+    description: &str,
+    module: &Table<'lua>,
+    docs: &[FunctionDoc],
+) -> Result<()> {
+    let docs_table = docs_table(lua_ctx)?;
+
+    let functions = lua_ctx.create_table()?;
+    for (i, doc) in docs.iter().enumerate() {
+        let entry = lua_ctx.create_table()?;
+        entry.set("name", format!("{}.{}", module_name, doc.name))?;
+        entry.set("signature", doc.signature)?;
+        entry.set("description", doc.description)?;
+        entry.set("example", doc.example)?;
+        functions.set(i + 1, entry.clone())?;
+
+        let function = module.get::<_, Value>(doc.name)?;
+        if let Value::Nil = function {
+            continue;
+        }
+        docs_table.raw_set(function, entry)?;
+    }
+
+    let module_entry = lua_ctx.create_table()?;
+    module_entry.set("name", module_name)?;
+    module_entry.set("description", description)?;
+    module_entry.set("functions", functions)?;
+    docs_table.raw_set(module.clone(), module_entry.clone())?;
+
+    let modules = modules_table(lua_ctx)?;
+    modules.set(modules.raw_len() + 1, module_entry)?;
+    Ok(())
+}
+
+/// Documents a function defined in Lua. The signature is shown as the heading when present.
+pub fn attach_doc<'lua>(
+    lua_ctx: Context<'lua>,
+    function: Function<'lua>,
+    signature: Option<&str>,
+    description: &str,
+) -> Result<()> {
+    let entry = lua_ctx.create_table()?;
+    if let Some(signature) = signature {
+        entry.set("name", signature.split('(').next().unwrap_or(signature))?;
+        entry.set("signature", signature)?;
+    }
+    entry.set("description", description.trim_end())?;
+    entry.set("example", "")?;
+    docs_table(lua_ctx)?.raw_set(function, entry)?;
+    Ok(())
+}
+
+// The name and signature of a function defined on the line, for `function a.b(x)`,
+// `local function f(x)` and `a.b = function(x)`
+fn parse_definition(line: &str) -> Option<(&str, String)> {
+    let is_name_char = |c: char| c.is_alphanumeric() || c == '_' || c == '.' || c == ':';
+    let line = line
+        .strip_prefix("local ")
+        .map(str::trim_start)
+        .unwrap_or(line);
+    let (name, rest) = match line.strip_prefix("function") {
+        Some(rest) if rest.starts_with(char::is_whitespace) => {
+            let rest = rest.trim_start();
+            let end = rest.find(|c| !is_name_char(c)).unwrap_or(rest.len());
+            (&rest[..end], &rest[end..])
+        }
+        _ => {
+            let (name, value) = line.split_once('=')?;
+            let name = name.trim();
+            let rest = value.trim_start().strip_prefix("function")?;
+            if name.is_empty() || !name.chars().all(is_name_char) {
+                return None;
+            }
+            (name, rest)
+        }
+    };
+    let parameters = rest.trim_start().strip_prefix('(')?;
+    let parameters = &parameters[..parameters.find(')')?];
+    if name.is_empty() {
+        return None;
+    }
+    Some((name, format!("{}({})", name, parameters)))
+}
+
+// Strip the `---` of an annotation line, None for any other line
+fn annotation_text(line: &str) -> Option<&str> {
+    let text = line.trim().strip_prefix("---")?;
+    Some(text.strip_prefix(' ').unwrap_or(text))
+}
+
+/// Documents the global functions in the source that are preceded by `---` comments, e.g.
+///
+/// ```lua
+/// --- Greets someone by name.
+/// function greet(name) ... end
+/// ```
+///
+/// Runs after the source, since the functions have to exist to be documented. Only needed for
+/// chunks whose code Lua doesn't keep, annotations in files are found when asked for.
+pub fn attach_annotations(lua_ctx: Context, source: &str) -> Result<()> {
+    let mut description = String::new();
+    for line in source.lines() {
+        let line = line.trim();
+        if let Some(text) = annotation_text(line) {
+            description.push_str(text);
+            description.push('\n');
+            continue;
+        }
+        if description.is_empty() {
+            continue;
+        }
+        // Local functions can't be looked up once the chunk is done
+        let definition = parse_definition(line).filter(|_| !line.starts_with("local "));
+        if let Some((name, signature)) = definition {
+            if let Value::Function(function) = resolve_path(lua_ctx, &name.replace(':', "."))? {
+                attach_doc(lua_ctx, function, Some(&signature), &description)?;
+            }
+        }
+        description.clear();
+    }
+    Ok(())
+}
+
+// Document a function from the `---` comments above its definition, if it has any
+fn read_annotations<'lua>(lua_ctx: Context<'lua>, function: Function<'lua>) -> Result<bool> {
+    let (source, line) = match traceback::function_source(lua_ctx, function.clone())? {
+        Some((source, line)) if line > 0 => (source, line),
+        _ => return Ok(false),
+    };
+    let source = match source.strip_prefix('@') {
+        Some(path) => match std::fs::read_to_string(path) {
+            Ok(source) => source,
+            Err(_) => return Ok(false),
+        },
+        None if source.starts_with('=') => return Ok(false),
+        None => source,
+    };
+    let lines = source.lines().collect::<Vec<_>>();
+    let definition = match lines.get(line - 1) {
+        Some(definition) => definition.trim(),
+        None => return Ok(false),
+    };
+    let mut description = lines[..line - 1]
+        .iter()
+        .rev()
+        .map_while(|line| annotation_text(line))
+        .collect::<Vec<_>>();
+    if description.is_empty() {
+        return Ok(false);
+    }
+    description.reverse();
+    let signature = parse_definition(definition).map(|(_, signature)| signature);
+    attach_doc(
+        lua_ctx,
+        function,
+        signature.as_deref(),
+        &description.join("\n"),
+    )?;
+    Ok(true)
+}
+
+// The docs entry of a value, reading the annotations of functions documented in their source
+fn find_entry<'lua>(lua_ctx: Context<'lua>, value: Value<'lua>) -> Result<Option<Table<'lua>>> {
+    if let Value::Nil = value {
+        return Ok(None);
+    }
+    let docs_table = docs_table(lua_ctx)?;
+    if let Some(entry) = docs_table.raw_get::<_, Option<Table>>(value.clone())? {
+        return Ok(Some(entry));
+    }
+    match value {
+        Value::Function(function) if read_annotations(lua_ctx, function.clone())? => {
+            docs_table.raw_get(function)
+        }
+        _ => Ok(None),
+    }
+}
+
+/// The first line of a value's documentation, for listing it next to completions.
+pub fn summary<'lua>(lua_ctx: Context<'lua>, value: Value<'lua>) -> Result<Option<String>> {
+    let entry = match find_entry(lua_ctx, value)? {
+        Some(entry) => entry,
+        None => return Ok(None),
+    };
+    let description = entry.get::<_, String>("description")?;
+    Ok(description.lines().next().map(str::to_string))
+}
+
+// Resolve a dotted path such as "http.get" starting from the globals table
+fn resolve_path<'lua>(lua_ctx: Context<'lua>, path: &str) -> Result<Value<'lua>> {
+    let mut value = Value::Table(lua_ctx.globals());
+    for part in path.split('.') {
+        value = match value {
+            // Raw, so strict mode's __index doesn't raise for names that aren't defined
+            Value::Table(table) => table.raw_get(part)?,
+            _ => return Ok(Value::Nil),
+        };
+    }
+    Ok(value)
+}
+
+fn print_function_doc(entry: &Table) -> Result<()> {
+    // Functions documented with doc.attach may not have a signature
+    if let Some(signature) = entry.get::<_, Option<String>>("signature")? {
+        println!("{}", signature.cyan().bold());
+    }
+    for line in entry.get::<_, String>("description")?.lines() {
+        println!("  {}", line);
+    }
+    let example = entry.get::<_, String>("example")?;
+    if !example.is_empty() {
+        println!("  {}", "Example:".bold());
+        for line in example.lines() {
+            println!("    {}", line.green());
+        }
+    }
+    Ok(())
+}
+
+fn print_module_doc(entry: &Table) -> Result<()> {
+    println!(
+        "{} - {}",
+        entry.get::<_, String>("name")?.cyan().bold(),
+        entry.get::<_, String>("description")?
+    );
+    for function in entry
+        .get::<_, Table>("functions")?
+        .sequence_values::<Table>()
+    {
+        let function = function?;
+        println!(
+            "  {:<40} {}",
+            function.get::<_, String>("signature")?.cyan(),
+            function.get::<_, String>("description")?
+        );
+    }
+    Ok(())
+}
+
+fn print_overview(lua_ctx: Context) -> Result<()> {
+    println!("{}", "Available modules:".bold());
+    for module in modules_table(lua_ctx)?.sequence_values::<Table>() {
+        let module = module?;
+        println!(
+            "  {:<10} {}",
+            module.get::<_, String>("name")?.cyan(),
+            module.get::<_, String>("description")?
+        );
+    }
+    println!("Use help(\"module\") or help(\"module.function\") for details.");
+    Ok(())
+}
+
+/// Prints the documentation of a value, or of the value at a dotted path when given a string.
+/// Prints an overview of the documented modules for nil.
+pub fn print_help<'lua>(lua_ctx: Context<'lua>, value: Value<'lua>) -> Result<()> {
+    let (label, value) = match value {
+        Value::Nil => return print_overview(lua_ctx),
+        Value::String(path) => {
+            let path = path.to_str()?.to_string();
+            let value = resolve_path(lua_ctx, &path)?;
+            (path, value)
+        }
+        value => (value.type_name().to_string(), value),
+    };
+
+    match find_entry(lua_ctx, value)? {
+        Some(entry) if entry.contains_key("functions")? => print_module_doc(&entry),
+        Some(entry) => print_function_doc(&entry),
+        None => {
+            println!("No documentation found for {}", label.yellow());
+            Ok(())
+        }
+    }
+}
+
+const DOC_DOCS: &[FunctionDoc] = &[
+    FunctionDoc {
+        name: "attach",
+        signature: "doc.attach(function, description, [signature])",
+        description: "Documents a function for help(), returning the function",
+        example: "greet = doc.attach(function(name)\n    print(\"hi \" .. name)\nend, \"Greets someone\", \"greet(name)\")",
+    },
+    FunctionDoc {
+        name: "get",
+        signature: "doc.get(value)",
+        description: "Returns the description of a documented value, or nil",
+        example: "print(doc.get(http.get))",
+    },
+];
+
+/// Names of the documented modules, in the order they were loaded.
+pub fn module_names(lua: &Lua) -> Result<Vec<String>> {
+    lua.context(|lua_ctx| {
+        modules_table(lua_ctx)?
+            .sequence_values::<Table>()
+            .map(|module| module?.get::<_, String>("name"))
+            .collect()
+    })
+}
+
+pub fn load_help_library(lua: &Lua) -> Result<()> {
+    lua.context(|lua_ctx| {
+        lua_ctx.globals().set(
+            "help",
+            lua_ctx.create_function(|ctx, value: Value| print_help(ctx, value))?,
+        )?;
+
+        let doc_lib = lua_ctx.create_table()?;
+        doc_lib.set(
+            "attach",
+            lua_ctx.create_function(
+                |ctx, (function, description, signature): (Function, String, Option<String>)| {
+                    attach_doc(ctx, function.clone(), signature.as_deref(), &description)?;
+                    Ok(function)
+                },
+            )?,
+        )?;
+        doc_lib.set(
+            "get",
+            lua_ctx.create_function(|ctx, value: Value| match find_entry(ctx, value)? {
+                Some(entry) => Ok(Some(entry.get::<_, String>("description")?)),
+                None => Ok(None),
+            })?,
+        )?;
+        register_module_docs(
+            lua_ctx,
+            "doc",
+            "Documentation for your own functions",
+            &doc_lib,
+            DOC_DOCS,
+        )?;
+        lua_ctx.globals().set("doc", doc_lib)?;
+        Ok(())
+    })
+}
@@ -0,0 +1,105 @@
+/*
+   Copyright (C) 2022  Kalka
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License, or (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use hmac::{Mac, SimpleHmac};
+use rlua::{Lua, Result, Value};
+
+use crate::hash::{data_argument, encode_digest};
+use crate::help::{self, FunctionDoc};
+
+const HMAC_DOCS: &[FunctionDoc] = &[
+    FunctionDoc {
+        name: "sha256",
+        signature: "hmac.sha256(key, message[, encoding]) -> string",
+        description: "Signs the message with the key using HMAC-SHA256. Both can be strings or lists of bytes. Returns the signature in lowercase hex, or with encoding \"base64\" or \"raw\" as base64 or the bytes themselves.",
+        example: "local signature = hmac.sha256(secret, timestamp .. \".\" .. body)\nrequest.headers[\"X-Signature\"] = \"sha256=\" .. signature",
+    },
+    FunctionDoc {
+        name: "sha1",
+        signature: "hmac.sha1(key, message[, encoding]) -> string",
+        description: "Signs the message with HMAC-SHA1, returning hex, base64 or raw bytes like hmac.sha256. Older webhooks and OAuth 1.0 still use it.",
+        example: "local signature = hmac.sha1(consumer_secret .. \"&\" .. token_secret, base_string, \"base64\")",
+    },
+    FunctionDoc {
+        name: "sha512",
+        signature: "hmac.sha512(key, message[, encoding]) -> string",
+        description: "Signs the message with HMAC-SHA512, returning hex, base64 or raw bytes like hmac.sha256.",
+        example: "local signature = hmac.sha512(api_secret, nonce .. path .. body)",
+    },
+    FunctionDoc {
+        name: "equals",
+        signature: "hmac.equals(a, b) -> boolean",
+        description: "Whether two signatures are the same, taking as long whichever byte they differ at, so checking a signature doesn't tell an attacker how much of it they got right.",
+        example: "if not hmac.equals(hmac.sha256(secret, body), request.headers[\"X-Signature\"]) then\n    error(\"bad signature\")\nend",
+    },
+];
+
+// Signs a message with a key
+type Signer = fn(&[u8], &[u8]) -> Vec<u8>;
+
+/// The HMAC of the message with the key, using the hash D.
+pub fn sign<D>(key: &[u8], message: &[u8]) -> Vec<u8>
+where
+    D: sha2::Digest + sha2::digest::core_api::BlockSizeUser,
+{
+    let mut mac = SimpleHmac::<D>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(message);
+    mac.finalize().into_bytes().to_vec()
+}
+
+pub fn load_hmac_library(lua: &Lua) -> Result<()> {
+    lua.context(|lua_ctx| {
+        let hmac = lua_ctx.create_table()?;
+
+        let algorithms: [(&str, Signer); 3] = [
+            ("sha256", sign::<sha2::Sha256>),
+            ("sha1", sign::<sha1::Sha1>),
+            ("sha512", sign::<sha2::Sha512>),
+        ];
+        for (name, sign) in algorithms {
+            hmac.set(
+                name,
+                lua_ctx.create_function(
+                    move |lua_ctx, (key, message, encoding): (Value, Value, Option<String>)| {
+                        let key = data_argument(key, name, 1)?;
+                        let message = data_argument(message, name, 2)?;
+                        encode_digest(lua_ctx, &sign(&key, &message), encoding, name, 3)
+                    },
+                )?,
+            )?;
+        }
+
+        hmac.set(
+            "equals",
+            lua_ctx.create_function(|_, (a, b): (rlua::String, rlua::String)| {
+                let (a, b) = (a.as_bytes(), b.as_bytes());
+                // Only the length can end the comparison early, and signatures' lengths are public
+                Ok(a.len() == b.len()
+                    && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0)
+            })?,
+        )?;
+
+        help::register_module_docs(
+            lua_ctx,
+            "hmac",
+            "Signing messages with HMAC",
+            &hmac,
+            HMAC_DOCS,
+        )?;
+        lua_ctx.globals().set("hmac", hmac)?;
+        Ok(())
+    })
+}
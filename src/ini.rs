@@ -0,0 +1,378 @@
+/*
+   Copyright (C) 2022  Kalka
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License, or (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use rlua::{Context, Lua, Result, Table, Value};
+use std::collections::HashSet;
+
+use crate::error;
+use crate::help::{self, FunctionDoc};
+
+const INI_DOCS: &[FunctionDoc] = &[
+    FunctionDoc {
+        name: "decode",
+        signature: "ini.decode(text) -> table",
+        description: "Parses an INI file into a table of sections, each a table of its keys, with any keys before the first section in the table itself. Values are strings, a key without = is true, and a key given several times in a section, like ExecStart in a systemd unit, becomes a list of its values. Lines starting with ; or # are comments. The table remembers the text it came from, so ini.encode can keep its comments and layout.",
+        example: "local unit = ini.decode(fs.read(\"/etc/systemd/system/app.service\"))\nprint(unit.Service.ExecStart)",
+    },
+    FunctionDoc {
+        name: "encode",
+        signature: "ini.encode(table) -> string",
+        description: "Turns a table of sections back into an INI file, with tables as sections, lists as keys given several times, and numbers and booleans as text. For a table from ini.decode, comments, blank lines and the order of sections and keys stay as they were, unchanged keys are written as they were, removed ones are left out, and new keys go at the end of their section and new sections at the end.",
+        example: "local desktop = ini.decode(fs.read(path))\ndesktop[\"Desktop Entry\"].Exec = \"app --verbose %U\"\nfs.write(path, ini.encode(desktop))",
+    },
+];
+
+enum Line<'a> {
+    // Blank lines and comments
+    Other(&'a str),
+    Section {
+        raw: &'a str,
+        name: String,
+    },
+    Entry {
+        raw: &'a str,
+        // What comes before the key and between it and the value, to write changes alike
+        indent: &'a str,
+        separator: &'a str,
+        key: String,
+        // None for a key without =
+        value: Option<String>,
+    },
+}
+
+fn parse(text: &str) -> Result<Vec<Line<'_>>> {
+    let mut lines = Vec::new();
+    for (index, raw) in text.lines().enumerate() {
+        let trimmed = raw.trim();
+        if trimmed.is_empty() || trimmed.starts_with([';', '#']) {
+            lines.push(Line::Other(raw));
+        } else if let Some(section) = trimmed.strip_prefix('[') {
+            let name = section.strip_suffix(']').ok_or_else(|| {
+                rlua::Error::external(error::Error::Ini {
+                    line: index + 1,
+                    message: "the section's name has no closing ]".to_string(),
+                })
+            })?;
+            lines.push(Line::Section {
+                raw,
+                name: name.trim().to_string(),
+            });
+        } else {
+            let indent = &raw[..raw.len() - raw.trim_start().len()];
+            let line = raw.trim_start();
+            match line.find('=') {
+                Some(equals) => {
+                    let key = line[..equals].trim_end();
+                    let value = line[equals + 1..].trim_start();
+                    lines.push(Line::Entry {
+                        raw,
+                        indent,
+                        separator: &line[key.len()..line.len() - value.len()],
+                        key: key.to_string(),
+                        value: Some(value.trim_end().to_string()),
+                    });
+                }
+                None => lines.push(Line::Entry {
+                    raw,
+                    indent,
+                    separator: " = ",
+                    key: line.trim_end().to_string(),
+                    value: None,
+                }),
+            }
+        }
+    }
+    Ok(lines)
+}
+
+// The metatable field a decoded table keeps its text in
+const SOURCE_FIELD: &str = "__ini_source";
+
+fn decode(lua_ctx: Context, text: String) -> Result<Table> {
+    let root = lua_ctx.create_table()?;
+    let mut section = root.clone();
+    for line in parse(&text)? {
+        match line {
+            Line::Other(_) => {}
+            Line::Section { name, .. } => {
+                // Sections given twice are merged
+                section = match root.raw_get::<_, Value>(name.as_str())? {
+                    Value::Table(section) => section,
+                    _ => {
+                        let table = lua_ctx.create_table()?;
+                        root.raw_set(name, table.clone())?;
+                        table
+                    }
+                };
+            }
+            Line::Entry { key, value, .. } => {
+                let value = match value {
+                    Some(value) => Value::String(lua_ctx.create_string(&value)?),
+                    None => Value::Boolean(true),
+                };
+                match section.raw_get::<_, Value>(key.as_str())? {
+                    Value::Nil => section.raw_set(key, value)?,
+                    Value::Table(values) => values.raw_set(values.raw_len() + 1, value)?,
+                    first => section.raw_set(key, lua_ctx.create_sequence_from([first, value])?)?,
+                }
+            }
+        }
+    }
+    let metatable = lua_ctx.create_table()?;
+    metatable.raw_set(SOURCE_FIELD, text)?;
+    root.set_metatable(Some(metatable));
+    Ok(root)
+}
+
+fn encode_error(message: String) -> rlua::Error {
+    rlua::Error::RuntimeError(format!("cannot encode to INI: {}", message))
+}
+
+// A value as it's written after the =
+fn format_value(value: Value) -> Result<String> {
+    let text = match value {
+        Value::String(string) => string.to_str()?.to_string(),
+        Value::Integer(integer) => integer.to_string(),
+        Value::Number(number) => number.to_string(),
+        Value::Boolean(boolean) => boolean.to_string(),
+        value => return Err(encode_error(format!("{} values", value.type_name()))),
+    };
+    if text.contains(['\n', '\r']) {
+        return Err(encode_error(format!(
+            "values can't have line breaks, got {:?}",
+            text
+        )));
+    }
+    Ok(text)
+}
+
+// The values of a key, several for a list
+fn values_of(value: Value) -> Result<Vec<Value>> {
+    match value {
+        Value::Table(list) => list.sequence_values().collect(),
+        value => Ok(vec![value]),
+    }
+}
+
+// How new keys are written, following the key before them in the section
+struct Style {
+    indent: String,
+    separator: String,
+}
+
+impl Default for Style {
+    fn default() -> Self {
+        Style {
+            indent: String::new(),
+            separator: " = ".to_string(),
+        }
+    }
+}
+
+struct Encoder<'a, 'lua> {
+    root: &'a Table<'lua>,
+    lines: Vec<String>,
+    // Where the next key of the section goes: after its last key, before any blank lines and
+    // comments that follow
+    section_end: usize,
+    // Keys already written, by section, "" for the keys before the first section
+    written: HashSet<(String, String)>,
+    sections: HashSet<String>,
+}
+
+impl<'lua> Encoder<'_, 'lua> {
+    fn write_key(
+        &mut self,
+        section: &str,
+        key: &str,
+        value: Value<'lua>,
+        style: &Style,
+    ) -> Result<()> {
+        for value in values_of(value)? {
+            let value = format_value(value)?;
+            let line = format!("{}{}{}{}", style.indent, key, style.separator, value);
+            self.lines.insert(self.section_end, line);
+            self.section_end += 1;
+        }
+        self.written.insert((section.to_string(), key.to_string()));
+        Ok(())
+    }
+
+    // Writes the keys of the section that aren't written yet, in order
+    fn write_new_keys(&mut self, section: &str, table: &Table<'lua>, style: &Style) -> Result<()> {
+        let mut keys = Vec::new();
+        for pair in table.clone().pairs::<Value, Value>() {
+            let (key, value) = pair?;
+            let key = match key {
+                Value::String(key) => key.to_str()?.to_string(),
+                key => return Err(encode_error(format!("{} keys", key.type_name()))),
+            };
+            // Sections aren't keys of the table before the first section
+            let is_section =
+                section.is_empty() && matches!(value, Value::Table(_)) && !is_list(&value)?;
+            if !is_section && !self.written.contains(&(section.to_string(), key.clone())) {
+                keys.push((key, value));
+            }
+        }
+        keys.sort_by(|(left, _), (right, _)| left.cmp(right));
+        for (key, value) in keys {
+            self.write_key(section, &key, value, style)?;
+        }
+        Ok(())
+    }
+
+    fn section(&self, name: &str) -> Result<Option<Table<'lua>>> {
+        match self.root.raw_get::<_, Value>(name)? {
+            Value::Table(section) if !is_list(&Value::Table(section.clone()))? => Ok(Some(section)),
+            _ => Ok(None),
+        }
+    }
+}
+
+// Whether a table at the top is a list of values for a key given several times rather than a
+// section
+fn is_list(value: &Value) -> Result<bool> {
+    match value {
+        Value::Table(table) => Ok(table.raw_len() > 0),
+        _ => Ok(false),
+    }
+}
+
+fn encode(root: Table) -> Result<String> {
+    let source = match root.get_metatable() {
+        Some(metatable) => metatable.raw_get::<_, Option<String>>(SOURCE_FIELD)?,
+        None => None,
+    };
+    let source = source.unwrap_or_default();
+    let mut encoder = Encoder {
+        root: &root,
+        lines: Vec::new(),
+        section_end: 0,
+        written: HashSet::new(),
+        sections: HashSet::new(),
+    };
+    // The section being written, None while it was removed from the table
+    let mut current: Option<(String, Table)> = Some((String::new(), root.clone()));
+    let mut style = Style::default();
+    for line in parse(&source)? {
+        match line {
+            Line::Other(raw) => {
+                if current.is_some() {
+                    encoder.lines.push(raw.to_string());
+                }
+            }
+            Line::Section { raw, name } => {
+                if let Some((section, table)) = &current {
+                    encoder.write_new_keys(section, table, &style)?;
+                }
+                style = Style::default();
+                current = encoder.section(&name)?.map(|table| (name.clone(), table));
+                if current.is_some() {
+                    encoder.lines.push(raw.to_string());
+                    encoder.section_end = encoder.lines.len();
+                    encoder.sections.insert(name);
+                }
+            }
+            Line::Entry {
+                raw,
+                indent,
+                separator,
+                key,
+                value,
+            } => {
+                let Some((section, table)) = &current else {
+                    continue;
+                };
+                style = Style {
+                    indent: indent.to_string(),
+                    separator: separator.to_string(),
+                };
+                if encoder.written.contains(&(section.clone(), key.clone())) {
+                    continue;
+                }
+                let current_value = table.raw_get::<_, Value>(key.as_str())?;
+                encoder.section_end = encoder.lines.len();
+                let unchanged = match (&value, &current_value) {
+                    (None, Value::Boolean(true)) => true,
+                    (Some(value), Value::String(current)) => current.as_bytes() == value.as_bytes(),
+                    _ => false,
+                };
+                if unchanged {
+                    encoder.lines.push(raw.to_string());
+                    encoder.section_end += 1;
+                    encoder.written.insert((section.clone(), key));
+                } else if !matches!(current_value, Value::Nil) {
+                    let section = section.clone();
+                    encoder.write_key(&section, &key, current_value, &style)?;
+                }
+            }
+        }
+    }
+    if let Some((section, table)) = &current {
+        encoder.write_new_keys(section, table, &style)?;
+    }
+    let mut new_sections = Vec::new();
+    for pair in root.clone().pairs::<String, Value>() {
+        let (name, value) = pair?;
+        if matches!(value, Value::Table(_))
+            && !is_list(&value)?
+            && !encoder.sections.contains(&name)
+        {
+            new_sections.push(name);
+        }
+    }
+    new_sections.sort();
+    for name in new_sections {
+        let Some(table) = encoder.section(&name)? else {
+            continue;
+        };
+        if encoder
+            .lines
+            .last()
+            .is_some_and(|line| !line.trim().is_empty())
+        {
+            encoder.lines.push(String::new());
+        }
+        encoder.lines.push(format!("[{}]", name));
+        encoder.section_end = encoder.lines.len();
+        encoder.sections.insert(name.clone());
+        encoder.write_new_keys(&name, &table, &Style::default())?;
+    }
+    let mut text = encoder.lines.join("\n");
+    text.push('\n');
+    Ok(text)
+}
+
+pub fn load_ini_library(lua: &Lua) -> Result<()> {
+    lua.context(|lua_ctx| {
+        let ini = lua_ctx.create_table()?;
+
+        ini.set(
+            "decode",
+            lua_ctx.create_function(|lua_ctx, text: String| decode(lua_ctx, text))?,
+        )?;
+
+        ini.set(
+            "encode",
+            lua_ctx.create_function(|_, table: Table| encode(table))?,
+        )?;
+
+        help::register_module_docs(lua_ctx, "ini", "INI files", &ini, INI_DOCS)?;
+        lua_ctx.globals().set("ini", ini)?;
+
+        Ok(())
+    })
+}
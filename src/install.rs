@@ -0,0 +1,842 @@
+/*
+   Copyright (C) 2022  Kalka
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License, or (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use rlua::{HookTriggers, Lua, Table, Value};
+use std::cmp::Ordering;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use tempfile::TempDir;
+
+/// Directory packages are installed into, which `require` looks in next to the script and in
+/// the project root.
+pub const MODULES_DIR: &str = "lua_modules";
+
+const LUAROCKS_SERVER: &str = "https://luarocks.org";
+// The server's list of packages and their versions, as a Lua file
+const MANIFEST_FILE: &str = "manifest-5.4";
+// Directories of a git repository without a rockspec that hold its modules, before its root
+const SOURCE_DIRS: &[&str] = &["src", "lua"];
+// Directories of a git repository that never hold modules
+const SKIPPED_DIRS: &[&str] = &["spec", "test", "tests", "examples", "rockspecs"];
+// Limits for running rockspecs and manifests, which anyone can publish. The manifest of the
+// whole server is the largest of them by far
+const EVALUATE_INSTRUCTION_LIMIT: u32 = 200_000_000;
+const EVALUATE_CHECK_INTERVAL: u32 = 10_000;
+const EVALUATE_MEMORY_LIMIT: usize = 512 * 1024 * 1024;
+
+/// A package that was installed, with where it came from: the version and rockspec of a rock,
+/// or the commit and URL of a git repository.
+#[derive(Clone)]
+pub struct Installed {
+    pub name: String,
+    pub version: String,
+    pub source: String,
+}
+
+/// What a package was asked for as: a name on LuaRocks with version constraints like `>= 3.0`,
+/// or a git repository with an optional branch, tag or commit. Repositories are named after
+/// their URL unless given a name.
+pub enum Spec {
+    Rock {
+        name: String,
+        constraints: String,
+    },
+    Git {
+        name: Option<String>,
+        url: String,
+        reference: Option<String>,
+    },
+}
+
+impl Spec {
+    /// Reads a package given on the command line, like `inspect`, `inspect@3.1.3`, or a git URL
+    /// with a branch, tag or commit after `#`.
+    pub fn parse(text: &str) -> Spec {
+        if text.contains("://") || text.starts_with("git@") || text.ends_with(".git") {
+            let (url, reference) = match text.rsplit_once('#') {
+                Some((url, reference)) => (url, Some(reference.to_string())),
+                None => (text, None),
+            };
+            let url = url.strip_prefix("git+").unwrap_or(url).to_string();
+            return Spec::Git {
+                name: None,
+                url,
+                reference,
+            };
+        }
+        match text.split_once('@') {
+            Some((name, version)) => Spec::Rock {
+                name: name.to_string(),
+                constraints: format!("== {}", version),
+            },
+            None => Spec::Rock {
+                name: text.to_string(),
+                constraints: String::new(),
+            },
+        }
+    }
+}
+
+// A LuaRocks version, like `1.2.3-1`, split into its numbers and its revision
+#[derive(PartialEq, Eq)]
+struct Version {
+    numbers: Vec<u64>,
+    revision: Option<u64>,
+}
+
+impl Version {
+    // Versions like `scm-1` or `dev-1` aren't releases and are never picked
+    fn parse(text: &str) -> Option<Version> {
+        let (numbers, revision) = match text.rsplit_once('-') {
+            Some((numbers, revision)) => (numbers, Some(revision.parse().ok()?)),
+            None => (text, None),
+        };
+        let numbers = numbers
+            .split('.')
+            .map(|number| number.parse().ok())
+            .collect::<Option<Vec<u64>>>()?;
+        Some(Version { numbers, revision })
+    }
+
+    // Compares the numbers as if the shorter one ended with zeros, and the revisions when both
+    // have one
+    fn compare(&self, other: &Version) -> Ordering {
+        let length = self.numbers.len().max(other.numbers.len());
+        for index in 0..length {
+            let left = self.numbers.get(index).unwrap_or(&0);
+            let right = other.numbers.get(index).unwrap_or(&0);
+            match left.cmp(right) {
+                Ordering::Equal => {}
+                ordering => return ordering,
+            }
+        }
+        match (self.revision, other.revision) {
+            (Some(left), Some(right)) => left.cmp(&right),
+            _ => Ordering::Equal,
+        }
+    }
+
+    // `~> 1.2` allows anything from 1.2 up to but not including 1.3
+    fn next_release(&self) -> Version {
+        let mut numbers = self.numbers.clone();
+        if let Some(last) = numbers.last_mut() {
+            *last += 1;
+        }
+        Version {
+            numbers,
+            revision: None,
+        }
+    }
+}
+
+// Whether the version meets every constraint in a list like `>= 1.0, < 2.0`
+fn satisfies(version: &Version, constraints: &str) -> Result<bool, String> {
+    for constraint in constraints.split(',').map(str::trim) {
+        if constraint.is_empty() {
+            continue;
+        }
+        let operator_length = constraint
+            .find(|character: char| character.is_ascii_digit())
+            .ok_or_else(|| format!("invalid version constraint '{}'", constraint))?;
+        let (operator, wanted) = constraint.split_at(operator_length);
+        let wanted = Version::parse(wanted.trim())
+            .ok_or_else(|| format!("invalid version constraint '{}'", constraint))?;
+        let ordering = version.compare(&wanted);
+        let met = match operator.trim() {
+            "" | "==" => ordering == Ordering::Equal,
+            "~=" => ordering != Ordering::Equal,
+            ">=" => ordering != Ordering::Less,
+            "<=" => ordering != Ordering::Greater,
+            ">" => ordering == Ordering::Greater,
+            "<" => ordering == Ordering::Less,
+            "~>" => {
+                ordering != Ordering::Less
+                    && version.compare(&wanted.next_release()) == Ordering::Less
+            }
+            operator => return Err(format!("unknown version operator '{}'", operator)),
+        };
+        if !met {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Whether a version like `5.4` meets constraints like `>= 5.3, < 6`.
+pub fn version_satisfies(version: &str, constraints: &str) -> Result<bool, String> {
+    let version =
+        Version::parse(version).ok_or_else(|| format!("invalid version '{}'", version))?;
+    satisfies(&version, constraints)
+}
+
+#[tokio::main]
+async fn download(url: &str) -> Result<Vec<u8>, String> {
+    let response = reqwest::get(url).await.map_err(|error| error.to_string())?;
+    if !response.status().is_success() {
+        return Err(format!("{} returned {}", url, response.status()));
+    }
+    let bytes = response.bytes().await.map_err(|error| error.to_string())?;
+    Ok(bytes.to_vec())
+}
+
+/// Runs a rockspec, manifest or other Lua file assigning globals, without access to anything and
+/// within limits on how long it runs and how much memory it takes, and reads what it assigned
+/// with `read`.
+pub fn evaluate<T>(
+    source: &[u8],
+    name: &str,
+    read: impl FnOnce(Table) -> rlua::Result<T>,
+) -> Result<T, String> {
+    let lua = Lua::new();
+    lua.set_memory_limit(Some(EVALUATE_MEMORY_LIMIT));
+    let file = name.to_string();
+    let mut instructions = 0;
+    lua.set_hook(
+        HookTriggers {
+            every_nth_instruction: Some(EVALUATE_CHECK_INTERVAL),
+            ..Default::default()
+        },
+        move |_, _| {
+            instructions += EVALUATE_CHECK_INTERVAL;
+            if instructions > EVALUATE_INSTRUCTION_LIMIT {
+                return Err(rlua::Error::RuntimeError(format!(
+                    "{} ran for too long",
+                    file
+                )));
+            }
+            Ok(())
+        },
+    );
+    lua.context(|lua_ctx| {
+        let globals = lua_ctx.create_table()?;
+        lua_ctx
+            .load(source)
+            .set_name(&format!("={}", name))?
+            .set_environment(globals.clone())?
+            .exec()?;
+        read(globals)
+    })
+    .map_err(|error| crate::report::error_message(&error))
+}
+
+// What a rockspec says about installing a package
+struct Rockspec {
+    source_url: String,
+    source_dir: Option<String>,
+    reference: Option<String>,
+    // Module names and the files they're in, relative to the source
+    modules: Vec<(String, String)>,
+    dependencies: Vec<String>,
+}
+
+fn read_rockspec(source: &[u8], name: &str) -> Result<Rockspec, String> {
+    let (source, build_type, mut modules, dependencies) = evaluate(source, name, |globals| {
+        let mut source = None;
+        if let Some(table) = globals.get::<_, Option<Table>>("source")? {
+            let field = |key| table.get::<_, Option<String>>(key);
+            source = Some((
+                field("url")?,
+                field("dir")?,
+                field("tag")?.or(field("branch")?),
+            ));
+        }
+        let mut build_type = None;
+        let mut modules = Vec::new();
+        if let Some(build) = globals.get::<_, Option<Table>>("build")? {
+            build_type = build.get::<_, Option<String>>("type")?;
+            if let Some(table) = build.get::<_, Option<Table>>("modules")? {
+                for pair in table.pairs::<String, Value>() {
+                    let (module, file) = pair?;
+                    let file = match file {
+                        Value::String(file) => file.to_str()?.to_string(),
+                        // C modules list their sources in a table
+                        _ => String::new(),
+                    };
+                    modules.push((module, file));
+                }
+            }
+        }
+        let mut dependencies = Vec::new();
+        if let Some(table) = globals.get::<_, Option<Table>>("dependencies")? {
+            for dependency in table.sequence_values::<String>() {
+                dependencies.push(dependency?);
+            }
+        }
+        Ok((source, build_type, modules, dependencies))
+    })?;
+    match build_type.as_deref() {
+        None | Some("builtin") | Some("none") => {}
+        Some(build_type) => {
+            return Err(format!(
+                "{} needs a {} build, only pure-Lua packages can be installed",
+                name, build_type
+            ))
+        }
+    }
+    if let Some((module, _)) = modules.iter().find(|(_, file)| !file.ends_with(".lua")) {
+        return Err(format!(
+            "{} has the C module {}, only pure-Lua packages can be installed",
+            name, module
+        ));
+    }
+    modules.sort();
+    let (url, source_dir, reference) = source.ok_or_else(|| format!("{} has no source", name))?;
+    Ok(Rockspec {
+        source_url: url.ok_or_else(|| format!("{} has no source url", name))?,
+        source_dir,
+        reference,
+        modules,
+        dependencies,
+    })
+}
+
+// A fresh directory to fetch a package into, with a random name only the user can open,
+// removed once it's installed
+fn work_dir(name: &str) -> Result<TempDir, String> {
+    tempfile::Builder::new()
+        .prefix(&format!("rluaterm-install-{}-", name))
+        .tempdir()
+        .map_err(|error| error.to_string())
+}
+
+fn run(command: &mut Command) -> Result<(), String> {
+    let program = command.get_program().to_string_lossy().into_owned();
+    let status = command
+        .status()
+        .map_err(|error| format!("could not run {} [{}]", program, error))?;
+    if !status.success() {
+        return Err(format!("{} failed with {}", program, status));
+    }
+    Ok(())
+}
+
+// Whether a git reference is a full commit hash rather than a branch or tag
+fn is_commit(reference: &str) -> bool {
+    reference.len() == 40
+        && reference
+            .chars()
+            .all(|character| character.is_ascii_hexdigit())
+}
+
+fn clone(url: &str, reference: Option<&str>, dir: &Path) -> Result<(), String> {
+    // Commits can't be cloned directly, only fetched
+    if let Some(commit) = reference.filter(|reference| is_commit(reference)) {
+        run(Command::new("git").args(["init", "--quiet"]).arg(dir))?;
+        run(Command::new("git")
+            .arg("-C")
+            .arg(dir)
+            .args(["fetch", "--quiet", "--depth", "1", url, commit]))?;
+        return run(Command::new("git").arg("-C").arg(dir).args([
+            "checkout",
+            "--quiet",
+            "FETCH_HEAD",
+        ]));
+    }
+    let mut command = Command::new("git");
+    command.args(["clone", "--quiet", "--depth", "1"]);
+    if let Some(reference) = reference {
+        command.args(["--branch", reference]);
+    }
+    run(command.arg(url).arg(dir))
+}
+
+// Fetches a rockspec's source into the directory and returns where its files are
+fn fetch_source(rockspec: &Rockspec, dir: &Path) -> Result<PathBuf, String> {
+    let url = rockspec.source_url.as_str();
+    if let Some(url) = url.strip_prefix("git+") {
+        clone(url, rockspec.reference.as_deref(), &dir.join("source"))?;
+        return Ok(dir.join("source"));
+    }
+    if url.starts_with("git://") {
+        clone(url, rockspec.reference.as_deref(), &dir.join("source"))?;
+        return Ok(dir.join("source"));
+    }
+    let file_name = url.rsplit('/').next().unwrap_or("source");
+    let archive = dir.join(file_name);
+    std::fs::write(&archive, download(url)?).map_err(|error| error.to_string())?;
+    let extracted = dir.join("source");
+    std::fs::create_dir_all(&extracted).map_err(|error| error.to_string())?;
+    if file_name.ends_with(".zip") {
+        run(Command::new("unzip")
+            .arg("-q")
+            .arg(&archive)
+            .arg("-d")
+            .arg(&extracted))?;
+    } else if file_name.ends_with(".lua") {
+        std::fs::rename(&archive, extracted.join(file_name)).map_err(|error| error.to_string())?;
+    } else {
+        run(Command::new("tar")
+            .arg("-xf")
+            .arg(&archive)
+            .arg("-C")
+            .arg(&extracted))?;
+    }
+    if let Some(source_dir) = &rockspec.source_dir {
+        return Ok(extracted.join(source_dir));
+    }
+    // Archives usually hold a single directory named after the package
+    let entries = std::fs::read_dir(&extracted)
+        .map_err(|error| error.to_string())?
+        .filter_map(|entry| entry.ok())
+        .collect::<Vec<_>>();
+    match entries.as_slice() {
+        [entry] if entry.path().is_dir() => Ok(entry.path()),
+        _ => Ok(extracted),
+    }
+}
+
+// Whether a module name is dotted segments of letters, digits, `_` and `-`, which can't lead
+// out of the modules directory
+fn is_module_name(module: &str) -> bool {
+    module.split('.').all(|segment| {
+        !segment.is_empty()
+            && segment.chars().all(|character| {
+                character.is_ascii_alphanumeric() || matches!(character, '_' | '-')
+            })
+    })
+}
+
+// Copies a module's file from the package in `root` into the modules directory. Rockspecs can't
+// be trusted, so the module name and the file both have to stay inside their directories
+fn copy_module(root: &Path, source: &Path, modules_dir: &Path, module: &str) -> Result<(), String> {
+    if !is_module_name(module) {
+        return Err(format!("invalid module name '{}'", module));
+    }
+    let outside = |path: &Path| format!("{} is outside of the package", path.display());
+    let root = root.canonicalize().map_err(|error| error.to_string())?;
+    let source = source
+        .canonicalize()
+        .map_err(|error| format!("could not copy {} [{}]", source.display(), error))?;
+    if !source.starts_with(&root) {
+        return Err(outside(&source));
+    }
+    let target = modules_dir.join(format!("{}.lua", module.replace('.', "/")));
+    if let Some(parent) = target.parent() {
+        std::fs::create_dir_all(parent).map_err(|error| error.to_string())?;
+        // A symlink in the modules directory could still lead elsewhere
+        let modules_dir = modules_dir
+            .canonicalize()
+            .map_err(|error| error.to_string())?;
+        let parent = parent.canonicalize().map_err(|error| error.to_string())?;
+        if !parent.starts_with(&modules_dir) {
+            return Err(format!(
+                "{} is outside of {}",
+                target.display(),
+                modules_dir.display()
+            ));
+        }
+    }
+    std::fs::copy(&source, &target)
+        .map_err(|error| format!("could not copy {} [{}]", source.display(), error))?;
+    Ok(())
+}
+
+// The .lua files under the directory as module names and paths, for repositories without a
+// rockspec
+fn find_modules(dir: &Path, prefix: &str, modules: &mut Vec<(String, PathBuf)>) {
+    let mut entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries.filter_map(|entry| entry.ok()).collect::<Vec<_>>(),
+        Err(_) => return,
+    };
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let path = entry.path();
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if path.is_dir() {
+            if !name.starts_with('.') && !SKIPPED_DIRS.contains(&name.as_str()) {
+                find_modules(&path, &format!("{}{}.", prefix, name), modules);
+            }
+        } else if let Some(stem) = name.strip_suffix(".lua") {
+            // `name/init.lua` is loaded by `require "name"`, and is found under that name
+            let module = match stem {
+                "init" => prefix.trim_end_matches('.').to_string(),
+                stem => format!("{}{}", prefix, stem),
+            };
+            if !is_module_name(&module) {
+                continue;
+            }
+            modules.push((module, path));
+        }
+    }
+}
+
+// Every package being installed, so dependencies shared by several are only installed once
+struct Installer {
+    modules_dir: PathBuf,
+    manifest: Option<Vec<(String, Vec<String>)>>,
+    locked: Vec<Installed>,
+    installed: Vec<Installed>,
+}
+
+impl Installer {
+    // Names on the server and their versions, downloaded the first time a rock is installed
+    fn versions(&mut self, name: &str) -> Result<Vec<String>, String> {
+        if self.manifest.is_none() {
+            let url = format!("{}/{}", LUAROCKS_SERVER, MANIFEST_FILE);
+            let source = download(&url)?;
+            let manifest = evaluate(&source, MANIFEST_FILE, |globals| {
+                let mut packages = Vec::new();
+                let repository = globals.get::<_, Table>("repository")?;
+                for pair in repository.pairs::<String, Table>() {
+                    let (package, versions) = pair?;
+                    let mut names = Vec::new();
+                    for pair in versions.pairs::<String, Value>() {
+                        names.push(pair?.0);
+                    }
+                    packages.push((package, names));
+                }
+                Ok(packages)
+            })?;
+            self.manifest = Some(manifest);
+        }
+        let manifest = self.manifest.as_ref().unwrap();
+        Ok(manifest
+            .iter()
+            .find(|(package, _)| package == name)
+            .map(|(_, versions)| versions.clone())
+            .unwrap_or_default())
+    }
+
+    fn install(&mut self, spec: &Spec) -> Result<(), String> {
+        match spec {
+            Spec::Rock { name, constraints } => self.install_rock(name, constraints),
+            Spec::Git {
+                name,
+                url,
+                reference,
+            } => self.install_git(name.as_deref(), url, reference.as_deref()),
+        }
+    }
+
+    // The locked version of a package, when it still comes from the same place
+    fn locked(&self, name: &str, source: impl Fn(&str) -> bool) -> Option<&Installed> {
+        self.locked
+            .iter()
+            .find(|package| package.name == name && source(&package.source))
+    }
+
+    fn install_rock(&mut self, name: &str, constraints: &str) -> Result<(), String> {
+        if self.installed.iter().any(|package| package.name == name) {
+            return Ok(());
+        }
+        // The locked rockspec is used as long as it still meets the constraints
+        let locked = self
+            .locked(name, |source| source.starts_with(LUAROCKS_SERVER))
+            .filter(|package| {
+                Version::parse(&package.version)
+                    .is_some_and(|version| satisfies(&version, constraints).unwrap_or(false))
+            })
+            .cloned();
+        if let Some(locked) = locked {
+            return self.install_rockspec(name, locked.version, locked.source);
+        }
+        let mut versions = Vec::new();
+        for version in self.versions(name)? {
+            if let Some(parsed) = Version::parse(&version) {
+                if satisfies(&parsed, constraints)? {
+                    versions.push((parsed, version));
+                }
+            }
+        }
+        versions.sort_by(|(left, _), (right, _)| left.compare(right));
+        let version = match versions.pop() {
+            Some((_, version)) => version,
+            None if constraints.is_empty() => return Err(format!("no package named {}", name)),
+            None => return Err(format!("no version of {} matches '{}'", name, constraints)),
+        };
+        let url = format!("{}/{}-{}.rockspec", LUAROCKS_SERVER, name, version);
+        self.install_rockspec(name, version, url)
+    }
+
+    fn install_rockspec(&mut self, name: &str, version: String, url: String) -> Result<(), String> {
+        let rockspec = read_rockspec(&download(&url)?, name)?;
+        let dir = work_dir(name)?;
+        let result = self.install_modules(name, &rockspec, dir.path());
+        let _ = dir.close();
+        result?;
+        self.installed.push(Installed {
+            name: name.to_string(),
+            version,
+            source: url,
+        });
+        self.install_dependencies(&rockspec.dependencies)
+    }
+
+    fn install_modules(&self, name: &str, rockspec: &Rockspec, dir: &Path) -> Result<(), String> {
+        let source = fetch_source(rockspec, dir)?;
+        if rockspec.modules.is_empty() {
+            return Err(format!("{} doesn't list any modules", name));
+        }
+        for (module, file) in &rockspec.modules {
+            copy_module(dir, &source.join(file), &self.modules_dir, module)?;
+        }
+        Ok(())
+    }
+
+    fn install_git(
+        &mut self,
+        name: Option<&str>,
+        url: &str,
+        reference: Option<&str>,
+    ) -> Result<(), String> {
+        let name = name.map_or_else(
+            || {
+                url.trim_end_matches('/')
+                    .rsplit(['/', ':'])
+                    .next()
+                    .unwrap_or(url)
+                    .trim_end_matches(".git")
+                    .to_string()
+            },
+            str::to_string,
+        );
+        if self.installed.iter().any(|package| package.name == name) {
+            return Ok(());
+        }
+        // The branch or tag is part of the source, so changing it doesn't get the locked commit
+        let source = match reference {
+            Some(reference) if !is_commit(reference) => format!("{}#{}", url, reference),
+            _ => url.to_string(),
+        };
+        // The locked commit is used unless a commit was asked for
+        let locked = self
+            .locked(&name, |locked_source| locked_source == source)
+            .filter(|_| !reference.is_some_and(is_commit))
+            .map(|package| package.version.clone());
+        let reference = locked.as_deref().or(reference);
+        let dir = work_dir(&name)?;
+        let result = self.install_repository(&name, url, reference, dir.path());
+        let _ = dir.close();
+        let (commit, dependencies) = result?;
+        self.installed.push(Installed {
+            name,
+            version: commit,
+            source,
+        });
+        self.install_dependencies(&dependencies)
+    }
+
+    // Installs the modules of a repository, as listed in its rockspec if it has one, and
+    // returns the commit it was installed from and its dependencies
+    fn install_repository(
+        &self,
+        name: &str,
+        url: &str,
+        reference: Option<&str>,
+        dir: &Path,
+    ) -> Result<(String, Vec<String>), String> {
+        let repository = dir.join("source");
+        clone(url, reference, &repository)?;
+        let commit = Command::new("git")
+            .arg("-C")
+            .arg(&repository)
+            .args(["rev-parse", "HEAD"])
+            .output()
+            .map_err(|error| format!("could not run git [{}]", error))?;
+        let commit = String::from_utf8_lossy(&commit.stdout).trim().to_string();
+        let rockspec = std::fs::read_dir(&repository)
+            .map_err(|error| error.to_string())?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .find(|path| {
+                path.extension()
+                    .is_some_and(|extension| extension == "rockspec")
+            });
+        if let Some(path) = rockspec {
+            let source = std::fs::read(&path).map_err(|error| error.to_string())?;
+            let rockspec = read_rockspec(&source, name)?;
+            for (module, file) in &rockspec.modules {
+                copy_module(
+                    &repository,
+                    &repository.join(file),
+                    &self.modules_dir,
+                    module,
+                )?;
+            }
+            return Ok((commit, rockspec.dependencies));
+        }
+        let root = SOURCE_DIRS
+            .iter()
+            .map(|dir| repository.join(dir))
+            .find(|dir| dir.is_dir())
+            .unwrap_or_else(|| repository.clone());
+        let mut modules = Vec::new();
+        find_modules(&root, "", &mut modules);
+        if modules.is_empty() {
+            return Err(format!("{} has no Lua files", url));
+        }
+        for (module, path) in &modules {
+            copy_module(&repository, path, &self.modules_dir, module)?;
+        }
+        Ok((commit, Vec::new()))
+    }
+
+    // Dependencies look like `inspect >= 3.0`, Lua itself is always there
+    fn install_dependencies(&mut self, dependencies: &[String]) -> Result<(), String> {
+        for dependency in dependencies {
+            let dependency = dependency.trim();
+            let (name, constraints) = match dependency.find([' ', '=', '<', '>', '~']) {
+                Some(index) => (&dependency[..index], dependency[index..].trim()),
+                None => (dependency, ""),
+            };
+            if name == "lua" {
+                continue;
+            }
+            self.install_rock(name, constraints)?;
+        }
+        Ok(())
+    }
+}
+
+/// Installs the packages and their dependencies into `lua_modules` in the directory. Rocks
+/// come from LuaRocks and must be pure Lua. Repositories are installed from their rockspec if
+/// they have one, and otherwise from the .lua files in their `src` or `lua` directory or root.
+/// Packages in `locked` are installed at the version or commit they were locked at.
+pub fn install(
+    packages: &[Spec],
+    dir: &Path,
+    locked: Vec<Installed>,
+) -> Result<Vec<Installed>, String> {
+    let mut installer = Installer {
+        modules_dir: dir.join(MODULES_DIR),
+        manifest: None,
+        locked,
+        installed: Vec::new(),
+    };
+    for package in packages {
+        let name = match package {
+            Spec::Rock { name, .. } => name,
+            Spec::Git { name, url, .. } => name.as_ref().unwrap_or(url),
+        };
+        installer
+            .install(package)
+            .map_err(|error| format!("{}: {}", name, error))?;
+    }
+    Ok(installer.installed)
+}
+
+/// Reads the packages locked in a lockfile, none if there isn't one.
+pub fn read_lock(path: &Path) -> Result<Vec<Installed>, String> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(_) => return Ok(Vec::new()),
+    };
+    let lock = text
+        .parse::<toml::Table>()
+        .map_err(|error| format!("{}: {}", path.display(), error.message()))?;
+    let packages = match lock.get("package") {
+        Some(packages) => packages
+            .as_array()
+            .ok_or_else(|| format!("{}: package must be a list", path.display()))?,
+        None => return Ok(Vec::new()),
+    };
+    let mut locked = Vec::new();
+    for package in packages {
+        let field = |key| {
+            package
+                .get(key)
+                .and_then(|value| value.as_str())
+                .map(str::to_string)
+                .ok_or_else(|| format!("{}: every package needs a {}", path.display(), key))
+        };
+        locked.push(Installed {
+            name: field("name")?,
+            version: field("version")?,
+            source: field("source")?,
+        });
+    }
+    Ok(locked)
+}
+
+/// Writes the installed packages to a lockfile, so installing again gets the same versions.
+pub fn write_lock(path: &Path, installed: &[Installed]) -> Result<(), String> {
+    let mut installed = installed.to_vec();
+    installed.sort_by(|left, right| left.name.cmp(&right.name));
+    let packages = installed
+        .into_iter()
+        .map(|package| {
+            let mut table = toml::Table::new();
+            table.insert("name".to_string(), package.name.into());
+            table.insert("version".to_string(), package.version.into());
+            table.insert("source".to_string(), package.source.into());
+            toml::Value::Table(table)
+        })
+        .collect::<Vec<_>>();
+    let mut lock = toml::Table::new();
+    lock.insert("package".to_string(), toml::Value::Array(packages));
+    let text = toml::to_string(&lock).map_err(|error| error.to_string())?;
+    std::fs::write(
+        path,
+        format!(
+            "# Written by rluaterm install, don't edit by hand\n\n{}",
+            text
+        ),
+    )
+    .map_err(|error| format!("could not write {} [{}]", path.display(), error))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version(text: &str) -> Version {
+        Version::parse(text).unwrap()
+    }
+
+    #[test]
+    fn parses_versions() {
+        let parsed = version("1.2.3-1");
+        assert_eq!(parsed.numbers, vec![1, 2, 3]);
+        assert_eq!(parsed.revision, Some(1));
+        assert_eq!(version("5.4").revision, None);
+        assert!(Version::parse("scm-1").is_none());
+        assert!(Version::parse("dev-1").is_none());
+        assert!(Version::parse("1.0-x").is_none());
+    }
+
+    #[test]
+    fn compares_versions() {
+        assert_eq!(version("1.2").compare(&version("1.2.0")), Ordering::Equal);
+        assert_eq!(version("1.10").compare(&version("1.9")), Ordering::Greater);
+        assert_eq!(version("1.2-1").compare(&version("1.2-2")), Ordering::Less);
+        // Revisions only count when both versions have one
+        assert_eq!(version("1.2").compare(&version("1.2-3")), Ordering::Equal);
+    }
+
+    #[test]
+    fn checks_constraints() {
+        let check = |text, constraints| satisfies(&version(text), constraints).unwrap();
+        assert!(check("1.2.3-1", ""));
+        assert!(check("1.2.3-1", "1.2.3"));
+        assert!(check("1.2.3-1", "== 1.2.3"));
+        assert!(!check("1.2.3-1", "~= 1.2.3"));
+        assert!(check("2.0-1", ">= 1.0, < 3"));
+        assert!(!check("3.0-1", ">= 1.0, < 3"));
+        assert!(check("1.0", "<= 1.0"));
+        assert!(!check("1.0", "> 1.0"));
+        assert!(check("1.2.9", "~> 1.2"));
+        assert!(!check("1.3", "~> 1.2"));
+        assert!(!check("1.1", "~> 1.2"));
+    }
+
+    #[test]
+    fn rejects_invalid_constraints() {
+        assert!(satisfies(&version("1.0"), ">= x").is_err());
+        assert!(satisfies(&version("1.0"), "=> 1.0").is_err());
+        assert!(version_satisfies("scm-1", ">= 1.0").is_err());
+        assert_eq!(version_satisfies("5.4", ">= 5.3, < 6"), Ok(true));
+    }
+}
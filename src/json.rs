@@ -0,0 +1,184 @@
+/*
+   Copyright (C) 2022  Kalka
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License, or (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use rlua::{Context, LightUserData, Lua, Result, Table, Value};
+
+use crate::error;
+use crate::help::{self, FunctionDoc};
+
+const JSON_DOCS: &[FunctionDoc] = &[
+    FunctionDoc {
+        name: "decode",
+        signature: "json.decode(text) -> value",
+        description: "Parses JSON into Lua values. Objects and arrays become tables, whole numbers integers and other numbers floats, and null becomes json.null so arrays keep their length. Raises an error saying where the JSON is invalid.",
+        example: "local package = json.decode(fs.read(\"package.json\"))\nprint(package.name, package.version)",
+    },
+    FunctionDoc {
+        name: "encode",
+        signature: "json.encode(value[, options]) -> string",
+        description: "Turns a Lua value into JSON. Tables with nothing but the keys 1 to n become arrays and other tables objects, empty ones included, and json.null becomes null. options.pretty indents the JSON over several lines, and options.sort_keys writes object keys in order instead of the order the table holds them in. Raises an error for functions and other values JSON can't hold, and for tables nested inside themselves.",
+        example: "fs.write(\"settings.json\", json.encode(settings, { pretty = true, sort_keys = true }))",
+    },
+];
+
+/// json.null, which stands in for JSON's null in Lua where nil would leave a hole in a table.
+pub fn null<'lua>() -> Value<'lua> {
+    Value::LightUserData(LightUserData(std::ptr::null_mut()))
+}
+
+// Deeper tables are almost certainly cycles
+const MAX_DEPTH: usize = 100;
+
+fn encode_error(message: String) -> rlua::Error {
+    rlua::Error::RuntimeError(format!("cannot encode to JSON: {}", message))
+}
+
+/// Converts a Lua value to JSON. Tables with nothing but the keys 1 to n become arrays, and
+/// other tables objects, with their number keys turned into strings.
+pub fn to_json(value: Value) -> Result<serde_json::Value> {
+    to_json_at_depth(value, 0)
+}
+
+fn to_json_at_depth(value: Value, depth: usize) -> Result<serde_json::Value> {
+    Ok(match value {
+        Value::Nil => serde_json::Value::Null,
+        Value::LightUserData(LightUserData(pointer)) if pointer.is_null() => {
+            serde_json::Value::Null
+        }
+        Value::Boolean(boolean) => serde_json::Value::Bool(boolean),
+        Value::Integer(integer) => serde_json::Value::from(integer),
+        Value::Number(number) => serde_json::Number::from_f64(number)
+            .map(serde_json::Value::Number)
+            .ok_or_else(|| encode_error(format!("{} has no JSON representation", number)))?,
+        Value::String(string) => serde_json::Value::String(string.to_str()?.to_string()),
+        Value::Table(table) => {
+            if depth >= MAX_DEPTH {
+                return Err(encode_error("tables are nested too deeply".to_string()));
+            }
+            let length = table.raw_len() as usize;
+            let pairs = table
+                .clone()
+                .pairs::<Value, Value>()
+                .collect::<Result<Vec<_>>>()?;
+            if length > 0 && pairs.len() == length {
+                let mut array = Vec::with_capacity(length);
+                for index in 1..=length {
+                    array.push(to_json_at_depth(table.raw_get(index as i64)?, depth + 1)?);
+                }
+                serde_json::Value::Array(array)
+            } else {
+                let mut object = serde_json::Map::new();
+                for (key, value) in pairs {
+                    let key = match key {
+                        Value::String(key) => key.to_str()?.to_string(),
+                        Value::Integer(key) => key.to_string(),
+                        Value::Number(key) => key.to_string(),
+                        key => {
+                            return Err(encode_error(format!("{} keys", key.type_name())));
+                        }
+                    };
+                    object.insert(key, to_json_at_depth(value, depth + 1)?);
+                }
+                serde_json::Value::Object(object)
+            }
+        }
+        value => return Err(encode_error(format!("{} values", value.type_name()))),
+    })
+}
+
+/// Converts JSON to a Lua value, with json.null for null.
+pub fn to_lua<'lua>(lua_ctx: Context<'lua>, value: serde_json::Value) -> Result<Value<'lua>> {
+    Ok(match value {
+        serde_json::Value::Null => null(),
+        serde_json::Value::Bool(boolean) => Value::Boolean(boolean),
+        serde_json::Value::Number(number) => match number.as_i64() {
+            Some(integer) => Value::Integer(integer),
+            None => Value::Number(number.as_f64().unwrap_or(f64::NAN)),
+        },
+        serde_json::Value::String(string) => Value::String(lua_ctx.create_string(&string)?),
+        serde_json::Value::Array(array) => {
+            let table = lua_ctx.create_table()?;
+            for (index, value) in array.into_iter().enumerate() {
+                table.raw_set(index as i64 + 1, to_lua(lua_ctx, value)?)?;
+            }
+            Value::Table(table)
+        }
+        serde_json::Value::Object(object) => {
+            let table = lua_ctx.create_table()?;
+            for (key, value) in object {
+                table.raw_set(key, to_lua(lua_ctx, value)?)?;
+            }
+            Value::Table(table)
+        }
+    })
+}
+
+fn encode(value: Value, options: Option<Table>) -> Result<String> {
+    let (pretty, sort_keys) = match options {
+        Some(options) => (
+            options.get::<_, Option<bool>>("pretty")?.unwrap_or(false),
+            options
+                .get::<_, Option<bool>>("sort_keys")?
+                .unwrap_or(false),
+        ),
+        None => (false, false),
+    };
+    let mut json = to_json(value)?;
+    if sort_keys {
+        json.sort_all_objects();
+    }
+    let encoded = if pretty {
+        serde_json::to_string_pretty(&json)
+    } else {
+        serde_json::to_string(&json)
+    };
+    encoded.map_err(|error| encode_error(error.to_string()))
+}
+
+pub fn load_json_library(lua: &Lua) -> Result<()> {
+    lua.context(|lua_ctx| {
+        let json = lua_ctx.create_table()?;
+
+        json.set(
+            "decode",
+            lua_ctx.create_function(|lua_ctx, text: rlua::String| {
+                let value = serde_json::from_slice(text.as_bytes())
+                    .map_err(|source| rlua::Error::external(error::Error::Json { source }))?;
+                to_lua(lua_ctx, value)
+            })?,
+        )?;
+
+        json.set(
+            "encode",
+            lua_ctx.create_function(|_, (value, options): (Value, Option<Table>)| {
+                encode(value, options)
+            })?,
+        )?;
+
+        json.set("null", null())?;
+
+        help::register_module_docs(
+            lua_ctx,
+            "json",
+            "JSON encoding and decoding",
+            &json,
+            JSON_DOCS,
+        )?;
+        lua_ctx.globals().set("json", json)?;
+
+        Ok(())
+    })
+}
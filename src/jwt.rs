@@ -0,0 +1,318 @@
+/*
+   Copyright (C) 2022  Kalka
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License, or (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use rlua::{Context, Lua, MultiValue, Result, Table, Value};
+
+use crate::crypto::Key;
+use crate::help::{self, FunctionDoc};
+use crate::{error, hmac, json};
+
+const JWT_DOCS: &[FunctionDoc] = &[
+    FunctionDoc {
+        name: "encode",
+        signature: "jwt.encode(claims, key[, algorithm[, options]]) -> token",
+        description: "Signs a table of claims as a JWT. The key is a secret string for \"HS256\" (the default for strings), \"HS384\" and \"HS512\", or a key from crypto.generate or crypto.key, or its PEM, for \"RS256\" and \"EdDSA\", which are the defaults for RSA and Ed25519 keys. options.header adds fields to the header, like kid, but can't change alg or typ.",
+        example: "local token = jwt.encode({ sub = \"deploy-bot\", exp = os.time() + 3600 }, secret)\nhttp.set_header(\"Authorization\", \"Bearer \" .. token)",
+    },
+    FunctionDoc {
+        name: "decode",
+        signature: "jwt.decode(token, key[, options]) -> claims, header",
+        description: "Checks a JWT's signature with the key, which has to suit the algorithm the token names, and returns its claims and header. Tokens past their exp or before their nbf are refused too, allowing options.leeway seconds of clock difference, unless options.verify_exp is false. With options.verify false nothing is checked and the key can be nil, for reading tokens meant for someone else. Raises an error saying why a token is refused.",
+        example: "local claims = jwt.decode(token, crypto.key(fs.read(\"issuer.pub\")), { leeway = 30 })\nprint(claims.sub)",
+    },
+];
+
+fn jwt_error(message: impl ToString) -> rlua::Error {
+    rlua::Error::external(error::Error::Decode {
+        format: "JWT",
+        message: message.to_string(),
+    })
+}
+
+// What a token is signed with
+enum SigningKey {
+    Secret(Vec<u8>),
+    Key(Box<Key>),
+}
+
+impl SigningKey {
+    fn from_lua(value: Value, function: &str) -> Result<SigningKey> {
+        match value {
+            // Secrets are used as they are, unless they're a PEM key
+            Value::String(string) if string.as_bytes().starts_with(b"-----BEGIN ") => {
+                Ok(SigningKey::Key(Box::new(Key::from_pem(string.to_str()?)?)))
+            }
+            Value::String(string) => Ok(SigningKey::Secret(string.as_bytes().to_vec())),
+            Value::UserData(key) => match key.borrow::<Key>() {
+                Ok(key) => Ok(SigningKey::Key(Box::new(key.clone()))),
+                Err(_) => Err(bad_key(function, "userdata")),
+            },
+            value => Err(bad_key(function, value.type_name())),
+        }
+    }
+
+    fn default_algorithm(&self) -> &'static str {
+        match self {
+            SigningKey::Secret(_) => "HS256",
+            SigningKey::Key(key) if key.kind() == "rsa" => "RS256",
+            SigningKey::Key(_) => "EdDSA",
+        }
+    }
+
+    // The algorithms the key can sign with, for error messages
+    fn algorithms(&self) -> &'static str {
+        match self {
+            SigningKey::Secret(_) => "HS256, HS384 or HS512",
+            key => key.default_algorithm(),
+        }
+    }
+
+    // Whether the key is the kind the algorithm uses, so a token can't pick an algorithm that
+    // turns a public key into an HMAC secret
+    fn suits(&self, algorithm: &str) -> bool {
+        match self {
+            SigningKey::Secret(_) => matches!(algorithm, "HS256" | "HS384" | "HS512"),
+            SigningKey::Key(key) => match key.kind() {
+                "rsa" => algorithm == "RS256",
+                _ => algorithm == "EdDSA",
+            },
+        }
+    }
+
+    fn sign(&self, algorithm: &str, message: &[u8]) -> Option<Vec<u8>> {
+        match (self, algorithm) {
+            (SigningKey::Secret(secret), "HS256") => {
+                Some(hmac::sign::<sha2::Sha256>(secret, message))
+            }
+            (SigningKey::Secret(secret), "HS384") => {
+                Some(hmac::sign::<sha2::Sha384>(secret, message))
+            }
+            (SigningKey::Secret(secret), "HS512") => {
+                Some(hmac::sign::<sha2::Sha512>(secret, message))
+            }
+            (SigningKey::Key(key), _) => key.sign(message),
+            _ => None,
+        }
+    }
+
+    fn verify(&self, algorithm: &str, message: &[u8], signature: &[u8]) -> bool {
+        match self {
+            SigningKey::Secret(_) => self.sign(algorithm, message).is_some_and(|expected| {
+                expected.len() == signature.len()
+                    && expected
+                        .iter()
+                        .zip(signature)
+                        .fold(0, |diff, (a, b)| diff | (a ^ b))
+                        == 0
+            }),
+            SigningKey::Key(key) => key.verify(message, signature),
+        }
+    }
+}
+
+fn bad_key(function: &str, got: &str) -> rlua::Error {
+    rlua::Error::RuntimeError(format!(
+        "bad argument #2 to '{}' (secret string or crypto key expected, got {})",
+        function, got
+    ))
+}
+
+fn encode_part(value: &serde_json::Value) -> String {
+    URL_SAFE_NO_PAD.encode(serde_json::to_vec(value).expect("JSON values always serialize"))
+}
+
+fn encode(
+    claims: Table,
+    key: Value,
+    algorithm: Option<String>,
+    options: Option<Table>,
+) -> Result<String> {
+    let key = SigningKey::from_lua(key, "encode")?;
+    let algorithm = algorithm.unwrap_or_else(|| key.default_algorithm().to_string());
+    if !key.suits(&algorithm) {
+        return Err(rlua::Error::RuntimeError(format!(
+            "bad argument #3 to 'encode' ({} can't sign with this key, it signs with {})",
+            algorithm,
+            key.algorithms()
+        )));
+    }
+    if let SigningKey::Key(key) = &key {
+        if !key.is_private() {
+            return Err(rlua::Error::RuntimeError(
+                "bad argument #2 to 'encode' (private key expected, got a public key)".to_string(),
+            ));
+        }
+    }
+    let mut header = serde_json::Map::new();
+    if let Some(extra) = options
+        .map(|options| options.get::<_, Option<Table>>("header"))
+        .transpose()?
+        .flatten()
+    {
+        if let serde_json::Value::Object(extra) = json::to_json(Value::Table(extra))? {
+            header.extend(extra);
+        }
+    }
+    // Set last, so the header always names the algorithm the token is signed with
+    header.insert("alg".to_string(), algorithm.clone().into());
+    header.insert("typ".to_string(), "JWT".into());
+    let claims = match json::to_json(Value::Table(claims))? {
+        // Empty tables become arrays
+        serde_json::Value::Array(array) if array.is_empty() => {
+            serde_json::Value::Object(serde_json::Map::new())
+        }
+        serde_json::Value::Object(claims) => serde_json::Value::Object(claims),
+        _ => {
+            return Err(rlua::Error::RuntimeError(
+                "bad argument #1 to 'encode' (table of claims expected, got a list)".to_string(),
+            ))
+        }
+    };
+    let signing_input = format!(
+        "{}.{}",
+        encode_part(&serde_json::Value::Object(header)),
+        encode_part(&claims)
+    );
+    let signature = key
+        .sign(&algorithm, signing_input.as_bytes())
+        .expect("the key suits the algorithm");
+    Ok(format!(
+        "{}.{}",
+        signing_input,
+        URL_SAFE_NO_PAD.encode(signature)
+    ))
+}
+
+fn decode_part(part: &str, name: &str) -> Result<serde_json::Map<String, serde_json::Value>> {
+    let bytes = URL_SAFE_NO_PAD
+        .decode(part)
+        .map_err(|_| jwt_error(format!("its {} isn't base64url", name)))?;
+    match serde_json::from_slice(&bytes) {
+        Ok(serde_json::Value::Object(object)) => Ok(object),
+        _ => Err(jwt_error(format!("its {} isn't a JSON object", name))),
+    }
+}
+
+fn decode<'lua>(
+    lua_ctx: Context<'lua>,
+    (token, key, options): (String, Value<'lua>, Option<Table<'lua>>),
+) -> Result<MultiValue<'lua>> {
+    let (verify, verify_exp, leeway) = match &options {
+        Some(options) => (
+            options.get::<_, Option<bool>>("verify")?.unwrap_or(true),
+            options
+                .get::<_, Option<bool>>("verify_exp")?
+                .unwrap_or(true),
+            options.get::<_, Option<f64>>("leeway")?.unwrap_or(0.0),
+        ),
+        None => (true, true, 0.0),
+    };
+    let parts = token.trim().split('.').collect::<Vec<_>>();
+    let [header_part, claims_part, signature_part] = parts[..] else {
+        return Err(jwt_error("it doesn't have three parts separated by dots"));
+    };
+    let header = decode_part(header_part, "header")?;
+    let claims = decode_part(claims_part, "payload")?;
+
+    if verify {
+        let key = SigningKey::from_lua(key, "decode")?;
+        let algorithm = header
+            .get("alg")
+            .and_then(|algorithm| algorithm.as_str())
+            .ok_or_else(|| jwt_error("its header has no alg"))?;
+        if !key.suits(algorithm) {
+            return Err(jwt_error(format!(
+                "it's signed with {}, but the key is for {}",
+                algorithm,
+                key.algorithms()
+            )));
+        }
+        let signature = URL_SAFE_NO_PAD
+            .decode(signature_part)
+            .map_err(|_| jwt_error("its signature isn't base64url"))?;
+        let signing_input = &token.trim()[..header_part.len() + 1 + claims_part.len()];
+        if !key.verify(algorithm, signing_input.as_bytes(), &signature) {
+            return Err(jwt_error("its signature doesn't match the key"));
+        }
+    }
+    if verify && verify_exp {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        if let Some(expires) = claims.get("exp") {
+            let expires = expires
+                .as_f64()
+                .ok_or_else(|| jwt_error("its exp isn't a number"))?;
+            if now > expires + leeway {
+                return Err(jwt_error(format!(
+                    "it expired {} seconds ago",
+                    (now - expires).floor()
+                )));
+            }
+        }
+        if let Some(not_before) = claims.get("nbf") {
+            let not_before = not_before
+                .as_f64()
+                .ok_or_else(|| jwt_error("its nbf isn't a number"))?;
+            if now + leeway < not_before {
+                return Err(jwt_error(format!(
+                    "it isn't valid for another {} seconds",
+                    (not_before - now).ceil()
+                )));
+            }
+        }
+    }
+
+    Ok(MultiValue::from_vec(vec![
+        json::to_lua(lua_ctx, serde_json::Value::Object(claims))?,
+        json::to_lua(lua_ctx, serde_json::Value::Object(header))?,
+    ]))
+}
+
+pub fn load_jwt_library(lua: &Lua) -> Result<()> {
+    lua.context(|lua_ctx| {
+        let jwt = lua_ctx.create_table()?;
+
+        jwt.set(
+            "encode",
+            lua_ctx.create_function(
+                |_,
+                 (claims, key, algorithm, options): (
+                    Table,
+                    Value,
+                    Option<String>,
+                    Option<Table>,
+                )| { encode(claims, key, algorithm, options) },
+            )?,
+        )?;
+        jwt.set("decode", lua_ctx.create_function(decode)?)?;
+
+        help::register_module_docs(
+            lua_ctx,
+            "jwt",
+            "Signing and checking JSON Web Tokens",
+            &jwt,
+            JWT_DOCS,
+        )?;
+        lua_ctx.globals().set("jwt", jwt)?;
+        Ok(())
+    })
+}
@@ -0,0 +1,322 @@
+/*
+   Copyright (C) 2022  Kalka
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License, or (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+// Splits Lua source into tokens for the tools that look at code without running it
+
+const KEYWORDS: &[&str] = &[
+    "and", "break", "do", "else", "elseif", "end", "false", "for", "function", "goto", "if", "in",
+    "local", "nil", "not", "or", "repeat", "return", "then", "true", "until", "while",
+];
+
+// Operators and punctuation, longest first so that `...` isn't read as `..` and `.`
+const SYMBOLS: &[&str] = &[
+    "...", "..", "==", "~=", "<=", ">=", "<<", ">>", "//", "::", "+", "-", "*", "/", "%", "^", "#",
+    "&", "~", "|", "<", ">", "=", "(", ")", "{", "}", "[", "]", ";", ":", ",", ".",
+];
+
+pub const UNARY_OPERATORS: &[&str] = &["not", "-", "#", "~"];
+pub const BINARY_OPERATORS: &[&str] = &[
+    "+", "-", "*", "/", "//", "%", "^", "..", "==", "~=", "<", "<=", ">", ">=", "and", "or", "&",
+    "|", "~", "<<", ">>",
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TokenKind {
+    Name,
+    Keyword,
+    Number,
+    String,
+    Symbol,
+    Comment,
+}
+
+/// A token along with where it starts, lines and columns counting from 1.
+#[derive(Clone, Debug)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub text: String,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Token {
+    /// Whether the token is the given keyword or symbol.
+    pub fn is(&self, text: &str) -> bool {
+        matches!(self.kind, TokenKind::Keyword | TokenKind::Symbol) && self.text == text
+    }
+}
+
+/// Why the source couldn't be split into tokens, and where.
+#[derive(Debug)]
+pub struct LexError {
+    pub message: String,
+    pub line: usize,
+    pub column: usize,
+}
+
+struct Lexer {
+    chars: Vec<char>,
+    position: usize,
+    line: usize,
+    column: usize,
+}
+
+impl Lexer {
+    fn peek(&self, offset: usize) -> Option<char> {
+        self.chars.get(self.position + offset).copied()
+    }
+
+    fn advance(&mut self) -> Option<char> {
+        let c = self.peek(0)?;
+        self.position += 1;
+        if c == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+        Some(c)
+    }
+
+    fn error(&self, message: &str, line: usize, column: usize) -> LexError {
+        LexError {
+            message: message.to_string(),
+            line,
+            column,
+        }
+    }
+
+    // The level of a long bracket such as `[==[` starting at the current position
+    fn long_bracket_level(&self) -> Option<usize> {
+        if self.peek(0) != Some('[') {
+            return None;
+        }
+        let mut level = 0;
+        while self.peek(level + 1) == Some('=') {
+            level += 1;
+        }
+        (self.peek(level + 1) == Some('[')).then_some(level)
+    }
+
+    fn skip_long_bracket(
+        &mut self,
+        level: usize,
+        line: usize,
+        column: usize,
+    ) -> Result<(), LexError> {
+        for _ in 0..level + 2 {
+            self.advance();
+        }
+        loop {
+            match self.advance() {
+                Some(']') => {
+                    let mut equals = 0;
+                    while equals < level && self.peek(equals) == Some('=') {
+                        equals += 1;
+                    }
+                    if equals == level && self.peek(level) == Some(']') {
+                        for _ in 0..level + 1 {
+                            self.advance();
+                        }
+                        return Ok(());
+                    }
+                }
+                Some(_) => {}
+                None => return Err(self.error("unfinished long string or comment", line, column)),
+            }
+        }
+    }
+
+    fn skip_quoted_string(&mut self, line: usize, column: usize) -> Result<(), LexError> {
+        let quote = self.advance();
+        loop {
+            match self.advance() {
+                Some('\\') => {
+                    // \z skips the whitespace after it, line breaks included
+                    if self.advance() == Some('z') {
+                        while self.peek(0).is_some_and(char::is_whitespace) {
+                            self.advance();
+                        }
+                    }
+                }
+                Some('\n') | None => return Err(self.error("unfinished string", line, column)),
+                c if c == quote => return Ok(()),
+                Some(_) => {}
+            }
+        }
+    }
+
+    fn skip_number(&mut self) {
+        let hex = self.peek(0) == Some('0') && matches!(self.peek(1), Some('x' | 'X'));
+        let exponents: &[char] = if hex { &['p', 'P'] } else { &['e', 'E'] };
+        if hex {
+            self.advance();
+            self.advance();
+        }
+        while let Some(c) = self.peek(0) {
+            if exponents.contains(&c) && matches!(self.peek(1), Some('+' | '-')) {
+                self.advance();
+            } else if !(c.is_ascii_alphanumeric() || c == '.') {
+                break;
+            }
+            self.advance();
+        }
+    }
+
+    fn next_token(&mut self) -> Result<Option<Token>, LexError> {
+        while self.peek(0).is_some_and(char::is_whitespace) {
+            self.advance();
+        }
+        let (line, column, start) = (self.line, self.column, self.position);
+        let c = match self.peek(0) {
+            Some(c) => c,
+            None => return Ok(None),
+        };
+        let kind = if c == '-' && self.peek(1) == Some('-') {
+            self.advance();
+            self.advance();
+            match self.long_bracket_level() {
+                Some(level) => self.skip_long_bracket(level, line, column)?,
+                None => {
+                    while self.peek(0).is_some_and(|c| c != '\n') {
+                        self.advance();
+                    }
+                }
+            }
+            TokenKind::Comment
+        } else if c.is_alphabetic() || c == '_' {
+            while self
+                .peek(0)
+                .is_some_and(|c| c.is_alphanumeric() || c == '_')
+            {
+                self.advance();
+            }
+            let text: String = self.chars[start..self.position].iter().collect();
+            if KEYWORDS.contains(&text.as_str()) {
+                TokenKind::Keyword
+            } else {
+                TokenKind::Name
+            }
+        } else if c.is_ascii_digit()
+            || (c == '.' && self.peek(1).is_some_and(|c| c.is_ascii_digit()))
+        {
+            self.skip_number();
+            TokenKind::Number
+        } else if c == '"' || c == '\'' {
+            self.skip_quoted_string(line, column)?;
+            TokenKind::String
+        } else if let Some(level) = self.long_bracket_level() {
+            self.skip_long_bracket(level, line, column)?;
+            TokenKind::String
+        } else {
+            let rest: String = self.chars[start..(start + 3).min(self.chars.len())]
+                .iter()
+                .collect();
+            let symbol = SYMBOLS
+                .iter()
+                .find(|symbol| rest.starts_with(*symbol))
+                .ok_or_else(|| self.error(&format!("unexpected symbol '{}'", c), line, column))?;
+            for _ in 0..symbol.chars().count() {
+                self.advance();
+            }
+            TokenKind::Symbol
+        };
+        Ok(Some(Token {
+            kind,
+            text: self.chars[start..self.position].iter().collect(),
+            line,
+            column,
+        }))
+    }
+}
+
+/// Splits the source into tokens, comments included. A shebang line is skipped.
+pub fn tokenize(source: &str) -> Result<Vec<Token>, LexError> {
+    let mut lexer = Lexer {
+        chars: source.chars().collect(),
+        position: 0,
+        line: 1,
+        column: 1,
+    };
+    if source.starts_with('#') {
+        while lexer.peek(0).is_some_and(|c| c != '\n') {
+            lexer.advance();
+        }
+    }
+    let mut tokens = Vec::new();
+    while let Some(token) = lexer.next_token()? {
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds_and_lines(source: &str) -> Vec<(TokenKind, String, usize)> {
+        tokenize(source)
+            .unwrap()
+            .into_iter()
+            .map(|token| (token.kind, token.text, token.line))
+            .collect()
+    }
+
+    #[test]
+    fn skips_escaped_quotes() {
+        let tokens = kinds_and_lines(r#"x = "say \"hi\"" .. 'it\'s'"#);
+        assert_eq!(
+            tokens[2],
+            (TokenKind::String, r#""say \"hi\"""#.to_string(), 1)
+        );
+        assert_eq!(tokens[4], (TokenKind::String, r"'it\'s'".to_string(), 1));
+    }
+
+    #[test]
+    fn skips_escaped_line_breaks() {
+        let tokens = kinds_and_lines("x = \"a\\\nb\"\ny");
+        assert_eq!(tokens[2].0, TokenKind::String);
+        assert_eq!(tokens[3], (TokenKind::Name, "y".to_string(), 3));
+    }
+
+    #[test]
+    fn skips_whitespace_after_z() {
+        let tokens = kinds_and_lines("x = \"a\\z\n\n   b\"\ny");
+        assert_eq!(
+            tokens[2],
+            (TokenKind::String, "\"a\\z\n\n   b\"".to_string(), 1)
+        );
+        assert_eq!(tokens[3], (TokenKind::Name, "y".to_string(), 4));
+    }
+
+    #[test]
+    fn rejects_unfinished_strings() {
+        let error = tokenize("x = \"abc\ny\"").unwrap_err();
+        assert_eq!((error.line, error.column), (1, 5));
+        assert!(tokenize("x = 'abc\\").is_err());
+        assert!(tokenize("x = [==[abc]=]").is_err());
+    }
+
+    #[test]
+    fn matches_long_bracket_levels() {
+        let tokens = kinds_and_lines("x = [==[a]]b]=]c]==] y");
+        assert_eq!(
+            tokens[2],
+            (TokenKind::String, "[==[a]]b]=]c]==]".to_string(), 1)
+        );
+        assert_eq!(tokens[3].1, "y");
+    }
+}
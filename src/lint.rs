@@ -0,0 +1,747 @@
+/*
+   Copyright (C) 2022  Kalka
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License, or (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use rlua::{Lua, Result, Value};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use crate::lexer::{self, Token, TokenKind};
+use crate::{compile, report};
+
+// Globals that exist while a script runs without being in a fresh interpreter's globals
+const EXTRA_GLOBALS: &[&str] = &["_ENV", "arg"];
+
+/// A problem found in a file, at a line and column counting from 1.
+pub struct Diagnostic {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum LocalKind {
+    Variable,
+    Function,
+    LoopVariable,
+    // Arguments and `self` often go unused on purpose, so they are never reported
+    Argument,
+}
+
+struct Local {
+    name: String,
+    line: usize,
+    column: usize,
+    kind: LocalKind,
+    used: bool,
+}
+
+// What the start of an expression statement turned out to be
+enum Target {
+    Name(Token),
+    Call,
+    Other,
+}
+
+type ParseResult<T> = std::result::Result<T, Diagnostic>;
+
+// Walks the tokens the way Lua's parser does, keeping track of the locals in scope instead of
+// building a syntax tree
+struct Linter<'a> {
+    tokens: Vec<Token>,
+    position: usize,
+    scopes: Vec<Vec<Local>>,
+    known_globals: &'a HashSet<String>,
+    global_reads: Vec<Token>,
+    global_writes: HashSet<String>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Linter<'_> {
+    fn peek(&self, offset: usize) -> Option<&Token> {
+        self.tokens.get(self.position + offset)
+    }
+
+    fn check(&self, text: &str) -> bool {
+        self.peek(0).is_some_and(|token| token.is(text))
+    }
+
+    fn accept(&mut self, text: &str) -> bool {
+        let found = self.check(text);
+        if found {
+            self.position += 1;
+        }
+        found
+    }
+
+    fn next(&mut self) -> ParseResult<Token> {
+        let token = self
+            .peek(0)
+            .cloned()
+            .ok_or_else(|| self.error("unexpected end of file"))?;
+        self.position += 1;
+        Ok(token)
+    }
+
+    fn expect(&mut self, text: &str) -> ParseResult<Token> {
+        if !self.check(text) {
+            return Err(self.error(&format!("'{}' expected", text)));
+        }
+        self.next()
+    }
+
+    fn expect_name(&mut self) -> ParseResult<Token> {
+        match self.peek(0) {
+            Some(token) if token.kind == TokenKind::Name => self.next(),
+            _ => Err(self.error("name expected")),
+        }
+    }
+
+    fn error(&self, message: &str) -> Diagnostic {
+        let (line, column) = self
+            .peek(0)
+            .or(self.tokens.last())
+            .map_or((1, 1), |token| (token.line, token.column));
+        Diagnostic {
+            line,
+            column,
+            message: message.to_string(),
+        }
+    }
+
+    fn report(&mut self, token: &Token, message: String) {
+        self.diagnostics.push(Diagnostic {
+            line: token.line,
+            column: token.column,
+            message,
+        });
+    }
+
+    fn find_local(&mut self, name: &str) -> Option<&mut Local> {
+        self.scopes
+            .iter_mut()
+            .rev()
+            .flat_map(|scope| scope.iter_mut().rev())
+            .find(|local| local.name == name)
+    }
+
+    fn declare(&mut self, token: &Token, kind: LocalKind) {
+        if token.text != "_" {
+            if let Some(local) = self.find_local(&token.text) {
+                let message = format!(
+                    "'{}' shadows a local defined on line {}",
+                    token.text, local.line
+                );
+                self.report(token, message);
+            }
+        }
+        self.scopes.last_mut().unwrap().push(Local {
+            name: token.text.clone(),
+            line: token.line,
+            column: token.column,
+            kind,
+            used: false,
+        });
+    }
+
+    fn read(&mut self, token: &Token) {
+        match self.find_local(&token.text) {
+            Some(local) => local.used = true,
+            None => self.global_reads.push(token.clone()),
+        }
+    }
+
+    fn write(&mut self, token: &Token) {
+        // Assigning a local doesn't count as using it
+        if self.find_local(&token.text).is_none() {
+            self.global_writes.insert(token.text.clone());
+        }
+    }
+
+    fn close_scope(&mut self) {
+        for local in self.scopes.pop().unwrap_or_default() {
+            if local.used || local.name.starts_with('_') {
+                continue;
+            }
+            let what = match local.kind {
+                LocalKind::Variable => "local",
+                LocalKind::Function => "local function",
+                LocalKind::LoopVariable => "loop variable",
+                LocalKind::Argument => continue,
+            };
+            self.diagnostics.push(Diagnostic {
+                line: local.line,
+                column: local.column,
+                message: format!("unused {} '{}'", what, local.name),
+            });
+        }
+    }
+
+    fn at_block_end(&self) -> bool {
+        match self.peek(0) {
+            None => true,
+            Some(token) => ["end", "else", "elseif", "until"]
+                .iter()
+                .any(|keyword| token.is(keyword)),
+        }
+    }
+
+    // Parses statements up to the end of the block, returning whether control never falls
+    // through its end
+    fn block(&mut self) -> ParseResult<bool> {
+        let mut terminated = false;
+        let mut reported = false;
+        while !self.at_block_end() {
+            if self.check("return") {
+                self.warn_unreachable(terminated, &mut reported);
+                self.next()?;
+                if !self.at_block_end() && !self.check(";") {
+                    self.expression_list()?;
+                }
+                self.accept(";");
+                return Ok(true);
+            }
+            // A label can be jumped to, so whatever follows it is reachable again
+            if self.check("::") {
+                terminated = false;
+                reported = false;
+            } else if !self.check(";") {
+                self.warn_unreachable(terminated, &mut reported);
+            }
+            terminated |= self.statement()?;
+        }
+        Ok(terminated)
+    }
+
+    fn warn_unreachable(&mut self, terminated: bool, reported: &mut bool) {
+        if terminated && !*reported {
+            if let Some(token) = self.peek(0).cloned() {
+                self.report(&token, "unreachable code".to_string());
+            }
+            *reported = true;
+        }
+    }
+
+    fn scoped_block(&mut self) -> ParseResult<bool> {
+        self.scopes.push(Vec::new());
+        let terminated = self.block()?;
+        self.close_scope();
+        Ok(terminated)
+    }
+
+    fn statement(&mut self) -> ParseResult<bool> {
+        let token = self.next()?;
+        match token.text.as_str() {
+            ";" if token.kind == TokenKind::Symbol => Ok(false),
+            "::" if token.kind == TokenKind::Symbol => {
+                self.expect_name()?;
+                self.expect("::")?;
+                Ok(false)
+            }
+            "break" if token.kind == TokenKind::Keyword => Ok(true),
+            "goto" if token.kind == TokenKind::Keyword => {
+                self.expect_name()?;
+                Ok(true)
+            }
+            "do" if token.kind == TokenKind::Keyword => {
+                let terminated = self.scoped_block()?;
+                self.expect("end")?;
+                Ok(terminated)
+            }
+            "while" if token.kind == TokenKind::Keyword => {
+                self.expression()?;
+                self.expect("do")?;
+                self.scoped_block()?;
+                self.expect("end")?;
+                Ok(false)
+            }
+            "repeat" if token.kind == TokenKind::Keyword => {
+                // The condition can see the locals of the loop body
+                self.scopes.push(Vec::new());
+                self.block()?;
+                self.expect("until")?;
+                self.expression()?;
+                self.close_scope();
+                Ok(false)
+            }
+            "if" if token.kind == TokenKind::Keyword => self.if_statement(),
+            "for" if token.kind == TokenKind::Keyword => self.for_statement(),
+            "function" if token.kind == TokenKind::Keyword => {
+                let name = self.expect_name()?;
+                let mut is_method = false;
+                if self.check(".") || self.check(":") {
+                    self.read(&name);
+                    while self.accept(".") {
+                        self.expect_name()?;
+                    }
+                    if self.accept(":") {
+                        self.expect_name()?;
+                        is_method = true;
+                    }
+                } else {
+                    self.write(&name);
+                }
+                self.function_body(is_method)?;
+                Ok(false)
+            }
+            "local" if token.kind == TokenKind::Keyword => {
+                if self.accept("function") {
+                    // The function can call itself, so its name is in scope in its body
+                    let name = self.expect_name()?;
+                    self.declare(&name, LocalKind::Function);
+                    self.function_body(false)?;
+                    return Ok(false);
+                }
+                let mut names = Vec::new();
+                loop {
+                    names.push(self.expect_name()?);
+                    if self.accept("<") {
+                        self.expect_name()?;
+                        self.expect(">")?;
+                    }
+                    if !self.accept(",") {
+                        break;
+                    }
+                }
+                // The values are evaluated before the new locals come into scope
+                if self.accept("=") {
+                    self.expression_list()?;
+                }
+                for name in names {
+                    self.declare(&name, LocalKind::Variable);
+                }
+                Ok(false)
+            }
+            _ => {
+                self.position -= 1;
+                self.expression_statement()
+            }
+        }
+    }
+
+    fn if_statement(&mut self) -> ParseResult<bool> {
+        self.expression()?;
+        self.expect("then")?;
+        let mut terminated = self.scoped_block()?;
+        let mut has_else = false;
+        loop {
+            if self.accept("elseif") {
+                self.expression()?;
+                self.expect("then")?;
+                terminated &= self.scoped_block()?;
+            } else if self.accept("else") {
+                terminated &= self.scoped_block()?;
+                has_else = true;
+            } else {
+                self.expect("end")?;
+                return Ok(terminated && has_else);
+            }
+        }
+    }
+
+    fn for_statement(&mut self) -> ParseResult<bool> {
+        let mut names = vec![self.expect_name()?];
+        if self.accept("=") {
+            self.expression()?;
+            self.expect(",")?;
+            self.expression()?;
+            if self.accept(",") {
+                self.expression()?;
+            }
+        } else {
+            while self.accept(",") {
+                names.push(self.expect_name()?);
+            }
+            self.expect("in")?;
+            self.expression_list()?;
+        }
+        self.expect("do")?;
+        self.scopes.push(Vec::new());
+        for name in &names {
+            self.declare(name, LocalKind::LoopVariable);
+        }
+        self.scoped_block()?;
+        self.close_scope();
+        self.expect("end")?;
+        Ok(false)
+    }
+
+    fn expression_statement(&mut self) -> ParseResult<bool> {
+        let start = self.position;
+        let mut targets = vec![self.suffixed_expression()?];
+        if !self.check("=") && !self.check(",") {
+            return match targets.pop() {
+                Some(Target::Call) => Ok(self.never_returns(start)),
+                _ => Err(self.error("syntax error")),
+            };
+        }
+        while self.accept(",") {
+            targets.push(self.suffixed_expression()?);
+        }
+        self.expect("=")?;
+        self.expression_list()?;
+        for target in targets {
+            if let Target::Name(name) = target {
+                self.write(&name);
+            }
+        }
+        Ok(false)
+    }
+
+    fn function_body(&mut self, is_method: bool) -> ParseResult<()> {
+        self.scopes.push(Vec::new());
+        if is_method {
+            let function = self.tokens[self.position - 1].clone();
+            self.scopes.last_mut().unwrap().push(Local {
+                name: "self".to_string(),
+                line: function.line,
+                column: function.column,
+                kind: LocalKind::Argument,
+                used: false,
+            });
+        }
+        self.expect("(")?;
+        if !self.check(")") {
+            loop {
+                if self.accept("...") {
+                    break;
+                }
+                let name = self.expect_name()?;
+                self.declare(&name, LocalKind::Argument);
+                if !self.accept(",") {
+                    break;
+                }
+            }
+        }
+        self.expect(")")?;
+        self.block()?;
+        self.expect("end")?;
+        self.close_scope();
+        Ok(())
+    }
+
+    fn expression_list(&mut self) -> ParseResult<()> {
+        self.expression()?;
+        while self.accept(",") {
+            self.expression()?;
+        }
+        Ok(())
+    }
+
+    // Operator precedence doesn't matter for the checks, so operands are just read in order
+    fn expression(&mut self) -> ParseResult<()> {
+        loop {
+            while lexer::UNARY_OPERATORS
+                .iter()
+                .any(|operator| self.check(operator))
+            {
+                self.next()?;
+            }
+            self.simple_expression()?;
+            if !lexer::BINARY_OPERATORS
+                .iter()
+                .any(|operator| self.check(operator))
+            {
+                return Ok(());
+            }
+            self.next()?;
+        }
+    }
+
+    fn simple_expression(&mut self) -> ParseResult<()> {
+        let token = self
+            .peek(0)
+            .cloned()
+            .ok_or_else(|| self.error("unexpected end of file"))?;
+        match token.kind {
+            TokenKind::Number | TokenKind::String => {
+                self.next()?;
+            }
+            _ if ["nil", "true", "false", "..."]
+                .iter()
+                .any(|text| token.is(text)) =>
+            {
+                self.next()?;
+            }
+            _ if token.is("function") => {
+                self.next()?;
+                self.function_body(false)?;
+            }
+            _ if token.is("{") => self.table()?,
+            _ => {
+                if let Target::Name(name) = self.suffixed_expression()? {
+                    self.read(&name);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn table(&mut self) -> ParseResult<()> {
+        self.expect("{")?;
+        while !self.check("}") {
+            if self.accept("[") {
+                self.expression()?;
+                self.expect("]")?;
+                self.expect("=")?;
+            } else if self
+                .peek(0)
+                .is_some_and(|token| token.kind == TokenKind::Name)
+                && self.peek(1).is_some_and(|token| token.is("="))
+            {
+                self.position += 2;
+            }
+            self.expression()?;
+            if !self.accept(",") && !self.accept(";") {
+                break;
+            }
+        }
+        self.expect("}")?;
+        Ok(())
+    }
+
+    // A name is only resolved once it's known whether it's read or assigned
+    fn suffixed_expression(&mut self) -> ParseResult<Target> {
+        let mut target = if self.accept("(") {
+            self.expression()?;
+            self.expect(")")?;
+            Target::Other
+        } else {
+            Target::Name(self.expect_name()?)
+        };
+        loop {
+            let is_suffix = [".", "[", ":", "(", "{"]
+                .iter()
+                .any(|text| self.check(text))
+                || self
+                    .peek(0)
+                    .is_some_and(|token| token.kind == TokenKind::String);
+            if !is_suffix {
+                return Ok(target);
+            }
+            if let Target::Name(name) = &target {
+                let name = name.clone();
+                self.read(&name);
+            }
+            target = if self.accept(".") {
+                self.expect_name()?;
+                Target::Other
+            } else if self.accept("[") {
+                self.expression()?;
+                self.expect("]")?;
+                Target::Other
+            } else {
+                if self.accept(":") {
+                    self.expect_name()?;
+                }
+                self.call_arguments()?;
+                Target::Call
+            };
+        }
+    }
+
+    fn call_arguments(&mut self) -> ParseResult<()> {
+        if self.check("{") {
+            return self.table();
+        }
+        if self
+            .peek(0)
+            .is_some_and(|token| token.kind == TokenKind::String)
+        {
+            self.next()?;
+            return Ok(());
+        }
+        self.expect("(")?;
+        if !self.check(")") {
+            self.expression_list()?;
+        }
+        self.expect(")")?;
+        Ok(())
+    }
+
+    // Whether the call statement starting at the token is to error() or os.exit(), which never
+    // return
+    fn never_returns(&mut self, start: usize) -> bool {
+        let callee: Vec<String> = self.tokens[start..]
+            .iter()
+            .take(4)
+            .map(|token| token.text.clone())
+            .collect();
+        self.find_local(&callee[0]).is_none()
+            && (callee.starts_with(&["error".into(), "(".into()])
+                || callee.starts_with(&["os".into(), ".".into(), "exit".into(), "(".into()]))
+    }
+}
+
+/// Checks Lua source for unused locals, shadowed locals, undefined globals and unreachable code.
+/// Globals count as defined when they are in `known_globals` or assigned anywhere in the source.
+pub fn lint_source(source: &str, known_globals: &HashSet<String>) -> Vec<Diagnostic> {
+    let tokens = match lexer::tokenize(source) {
+        Ok(tokens) => tokens,
+        Err(error) => {
+            return vec![Diagnostic {
+                line: error.line,
+                column: error.column,
+                message: error.message,
+            }]
+        }
+    };
+    let mut linter = Linter {
+        tokens: tokens
+            .into_iter()
+            .filter(|token| token.kind != TokenKind::Comment)
+            .collect(),
+        position: 0,
+        scopes: vec![Vec::new()],
+        known_globals,
+        global_reads: Vec::new(),
+        global_writes: HashSet::new(),
+        diagnostics: Vec::new(),
+    };
+    let parsed = linter.block().and_then(|_| match linter.peek(0) {
+        Some(token) => Err(Diagnostic {
+            line: token.line,
+            column: token.column,
+            message: format!("unexpected '{}'", token.text),
+        }),
+        None => Ok(()),
+    });
+    if let Err(diagnostic) = parsed {
+        return vec![diagnostic];
+    }
+    linter.close_scope();
+
+    for token in std::mem::take(&mut linter.global_reads) {
+        if !linter.known_globals.contains(&token.text)
+            && !linter.global_writes.contains(&token.text)
+        {
+            linter.report(&token, format!("undefined global '{}'", token.text));
+        }
+    }
+    let mut diagnostics = linter.diagnostics;
+    diagnostics.sort_by_key(|diagnostic| (diagnostic.line, diagnostic.column));
+    diagnostics
+}
+
+/// The Lua files among the paths, looking through directories recursively.
+pub fn lua_files(paths: &[String]) -> Vec<PathBuf> {
+    fn walk(path: &Path, files: &mut Vec<PathBuf>) {
+        let Ok(entries) = std::fs::read_dir(path) else {
+            return;
+        };
+        let mut entries: Vec<PathBuf> = entries.flatten().map(|entry| entry.path()).collect();
+        entries.sort();
+        for entry in entries {
+            if entry.is_dir() {
+                walk(&entry, files);
+            } else if entry
+                .extension()
+                .is_some_and(|extension| extension == "lua")
+            {
+                files.push(entry);
+            }
+        }
+    }
+
+    let mut files = Vec::new();
+    for path in paths {
+        let path = Path::new(path);
+        if path.is_dir() {
+            walk(path, &mut files);
+        } else {
+            files.push(path.to_path_buf());
+        }
+    }
+    files
+}
+
+/// Lints the files, printing a `file:line:col: message` line for every problem found, syntax
+/// errors included. Returns the number of problems.
+pub fn lint(lua: &Lua, paths: &[PathBuf]) -> Result<usize> {
+    let mut known_globals: HashSet<String> =
+        EXTRA_GLOBALS.iter().map(|name| name.to_string()).collect();
+    lua.context(|lua_ctx| {
+        for pair in lua_ctx.globals().pairs::<Value, Value>() {
+            if let (Value::String(name), _) = pair? {
+                known_globals.insert(name.to_str()?.to_string());
+            }
+        }
+        Ok(())
+    })?;
+
+    let mut problems = 0;
+    for path in paths {
+        let source = std::fs::read_to_string(path).map_err(rlua::Error::external)?;
+        let diagnostics = match syntax_error(lua, path, &source)? {
+            Some(diagnostic) => vec![diagnostic],
+            None => lint_source(&source, &known_globals),
+        };
+        print_diagnostics(path, &diagnostics);
+        problems += diagnostics.len();
+    }
+    Ok(problems)
+}
+
+/// Lua's own report of the first syntax error in the source, if it has one.
+pub fn syntax_error(lua: &Lua, path: &Path, source: &str) -> Result<Option<Diagnostic>> {
+    let mut contents = source.as_bytes().to_vec();
+    crate::strip_shebang(&mut contents);
+    lua.context(|lua_ctx| {
+        match lua_ctx
+            .load(&contents)
+            .set_name(&format!("@{}", path.display()))?
+            .into_function()
+        {
+            Ok(_) => Ok(None),
+            Err(error) => Ok(report::syntax_error_position(&error, source).map(
+                |(line, column, message)| Diagnostic {
+                    line,
+                    column,
+                    message,
+                },
+            )),
+        }
+    })
+}
+
+fn print_diagnostics(path: &Path, diagnostics: &[Diagnostic]) {
+    for diagnostic in diagnostics {
+        println!(
+            "{}:{}:{}: {}",
+            path.display(),
+            diagnostic.line,
+            diagnostic.column,
+            diagnostic.message
+        );
+    }
+}
+
+/// Parses the files without running them, printing a `file:line:col: message` line for each
+/// one with a syntax error. Compiled files are skipped. Returns the number of files with errors.
+pub fn check(lua: &Lua, paths: &[PathBuf]) -> Result<usize> {
+    let mut failed = 0;
+    for path in paths {
+        let contents = std::fs::read(path).map_err(rlua::Error::external)?;
+        if compile::is_bytecode(&contents) {
+            continue;
+        }
+        let source = String::from_utf8_lossy(&contents);
+        if let Some(diagnostic) = syntax_error(lua, path, &source)? {
+            print_diagnostics(path, &[diagnostic]);
+            failed += 1;
+        }
+    }
+    Ok(failed)
+}
@@ -14,110 +14,668 @@
    You should have received a copy of the GNU Affero General Public License
    along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
+mod archive;
+mod bench;
+mod binary;
+mod bundle;
+mod cbor;
+mod cli;
+mod clipboard;
+mod commands;
+mod compile;
+mod completion;
+mod compress;
+mod config;
+mod crypto;
+mod csv;
+mod encode;
+mod env;
+mod error;
+mod formatter;
+mod fs;
+mod hash;
+mod help;
+mod hmac;
+mod ini;
+mod install;
+mod json;
+mod jwt;
+mod lexer;
+mod lint;
+mod msgpack;
+mod output;
+mod pager;
+mod password;
+mod path;
+mod persist;
+mod proc;
+mod profiler;
+mod project;
+mod proto;
+mod pty;
+mod random;
+mod remote;
+mod repl;
+mod report;
+mod require;
+mod sandbox;
+mod schedule;
+mod secrets;
+mod signal;
+mod standalone;
+mod strict;
+mod sys;
+mod testing;
+mod time;
+mod timeout;
+mod timer;
+mod tls;
+mod toml;
+mod traceback;
+mod transcript;
+mod watch;
+mod xml;
+mod yaml;
+
+use clap::{CommandFactory, FromArgMatches};
 use colored::Colorize;
 use cumulus::{logger, util};
+use help::FunctionDoc;
 // todo: find out how to check for windows early in the compilation since colored::control
 // apparently doesn't exist on non-windows platforms
-use rlua::{Function, Lua, Result, Table, UserDataMethods, Variadic};
+use rlua::{Function, HookTriggers, Lua, MultiValue, Result, Table, UserDataMethods, Variadic};
 use std::collections::HashMap;
-use std::io::{Read, Write};
+use std::io::{IsTerminal, Read};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 const LUA_VERSION: &str = "Lua 5.4.3";
 const LUA_COPYRIGHT: &str = "  Copyright (C) 1994-2021 Lua.org, PUC-Rio";
 const LUA_AUTHORS: &str = "R. Ierusalimschy, L. H. de Figueiredo, W. Celes";
 
+// Number of VM instructions between checks for a pending interrupt
+const INTERRUPT_CHECK_INTERVAL: u32 = 1000;
+
+// Set by the Ctrl+C handler, consumed by the Lua debug hook
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+const INTERRUPTED_MESSAGE: &str = "interrupted";
+// Exit code for scripts stopped with Ctrl+C, as shells report for SIGINT
+const INTERRUPTED_EXIT_CODE: i32 = 130;
+
+// The error os.exit unwinds the stack with, told apart from others by its type so no error
+// message can pass for it
+#[derive(Debug)]
+struct ExitRequest(i32);
+
+impl std::fmt::Display for ExitRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "exit with code {}", self.0)
+    }
+}
+
+impl std::error::Error for ExitRequest {}
+
+// pcall, xpcall and coroutine.resume raise os.exit's error again instead of catching it, so
+// scripts can't carry on after exiting. xpcall's message handler isn't called for it either
+const EXIT_PASSTHROUGH_SOURCE: &str = r#"
+local pcall, xpcall, resume, is_exit, error = ...
+
+local function rethrow(ok, ...)
+    if not ok and is_exit((...)) then
+        error((...), 0)
+    end
+    return ok, ...
+end
+
+local function passing_pcall(f, ...)
+    return rethrow(pcall(f, ...))
+end
+
+local function passing_xpcall(f, handler, ...)
+    return rethrow(xpcall(f, function(err)
+        if is_exit(err) then
+            return err
+        end
+        return handler(err)
+    end, ...))
+end
+
+local function passing_resume(co, ...)
+    return rethrow(resume(co, ...))
+end
+
+return passing_pcall, passing_xpcall, passing_resume
+"#;
+
+// Name scripts read from stdin are reported with
+const STDIN_SCRIPT_NAME: &str = "stdin";
+
+// Name code given with -e is loaded with, as in the standalone lua interpreter
+const COMMAND_LINE_CHUNK_NAME: &str = "=(command line)";
+
+// Startup file loaded from the user's config directory
+const USER_RC_FILE: &str = "init.lua";
+// Startup file loaded from the current directory, after the user's one
+const PROJECT_RC_FILE: &str = ".rluaterm.lua";
+
 fn main() -> Result<()> {
     logger::open_log_file_for_saving(None).unwrap();
 
-    util::attach_interrupt_handler(Some(|| {}));
+    util::attach_interrupt_handler(Some(|| INTERRUPTED.store(true, Ordering::SeqCst)));
 
     colored::control::set_virtual_terminal(true).unwrap();
 
-    let args = std::env::args().collect::<Vec<String>>();
-    let args_length = args.len();
+    let mut raw_args = std::env::args().collect::<Vec<String>>();
+    // Executables made with `rluaterm build` only run their script, with every argument
+    if let Some(script) = standalone::embedded_script() {
+        let lua = create_lua(None)?;
+        set_arg_table(&lua, &raw_args, 0)?;
+        let exit_code = run_chunk(&lua, &script, &raw_args[0], &raw_args[0], &raw_args[1..])?;
+        std::process::exit(exit_code);
+    }
+    let matches = cli::Cli::command().get_matches();
+    let cli = cli::Cli::from_arg_matches(&matches).unwrap_or_else(|error| error.exit());
+    // -e and -l run in the order they were given, like in the standalone lua interpreter
+    let preloads = cli::preloads(&matches);
 
-    let lua = Lua::new();
-    load_lua_log_library(&lua)?;
-    load_color_library(&lua)?;
-    load_http_library(&lua)?;
-    load_memory_library(&lua)?;
-    // if 1st argument is a lua file, run it
-    if args_length > 1 {
-        let file_path = &args[1];
-        if file_path.ends_with(".lua") {
-            // If the file does not exist, exit
-            if !std::path::Path::new(file_path).exists() {
-                logger::error(&format!("File {} does not exist", file_path));
+    // Flags take precedence over the config files
+    let mut config = config::load();
+    if cli.color.is_some() {
+        config.color = cli.color;
+    }
+    if cli.log_level.is_some() {
+        config.log_level = cli.log_level;
+    }
+    config::install(config);
+
+    if cli.version {
+        print_version(&create_lua(None)?)?;
+        return Ok(());
+    }
+
+    let run = match cli.command {
+        None => cli.run,
+        Some(cli::Command::Run(run)) => run,
+        Some(cli::Command::Schedule(run)) => {
+            schedule::run_after_script();
+            run
+        }
+        Some(cli::Command::Repl(session)) => cli::RunArgs {
+            session,
+            interactive: true,
+            ..Default::default()
+        },
+        // `rluaterm attach <address>` is a client for another process and needs no state of
+        // its own
+        Some(cli::Command::Attach { address, token }) => {
+            if let Err(error) = remote::attach(&address, token) {
+                logger::error(&format!("Failed to attach to {} [{}]", address, error));
                 std::process::exit(1);
             }
-
-            lua.context(|lua_ctx| {
-                // Open the file
-                let file_stream = std::fs::File::open(file_path).unwrap();
-                // Read the file
-                let mut reader = std::io::BufReader::new(file_stream);
-                // Read the file into a string
-                let mut contents = String::new();
-                reader.read_to_string(&mut contents).unwrap();
-                let load_result = lua_ctx.load(&contents).exec();
-                if load_result.is_err() {
+            return Ok(());
+        }
+        // `rluaterm completions <shell>` prints a completion script generated from the options
+        Some(cli::Command::Completions { shell }) => {
+            clap_complete::generate(
+                shell,
+                &mut cli::Cli::command(),
+                "rluaterm",
+                &mut std::io::stdout(),
+            );
+            return Ok(());
+        }
+        // `rluaterm install [packages]` installs into lua_modules in the current directory, the
+        // project's locked dependencies when no packages are given
+        Some(cli::Command::Install { packages }) => {
+            let root = std::path::Path::new(".");
+            let result = if packages.is_empty() {
+                project::install_dependencies(root)
+            } else {
+                let specs = packages
+                    .iter()
+                    .map(|package| install::Spec::parse(package))
+                    .collect::<Vec<_>>();
+                install::install(&specs, root, Vec::new())
+            };
+            match result {
+                Ok(installed) => {
+                    for package in installed {
+                        logger::info(&format!(
+                            "Installed {} {} from {}",
+                            package.name, package.version, package.source
+                        ));
+                    }
+                }
+                Err(error) => {
+                    logger::error(&format!("Failed to install {}", error));
+                    std::process::exit(1);
+                }
+            }
+            return Ok(());
+        }
+        // `rluaterm test [paths]` runs the tests found under the paths, or the current directory
+        Some(cli::Command::Test {
+            paths,
+            filter,
+            jobs,
+        }) => {
+            let paths = if paths.is_empty() {
+                vec![".".to_string()]
+            } else {
+                paths
+            };
+            let files = testing::test_files(&paths);
+            if files.is_empty() {
+                logger::error(
+                    "No test files found, tests are named name_test.lua or test_name.lua",
+                );
+                std::process::exit(1);
+            }
+            let jobs = jobs.unwrap_or_else(|| {
+                std::thread::available_parallelism().map_or(1, |jobs| jobs.get())
+            });
+            std::process::exit(testing::run_tests(&files, filter.as_deref(), jobs));
+        }
+        // `rluaterm compile <script>` only compiles the script and never runs it
+        Some(cli::Command::Compile {
+            script,
+            output,
+            strip,
+        }) => {
+            let output = output.unwrap_or_else(|| compile::default_output(&script));
+            match compile::compile(&script, &output, strip) {
+                Ok(true) => {}
+                Ok(false) => std::process::exit(1),
+                Err(error) => {
+                    logger::error(&format!(
+                        "Failed to compile {} [{}]",
+                        script.display(),
+                        error
+                    ));
+                    std::process::exit(1);
+                }
+            }
+            logger::info(&format!(
+                "Compiled {} to {}",
+                script.display(),
+                output.display()
+            ));
+            return Ok(());
+        }
+        // `rluaterm check <files>` looks for syntax errors without running anything
+        Some(cli::Command::Check { paths }) => {
+            let files = lint::lua_files(&paths);
+            match lint::check(&Lua::new(), &files) {
+                Ok(0) => {}
+                Ok(failed) => {
+                    logger::error(&format!(
+                        "{} of {} files have syntax errors",
+                        failed,
+                        files.len()
+                    ));
+                    std::process::exit(1);
+                }
+                Err(error) => {
+                    logger::error(&format!("Failed to check [{}]", error));
+                    std::process::exit(1);
+                }
+            }
+            return Ok(());
+        }
+        // `rluaterm lint <files>` checks scripts without running them
+        Some(cli::Command::Lint { paths }) => {
+            let files = lint::lua_files(&paths);
+            match lint::lint(&create_lua(None)?, &files) {
+                Ok(0) => {}
+                Ok(problems) => {
+                    let plural = if problems == 1 { "" } else { "s" };
+                    logger::error(&format!("Found {} problem{}", problems, plural));
+                    std::process::exit(1);
+                }
+                Err(error) => {
+                    logger::error(&format!("Failed to lint [{}]", error));
+                    std::process::exit(1);
+                }
+            }
+            return Ok(());
+        }
+        // `rluaterm fmt <files>` formats scripts in place
+        Some(cli::Command::Fmt { check, paths }) => {
+            let files = lint::lua_files(&paths);
+            let (changed, failed) = match formatter::format_files(&Lua::new(), &files, check) {
+                Ok(result) => result,
+                Err(error) => {
+                    logger::error(&format!("Failed to format [{}]", error));
+                    std::process::exit(1);
+                }
+            };
+            if check {
+                for path in &changed {
+                    println!("{}", path.display());
+                }
+                if !changed.is_empty() {
                     logger::error(&format!(
-                        "Failed to load file: {} [{}]",
-                        file_path,
-                        load_result.unwrap_err()
+                        "{} of {} files need formatting",
+                        changed.len(),
+                        files.len()
                     ));
                 }
-                // Check if the file has a main function
-                // find in contents the string "function main"
-                if contents.contains("function main") {
-                    // Run the main function
-                    let main_result = lua_ctx
-                        .globals()
-                        .get::<_, Function>("main")?
-                        .call::<_, ()>(());
-                    if main_result.is_err() {
+            } else {
+                logger::info(&format!(
+                    "Formatted {} of {} files",
+                    changed.len(),
+                    files.len()
+                ));
+            }
+            if failed || (check && !changed.is_empty()) {
+                std::process::exit(1);
+            }
+            return Ok(());
+        }
+        // `rluaterm build <script>` makes an executable out of the script
+        Some(cli::Command::Build { script, output }) => {
+            let output = output.unwrap_or_else(|| standalone::default_output(&script));
+            if output == script {
+                logger::error(
+                    "The executable would replace the script, choose another name with -o",
+                );
+                std::process::exit(1);
+            }
+            match standalone::build(&script, &output) {
+                Ok(true) => logger::info(&format!(
+                    "Built {} from {}",
+                    output.display(),
+                    script.display()
+                )),
+                Ok(false) => std::process::exit(1),
+                Err(error) => {
+                    logger::error(&format!("Failed to build {} [{}]", script.display(), error));
+                    std::process::exit(1);
+                }
+            }
+            return Ok(());
+        }
+        // `rluaterm bundle <script>` packs the script and its modules into one file
+        Some(cli::Command::Bundle { script, output }) => {
+            let output = output.unwrap_or_else(|| bundle::default_output(&script));
+            match bundle::bundle(&script, &output) {
+                Ok(modules) => logger::info(&format!(
+                    "Bundled {} with {} modules into {}",
+                    script.display(),
+                    modules,
+                    output.display()
+                )),
+                Err(error) => {
+                    logger::error(&format!(
+                        "Failed to bundle {} [{}]",
+                        script.display(),
+                        error
+                    ));
+                    std::process::exit(1);
+                }
+            }
+            return Ok(());
+        }
+    };
+
+    let session = run.session;
+    let interactive = run.interactive;
+    let listen_address = session.listen;
+    let extra_module_dirs = project::extra_module_dirs(&session.path);
+    // An allowlist implies the sandbox
+    let allowed_hosts = session.allow_net;
+    let allowed_dirs = session.allow_read;
+    let sandboxed = session.sandbox || !allowed_hosts.is_empty() || !allowed_dirs.is_empty();
+
+    // Everything after the script is passed to it, options included
+    let (script, mut script_args) = match run.script.split_first() {
+        Some((script, script_args)) => (Some(script.as_str()), script_args.to_vec()),
+        None => (None, Vec::new()),
+    };
+    if let Some(option) = script.filter(|script| script.starts_with('-') && *script != "-") {
+        cli::Cli::command()
+            .error(
+                clap::error::ErrorKind::UnknownArgument,
+                format!("unexpected argument '{}' found", option),
+            )
+            .exit();
+    }
+    // Where the script is among the arguments, for the `arg` table
+    let script_index = script.map(|_| raw_args.len() - script_args.len() - 1);
+
+    // Start listening before the script runs, so it can be inspected while it's running
+    if let Some(address) = &listen_address {
+        if let Err(error) = remote::listen(address, session.token) {
+            logger::error(&format!("Failed to listen on {} [{}]", address, error));
+            std::process::exit(1);
+        }
+    }
+    // A script path of `-`, or piped input without anything else to run, reads the script
+    // from stdin
+    let read_stdin = script == Some("-")
+        || (script.is_none()
+            && preloads.is_empty()
+            && !interactive
+            && listen_address.is_none()
+            && !std::io::stdin().is_terminal());
+    let mut file_path = if read_stdin {
+        STDIN_SCRIPT_NAME
+    } else {
+        script.unwrap_or("")
+    };
+    // A name that isn't a file can be one of the scripts of the project in the current
+    // directory, which runs with the arguments it comes with before the ones given
+    let mut project = None;
+    if !read_stdin && script.is_some() && !std::path::Path::new(file_path).exists() {
+        match project::open_script(std::path::Path::new("."), file_path) {
+            Ok(Some((script_project, args))) => {
+                let index = script_index.unwrap_or(0) + 1;
+                raw_args.splice(index..index, args.iter().cloned());
+                script_args.splice(0..0, args);
+                project = Some(script_project);
+            }
+            Ok(None) => {
+                logger::error(&format!("File {} does not exist", file_path));
+                std::process::exit(1);
+            }
+            Err(error) => {
+                logger::error(&format!("Failed to run script {} [{}]", file_path, error));
+                std::process::exit(1);
+            }
+        }
+    }
+    // A directory is run as a project, starting at its init.lua or main.lua
+    if !read_stdin && std::path::Path::new(file_path).is_dir() {
+        project = Some(
+            project::open(std::path::Path::new(file_path)).unwrap_or_else(|error| {
+                logger::error(&format!("Failed to run project {} [{}]", file_path, error));
+                std::process::exit(1);
+            }),
+        );
+    }
+    // The project's options apply unless overridden on the command line
+    let strict = session.strict || project.as_ref().is_some_and(|project| project.strict);
+    timeout::set_timeout(
+        session
+            .timeout
+            .or(project.as_ref().and_then(|project| project.timeout)),
+    );
+    let entry = project
+        .as_ref()
+        .map(|project| project.entry.to_string_lossy().into_owned());
+    if let Some(entry) = &entry {
+        file_path = entry;
+    }
+    let has_script = read_stdin || script.is_some();
+
+    // Every interpreter, including the fresh one for each rerun in watch mode, is set up the same
+    let setup = || -> Result<Lua> {
+        let mut policy = sandboxed.then(|| sandbox::Policy {
+            allowed_hosts: allowed_hosts.clone(),
+            allowed_dirs: allowed_dirs.iter().map(PathBuf::from).collect(),
+        });
+        // The project's own modules come first
+        let mut module_dirs = project
+            .as_ref()
+            .map_or(Vec::new(), |project| project.module_dirs.clone());
+        module_dirs.extend(extra_module_dirs.iter().cloned());
+        // The sandbox only lets modules be required from the directories it can read
+        if let Some(policy) = &mut policy {
+            policy.allowed_dirs.extend(module_dirs.iter().cloned());
+            policy
+                .allowed_dirs
+                .extend(project.as_ref().map(|project| project.root.clone()));
+        }
+        // Modules next to the script are found wherever rluaterm is started from, but only
+        // when the sandbox lets them be read
+        let mut searcher_dirs = if has_script && !read_stdin {
+            let root = project.as_ref().map(|project| project.root.as_path());
+            require::module_dirs(std::path::Path::new(file_path), root)
+        } else {
+            Vec::new()
+        };
+        if let Some(policy) = &policy {
+            searcher_dirs.retain(|dir| policy.allows_dir(dir));
+        }
+        let sandboxed = policy.is_some();
+        let lua = create_lua(policy)?;
+        require::install_searcher(&lua, &searcher_dirs)?;
+        if !sandboxed {
+            project::add_module_dirs(&lua, &module_dirs)?;
+        }
+        run_startup(&lua, &raw_args, script_index)?;
+        if strict {
+            strict::enable(&lua)?;
+        }
+        Ok(lua)
+    };
+
+    if run.watch {
+        if read_stdin || script.is_none() {
+            logger::error("--watch needs a script file");
+            std::process::exit(1);
+        }
+        // Every run starts from a fresh state, so nothing is left over from the previous one
+        return watch::watch(std::path::Path::new(file_path), || {
+            let lua = setup()?;
+            let exit_code = match run_preloads(&lua, &preloads)? {
+                Some(exit_code) => exit_code,
+                None => run_script(&lua, file_path, read_stdin, &script_args)?,
+            };
+            Ok((lua, exit_code))
+        });
+    }
+
+    if run.output_format.as_deref() == Some("json") {
+        output::enable_json();
+    }
+    let lua = setup()?;
+    if let Some(exit_code) = run_preloads(&lua, &preloads)? {
+        output::finish(exit_code);
+        std::process::exit(exit_code);
+    }
+    let mut exit_code = 0;
+    if has_script {
+        let profile = run.profile || run.profile_output.is_some();
+        if profile {
+            profiler::start(&lua)?;
+        }
+        exit_code = run_script(&lua, file_path, read_stdin, &script_args)?;
+        if profile {
+            if let Some((report, folded)) = profiler::stop(&lua)? {
+                // The report goes to stderr, to keep it apart from what the script prints
+                eprintln!("{}", report);
+                if let Some(path) = &run.profile_output {
+                    if let Err(error) = std::fs::write(path, folded + "\n") {
                         logger::error(&format!(
-                            "Failed to run main function in file: {} [{}]",
-                            file_path,
-                            main_result.unwrap_err()
+                            "Failed to write profile to {} [{}]",
+                            path.display(),
+                            error
                         ));
                     }
                 }
-                Ok(())
-            })?;
+            }
         }
     }
 
-    if args_length == 1 {
+    // Without a script or code to run, and with -i after them, start the REPL
+    let start_repl =
+        interactive || (!has_script && preloads.is_empty() && listen_address.is_none());
+    if start_repl {
         println!(
             "{}",
             format!("{}  {}\n{}", LUA_VERSION, LUA_COPYRIGHT, LUA_AUTHORS)
                 .cyan()
                 .bold()
         );
-        lua_interpret_loop(&lua)?;
+        exit_code = repl::lua_interpret_loop(&lua)?;
+    } else if listen_address.is_some() {
+        remote::serve(&lua);
     }
 
+    output::finish(exit_code);
+    if exit_code != 0 {
+        std::process::exit(exit_code);
+    }
     Ok(())
 }
 
+// The headers in `http.headers`, which every request sends
+fn request_headers(lua_ctx: rlua::Context) -> Result<Vec<(String, String)>> {
+    let headers = lua_ctx
+        .globals()
+        .get::<_, Option<Table>>("http")?
+        .map(|http| http.get::<_, Option<Table>>("headers"))
+        .transpose()?
+        .flatten();
+    match headers {
+        Some(headers) => headers.pairs::<String, String>().collect(),
+        None => Ok(Vec::new()),
+    }
+}
+
+async fn send_get(
+    url: &str,
+    headers: &[(String, String)],
+) -> std::result::Result<reqwest::Response, reqwest::Error> {
+    let mut request = reqwest::Client::new().get(url);
+    for (name, value) in headers {
+        request = request.header(name, value);
+    }
+    request.send().await
+}
+
 #[tokio::main]
-async fn get_http(url: &str) -> reqwest::Result<HashMap<String, String>> {
-    let resp = reqwest::get(url).await?;
+async fn get_http(
+    url: &str,
+    headers: &[(String, String)],
+) -> std::result::Result<HashMap<String, String>, error::Error> {
+    let http_error = |source| error::Error::Http {
+        url: url.to_string(),
+        source,
+    };
+    let resp = send_get(url, headers).await.map_err(http_error)?;
     let mut data = HashMap::new();
     if !resp.status().is_success() {
         data.insert("error".to_string(), resp.status().to_string());
         return Ok(data);
     }
     data.insert("status".to_string(), resp.status().to_string());
-    data.insert("text".to_string(), resp.text().await?);
+    data.insert("text".to_string(), resp.text().await.map_err(http_error)?);
 
     Ok(data)
 }
 
 #[tokio::main]
-async fn get_http_json(url: &str) -> reqwest::Result<HashMap<String, String>> {
-    let resp = reqwest::get(url).await?;
+async fn get_http_json(
+    url: &str,
+    headers: &[(String, String)],
+) -> std::result::Result<HashMap<String, String>, error::Error> {
+    let http_error = |source| error::Error::Http {
+        url: url.to_string(),
+        source,
+    };
+    let resp = send_get(url, headers).await.map_err(http_error)?;
     let mut data = HashMap::new();
     if !resp.status().is_success() {
         data.insert("error".to_string(), resp.status().to_string());
@@ -126,14 +684,12 @@ async fn get_http_json(url: &str) -> reqwest::Result<HashMap<String, String>> {
 
     // Ensure the response is valid json
 
-    if !resp
+    let is_json = resp
         .headers()
         .get("content-type")
-        .unwrap()
-        .to_str()
-        .unwrap()
-        .contains("application/json")
-    {
+        .and_then(|content_type| content_type.to_str().ok())
+        .is_some_and(|content_type| content_type.contains("application/json"));
+    if !is_json {
         data.insert(
             "error".to_string(),
             "Response is not valid json".to_string(),
@@ -141,11 +697,37 @@ async fn get_http_json(url: &str) -> reqwest::Result<HashMap<String, String>> {
         return Ok(data);
     }
 
-    data = resp.json::<HashMap<String, String>>().await?;
+    data = resp
+        .json::<HashMap<String, String>>()
+        .await
+        .map_err(|_| error::Error::NotJson {
+            url: url.to_string(),
+        })?;
 
     Ok(data)
 }
 
+const HTTP_DOCS: &[FunctionDoc] = &[
+    FunctionDoc {
+        name: "get",
+        signature: "http.get(url) -> table",
+        description: "Performs a GET request and returns a table with `status` and `text`, or `error` on a non-success status. Raises an error when the request can't be made.",
+        example: "local r = http.get(\"https://example.com\")\nlog.info(r.status)",
+    },
+    FunctionDoc {
+        name: "json",
+        signature: "http.json(url) -> table",
+        description: "Performs a GET request and decodes a flat JSON object response into a table. Raises an error when the request can't be made or the response isn't a flat JSON object.",
+        example: "local data = http.json(\"https://httpbin.org/json\")",
+    },
+    FunctionDoc {
+        name: "set_header",
+        signature: "http.set_header(key, value)",
+        description: "Sets a header in the `http.headers` table, which http.get and http.json send with every request.",
+        example: "http.set_header(\"Accept\", \"text/plain\")",
+    },
+];
+
 fn load_http_library(lua: &Lua) -> Result<()> {
     lua.context(|lua_ctx| {
         let http_module = lua_ctx.create_table()?;
@@ -157,9 +739,9 @@ fn load_http_library(lua: &Lua) -> Result<()> {
         http_module.set(
             "get",
             lua_ctx.create_function(|ctx, url: String| {
-                let response = get_http(&url);
+                let headers = request_headers(ctx)?;
+                let response_data = get_http(&url, &headers).map_err(rlua::Error::external)?;
                 let response_table = ctx.create_table()?;
-                let response_data = response.unwrap();
                 for (key, value) in response_data {
                     response_table.set(key, value)?;
                 }
@@ -170,9 +752,9 @@ fn load_http_library(lua: &Lua) -> Result<()> {
         http_module.set(
             "json",
             lua_ctx.create_function(|ctx, url: String| {
-                let response = get_http_json(&url);
+                let headers = request_headers(ctx)?;
+                let response_data = get_http_json(&url, &headers).map_err(rlua::Error::external)?;
                 let response_table = ctx.create_table()?;
-                let response_data = response.unwrap();
                 for (key, value) in response_data {
                     response_table.set(key, value)?;
                 }
@@ -190,6 +772,7 @@ fn load_http_library(lua: &Lua) -> Result<()> {
             })?,
         )?;
 
+        help::register_module_docs(lua_ctx, "http", "HTTP requests", &http_module, HTTP_DOCS)?;
         lua_ctx.globals().set("http", http_module)?;
 
         Ok(())
@@ -197,6 +780,87 @@ fn load_http_library(lua: &Lua) -> Result<()> {
     Ok(())
 }
 
+const MEMORY_DOCS: &[FunctionDoc] = &[
+    FunctionDoc {
+        name: "alloc",
+        signature: "memory.alloc() -> pointer",
+        description: "Allocates an 8 byte buffer and returns its address.",
+        example: "local p = memory.alloc()",
+    },
+    FunctionDoc {
+        name: "free",
+        signature: "memory.free(pointer)",
+        description: "Frees memory previously allocated with memory.alloc.",
+        example: "memory.free(p)",
+    },
+    FunctionDoc {
+        name: "read",
+        signature: "memory.read(pointer) -> bytes",
+        description: "Reads the 8 byte buffer at the pointer.",
+        example: "local bytes = memory.read(p)",
+    },
+    FunctionDoc {
+        name: "write",
+        signature: "memory.write(pointer, bytes)",
+        description: "Writes 8 bytes to the buffer at the pointer.",
+        example: "memory.write(p, {1, 2, 3, 4, 5, 6, 7, 8})",
+    },
+    FunctionDoc {
+        name: "allocate_int",
+        signature: "memory.allocate_int() -> pointer",
+        description: "Allocates an integer and returns its address.",
+        example: "local p = memory.allocate_int()",
+    },
+    FunctionDoc {
+        name: "read_int",
+        signature: "memory.read_int(pointer) -> integer",
+        description: "Reads the integer at the pointer.",
+        example: "local n = memory.read_int(p)",
+    },
+    FunctionDoc {
+        name: "write_int",
+        signature: "memory.write_int(pointer, n)",
+        description: "Writes an integer to the pointer.",
+        example: "memory.write_int(p, 42)",
+    },
+    FunctionDoc {
+        name: "allocate_float",
+        signature: "memory.allocate_float() -> pointer",
+        description: "Allocates a float and returns its address.",
+        example: "local p = memory.allocate_float()",
+    },
+    FunctionDoc {
+        name: "read_float",
+        signature: "memory.read_float(pointer) -> number",
+        description: "Reads the float at the pointer.",
+        example: "local f = memory.read_float(p)",
+    },
+    FunctionDoc {
+        name: "write_float",
+        signature: "memory.write_float(pointer, f)",
+        description: "Writes a float to the pointer.",
+        example: "memory.write_float(p, 3.14)",
+    },
+    FunctionDoc {
+        name: "allocate_string",
+        signature: "memory.allocate_string() -> pointer",
+        description: "Allocates an empty string and returns its address.",
+        example: "local p = memory.allocate_string()",
+    },
+    FunctionDoc {
+        name: "read_string",
+        signature: "memory.read_string(pointer) -> string",
+        description: "Reads the string at the pointer.",
+        example: "local s = memory.read_string(p)",
+    },
+    FunctionDoc {
+        name: "write_string",
+        signature: "memory.write_string(pointer, s)",
+        description: "Writes a string to the pointer.",
+        example: "memory.write_string(p, \"hello\")",
+    },
+];
+
 fn load_memory_library(lua: &Lua) -> Result<()> {
     lua.context(|lua_ctx| {
         let memory_module = lua_ctx.create_table()?;
@@ -340,12 +1004,94 @@ fn load_memory_library(lua: &Lua) -> Result<()> {
             })?,
         )?;
 
+        help::register_module_docs(
+            lua_ctx,
+            "memory",
+            "Raw memory allocation",
+            &memory_module,
+            MEMORY_DOCS,
+        )?;
         lua_ctx.globals().set("memory", memory_module)?;
         Ok(())
     })?;
     Ok(())
 }
 
+const COLOR_DOCS: &[FunctionDoc] = &[
+    FunctionDoc {
+        name: "red",
+        signature: "color.red(...) -> string",
+        description: "Colors the given strings red.",
+        example: "log.info(color.red(\"This is red!\"))",
+    },
+    FunctionDoc {
+        name: "green",
+        signature: "color.green(...) -> string",
+        description: "Colors the given strings green.",
+        example: "log.info(color.green(\"This is green!\"))",
+    },
+    FunctionDoc {
+        name: "yellow",
+        signature: "color.yellow(...) -> string",
+        description: "Colors the given strings yellow.",
+        example: "log.info(color.yellow(\"This is yellow!\"))",
+    },
+    FunctionDoc {
+        name: "blue",
+        signature: "color.blue(...) -> string",
+        description: "Colors the given strings blue.",
+        example: "log.info(color.blue(\"This is blue!\"))",
+    },
+    FunctionDoc {
+        name: "magenta",
+        signature: "color.magenta(...) -> string",
+        description: "Colors the given strings magenta.",
+        example: "log.info(color.magenta(\"This is magenta!\"))",
+    },
+    FunctionDoc {
+        name: "cyan",
+        signature: "color.cyan(...) -> string",
+        description: "Colors the given strings cyan.",
+        example: "log.info(color.cyan(\"This is cyan!\"))",
+    },
+    FunctionDoc {
+        name: "white",
+        signature: "color.white(...) -> string",
+        description: "Colors the given strings white.",
+        example: "log.info(color.white(\"This is white!\"))",
+    },
+    FunctionDoc {
+        name: "black",
+        signature: "color.black(...) -> string",
+        description: "Colors the given strings black.",
+        example: "log.info(color.black(\"This is black!\"))",
+    },
+    FunctionDoc {
+        name: "bold",
+        signature: "color.bold(...) -> string",
+        description: "Bolds the given strings.",
+        example: "log.info(color.bold(\"This is bold!\"))",
+    },
+    FunctionDoc {
+        name: "italic",
+        signature: "color.italic(...) -> string",
+        description: "Italicizes the given strings.",
+        example: "log.info(color.italic(\"This is italic!\"))",
+    },
+    FunctionDoc {
+        name: "underline",
+        signature: "color.underline(...) -> string",
+        description: "Underlines the given strings.",
+        example: "log.info(color.underline(\"This is underlined!\"))",
+    },
+    FunctionDoc {
+        name: "reverse",
+        signature: "color.reverse(...) -> string",
+        description: "Reverses the colors of the given strings.",
+        example: "log.info(color.reverse(\"This is reversed!\"))",
+    },
+];
+
 fn load_color_library(lua: &Lua) -> Result<()> {
     lua.context(|lua_ctx| {
         let color_module = lua_ctx.create_table()?;
@@ -506,36 +1252,80 @@ fn load_color_library(lua: &Lua) -> Result<()> {
             })?,
         )?;
 
+        help::register_module_docs(
+            lua_ctx,
+            "color",
+            "Terminal text colors and styles",
+            &color_module,
+            COLOR_DOCS,
+        )?;
         lua_ctx.globals().set("color", color_module)?;
         Ok(())
     })?;
     Ok(())
 }
 
+const LOG_DOCS: &[FunctionDoc] = &[
+    FunctionDoc {
+        name: "info",
+        signature: "log.info(...)",
+        description: "Logs the given strings at info level.",
+        example: "log.info(\"Hello from Lua!\")",
+    },
+    FunctionDoc {
+        name: "warn",
+        signature: "log.warn(...)",
+        description: "Logs the given strings at warn level.",
+        example: "log.warn(\"Hello from Lua!\")",
+    },
+    FunctionDoc {
+        name: "error",
+        signature: "log.error(...)",
+        description: "Logs the given strings at error level.",
+        example: "log.error(\"Hello from Lua!\")",
+    },
+];
+
 fn load_lua_log_library(lua: &Lua) -> Result<()> {
     lua.context(|lua_ctx| {
         let log_lib = lua_ctx.create_table()?;
         log_lib.set(
             "info",
             lua_ctx.create_function(|_, args: Variadic<String>| {
-                logger::info(format!("{} {}", "[LUA]".cyan().bold(), args.join(" ")).as_str());
+                if !config::log_enabled("info") {
+                    return Ok(());
+                }
+                let message = format!("{} {}", "[LUA]".cyan().bold(), args.join(" "));
+                logger::info(message.as_str());
+                transcript::record_output(&message);
                 Ok(())
             })?,
         )?;
         log_lib.set(
             "warn",
             lua_ctx.create_function(|_, args: Variadic<String>| {
-                logger::warn(format!("{} {}", "[LUA]".cyan().bold(), args.join(" ")).as_str());
+                if !config::log_enabled("warn") {
+                    return Ok(());
+                }
+                let message = format!("{} {}", "[LUA]".cyan().bold(), args.join(" "));
+                logger::warn(message.as_str());
+                transcript::record_output(&message);
                 Ok(())
             })?,
         )?;
         log_lib.set(
             "error",
             lua_ctx.create_function(|_, args: Variadic<String>| {
-                logger::error(format!("{} {}", "[LUA]".cyan().bold(), args.join(" ")).as_str());
+                if !config::log_enabled("error") {
+                    return Ok(());
+                }
+                let message = format!("{} {}", "[LUA]".cyan().bold(), args.join(" "));
+                logger::error(message.as_str());
+                transcript::record_output(&message);
                 Ok(())
             })?,
         )?;
+        help::register_module_docs(lua_ctx, "log", "Logging", &log_lib, LOG_DOCS)?;
         lua_ctx.globals().set("log", log_lib)?;
         Ok(())
     })
@@ -550,44 +1340,468 @@ fn load_util_library(lua: &Lua) -> Result<()> {
     })
 }
 
-fn lua_interpret_loop(lua: &Lua) -> Result<()> {
-    // Create a loop with a prompt
-    // Handle interrupt on the loop
-    loop {
-        // Print the prompt
-        print!("> ");
-        // Flush the output buffer
-        std::io::stdout().flush().unwrap();
-        // Read the input
-        let mut input = String::new();
-        std::io::stdin().read_line(&mut input).unwrap();
-        // Remove the newline character
-        input = input.trim().to_string();
-        // If the input is empty, continue
-        if input.is_empty() {
-            continue;
+fn attach_interrupt_hook(lua: &Lua) {
+    // Abort the running chunk when Ctrl+C has been pressed since the last check, or when it has
+    // run for longer than the timeout
+    lua.set_hook(
+        HookTriggers {
+            every_nth_instruction: Some(INTERRUPT_CHECK_INTERVAL),
+            ..Default::default()
+        },
+        |lua_ctx, _| check_interrupt(lua_ctx),
+    );
+}
+
+// What the interrupt hook does every INTERRUPT_CHECK_INTERVAL instructions, also called by the
+// profiler's hook while it replaces this one
+fn check_interrupt(lua_ctx: rlua::Context) -> Result<()> {
+    // Ctrl+C interrupts the running chunk unless the script handles it
+    if INTERRUPTED.swap(false, Ordering::SeqCst) && !signal::handle_interrupt(lua_ctx)? {
+        return Err(rlua::Error::RuntimeError(INTERRUPTED_MESSAGE.to_string()));
+    }
+    signal::dispatch_pending(lua_ctx)?;
+    // Abort the running chunk once it has used up its time
+    if let Some(timeout) = timeout::expired() {
+        return Err(rlua::Error::RuntimeError(format!(
+            "timed out after {:?}",
+            timeout
+        )));
+    }
+    // Let attached remote clients inspect the running script
+    remote::serve_pending(lua_ctx);
+    Ok(())
+}
+
+// os.exit unwinds the stack with an error rather than exiting on the spot, so whoever ran the
+// chunk can save the REPL history and transcript before exiting. Loaded after the traceback
+// handler, which looks for the real xpcall on the stack
+fn replace_os_exit(lua: &Lua) -> Result<()> {
+    lua.context(|lua_ctx| {
+        let globals = lua_ctx.globals();
+        let coroutine = globals.get::<_, Table>("coroutine")?;
+        let is_exit = lua_ctx.create_function(|_, value: rlua::Value| {
+            Ok(matches!(value, rlua::Value::Error(error) if requested_exit_code(&error).is_some()))
+        })?;
+        let (pcall, xpcall, resume) = lua_ctx
+            .load(EXIT_PASSTHROUGH_SOURCE)
+            .set_name("=os.exit")?
+            .call::<_, (Function, Function, Function)>((
+                globals.get::<_, Function>("pcall")?,
+                globals.get::<_, Function>("xpcall")?,
+                coroutine.get::<_, Function>("resume")?,
+                is_exit,
+                globals.get::<_, Function>("error")?,
+            ))?;
+        globals.set("pcall", pcall)?;
+        globals.set("xpcall", xpcall)?;
+        coroutine.set("resume", resume)?;
+
+        let os = globals.get::<_, Table>("os")?;
+        os.set(
+            "exit",
+            lua_ctx.create_function(|_, code: Option<rlua::Value>| {
+                let code = match code {
+                    None | Some(rlua::Value::Boolean(true)) => 0,
+                    Some(rlua::Value::Boolean(false)) => 1,
+                    Some(rlua::Value::Integer(code)) => code as i32,
+                    Some(rlua::Value::Number(code)) => code as i32,
+                    Some(code) => {
+                        return Err(rlua::Error::RuntimeError(format!(
+                            "bad argument #1 to 'exit' (number expected, got {})",
+                            code.type_name()
+                        )))
+                    }
+                };
+                Err::<(), _>(rlua::Error::external(ExitRequest(code)))
+            })?,
+        )?;
+        Ok(())
+    })
+}
+
+fn print_version(lua: &Lua) -> Result<()> {
+    println!("rluaterm {}", env!("CARGO_PKG_VERSION"));
+    println!("{}", LUA_VERSION);
+    println!("Modules: {}", help::module_names(lua)?.join(", "));
+    Ok(())
+}
+
+// Blank out a `#!` line at the top, so scripts can be made executable with
+// `#!/usr/bin/env rluaterm`. The newline stays so line numbers don't shift.
+fn strip_shebang(contents: &mut Vec<u8>) {
+    if contents.starts_with(b"#!") {
+        let end = contents
+            .iter()
+            .position(|byte| *byte == b'\n')
+            .unwrap_or(contents.len());
+        contents.drain(..end);
+    }
+}
+
+// Set the global `arg` like the standalone lua interpreter does: the script at index 0, its
+// arguments after it, and the interpreter and its options at negative indexes
+fn set_arg_table(lua: &Lua, args: &[String], script_index: usize) -> Result<()> {
+    lua.context(|lua_ctx| {
+        let arg_table = lua_ctx.create_table()?;
+        for (index, arg) in args.iter().enumerate() {
+            arg_table.set(index as i64 - script_index as i64, arg.as_str())?;
         }
-        // If the input is "exit", exit
-        if input == "exit" {
-            lua.context(|lua_ctx| {
-                lua_ctx.load("log.info('Exiting Lua interpreter')").exec()?;
-                Ok(())
-            })?;
-            break;
+        lua_ctx.globals().set("arg", arg_table)?;
+        Ok(())
+    })
+}
+
+// Put the built-in modules the config left in package.loaded, so they can be required like the
+// standard libraries can
+fn register_builtin_modules(lua: &Lua) -> Result<()> {
+    let names = help::module_names(lua)?;
+    lua.context(|lua_ctx| {
+        let globals = lua_ctx.globals();
+        let Some(package) = globals.get::<_, Option<Table>>("package")? else {
+            return Ok(());
+        };
+        let loaded = package.get::<_, Table>("loaded")?;
+        // Submodules such as os.signal come with the module they're in
+        for name in names.iter().filter(|name| !name.contains('.')) {
+            if let rlua::Value::Table(module) = globals.raw_get(name.as_str())? {
+                loaded.set(name.as_str(), module)?;
+            }
+        }
+        Ok(())
+    })
+}
+
+// A Lua state with everything rluaterm provides loaded, before any user code has run,
+// restricted to what the sandbox allows when given a policy
+fn create_lua(sandbox: Option<sandbox::Policy>) -> Result<Lua> {
+    let lua = Lua::new();
+    attach_interrupt_hook(&lua);
+    traceback::attach_traceback_handler(&lua)?;
+    replace_os_exit(&lua)?;
+    transcript::attach_print_recorder(&lua)?;
+    load_lua_log_library(&lua)?;
+    load_color_library(&lua)?;
+    load_http_library(&lua)?;
+    load_memory_library(&lua)?;
+    fs::load_fs_library(&lua)?;
+    path::load_path_library(&lua)?;
+    env::load_env_library(&lua)?;
+    proc::attach_exec(&lua)?;
+    proc::load_proc_library(&lua)?;
+    signal::attach_signal_library(&lua)?;
+    sys::load_sys_library(&lua)?;
+    clipboard::load_clipboard_library(&lua)?;
+    time::load_time_library(&lua)?;
+    timer::load_timer_library(&lua)?;
+    schedule::load_schedule_library(&lua)?;
+    json::load_json_library(&lua)?;
+    yaml::load_yaml_library(&lua)?;
+    toml::load_toml_library(&lua)?;
+    csv::load_csv_library(&lua)?;
+    msgpack::load_msgpack_library(&lua)?;
+    cbor::load_cbor_library(&lua)?;
+    xml::load_xml_library(&lua)?;
+    ini::load_ini_library(&lua)?;
+    encode::load_encode_library(&lua)?;
+    proto::load_proto_library(&lua)?;
+    binary::load_struct_library(&lua)?;
+    persist::load_persist_library(&lua)?;
+    hash::load_hash_library(&lua)?;
+    hmac::load_hmac_library(&lua)?;
+    random::load_random_library(&lua)?;
+    crypto::load_crypto_library(&lua)?;
+    password::load_password_library(&lua)?;
+    jwt::load_jwt_library(&lua)?;
+    tls::load_tls_library(&lua)?;
+    secrets::load_secrets_library(&lua)?;
+    compress::load_compress_library(&lua)?;
+    archive::load_archive_library(&lua)?;
+    help::load_help_library(&lua)?;
+    repl::load_repl_library(&lua)?;
+    strict::load_strict_library(&lua)?;
+    require::attach_reload(&lua)?;
+    profiler::load_profiler_library(&lua)?;
+    bench::load_bench_library(&lua)?;
+    config::apply(&lua)?;
+    register_builtin_modules(&lua)?;
+    if let Some(policy) = sandbox {
+        sandbox::apply(&lua, policy)?;
+    }
+    Ok(lua)
+}
+
+// Set up `arg` and run the startup files
+fn run_startup(lua: &Lua, raw_args: &[String], script_index: Option<usize>) -> Result<()> {
+    set_arg_table(lua, raw_args, script_index.unwrap_or(0))?;
+    commands::snapshot_builtin_globals(lua)?;
+    load_rc_files(lua)
+}
+
+// Run the code given with -e and load the libraries given with -l before any script,
+// returning the exit code if one of them failed or called os.exit
+fn run_preloads(lua: &Lua, preloads: &[(String, String)]) -> Result<Option<i32>> {
+    for (flag, value) in preloads {
+        let exit_code = match flag.as_str() {
+            "-l" => preload_library(lua, value)?,
+            _ => run_snippet(lua, value)?,
+        };
+        if exit_code.is_some() {
+            return Ok(exit_code);
+        }
+    }
+    Ok(None)
+}
+
+// Run the script, or the one read from stdin, returning the exit code it calls for
+fn run_script(lua: &Lua, file_path: &str, read_stdin: bool, script_args: &[String]) -> Result<i32> {
+    let mut bytes = Vec::new();
+    let (chunk_name, read_result) = if read_stdin {
+        let read_result = std::io::stdin().read_to_end(&mut bytes);
+        (format!("={}", STDIN_SCRIPT_NAME), read_result)
+    } else {
+        // Read the file into memory, it may be compiled rather than text
+        let read_result = std::fs::File::open(file_path)
+            .and_then(|file| std::io::BufReader::new(file).read_to_end(&mut bytes));
+        (format!("@{}", file_path), read_result)
+    };
+    if let Err(source) = read_result {
+        let error = error::Error::Read {
+            path: file_path.to_string(),
+            source,
+        };
+        return Ok(handle_error(
+            &format!("Failed to load file: {}", file_path),
+            &chunk_name,
+            "",
+            &rlua::Error::external(error),
+        ));
+    }
+    strip_shebang(&mut bytes);
+    run_chunk(lua, &bytes, &chunk_name, file_path, script_args)
+}
+
+// Run a script's source or bytecode, returning the exit code it calls for
+fn run_chunk(
+    lua: &Lua,
+    bytes: &[u8],
+    chunk_name: &str,
+    file_path: &str,
+    script_args: &[String],
+) -> Result<i32> {
+    lua.context(|lua_ctx| {
+        // Only used for reporting errors
+        let contents = String::from_utf8_lossy(bytes);
+        let script_args = Variadic::from_iter(script_args.iter().cloned());
+        let chunk = lua_ctx.load(bytes).set_name(chunk_name)?;
+        let function = if compile::is_bytecode(bytes) {
+            // Malformed bytecode can crash the interpreter, which is why rlua refuses it by
+            // default. Scripts given on the command line or built into the executable are
+            // trusted as much as rluaterm itself.
+            unsafe { chunk.into_function_allow_binary() }
         } else {
-            lua_interpret(&lua, &input)?;
+            chunk.into_function()
+        };
+        let load_result = function.and_then(|function| {
+            traceback::call_with_traceback::<_, MultiValue>(lua_ctx, function, script_args.clone())
+        });
+        match load_result {
+            Ok(results) => output::record_results(results),
+            Err(error) => {
+                return Ok(handle_error(
+                    &format!("Failed to load file: {}", file_path),
+                    chunk_name,
+                    &contents,
+                    &error,
+                ))
+            }
+        }
+        // A main function defined by the script is called with its arguments, however it was
+        // defined
+        if let rlua::Value::Function(main_function) = lua_ctx.globals().raw_get("main")? {
+            let main_result = traceback::call_with_traceback::<_, MultiValue>(
+                lua_ctx,
+                main_function,
+                script_args,
+            );
+            return Ok(match main_result {
+                // main can return the exit code
+                Ok(results) => {
+                    let exit_code = match results.iter().next() {
+                        Some(rlua::Value::Integer(code)) => *code as i32,
+                        _ => 0,
+                    };
+                    output::record_results(results);
+                    run_timers(lua_ctx, chunk_name, &contents, file_path).unwrap_or(exit_code)
+                }
+                Err(error) => handle_error(
+                    &format!("Failed to run main function in file: {}", file_path),
+                    chunk_name,
+                    &contents,
+                    &error,
+                ),
+            });
         }
+        Ok(run_timers(lua_ctx, chunk_name, &contents, file_path).unwrap_or(0))
+    })
+}
+
+// Keeps running the timers a script started once it's done, until none are left, returning the
+// exit code if one failed or called os.exit
+fn run_timers(
+    lua_ctx: rlua::Context,
+    chunk_name: &str,
+    contents: &str,
+    file_path: &str,
+) -> Option<i32> {
+    // `rluaterm schedule` runs the scheduler even when the script scheduled nothing, to say so
+    let (wait, running) = if schedule::runs_after_script() {
+        (
+            lua_ctx.create_function(|lua_ctx, ()| schedule::run(lua_ctx)),
+            "jobs",
+        )
+    } else if timer::pending(lua_ctx).unwrap_or(false) {
+        (
+            lua_ctx.create_function(|lua_ctx, ()| timer::wait(lua_ctx, None)),
+            "timers",
+        )
+    } else {
+        return None;
+    };
+    let result = wait.and_then(|wait| traceback::call_with_traceback::<_, ()>(lua_ctx, wait, ()));
+    match result {
+        Ok(()) => None,
+        Err(error) => Some(handle_error(
+            &format!("Failed to run {} in file: {}", running, file_path),
+            chunk_name,
+            contents,
+            &error,
+        )),
     }
-    Ok(())
 }
 
-fn lua_interpret(lua: &Lua, code: &str) -> Result<()> {
+// Run code given on the command line, returning the exit code if it failed or called os.exit
+fn run_snippet(lua: &Lua, code: &str) -> Result<Option<i32>> {
     lua.context(|lua_ctx| {
-        let result = lua_ctx.load(code).exec();
-        if result.is_err() {
-            logger::error(&result.unwrap_err().to_string());
+        let result = lua_ctx
+            .load(code)
+            .set_name(COMMAND_LINE_CHUNK_NAME)?
+            .into_function()
+            .and_then(|function| {
+                traceback::call_with_traceback::<_, MultiValue>(lua_ctx, function, ())
+            });
+        Ok(match result {
+            Ok(results) => {
+                output::record_results(results);
+                None
+            }
+            Err(error) => Some(handle_error("", COMMAND_LINE_CHUNK_NAME, code, &error)),
+        })
+    })
+}
+
+// Require a library given with -l into a global, named after the module or given as
+// `name=module`, returning the exit code if it failed or called os.exit
+fn preload_library(lua: &Lua, spec: &str) -> Result<Option<i32>> {
+    let (name, module) = spec.split_once('=').unwrap_or((spec, spec));
+    lua.context(|lua_ctx| {
+        let require = lua_ctx.globals().get::<_, Function>("require")?;
+        let result = traceback::call_with_traceback::<_, rlua::Value>(lua_ctx, require, module);
+        match result {
+            Ok(library) => {
+                lua_ctx.globals().set(name, library)?;
+                Ok(None)
+            }
+            Err(error) => Ok(Some(handle_error(
+                &format!("Failed to load library: {}", module),
+                COMMAND_LINE_CHUNK_NAME,
+                "",
+                &error,
+            ))),
         }
-        Ok(())
-    })?;
+    })
+}
+
+// ~/.config/rluaterm on every platform, so the rc file is easy to find
+fn config_dir() -> Option<PathBuf> {
+    dirs::home_dir().map(|home| home.join(".config").join("rluaterm"))
+}
+
+fn load_rc_files(lua: &Lua) -> Result<()> {
+    let mut rc_files = Vec::new();
+    if let Some(dir) = config_dir() {
+        rc_files.push(dir.join(USER_RC_FILE));
+    }
+    rc_files.push(PathBuf::from(PROJECT_RC_FILE));
+
+    for rc_file in rc_files {
+        if !rc_file.is_file() {
+            continue;
+        }
+        let contents = match std::fs::read_to_string(&rc_file) {
+            Ok(contents) => contents,
+            Err(error) => {
+                logger::error(&format!(
+                    "Failed to read rc file: {} [{}]",
+                    rc_file.display(),
+                    error
+                ));
+                continue;
+            }
+        };
+        lua.context(|lua_ctx| {
+            let chunk_name = format!("@{}", rc_file.display());
+            let result = traceback::exec_with_traceback(
+                lua_ctx,
+                lua_ctx.load(&contents).set_name(&chunk_name)?,
+            );
+            if let Err(error) = result {
+                // Nothing has started yet that would need to be cleaned up
+                if let Some(code) = requested_exit_code(&error) {
+                    std::process::exit(code);
+                }
+                report::report_error(
+                    &format!("Failed to load rc file: {}", rc_file.display()),
+                    &chunk_name,
+                    &contents,
+                    &error,
+                );
+            }
+            Ok(())
+        })?;
+    }
     Ok(())
 }
+
+// Errors returned by the hook reach us wrapped in a callback error with a traceback
+fn is_interrupted(error: &rlua::Error) -> bool {
+    match error {
+        rlua::Error::RuntimeError(message) => message == INTERRUPTED_MESSAGE,
+        rlua::Error::CallbackError { cause, .. } => is_interrupted(cause),
+        _ => false,
+    }
+}
+
+// The exit code passed to os.exit, if that's what the error came from
+fn requested_exit_code(error: &rlua::Error) -> Option<i32> {
+    match error {
+        rlua::Error::ExternalError(error) => {
+            error.downcast_ref::<ExitRequest>().map(|request| request.0)
+        }
+        rlua::Error::CallbackError { cause, .. } => requested_exit_code(cause),
+        _ => None,
+    }
+}
+
+// Report a chunk that failed and return the exit code it calls for
+fn handle_error(context: &str, chunk_name: &str, source: &str, error: &rlua::Error) -> i32 {
+    if let Some(code) = requested_exit_code(error) {
+        return code;
+    }
+    output::record_error(error);
+    if is_interrupted(error) {
+        logger::warn("Interrupted");
+        return INTERRUPTED_EXIT_CODE;
+    }
+    report::report_error(context, chunk_name, source, error);
+    1
+}
@@ -0,0 +1,221 @@
+/*
+   Copyright (C) 2022  Kalka
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License, or (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use rlua::{
+    Context, LightUserData, Lua, MetaMethod, MultiValue, Result, UserData, UserDataMethods, Value,
+};
+use std::io::Cursor;
+
+use crate::help::{self, FunctionDoc};
+use crate::{error, json};
+
+const MSGPACK_DOCS: &[FunctionDoc] = &[
+    FunctionDoc {
+        name: "encode",
+        signature: "msgpack.encode(value) -> bytes",
+        description: "Turns a Lua value into MessagePack. Tables with nothing but the keys 1 to n become arrays and other tables maps, empty ones included, and msgpack.null becomes nil. Strings of valid UTF-8 are written as str and others as bin. Raises an error for functions and other values MessagePack can't hold, and for tables nested inside themselves.",
+        example: "redis_set(\"session:\" .. id, msgpack.encode({ user = 42, roles = { \"admin\" } }))",
+    },
+    FunctionDoc {
+        name: "decode",
+        signature: "msgpack.decode(bytes[, position]) -> value, next",
+        description: "Reads a MessagePack value from the bytes, starting at position (1 by default), and returns it with the position just after it, so values packed one after another can be read in turn. Arrays and maps become tables, str and bin strings, and nil becomes msgpack.null, which is json.null. Raises an error for invalid MessagePack.",
+        example: "local position, messages = 1, {}\nwhile position <= #buffer do\n    messages[#messages + 1], position = msgpack.decode(buffer, position)\nend",
+    },
+    FunctionDoc {
+        name: "ext",
+        signature: "msgpack.ext(type, data) -> ext",
+        description: "An extension value of an application-defined type from -128 to 127, with its bytes. msgpack.decode returns them for ext values, with type and data fields.",
+        example: "local packed = msgpack.encode(msgpack.ext(1, string.pack(\">I4\", 7)))",
+    },
+];
+
+// Deeper tables are almost certainly cycles
+const MAX_DEPTH: usize = 100;
+
+/// A MessagePack extension value, with the application-defined type of its data.
+#[derive(Clone)]
+struct Ext {
+    kind: i8,
+    data: Vec<u8>,
+}
+
+impl UserData for Ext {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_meta_method(MetaMethod::Index, |lua_ctx, ext, key: String| {
+            Ok(match key.as_str() {
+                "type" => Value::Integer(ext.kind as i64),
+                "data" => Value::String(lua_ctx.create_string(&ext.data)?),
+                _ => Value::Nil,
+            })
+        });
+        methods.add_meta_method(MetaMethod::ToString, |_, ext, ()| {
+            Ok(format!(
+                "msgpack.ext({}, {} bytes)",
+                ext.kind,
+                ext.data.len()
+            ))
+        });
+    }
+}
+
+fn encode_error(message: String) -> rlua::Error {
+    rlua::Error::RuntimeError(format!("cannot encode to MessagePack: {}", message))
+}
+
+fn to_lua<'lua>(lua_ctx: Context<'lua>, value: rmpv::Value) -> Result<Value<'lua>> {
+    Ok(match value {
+        rmpv::Value::Nil => json::null(),
+        rmpv::Value::Boolean(boolean) => Value::Boolean(boolean),
+        rmpv::Value::Integer(integer) => match integer.as_i64() {
+            Some(integer) => Value::Integer(integer),
+            None => Value::Number(integer.as_f64().unwrap_or(f64::NAN)),
+        },
+        rmpv::Value::F32(float) => Value::Number(float as f64),
+        rmpv::Value::F64(float) => Value::Number(float),
+        rmpv::Value::String(string) => Value::String(lua_ctx.create_string(string.as_bytes())?),
+        rmpv::Value::Binary(bytes) => Value::String(lua_ctx.create_string(&bytes)?),
+        rmpv::Value::Array(array) => {
+            let table = lua_ctx.create_table()?;
+            for (index, value) in array.into_iter().enumerate() {
+                table.raw_set(index as i64 + 1, to_lua(lua_ctx, value)?)?;
+            }
+            Value::Table(table)
+        }
+        rmpv::Value::Map(map) => {
+            let table = lua_ctx.create_table()?;
+            for (key, value) in map {
+                table.raw_set(to_lua(lua_ctx, key)?, to_lua(lua_ctx, value)?)?;
+            }
+            Value::Table(table)
+        }
+        rmpv::Value::Ext(kind, data) => {
+            Value::UserData(lua_ctx.create_userdata(Ext { kind, data })?)
+        }
+    })
+}
+
+fn to_msgpack(value: Value, depth: usize) -> Result<rmpv::Value> {
+    Ok(match value {
+        Value::Nil => rmpv::Value::Nil,
+        Value::LightUserData(LightUserData(pointer)) if pointer.is_null() => rmpv::Value::Nil,
+        Value::Boolean(boolean) => rmpv::Value::Boolean(boolean),
+        Value::Integer(integer) => rmpv::Value::from(integer),
+        Value::Number(number) => rmpv::Value::F64(number),
+        Value::String(string) => match string.to_str() {
+            Ok(text) => rmpv::Value::from(text),
+            Err(_) => rmpv::Value::Binary(string.as_bytes().to_vec()),
+        },
+        Value::Table(table) => {
+            if depth >= MAX_DEPTH {
+                return Err(encode_error("tables are nested too deeply".to_string()));
+            }
+            let length = table.raw_len() as usize;
+            let pairs = table
+                .clone()
+                .pairs::<Value, Value>()
+                .collect::<Result<Vec<_>>>()?;
+            if length > 0 && pairs.len() == length {
+                let mut array = Vec::with_capacity(length);
+                for index in 1..=length {
+                    array.push(to_msgpack(table.raw_get(index as i64)?, depth + 1)?);
+                }
+                rmpv::Value::Array(array)
+            } else {
+                let mut map = Vec::with_capacity(pairs.len());
+                for (key, value) in pairs {
+                    map.push((to_msgpack(key, depth + 1)?, to_msgpack(value, depth + 1)?));
+                }
+                rmpv::Value::Map(map)
+            }
+        }
+        Value::UserData(userdata) if userdata.is::<Ext>() => {
+            let ext = userdata.borrow::<Ext>()?;
+            rmpv::Value::Ext(ext.kind, ext.data.clone())
+        }
+        value => return Err(encode_error(format!("{} values", value.type_name()))),
+    })
+}
+
+fn decode<'lua>(
+    lua_ctx: Context<'lua>,
+    (bytes, position): (rlua::String<'lua>, Option<i64>),
+) -> Result<MultiValue<'lua>> {
+    let bytes = bytes.as_bytes();
+    let position = position.unwrap_or(1);
+    if position < 1 || position as usize > bytes.len() {
+        return Err(rlua::Error::RuntimeError(format!(
+            "bad argument #2 to 'decode' (position {} is outside of the {} bytes)",
+            position,
+            bytes.len()
+        )));
+    }
+    let mut reader = Cursor::new(&bytes[position as usize - 1..]);
+    let value = rmpv::decode::read_value(&mut reader)
+        .map_err(|source| rlua::Error::external(error::Error::MsgPack { source }))?;
+    let next = position + reader.position() as i64;
+    Ok(MultiValue::from_vec(vec![
+        to_lua(lua_ctx, value)?,
+        Value::Integer(next),
+    ]))
+}
+
+pub fn load_msgpack_library(lua: &Lua) -> Result<()> {
+    lua.context(|lua_ctx| {
+        let msgpack = lua_ctx.create_table()?;
+
+        msgpack.set(
+            "encode",
+            lua_ctx.create_function(|lua_ctx, value: Value| {
+                let mut bytes = Vec::new();
+                rmpv::encode::write_value(&mut bytes, &to_msgpack(value, 0)?)
+                    .map_err(|error| encode_error(error.to_string()))?;
+                lua_ctx.create_string(&bytes)
+            })?,
+        )?;
+
+        msgpack.set("decode", lua_ctx.create_function(decode)?)?;
+
+        msgpack.set(
+            "ext",
+            lua_ctx.create_function(|_, (kind, data): (i64, rlua::String)| {
+                let kind = i8::try_from(kind).map_err(|_| {
+                    rlua::Error::RuntimeError(format!(
+                        "bad argument #1 to 'ext' (type must be from -128 to 127, got {})",
+                        kind
+                    ))
+                })?;
+                Ok(Ext {
+                    kind,
+                    data: data.as_bytes().to_vec(),
+                })
+            })?,
+        )?;
+
+        msgpack.set("null", json::null())?;
+
+        help::register_module_docs(
+            lua_ctx,
+            "msgpack",
+            "MessagePack encoding and decoding",
+            &msgpack,
+            MSGPACK_DOCS,
+        )?;
+        lua_ctx.globals().set("msgpack", msgpack)?;
+
+        Ok(())
+    })
+}
@@ -0,0 +1,112 @@
+/*
+   Copyright (C) 2022  Kalka
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License, or (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use rlua::MultiValue;
+use std::fs::File;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use crate::{json, report};
+
+// Set by --output-format=json, which makes rluaterm print a single JSON object with the outcome
+// of the script on stdout and everything else on stderr
+static JSON_OUTPUT: AtomicBool = AtomicBool::new(false);
+// Where the JSON goes, with stdout itself pointing at stderr
+static STDOUT: Mutex<Option<File>> = Mutex::new(None);
+// What the last chunk returned, or why it failed
+static RESULTS: Mutex<Vec<serde_json::Value>> = Mutex::new(Vec::new());
+static ERROR: Mutex<Option<String>> = Mutex::new(None);
+
+/// Output formats for `--output-format`.
+pub const FORMATS: &[&str] = &["text", "json"];
+
+// Points stdout at stderr, returning the original stdout
+#[cfg(unix)]
+fn redirect_stdout() -> Option<File> {
+    use std::os::fd::FromRawFd;
+    let _ = std::io::stdout().flush();
+    unsafe {
+        let original = libc::dup(libc::STDOUT_FILENO);
+        if original < 0 {
+            return None;
+        }
+        if libc::dup2(libc::STDERR_FILENO, libc::STDOUT_FILENO) < 0 {
+            libc::close(original);
+            return None;
+        }
+        Some(File::from_raw_fd(original))
+    }
+}
+
+// Logs can't be moved off stdout here, so they come before the JSON
+#[cfg(not(unix))]
+fn redirect_stdout() -> Option<File> {
+    None
+}
+
+/// Switches to printing the outcome as JSON, moving everything else printed to stderr.
+pub fn enable_json() {
+    JSON_OUTPUT.store(true, Ordering::SeqCst);
+    *STDOUT.lock().unwrap() = redirect_stdout();
+}
+
+/// Records what a chunk returned, to be printed as its result.
+pub fn record_results(values: MultiValue) {
+    if !JSON_OUTPUT.load(Ordering::SeqCst) {
+        return;
+    }
+    let results = values
+        .into_iter()
+        .map(json::to_json)
+        .collect::<rlua::Result<Vec<_>>>();
+    match results {
+        Ok(results) => *RESULTS.lock().unwrap() = results,
+        Err(error) => record_error(&error),
+    }
+}
+
+/// Records why a chunk failed, to be printed as its error.
+pub fn record_error(error: &rlua::Error) {
+    if !JSON_OUTPUT.load(Ordering::SeqCst) {
+        return;
+    }
+    let message = report::error_message(error);
+    let headline = message.lines().next().unwrap_or_default().to_string();
+    *ERROR.lock().unwrap() = Some(headline);
+}
+
+/// Prints the outcome as JSON when `--output-format=json` is on: whether it succeeded, the values
+/// the script returned, its error and the exit code.
+pub fn finish(exit_code: i32) {
+    if !JSON_OUTPUT.load(Ordering::SeqCst) {
+        return;
+    }
+    let error = ERROR.lock().unwrap().take();
+    let outcome = serde_json::json!({
+        "ok": error.is_none() && exit_code == 0,
+        "results": std::mem::take(&mut *RESULTS.lock().unwrap()),
+        "error": error,
+        "exit_code": exit_code,
+    });
+    let _ = std::io::stdout().flush();
+    match STDOUT.lock().unwrap().as_mut() {
+        Some(stdout) => {
+            let _ = writeln!(stdout, "{}", outcome);
+        }
+        None => println!("{}", outcome),
+    }
+}
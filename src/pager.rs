@@ -0,0 +1,130 @@
+/*
+   Copyright (C) 2022  Kalka
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License, or (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use colored::Colorize;
+use std::io::{BufRead, Write};
+use std::sync::Mutex;
+
+use crate::transcript;
+
+// Output of the chunk being run at the prompt, kept whole so `:page` can show what got cut off
+struct Output {
+    lines: Vec<String>,
+    limit: Option<usize>,
+}
+
+static CURRENT: Mutex<Option<Output>> = Mutex::new(None);
+static LAST: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+// Lines per page when the terminal size isn't known
+const DEFAULT_PAGE_HEIGHT: usize = 24;
+
+/// Starts collecting the output of a chunk, showing at most `limit` lines of it.
+pub fn begin(limit: Option<usize>) {
+    *CURRENT.lock().unwrap() = Some(Output {
+        lines: Vec::new(),
+        limit,
+    });
+}
+
+/// Collects printed text, returning the part of it within the limit that should be shown, or
+/// `None` when it's all past it. Output outside of `begin` and `finish` is always shown.
+pub fn show(text: &str) -> Option<String> {
+    let mut current = CURRENT.lock().unwrap();
+    let output = match current.as_mut() {
+        Some(output) => output,
+        None => return Some(text.to_string()),
+    };
+    let shown = output.lines.len();
+    // Split on every newline, as print("") and text ending in one take up lines too
+    let lines = text.split('\n').collect::<Vec<_>>();
+    output
+        .lines
+        .extend(lines.iter().map(|line| line.to_string()));
+    let left = match output.limit {
+        Some(limit) => limit.saturating_sub(shown),
+        None => return Some(text.to_string()),
+    };
+    match left {
+        0 => None,
+        left if lines.len() <= left => Some(text.to_string()),
+        left => Some(lines[..left].join("\n")),
+    }
+}
+
+/// Stops collecting, telling the user how much output was cut off.
+pub fn finish() {
+    let output = match CURRENT.lock().unwrap().take() {
+        Some(output) => output,
+        None => return,
+    };
+    if let Some(limit) = output.limit {
+        if output.lines.len() > limit {
+            let hint = format!(
+                "… {} more lines, use :page to view",
+                output.lines.len() - limit
+            );
+            println!("{}", hint.dimmed());
+            transcript::record_output(&hint);
+        }
+    }
+    *LAST.lock().unwrap() = output.lines;
+}
+
+fn page_height() -> usize {
+    std::env::var("LINES")
+        .ok()
+        .and_then(|lines| lines.parse::<usize>().ok())
+        .filter(|lines| *lines > 1)
+        .map(|lines| lines - 1)
+        .unwrap_or(DEFAULT_PAGE_HEIGHT)
+}
+
+/// Shows the whole output of the last chunk a page at a time.
+pub fn page_last_output() {
+    let lines = LAST.lock().unwrap().clone();
+    if lines.is_empty() {
+        println!("No output to page");
+        return;
+    }
+    let height = page_height();
+    let stdin = std::io::stdin();
+    let mut stdout = std::io::stdout();
+    for (page, chunk) in lines.chunks(height).enumerate() {
+        for line in chunk {
+            println!("{}", line);
+        }
+        let shown = (page * height + chunk.len()).min(lines.len());
+        if shown == lines.len() {
+            break;
+        }
+        print!(
+            "{}",
+            format!(
+                "-- {}/{} lines, Enter for more, q to quit --",
+                shown,
+                lines.len()
+            )
+            .reversed()
+        );
+        let _ = stdout.flush();
+        let mut answer = String::new();
+        if stdin.lock().read_line(&mut answer).unwrap_or(0) == 0 || answer.trim() == "q" {
+            println!();
+            break;
+        }
+    }
+}
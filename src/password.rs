@@ -0,0 +1,136 @@
+/*
+   Copyright (C) 2022  Kalka
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License, or (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use rlua::{Lua, Result, Table};
+
+use crate::error;
+use crate::help::{self, FunctionDoc};
+
+const PASSWORD_DOCS: &[FunctionDoc] = &[
+    FunctionDoc {
+        name: "hash",
+        signature: "password.hash(password[, options]) -> string",
+        description: "Hashes a password for storing, with a random salt, returning a string that has the algorithm and its settings in it. It's argon2id with the settings OWASP recommends unless options.algorithm is \"bcrypt\", with options.cost its work factor (12 by default). bcrypt raises an error for passwords longer than 72 bytes, rather than ignoring the rest.",
+        example: "users[name] = { password = password.hash(plain) }",
+    },
+    FunctionDoc {
+        name: "verify",
+        signature: "password.verify(password, hash) -> boolean",
+        description: "Whether the password is the one the hash was made from. Takes argon2 hashes and bcrypt hashes starting with $2a$, $2b$ or $2y$, like the ones password.hash and htpasswd -B make. Raises an error for hashes it can't read.",
+        example: "if not password.verify(attempt, users[name].password) then\n    error(\"wrong password\")\nend",
+    },
+];
+
+const BCRYPT_COST: u32 = 12;
+
+fn hash_error(message: impl ToString) -> rlua::Error {
+    rlua::Error::external(error::Error::Decode {
+        format: "password hash",
+        message: message.to_string(),
+    })
+}
+
+fn hash(password: &[u8], options: Option<Table>) -> Result<String> {
+    let (algorithm, cost) = match &options {
+        Some(options) => (
+            options.get::<_, Option<String>>("algorithm")?,
+            options.get::<_, Option<u32>>("cost")?,
+        ),
+        None => (None, None),
+    };
+    match algorithm.as_deref().unwrap_or("argon2id") {
+        "argon2id" => {
+            let mut salt = [0; 16];
+            getrandom::fill(&mut salt).map_err(|error| {
+                rlua::Error::RuntimeError(format!(
+                    "could not get random bytes from the system: {}",
+                    error
+                ))
+            })?;
+            let salt = SaltString::encode_b64(&salt).expect("16 bytes is a valid salt");
+            Argon2::default()
+                .hash_password(password, &salt)
+                .map(|hash| hash.to_string())
+                .map_err(|error| rlua::Error::RuntimeError(format!("cannot hash password: {}", error)))
+        }
+        "bcrypt" => bcrypt::non_truncating_hash(password, cost.unwrap_or(BCRYPT_COST)).map_err(
+            |error| match error {
+                bcrypt::BcryptError::CostNotAllowed(cost) => rlua::Error::RuntimeError(format!(
+                    "bad option 'cost' to 'hash' (4 to 31 expected, got {})",
+                    cost
+                )),
+                bcrypt::BcryptError::Truncation(_) => rlua::Error::RuntimeError(
+                    "bad argument #1 to 'hash' (bcrypt only takes passwords up to 72 bytes)"
+                        .to_string(),
+                ),
+                error => rlua::Error::RuntimeError(format!("cannot hash password: {}", error)),
+            },
+        ),
+        algorithm => Err(rlua::Error::RuntimeError(format!(
+            "bad option 'algorithm' to 'hash' (unknown algorithm '{}', expected argon2id or bcrypt)",
+            algorithm
+        ))),
+    }
+}
+
+fn verify(password: &[u8], hash: &str) -> Result<bool> {
+    if hash.starts_with("$2") {
+        return bcrypt::verify(password, hash).map_err(hash_error);
+    }
+    let parsed = PasswordHash::new(hash).map_err(hash_error)?;
+    if !parsed.algorithm.as_str().starts_with("argon2") {
+        return Err(hash_error(format!(
+            "{} hashes aren't supported, only argon2 and bcrypt",
+            parsed.algorithm
+        )));
+    }
+    match Argon2::default().verify_password(password, &parsed) {
+        Ok(()) => Ok(true),
+        Err(argon2::password_hash::Error::Password) => Ok(false),
+        Err(error) => Err(hash_error(error)),
+    }
+}
+
+pub fn load_password_library(lua: &Lua) -> Result<()> {
+    lua.context(|lua_ctx| {
+        let password = lua_ctx.create_table()?;
+
+        password.set(
+            "hash",
+            lua_ctx.create_function(|_, (plain, options): (rlua::String, Option<Table>)| {
+                hash(plain.as_bytes(), options)
+            })?,
+        )?;
+        password.set(
+            "verify",
+            lua_ctx.create_function(|_, (plain, hash): (rlua::String, String)| {
+                verify(plain.as_bytes(), &hash)
+            })?,
+        )?;
+
+        help::register_module_docs(
+            lua_ctx,
+            "password",
+            "Hashing and checking passwords",
+            &password,
+            PASSWORD_DOCS,
+        )?;
+        lua_ctx.globals().set("password", password)?;
+        Ok(())
+    })
+}
@@ -0,0 +1,222 @@
+/*
+   Copyright (C) 2022  Kalka
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License, or (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use rlua::{Lua, Result, Variadic};
+use std::path::{Component, Path, PathBuf, MAIN_SEPARATOR_STR};
+
+use crate::help::{self, FunctionDoc};
+
+// Paths are handled with std::path, which splits on both / and \ on Windows and only on / on
+// other platforms, and joins with the platform's separator.
+const PATH_DOCS: &[FunctionDoc] = &[
+    FunctionDoc {
+        name: "join",
+        signature: "path.join(...) -> string",
+        description: "Joins the parts with the platform's separator. An absolute part replaces everything before it.",
+        example: "path.join(\"src\", \"lib\", \"util.lua\") --> \"src/lib/util.lua\"",
+    },
+    FunctionDoc {
+        name: "dirname",
+        signature: "path.dirname(path) -> string",
+        description: "Everything but the last part of the path, or \".\" when there is nothing before it.",
+        example: "path.dirname(\"src/lib/util.lua\") --> \"src/lib\"",
+    },
+    FunctionDoc {
+        name: "basename",
+        signature: "path.basename(path) -> string",
+        description: "The last part of the path, or \"\" when it ends in .. or is a root.",
+        example: "path.basename(\"src/lib/util.lua\") --> \"util.lua\"",
+    },
+    FunctionDoc {
+        name: "ext",
+        signature: "path.ext(path) -> string",
+        description: "The extension of the last part, with its dot, or \"\" when there is none.",
+        example: "path.ext(\"archive.tar.gz\") --> \".gz\"",
+    },
+    FunctionDoc {
+        name: "normalize",
+        signature: "path.normalize(path) -> string",
+        description: "Removes . parts, repeated separators and parts followed by .., without looking at the filesystem.",
+        example: "path.normalize(\"src/./lib/../main.lua\") --> \"src/main.lua\"",
+    },
+    FunctionDoc {
+        name: "absolute",
+        signature: "path.absolute(path) -> string",
+        description: "The normalized path, relative to the current directory unless it's already absolute. The file doesn't have to exist.",
+        example: "path.absolute(\"../data\")",
+    },
+    FunctionDoc {
+        name: "relative",
+        signature: "path.relative(path[, base]) -> string",
+        description: "The path as seen from the base directory, the current directory by default. Returns the absolute path when there's no way from one to the other, as with paths on different Windows drives.",
+        example: "path.relative(\"/srv/app/logs/today.log\", \"/srv/app\") --> \"logs/today.log\"",
+    },
+    FunctionDoc {
+        name: "expand",
+        signature: "path.expand(path) -> string",
+        description: "Replaces a leading ~ with the user's home directory.",
+        example: "fs.read(path.expand(\"~/.config/tool.toml\"))",
+    },
+];
+
+fn to_string(path: &Path) -> String {
+    path.to_string_lossy().into_owned()
+}
+
+// The path with `.` parts, repeated separators and parts followed by `..` removed, without
+// looking at the filesystem. `..` at the start of a relative path is kept.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    // Parts that a `..` can remove
+    let mut depth = 0;
+    for component in path.components() {
+        match component {
+            Component::Prefix(_) | Component::RootDir => normalized.push(component),
+            Component::CurDir => {}
+            Component::ParentDir if depth > 0 => {
+                normalized.pop();
+                depth -= 1;
+            }
+            // There's nothing above the root
+            Component::ParentDir if normalized.has_root() => {}
+            Component::ParentDir => normalized.push(".."),
+            Component::Normal(name) => {
+                normalized.push(name);
+                depth += 1;
+            }
+        }
+    }
+    if normalized.as_os_str().is_empty() {
+        normalized.push(".");
+    }
+    normalized
+}
+
+fn absolute(path: &Path) -> Result<PathBuf> {
+    if path.is_absolute() {
+        return Ok(normalize(path));
+    }
+    let current_dir = std::env::current_dir().map_err(rlua::Error::external)?;
+    Ok(normalize(&current_dir.join(path)))
+}
+
+// The way from the base directory to the path, both absolute and normalized, if they share a
+// root
+fn relative(path: &Path, base: &Path) -> Option<PathBuf> {
+    let path = path.components().collect::<Vec<_>>();
+    let base = base.components().collect::<Vec<_>>();
+    if path.first() != base.first() {
+        return None;
+    }
+    let common = path.iter().zip(&base).take_while(|(a, b)| a == b).count();
+    let mut relative = PathBuf::new();
+    for _ in common..base.len() {
+        relative.push("..");
+    }
+    relative.extend(&path[common..]);
+    if relative.as_os_str().is_empty() {
+        relative.push(".");
+    }
+    Some(relative)
+}
+
+// The path with a leading `~` replaced by the user's home directory
+fn expand(path: &str) -> String {
+    let rest = match path.strip_prefix('~') {
+        Some(rest) if rest.is_empty() || rest.starts_with(std::path::is_separator) => rest,
+        _ => return path.to_string(),
+    };
+    match dirs::home_dir() {
+        Some(home) => format!("{}{}", home.display(), rest),
+        None => path.to_string(),
+    }
+}
+
+pub fn load_path_library(lua: &Lua) -> Result<()> {
+    lua.context(|lua_ctx| {
+        let path = lua_ctx.create_table()?;
+        path.set("sep", MAIN_SEPARATOR_STR)?;
+
+        path.set(
+            "join",
+            lua_ctx.create_function(|_, parts: Variadic<String>| {
+                Ok(to_string(&parts.iter().collect::<PathBuf>()))
+            })?,
+        )?;
+
+        path.set(
+            "dirname",
+            lua_ctx.create_function(|_, path: String| {
+                Ok(match Path::new(&path).parent() {
+                    Some(parent) if !parent.as_os_str().is_empty() => to_string(parent),
+                    // The parent of a root is itself
+                    None if Path::new(&path).has_root() => path,
+                    _ => ".".to_string(),
+                })
+            })?,
+        )?;
+
+        path.set(
+            "basename",
+            lua_ctx.create_function(|_, path: String| {
+                Ok(Path::new(&path)
+                    .file_name()
+                    .map(|name| name.to_string_lossy().into_owned())
+                    .unwrap_or_default())
+            })?,
+        )?;
+
+        path.set(
+            "ext",
+            lua_ctx.create_function(|_, path: String| {
+                Ok(Path::new(&path)
+                    .extension()
+                    .map(|extension| format!(".{}", extension.to_string_lossy()))
+                    .unwrap_or_default())
+            })?,
+        )?;
+
+        path.set(
+            "normalize",
+            lua_ctx
+                .create_function(|_, path: String| Ok(to_string(&normalize(Path::new(&path)))))?,
+        )?;
+
+        path.set(
+            "absolute",
+            lua_ctx
+                .create_function(|_, path: String| Ok(to_string(&absolute(Path::new(&path))?)))?,
+        )?;
+
+        path.set(
+            "relative",
+            lua_ctx.create_function(|_, (path, base): (String, Option<String>)| {
+                let path = absolute(Path::new(&path))?;
+                let base = absolute(Path::new(base.as_deref().unwrap_or(".")))?;
+                Ok(to_string(&relative(&path, &base).unwrap_or(path)))
+            })?,
+        )?;
+
+        path.set(
+            "expand",
+            lua_ctx.create_function(|_, path: String| Ok(expand(&path)))?,
+        )?;
+
+        help::register_module_docs(lua_ctx, "path", "Working with paths", &path, PATH_DOCS)?;
+        lua_ctx.globals().set("path", path)?;
+        Ok(())
+    })
+}
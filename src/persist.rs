@@ -0,0 +1,305 @@
+/*
+   Copyright (C) 2022  Kalka
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License, or (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use std::path::Path;
+
+use rlua::{Context, LightUserData, Lua, Result, Table, Value};
+
+use crate::help::{self, FunctionDoc};
+use crate::{error, json};
+
+const PERSIST_DOCS: &[FunctionDoc] = &[
+    FunctionDoc {
+        name: "save",
+        signature: "persist.save(path, value)",
+        description: "Saves a value to the file in a compact binary format, replacing the file only once the whole value is written, so it's never left half-saved. Tables can hold nil, booleans, numbers, strings, json.null and other tables, including ones that appear more than once or inside themselves, which are loaded back the same way. Metatables aren't saved. Raises an error for functions and other values that can't be saved, and when the file can't be written.",
+        example: "persist.save(\"seen.bin\", seen)",
+    },
+    FunctionDoc {
+        name: "load",
+        signature: "persist.load(path[, default]) -> value",
+        description: "Loads a value saved with persist.save. When the file doesn't exist, returns default if there is one. Raises an error when the file can't be read or wasn't saved by persist.save.",
+        example: "local seen = persist.load(\"seen.bin\", {})\nfor _, item in ipairs(feed.items) do\n    if not seen[item.id] then\n        notify(item)\n        seen[item.id] = true\n    end\nend\npersist.save(\"seen.bin\", seen)",
+    },
+];
+
+// Files start with this and the version of the format
+const MAGIC: &[u8] = b"RLPS";
+const VERSION: u8 = 1;
+
+// Deeper tables are almost certainly a mistake, and would overflow the stack when loaded
+const MAX_DEPTH: usize = 100;
+
+// Each value starts with one of these. Tables are their key and value pairs ended by a nil key,
+// and are numbered in the order they're first written, so later appearances are references.
+const NIL: u8 = 0;
+const FALSE: u8 = 1;
+const TRUE: u8 = 2;
+const INTEGER: u8 = 3;
+const FLOAT: u8 = 4;
+const STRING: u8 = 5;
+const TABLE: u8 = 6;
+const REFERENCE: u8 = 7;
+const NULL: u8 = 8;
+
+fn save_error(message: String) -> rlua::Error {
+    rlua::Error::RuntimeError(format!("cannot persist value: {}", message))
+}
+
+fn decode_error(message: &str) -> rlua::Error {
+    rlua::Error::external(error::Error::Decode {
+        format: "persisted data",
+        message: message.to_string(),
+    })
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+struct Writer<'lua> {
+    out: Vec<u8>,
+    // The number each table written so far was given
+    seen: Table<'lua>,
+    tables: u64,
+}
+
+impl<'lua> Writer<'lua> {
+    fn write(&mut self, value: Value<'lua>, depth: usize) -> Result<()> {
+        match value {
+            Value::Nil => self.out.push(NIL),
+            Value::Boolean(false) => self.out.push(FALSE),
+            Value::Boolean(true) => self.out.push(TRUE),
+            Value::Integer(integer) => {
+                self.out.push(INTEGER);
+                // Zigzag, so small negative numbers stay short
+                write_varint(&mut self.out, ((integer << 1) ^ (integer >> 63)) as u64);
+            }
+            Value::Number(number) => {
+                self.out.push(FLOAT);
+                self.out.extend_from_slice(&number.to_le_bytes());
+            }
+            Value::String(string) => {
+                self.out.push(STRING);
+                write_varint(&mut self.out, string.as_bytes().len() as u64);
+                self.out.extend_from_slice(string.as_bytes());
+            }
+            Value::LightUserData(LightUserData(pointer)) if pointer.is_null() => {
+                self.out.push(NULL)
+            }
+            Value::Table(table) => {
+                if let Some(number) = self.seen.raw_get::<_, Option<u64>>(table.clone())? {
+                    self.out.push(REFERENCE);
+                    write_varint(&mut self.out, number);
+                    return Ok(());
+                }
+                if depth >= MAX_DEPTH {
+                    return Err(save_error("tables are nested too deeply".to_string()));
+                }
+                self.seen.raw_set(table.clone(), self.tables)?;
+                self.tables += 1;
+                self.out.push(TABLE);
+                for pair in table.pairs::<Value, Value>() {
+                    let (key, value) = pair?;
+                    self.write(key, depth + 1)?;
+                    self.write(value, depth + 1)?;
+                }
+                self.out.push(NIL);
+            }
+            value => {
+                return Err(save_error(format!(
+                    "{} values can't be saved",
+                    value.type_name()
+                )))
+            }
+        }
+        Ok(())
+    }
+}
+
+struct Reader<'a, 'lua> {
+    bytes: &'a [u8],
+    position: usize,
+    tables: Vec<Table<'lua>>,
+}
+
+impl<'a, 'lua> Reader<'a, 'lua> {
+    fn byte(&mut self) -> Result<u8> {
+        let byte = *self
+            .bytes
+            .get(self.position)
+            .ok_or_else(|| decode_error("the file ends in the middle of a value"))?;
+        self.position += 1;
+        Ok(byte)
+    }
+
+    fn take(&mut self, length: usize) -> Result<&'a [u8]> {
+        let end = self
+            .position
+            .checked_add(length)
+            .filter(|end| *end <= self.bytes.len())
+            .ok_or_else(|| decode_error("the file ends in the middle of a value"))?;
+        let bytes = &self.bytes[self.position..end];
+        self.position = end;
+        Ok(bytes)
+    }
+
+    fn varint(&mut self) -> Result<u64> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(decode_error("a number is too long"))
+    }
+
+    fn read(&mut self, lua_ctx: Context<'lua>, depth: usize) -> Result<Value<'lua>> {
+        Ok(match self.byte()? {
+            NIL => Value::Nil,
+            FALSE => Value::Boolean(false),
+            TRUE => Value::Boolean(true),
+            INTEGER => {
+                let zigzag = self.varint()?;
+                Value::Integer((zigzag >> 1) as i64 ^ -((zigzag & 1) as i64))
+            }
+            FLOAT => {
+                let bytes = self.take(8)?;
+                Value::Number(f64::from_le_bytes(bytes.try_into().expect("8 bytes")))
+            }
+            STRING => {
+                let length = usize::try_from(self.varint()?)
+                    .map_err(|_| decode_error("a string is too long"))?;
+                Value::String(lua_ctx.create_string(self.take(length)?)?)
+            }
+            NULL => json::null(),
+            TABLE => {
+                if depth >= MAX_DEPTH {
+                    return Err(decode_error("tables are nested too deeply"));
+                }
+                let table = lua_ctx.create_table()?;
+                self.tables.push(table.clone());
+                loop {
+                    let key = self.read(lua_ctx, depth + 1)?;
+                    if let Value::Nil = key {
+                        break;
+                    }
+                    let value = self.read(lua_ctx, depth + 1)?;
+                    table
+                        .raw_set(key, value)
+                        .map_err(|_| decode_error("a table has a NaN key"))?;
+                }
+                Value::Table(table)
+            }
+            REFERENCE => {
+                let number = self.varint()?;
+                let table = usize::try_from(number)
+                    .ok()
+                    .and_then(|number| self.tables.get(number))
+                    .ok_or_else(|| decode_error("a table refers to one that isn't there"))?;
+                Value::Table(table.clone())
+            }
+            tag => return Err(decode_error(&format!("unknown value type {}", tag))),
+        })
+    }
+}
+
+fn save<'lua>(lua_ctx: Context<'lua>, (path, value): (String, Value<'lua>)) -> Result<()> {
+    let mut writer = Writer {
+        out: MAGIC.to_vec(),
+        seen: lua_ctx.create_table()?,
+        tables: 0,
+    };
+    writer.out.push(VERSION);
+    writer.write(value, 0)?;
+    // Writing next to the file and renaming it over the old one never leaves a partial file
+    let file_name = Path::new(&path)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let temporary = Path::new(&path).with_file_name(format!(".{}.tmp", file_name));
+    let write_error = |source| {
+        rlua::Error::external(error::Error::Write {
+            path: path.clone(),
+            source,
+        })
+    };
+    std::fs::write(&temporary, &writer.out).map_err(write_error)?;
+    std::fs::rename(&temporary, &path).map_err(move |source| {
+        let _ = std::fs::remove_file(&temporary);
+        write_error(source)
+    })
+}
+
+fn load<'lua>(
+    lua_ctx: Context<'lua>,
+    (path, default): (String, Value<'lua>),
+) -> Result<Value<'lua>> {
+    let bytes = match std::fs::read(&path) {
+        Ok(bytes) => bytes,
+        Err(error)
+            if error.kind() == std::io::ErrorKind::NotFound && !matches!(default, Value::Nil) =>
+        {
+            return Ok(default)
+        }
+        Err(source) => return Err(rlua::Error::external(error::Error::Read { path, source })),
+    };
+    if !bytes.starts_with(MAGIC) {
+        return Err(decode_error("the file wasn't saved by persist.save"));
+    }
+    let mut reader = Reader {
+        bytes: &bytes,
+        position: MAGIC.len(),
+        tables: Vec::new(),
+    };
+    let version = reader.byte()?;
+    if version != VERSION {
+        return Err(decode_error(&format!(
+            "the file is from version {} of the format, which this rluaterm can't read",
+            version
+        )));
+    }
+    let value = reader.read(lua_ctx, 0)?;
+    if reader.position != bytes.len() {
+        return Err(decode_error("there's more after the saved value"));
+    }
+    Ok(value)
+}
+
+pub fn load_persist_library(lua: &Lua) -> Result<()> {
+    lua.context(|lua_ctx| {
+        let persist = lua_ctx.create_table()?;
+
+        persist.set("save", lua_ctx.create_function(save)?)?;
+        persist.set("load", lua_ctx.create_function(load)?)?;
+
+        help::register_module_docs(
+            lua_ctx,
+            "persist",
+            "Saving Lua values to files between runs",
+            &persist,
+            PERSIST_DOCS,
+        )?;
+        lua_ctx.globals().set("persist", persist)?;
+        Ok(())
+    })
+}
@@ -0,0 +1,581 @@
+/*
+   Copyright (C) 2022  Kalka
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License, or (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use rlua::{
+    AnyUserData, Context, Function, Lua, MetaMethod, Result, Table, UserData, UserDataMethods,
+    Value,
+};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::process::{Child, ChildStdin, Command, ExitStatus, Stdio};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::error::Error;
+use crate::help::{self, FunctionDoc};
+use crate::pty;
+
+/// How often a running command is checked on, so Ctrl+C and --timeout can stop it.
+pub const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+const EXEC_DESCRIPTION: &str = "Runs a command and waits for it to finish, returning a table with its stdout, stderr, exit code and whether it succeeded (ok). A string is run by the shell, sh on Unix and cmd on Windows, while a table of the program and its arguments is run directly, so arguments don't need quoting. options.cwd sets the directory it runs in, options.env adds environment variables, and options.stdin is written to its input. Raises an error saying why when the command can't be started.";
+
+const PROC_DOCS: &[FunctionDoc] = &[
+    FunctionDoc {
+        name: "spawn",
+        signature: "proc.spawn{cmd, args, cwd, env, on_stdout, on_stderr} -> process",
+        description: "Starts the program cmd with the list of args, without a shell, and returns right away. on_stdout and on_stderr are called with each line the process writes, without its line ending, while process:wait() or process:poll() runs; output without a callback goes to the terminal. process:wait([timeout]) closes its input, calls the callbacks until it exits and returns the exit code, or nil when the timeout in seconds passes first. process:poll() calls the callbacks for the output so far and returns the exit code, or nil while it's running. process:write_stdin(data) writes to its input, process:close_stdin() closes it, process:kill() kills it, and process.pid is its id. Raises an error saying why when the program can't be started.",
+        example: "local server = proc.spawn{\n    cmd = \"python3\",\n    args = {\"-m\", \"http.server\"},\n    on_stderr = function(line) log.info(line) end,\n}\nserver:wait(10)\nserver:kill()",
+    },
+    FunctionDoc {
+        name: "pipeline",
+        signature: "proc.pipeline{command, ...} -> result",
+        description: "Runs the commands together like a shell pipe, each one's output going to the next one's input, and waits for them all. Commands are tables of the program and its arguments, run without a shell, or strings run by the shell. Returns a table with the stdout of the last command, its exit code, the codes of every command, and ok, which is true when they all succeeded. stdin = data writes the data to the first command's input, capture = false sends the last command's output to the terminal instead, and cwd and env apply to every command. Their stderr goes to the terminal. Raises an error saying why when a command can't be started.",
+        example: "local errors = proc.pipeline{{\"cat\", \"big.log\"}, {\"grep\", \"ERROR\"}, {\"wc\", \"-l\"}}\nprint(tonumber(errors.stdout))",
+    },
+    FunctionDoc {
+        name: "spawn_pty",
+        signature: "proc.spawn_pty(command[, options]) -> process",
+        description: "Starts a command in a pseudo-terminal, for programs like ssh, top and REPLs that only behave interactively when they're talking to a terminal. The command is a string run by the shell or a table of the program and its arguments. The terminal has the size of rluaterm's and follows it when it's resized, or options.columns by options.rows. process:read([timeout]) returns the raw bytes it printed, escape sequences and all, waiting up to the timeout in seconds for some and returning \"\" when none came, or nil once it's done. process:write(data) types into it, process:resize(columns, rows) resizes the terminal, process:kill() kills it and process.pid is its id. process:wait([timeout]) hands its output to options.on_output, or prints it when there's no callback, until it exits and returns the exit code, or nil when the timeout passes first. On Unix only. Raises an error saying why when the command can't be started.",
+        example: "local python = proc.spawn_pty({\"python3\", \"-i\"})\npython:write(\"print(6 * 7)\\n\")\nlocal output = \"\"\nrepeat\n    output = output .. python:read(1)\nuntil output:find(\"42\")\npython:write(\"exit()\\n\")\npython:wait()",
+    },
+];
+
+/// The error raised when a command can't be started or waited for.
+pub fn spawn_error(name: &str, source: std::io::Error) -> rlua::Error {
+    rlua::Error::external(Error::Spawn {
+        command: name.to_string(),
+        source,
+    })
+}
+
+/// The command to run and its name for errors. A string goes through the shell and a table of
+/// the program and its arguments is run as it is.
+pub fn command_line(command: Value, function: &str) -> Result<(Command, String)> {
+    match command {
+        Value::String(line) => {
+            let line = line.to_str()?.to_string();
+            let mut command = if cfg!(windows) {
+                let mut command = Command::new("cmd");
+                command.arg("/C");
+                command
+            } else {
+                let mut command = Command::new("sh");
+                command.arg("-c");
+                command
+            };
+            command.arg(&line);
+            Ok((command, line))
+        }
+        Value::Table(argv) => {
+            let argv = argv
+                .sequence_values::<String>()
+                .collect::<Result<Vec<_>>>()?;
+            let Some((program, args)) = argv.split_first() else {
+                return Err(rlua::Error::RuntimeError(format!(
+                    "bad argument #1 to '{}' (empty command)",
+                    function
+                )));
+            };
+            let mut command = Command::new(program);
+            command.args(args);
+            Ok((command, argv.join(" ")))
+        }
+        command => Err(rlua::Error::RuntimeError(format!(
+            "bad argument #1 to '{}' (string or table expected, got {})",
+            function,
+            command.type_name()
+        ))),
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Stream {
+    Stdout,
+    Stderr,
+}
+
+impl Stream {
+    fn callback(self) -> &'static str {
+        match self {
+            Stream::Stdout => "on_stdout",
+            Stream::Stderr => "on_stderr",
+        }
+    }
+}
+
+// Sends each line from the pipe to the process's handle. Lines keep being read after the
+// handle is gone, so the child never blocks on a full pipe.
+fn forward_lines<R: Read + Send + 'static>(
+    pipe: R,
+    stream: Stream,
+    sender: Sender<(Stream, Vec<u8>)>,
+) {
+    thread::spawn(move || {
+        let mut reader = BufReader::new(pipe);
+        loop {
+            let mut line = Vec::new();
+            match reader.read_until(b'\n', &mut line) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {
+                    if line.ends_with(b"\n") {
+                        line.pop();
+                        if line.ends_with(b"\r") {
+                            line.pop();
+                        }
+                    }
+                    let _ = sender.send((stream, line));
+                }
+            }
+        }
+    });
+}
+
+// A process started with proc.spawn. Its callbacks are kept in the userdata's user value.
+struct Process {
+    name: String,
+    child: Child,
+    stdin: Option<ChildStdin>,
+    output: Receiver<(Stream, Vec<u8>)>,
+    // Set once every piped stream is closed
+    output_done: bool,
+    status: Option<ExitStatus>,
+}
+
+impl Process {
+    // The lines received so far, waiting up to the timeout for the first one
+    fn receive(&mut self, timeout: Duration) -> Vec<(Stream, Vec<u8>)> {
+        if self.output_done {
+            thread::sleep(timeout);
+            return Vec::new();
+        }
+        let mut lines = Vec::new();
+        match self.output.recv_timeout(timeout) {
+            Ok(line) => lines.push(line),
+            Err(RecvTimeoutError::Timeout) => return lines,
+            Err(RecvTimeoutError::Disconnected) => {
+                self.output_done = true;
+                return lines;
+            }
+        }
+        lines.extend(self.output.try_iter());
+        lines
+    }
+
+    // The exit code once the process has exited and all of its output was received
+    fn exit_code(&mut self) -> Result<Option<i32>> {
+        if self.status.is_none() {
+            self.status = self
+                .child
+                .try_wait()
+                .map_err(|source| spawn_error(&self.name, source))?;
+        }
+        Ok(match self.status {
+            Some(status) if self.output_done => Some(exit_code(status)),
+            _ => None,
+        })
+    }
+
+    fn kill(&mut self) {
+        if self.status.is_none() {
+            let _ = self.child.kill();
+            self.status = self.child.wait().ok();
+        }
+    }
+}
+
+impl UserData for Process {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_function(
+            "wait",
+            |lua_ctx, (process, timeout): (AnyUserData, Option<f64>)| {
+                wait_process(lua_ctx, &process, timeout)
+            },
+        );
+        methods.add_function("poll", |lua_ctx, process: AnyUserData| {
+            dispatch(lua_ctx, &process, Duration::ZERO)
+        });
+        methods.add_method_mut("write_stdin", |_, process, data: rlua::String| {
+            let stdin = process.stdin.as_mut().ok_or_else(|| {
+                rlua::Error::RuntimeError(format!("stdin of {} is closed", process.name))
+            })?;
+            stdin
+                .write_all(data.as_bytes())
+                .and_then(|()| stdin.flush())
+                .map_err(|source| {
+                    rlua::Error::external(Error::Write {
+                        path: format!("stdin of {}", process.name),
+                        source,
+                    })
+                })
+        });
+        methods.add_method_mut("close_stdin", |_, process, ()| {
+            process.stdin = None;
+            Ok(())
+        });
+        methods.add_method_mut("kill", |_, process, ()| {
+            process.kill();
+            Ok(())
+        });
+        methods.add_meta_method(MetaMethod::Index, |_, process, key: String| {
+            Ok(match key.as_str() {
+                "pid" => Some(process.child.id()),
+                _ => None,
+            })
+        });
+        methods.add_meta_method(MetaMethod::ToString, |_, process, ()| {
+            Ok(format!("process {} ({})", process.child.id(), process.name))
+        });
+    }
+}
+
+// Calls the callbacks for the lines received within the timeout, returning the exit code once
+// the process is done. The handle isn't borrowed while the callbacks run, so they can use it.
+fn dispatch<'lua>(
+    lua_ctx: Context<'lua>,
+    process: &AnyUserData<'lua>,
+    timeout: Duration,
+) -> Result<Option<i32>> {
+    let (lines, code) = {
+        let mut process = process.borrow_mut::<Process>()?;
+        let lines = process.receive(timeout);
+        (lines, process.exit_code()?)
+    };
+    let callbacks = process.get_user_value::<Table>()?;
+    for (stream, line) in lines {
+        if let Some(callback) = callbacks.get::<_, Option<Function>>(stream.callback())? {
+            callback.call::<_, ()>(lua_ctx.create_string(&line)?)?;
+        }
+    }
+    Ok(code)
+}
+
+fn wait_process<'lua>(
+    lua_ctx: Context<'lua>,
+    process: &AnyUserData<'lua>,
+    timeout: Option<f64>,
+) -> Result<Option<i32>> {
+    // The process would wait for more input forever otherwise
+    process.borrow_mut::<Process>()?.stdin = None;
+    let deadline =
+        timeout.map(|timeout| Instant::now() + Duration::from_secs_f64(timeout.max(0.0)));
+    loop {
+        if let Some(code) = dispatch(lua_ctx, process, POLL_INTERVAL)? {
+            return Ok(Some(code));
+        }
+        if let Err(error) = crate::check_interrupt(lua_ctx) {
+            process.borrow_mut::<Process>()?.kill();
+            return Err(error);
+        }
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return Ok(None);
+        }
+    }
+}
+
+fn spawn<'lua>(lua_ctx: Context<'lua>, options: Table<'lua>) -> Result<AnyUserData<'lua>> {
+    let program = options.get::<_, Option<String>>("cmd")?.ok_or_else(|| {
+        rlua::Error::RuntimeError("bad argument #1 to 'spawn' (cmd expected)".to_string())
+    })?;
+    let args = match options.get::<_, Option<Table>>("args")? {
+        Some(args) => args
+            .sequence_values::<String>()
+            .collect::<Result<Vec<_>>>()?,
+        None => Vec::new(),
+    };
+    let callbacks = lua_ctx.create_table()?;
+    for stream in [Stream::Stdout, Stream::Stderr] {
+        callbacks.set(
+            stream.callback(),
+            options.get::<_, Option<Function>>(stream.callback())?,
+        )?;
+    }
+    let piped = |stream: Stream| -> Result<Stdio> {
+        Ok(match callbacks.contains_key(stream.callback())? {
+            true => Stdio::piped(),
+            false => Stdio::inherit(),
+        })
+    };
+
+    let mut command = Command::new(&program);
+    command
+        .args(&args)
+        .stdin(Stdio::piped())
+        .stdout(piped(Stream::Stdout)?)
+        .stderr(piped(Stream::Stderr)?);
+    configure(&mut command, Some(&options))?;
+    let name = std::iter::once(&program)
+        .chain(&args)
+        .cloned()
+        .collect::<Vec<_>>()
+        .join(" ");
+    let mut child = command
+        .spawn()
+        .map_err(|source| spawn_error(&name, source))?;
+
+    let (sender, output) = mpsc::channel();
+    if let Some(stdout) = child.stdout.take() {
+        forward_lines(stdout, Stream::Stdout, sender.clone());
+    }
+    if let Some(stderr) = child.stderr.take() {
+        forward_lines(stderr, Stream::Stderr, sender.clone());
+    }
+    let process = lua_ctx.create_userdata(Process {
+        name,
+        stdin: child.stdin.take(),
+        child,
+        output,
+        output_done: false,
+        status: None,
+    })?;
+    process.set_user_value(callbacks)?;
+    Ok(process)
+}
+
+/// Applies the cwd and env options to the command.
+pub fn configure(command: &mut Command, options: Option<&Table>) -> Result<()> {
+    let Some(options) = options else {
+        return Ok(());
+    };
+    if let Some(cwd) = options.get::<_, Option<String>>("cwd")? {
+        command.current_dir(cwd);
+    }
+    if let Some(env) = options.get::<_, Option<Table>>("env")? {
+        for pair in env.pairs::<String, String>() {
+            let (name, value) = pair?;
+            command.env(name, value);
+        }
+    }
+    Ok(())
+}
+
+// Reads everything from the pipe on a thread of its own, so a child filling one pipe doesn't
+// block while the other is being read
+fn read_all<R: Read + Send + 'static>(pipe: Option<R>) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut bytes = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut bytes);
+        }
+        bytes
+    })
+}
+
+fn kill_all(children: &mut [(Child, String)]) {
+    for (child, _) in children {
+        let _ = child.kill();
+        let _ = child.wait();
+    }
+}
+
+// Waits for the children to exit, killing them all when the script is interrupted or times out
+fn wait(lua_ctx: Context, children: &mut [(Child, String)]) -> Result<Vec<ExitStatus>> {
+    let mut statuses = vec![None; children.len()];
+    loop {
+        for ((child, name), status) in children.iter_mut().zip(&mut statuses) {
+            if status.is_none() {
+                *status = child
+                    .try_wait()
+                    .map_err(|source| spawn_error(name, source))?;
+            }
+        }
+        if statuses.iter().all(Option::is_some) {
+            return Ok(statuses.into_iter().flatten().collect());
+        }
+        if let Err(error) = crate::check_interrupt(lua_ctx) {
+            kill_all(children);
+            return Err(error);
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// The exit code, or 128 plus the signal for a child killed by one like shells report it.
+pub fn exit_code(status: ExitStatus) -> i32 {
+    #[cfg(unix)]
+    if let Some(signal) = std::os::unix::process::ExitStatusExt::signal(&status) {
+        return 128 + signal;
+    }
+    status.code().unwrap_or(-1)
+}
+
+// The stdin option, written to the command's input
+fn input(options: Option<&Table>) -> Result<Option<Vec<u8>>> {
+    Ok(match options {
+        Some(options) => options
+            .get::<_, Option<rlua::String>>("stdin")?
+            .map(|input| input.as_bytes().to_vec()),
+        None => None,
+    })
+}
+
+fn write_input(stdin: Option<ChildStdin>, input: Option<Vec<u8>>) {
+    if let (Some(mut stdin), Some(input)) = (stdin, input) {
+        // Writing ends when the child stops reading, which isn't an error
+        thread::spawn(move || {
+            let _ = stdin.write_all(&input);
+        });
+    }
+}
+
+fn exec<'lua>(
+    lua_ctx: Context<'lua>,
+    command: Value<'lua>,
+    options: Option<Table<'lua>>,
+) -> Result<Table<'lua>> {
+    let (mut command, name) = command_line(command, "exec")?;
+    configure(&mut command, options.as_ref())?;
+    let input = input(options.as_ref())?;
+    command
+        .stdin(if input.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    let mut child = command
+        .spawn()
+        .map_err(|source| spawn_error(&name, source))?;
+
+    write_input(child.stdin.take(), input);
+    let stdout = read_all(child.stdout.take());
+    let stderr = read_all(child.stderr.take());
+    let status = wait(lua_ctx, &mut [(child, name)])?[0];
+
+    let result = lua_ctx.create_table()?;
+    result.set(
+        "stdout",
+        lua_ctx.create_string(&stdout.join().unwrap_or_default())?,
+    )?;
+    result.set(
+        "stderr",
+        lua_ctx.create_string(&stderr.join().unwrap_or_default())?,
+    )?;
+    result.set("code", exit_code(status))?;
+    result.set("ok", status.success())?;
+    Ok(result)
+}
+
+// Runs the commands with each one's output going to the next one's input
+fn pipeline<'lua>(lua_ctx: Context<'lua>, stages: Table<'lua>) -> Result<Table<'lua>> {
+    let mut commands = stages
+        .clone()
+        .sequence_values::<Value>()
+        .map(|stage| command_line(stage?, "pipeline"))
+        .collect::<Result<Vec<_>>>()?;
+    if commands.is_empty() {
+        return Err(rlua::Error::RuntimeError(
+            "bad argument #1 to 'pipeline' (no commands)".to_string(),
+        ));
+    }
+    for (command, _) in &mut commands {
+        configure(command, Some(&stages))?;
+    }
+    let input = input(Some(&stages))?;
+    let capture = stages.get::<_, Option<bool>>("capture")?.unwrap_or(true);
+
+    let count = commands.len();
+    let mut children: Vec<(Child, String)> = Vec::new();
+    let mut previous_output = None;
+    for (index, (mut command, name)) in commands.into_iter().enumerate() {
+        let stdin = match previous_output.take() {
+            Some(output) => Stdio::from(output),
+            None if input.is_some() => Stdio::piped(),
+            None => Stdio::null(),
+        };
+        let stdout = match index + 1 == count && !capture {
+            true => Stdio::inherit(),
+            false => Stdio::piped(),
+        };
+        match command.stdin(stdin).stdout(stdout).spawn() {
+            Ok(mut child) => {
+                previous_output = child.stdout.take();
+                children.push((child, name));
+            }
+            Err(source) => {
+                kill_all(&mut children);
+                return Err(spawn_error(&name, source));
+            }
+        }
+    }
+    write_input(children[0].0.stdin.take(), input);
+    let stdout = read_all(previous_output);
+    let statuses = wait(lua_ctx, &mut children)?;
+
+    let result = lua_ctx.create_table()?;
+    if capture {
+        result.set(
+            "stdout",
+            lua_ctx.create_string(&stdout.join().unwrap_or_default())?,
+        )?;
+    }
+    let codes = statuses
+        .iter()
+        .map(|status| exit_code(*status))
+        .collect::<Vec<_>>();
+    result.set("code", codes[count - 1])?;
+    result.set("codes", codes)?;
+    result.set("ok", statuses.iter().all(ExitStatus::success))?;
+    Ok(result)
+}
+
+/// Adds `os.exec` to the os library.
+pub fn attach_exec(lua: &Lua) -> Result<()> {
+    lua.context(|lua_ctx| {
+        let function =
+            lua_ctx.create_function(|lua_ctx, (command, options): (Value, Option<Table>)| {
+                exec(lua_ctx, command, options)
+            })?;
+        help::attach_doc(
+            lua_ctx,
+            function.clone(),
+            Some("os.exec(command[, options]) -> result"),
+            EXEC_DESCRIPTION,
+        )?;
+        lua_ctx
+            .globals()
+            .get::<_, Table>("os")?
+            .set("exec", function)
+    })
+}
+
+pub fn load_proc_library(lua: &Lua) -> Result<()> {
+    lua.context(|lua_ctx| {
+        let proc = lua_ctx.create_table()?;
+
+        proc.set(
+            "spawn",
+            lua_ctx.create_function(|lua_ctx, options: Table| spawn(lua_ctx, options))?,
+        )?;
+
+        proc.set(
+            "pipeline",
+            lua_ctx.create_function(|lua_ctx, stages: Table| pipeline(lua_ctx, stages))?,
+        )?;
+
+        proc.set(
+            "spawn_pty",
+            lua_ctx.create_function(|lua_ctx, (command, options): (Value, Option<Table>)| {
+                pty::spawn_pty(lua_ctx, command, options)
+            })?,
+        )?;
+
+        help::register_module_docs(lua_ctx, "proc", "Child processes", &proc, PROC_DOCS)?;
+        lua_ctx.globals().set("proc", proc)?;
+        Ok(())
+    })
+}
@@ -0,0 +1,295 @@
+/*
+   Copyright (C) 2022  Kalka
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License, or (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use rlua::{Function, Lua, Result, Table};
+use std::sync::OnceLock;
+use std::time::Instant;
+
+use crate::help::{self, FunctionDoc};
+use crate::traceback;
+
+// Functions in these chunks are rluaterm's own and left out of reports, along with the C
+// functions they call
+const INTERNAL_SOURCES: &[&str] = &["=traceback", "=profiler"];
+
+// Rows of the report printed by profiler.report() and --profile
+const REPORT_ROWS: usize = 30;
+
+// Returns `start`, `stop`, `report` and `folded`. While running, a call and return hook times every
+// function: its total time counts the outermost call of recursive functions once, its self time
+// leaves out the functions it called. The hook also runs the interrupt checks every `interval`
+// instructions, and keeps doing only that once stopped, since it replaces the interrupt hook.
+const PROFILER_SOURCE: &str = r#"
+local sethook, getinfo, now, check, interval, internal_sources, format_report = ...
+local pairs, format, sort, concat = pairs, string.format, table.sort, table.concat
+
+local internal = {}
+for _, source in ipairs(internal_sources) do
+    internal[source] = true
+end
+
+local stats, folded, stack = {}, {}, {}
+local running = false
+local last_results, last_folded = {}, {}
+
+local function label(info)
+    if info.what == "main" then
+        return format("main chunk (%s)", info.short_src)
+    end
+    local name = info.name or "anonymous"
+    if info.what == "C" then
+        return name .. " [C]"
+    end
+    return format("%s (%s:%d)", name, info.short_src, info.linedefined)
+end
+
+local function enter(time, info)
+    local parent = stack[#stack]
+    local frame = { func = info.func, start = time, children = 0, path = parent and parent.path }
+    stack[#stack + 1] = frame
+    -- C functions called by rluaterm itself, rather than by a Lua function, aren't timed
+    if internal[info.source] or (info.what == "C" and (not parent or not parent.stat)) then
+        return
+    end
+    local stat = stats[info.func]
+    if not stat then
+        stat = { name = label(info), calls = 0, total = 0, self = 0, active = 0 }
+        stats[info.func] = stat
+    end
+    stat.calls = stat.calls + 1
+    stat.active = stat.active + 1
+    frame.stat = stat
+    local name = stat.name:gsub(";", ",")
+    frame.path = frame.path and (frame.path .. ";" .. name) or name
+end
+
+local function leave(time)
+    local frame = stack[#stack]
+    stack[#stack] = nil
+    local elapsed = time - frame.start
+    local parent = stack[#stack]
+    if parent then
+        parent.children = parent.children + elapsed
+    end
+    local stat = frame.stat
+    if not stat then
+        return
+    end
+    local self_time = elapsed - frame.children
+    stat.self = stat.self + self_time
+    stat.active = stat.active - 1
+    if stat.active == 0 then
+        stat.total = stat.total + elapsed
+    end
+    folded[frame.path] = (folded[frame.path] or 0) + self_time
+end
+
+local function hook(event)
+    if event == "count" then
+        return check()
+    end
+    local time = now()
+    if event == "return" then
+        -- Frames unwound by an error never return, they end with the one that does
+        local func = getinfo(2, "f").func
+        local index = #stack
+        while index > 0 and stack[index].func ~= func do
+            index = index - 1
+        end
+        -- Functions called before the profiler started aren't timed
+        if index == 0 then
+            return
+        end
+        for _ = index, #stack do
+            leave(time)
+        end
+        return
+    end
+    if event == "tail call" and #stack > 0 then
+        leave(time)
+    end
+    enter(time, getinfo(2, "fSn"))
+end
+
+local function start()
+    stats, folded, stack = {}, {}, {}
+    running = true
+    sethook(hook, "cr", interval)
+end
+
+local function stop()
+    if not running then
+        return nil
+    end
+    sethook(hook, "", interval)
+    running = false
+    local time = now()
+    while #stack > 0 do
+        leave(time)
+    end
+    last_folded = folded
+    local results = {}
+    for _, stat in pairs(stats) do
+        results[#results + 1] = {
+            name = stat.name,
+            calls = stat.calls,
+            total = stat.total,
+            self = stat.self,
+        }
+    end
+    sort(results, function(a, b)
+        return a.self > b.self
+    end)
+    last_results = results
+    return results
+end
+
+local function report(results)
+    print(format_report(results or last_results))
+end
+
+local function folded_stacks()
+    local lines = {}
+    for path, time in pairs(last_folded) do
+        local microseconds = math.floor(time * 1e6 + 0.5)
+        if microseconds > 0 then
+            lines[#lines + 1] = path .. " " .. microseconds
+        end
+    end
+    sort(lines)
+    return concat(lines, "\n")
+end
+
+return start, stop, report, folded_stacks
+"#;
+
+const PROFILER_DOCS: &[FunctionDoc] = &[
+    FunctionDoc {
+        name: "start",
+        signature: "profiler.start()",
+        description: "Starts timing every function call, discarding the previous results",
+        example: "profiler.start()",
+    },
+    FunctionDoc {
+        name: "stop",
+        signature: "profiler.stop() -> results",
+        description: "Stops the profiler and returns a list of {name, calls, total, self} with times in seconds, sorted by self time",
+        example: "local results = profiler.stop()\nprint(results[1].name, results[1].self)",
+    },
+    FunctionDoc {
+        name: "report",
+        signature: "profiler.report([results])",
+        description: "Prints the slowest functions of the results, or of the last profile",
+        example: "profiler.start()\nwork()\nprofiler.report(profiler.stop())",
+    },
+    FunctionDoc {
+        name: "folded",
+        signature: "profiler.folded() -> string",
+        description: "The last profile as folded stacks with self times in microseconds, for flamegraph.pl, inferno or speedscope",
+        example: "local file = io.open(\"profile.folded\", \"w\")\nfile:write(profiler.folded())\nfile:close()",
+    },
+];
+
+/// Seconds since the first call, from a monotonic clock.
+pub fn now() -> f64 {
+    static START: OnceLock<Instant> = OnceLock::new();
+    START.get_or_init(Instant::now).elapsed().as_secs_f64()
+}
+
+/// The report of the profiler's results, slowest functions by self time first.
+pub fn format_report(results: &Table) -> Result<String> {
+    let mut report = format!(
+        "{:>10} {:>12} {:>12}  {}\n",
+        "calls", "total ms", "self ms", "function"
+    );
+    let count = results.raw_len() as usize;
+    for row in results.clone().sequence_values::<Table>().take(REPORT_ROWS) {
+        let row = row?;
+        report.push_str(&format!(
+            "{:>10} {:>12.3} {:>12.3}  {}\n",
+            row.get::<_, i64>("calls")?,
+            row.get::<_, f64>("total")? * 1000.0,
+            row.get::<_, f64>("self")? * 1000.0,
+            row.get::<_, String>("name")?
+        ));
+    }
+    if count > REPORT_ROWS {
+        report.push_str(&format!("and {} more functions\n", count - REPORT_ROWS));
+    }
+    Ok(report.trim_end().to_string())
+}
+
+pub fn load_profiler_library(lua: &Lua) -> Result<()> {
+    lua.context(|lua_ctx| {
+        let now = lua_ctx.create_function(|_, ()| Ok(now()))?;
+        let check = lua_ctx.create_function(|lua_ctx, ()| crate::check_interrupt(lua_ctx))?;
+        let format_report = lua_ctx.create_function(|_, results: Table| format_report(&results))?;
+        let (start, stop, report, folded) =
+            lua_ctx
+                .load(PROFILER_SOURCE)
+                .set_name("=profiler")?
+                .call::<_, (Function, Function, Function, Function)>((
+                    traceback::sethook(lua_ctx)?,
+                    traceback::getinfo(lua_ctx)?,
+                    now,
+                    check,
+                    crate::INTERRUPT_CHECK_INTERVAL,
+                    INTERNAL_SOURCES.to_vec(),
+                    format_report,
+                ))?;
+
+        let profiler = lua_ctx.create_table()?;
+        profiler.set("start", start)?;
+        profiler.set("stop", stop)?;
+        profiler.set("report", report)?;
+        profiler.set("folded", folded)?;
+        help::register_module_docs(
+            lua_ctx,
+            "profiler",
+            "Timing functions",
+            &profiler,
+            PROFILER_DOCS,
+        )?;
+        lua_ctx.globals().set("profiler", profiler)?;
+        Ok(())
+    })
+}
+
+/// Starts the profiler, as `profiler.start()` does.
+pub fn start(lua: &Lua) -> Result<()> {
+    lua.context(|lua_ctx| {
+        let profiler = lua_ctx.globals().get::<_, Table>("profiler")?;
+        profiler.get::<_, Function>("start")?.call(())
+    })
+}
+
+/// Stops the profiler and returns its report and folded stacks, nothing if it wasn't running.
+pub fn stop(lua: &Lua) -> Result<Option<(String, String)>> {
+    lua.context(|lua_ctx| {
+        let profiler = lua_ctx.globals().get::<_, Table>("profiler")?;
+        let results = match profiler
+            .get::<_, Function>("stop")?
+            .call::<_, Option<Table>>(())?
+        {
+            Some(results) => results,
+            None => return Ok(None),
+        };
+        let folded = profiler
+            .get::<_, Function>("folded")?
+            .call::<_, String>(())?;
+        Ok(Some((format_report(&results)?, folded)))
+    })
+}
@@ -0,0 +1,341 @@
+/*
+   Copyright (C) 2022  Kalka
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License, or (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use rlua::{Lua, Result, Table, Value};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::install::{self, Spec};
+use crate::{require, timeout};
+
+// Project settings, read from the project directory
+const CONFIG_FILE: &str = "rluaterm.toml";
+// The same settings as Lua globals, for projects without an rluaterm.toml
+const LUA_MANIFEST_FILE: &str = "project.lua";
+/// Versions of the packages installed from the manifest's dependencies.
+pub const LOCK_FILE: &str = "rluaterm.lock";
+// Scripts run when the config doesn't name one, in order of preference
+const ENTRY_FILES: &[&str] = &["init.lua", "main.lua"];
+// The Lua version rlua is built with, checked against the project's requirement
+const LUA_VERSION: &str = "5.4";
+// Environment variable listing more directories to load modules from
+const PATH_VARIABLE: &str = "RLUATERM_PATH";
+#[cfg(windows)]
+const C_MODULE_EXTENSION: &str = "dll";
+#[cfg(not(windows))]
+const C_MODULE_EXTENSION: &str = "so";
+
+/// A directory run as a program, with the script it starts at, the other directories its
+/// modules live in, and the options it runs with.
+pub struct Project {
+    pub root: PathBuf,
+    pub entry: PathBuf,
+    pub module_dirs: Vec<PathBuf>,
+    pub strict: bool,
+    pub timeout: Option<Duration>,
+}
+
+// A project's settings, with the file they were read from for error messages
+struct Manifest {
+    path: PathBuf,
+    settings: toml::Table,
+}
+
+impl Manifest {
+    // The manifest of the project in the directory, from its rluaterm.toml or else its
+    // project.lua, empty if it has neither
+    fn read(root: &Path) -> std::result::Result<Manifest, String> {
+        let path = root.join(CONFIG_FILE);
+        if let Ok(text) = std::fs::read_to_string(&path) {
+            let settings = text
+                .parse::<toml::Table>()
+                .map_err(|error| format!("{}: {}", path.display(), error.message()))?;
+            return Ok(Manifest { path, settings });
+        }
+        let lua_path = root.join(LUA_MANIFEST_FILE);
+        if let Ok(source) = std::fs::read(&lua_path) {
+            let settings =
+                install::evaluate(&source, LUA_MANIFEST_FILE, |globals| {
+                    match to_toml(Value::Table(globals))? {
+                        toml::Value::Table(settings) => Ok(settings),
+                        _ => Ok(toml::Table::new()),
+                    }
+                })
+                .map_err(|error| format!("{}: {}", lua_path.display(), error))?;
+            return Ok(Manifest {
+                path: lua_path,
+                settings,
+            });
+        }
+        Ok(Manifest {
+            path,
+            settings: toml::Table::new(),
+        })
+    }
+
+    fn table(&self, name: &str) -> std::result::Result<Option<&toml::Table>, String> {
+        match self.settings.get(name) {
+            Some(value) => value
+                .as_table()
+                .map(Some)
+                .ok_or_else(|| self.error(&format!("{} must be a table", name))),
+            None => Ok(None),
+        }
+    }
+
+    fn setting(&self, key: &str) -> std::result::Result<Option<&toml::Value>, String> {
+        Ok(self
+            .table("project")?
+            .and_then(|settings| settings.get(key)))
+    }
+
+    fn string(&self, key: &str) -> std::result::Result<Option<&str>, String> {
+        match self.setting(key)? {
+            Some(value) => value
+                .as_str()
+                .map(Some)
+                .ok_or_else(|| self.error(&format!("project.{} must be a string", key))),
+            None => Ok(None),
+        }
+    }
+
+    fn error(&self, message: &str) -> String {
+        format!("{}: {}", self.path.display(), message)
+    }
+}
+
+// Converts the globals a project.lua assigns into the settings an rluaterm.toml holds
+fn to_toml(value: Value) -> Result<toml::Value> {
+    Ok(match value {
+        Value::Boolean(boolean) => toml::Value::Boolean(boolean),
+        Value::Integer(integer) => toml::Value::Integer(integer),
+        Value::Number(number) => toml::Value::Float(number),
+        Value::String(string) => toml::Value::String(string.to_str()?.to_string()),
+        Value::Table(table) => {
+            if table.raw_len() > 0 {
+                let mut array = Vec::new();
+                for value in table.sequence_values::<Value>() {
+                    array.push(to_toml(value?)?);
+                }
+                toml::Value::Array(array)
+            } else {
+                let mut settings = toml::Table::new();
+                for pair in table.pairs::<String, Value>() {
+                    let (key, value) = pair?;
+                    settings.insert(key, to_toml(value)?);
+                }
+                toml::Value::Table(settings)
+            }
+        }
+        value => {
+            return Err(rlua::Error::RuntimeError(format!(
+                "{} can't be a setting",
+                value.type_name()
+            )))
+        }
+    })
+}
+
+/// Reads the project in the directory. Its `rluaterm.toml`, or `project.lua` assigning the same
+/// settings as globals, can name the entry script, directories to load modules from relative to
+/// the project, the Lua version it needs, and options to run it with:
+///
+/// ```toml
+/// [project]
+/// entry = "src/main.lua"
+/// path = ["lib"]
+/// lua = ">= 5.4"
+/// strict = true
+/// timeout = "30s"
+/// ```
+pub fn open(root: &Path) -> std::result::Result<Project, String> {
+    let manifest = Manifest::read(root)?;
+    let entry = match manifest.string("entry")? {
+        Some(entry) => root.join(entry),
+        None => ENTRY_FILES
+            .iter()
+            .map(|name| root.join(name))
+            .find(|path| path.is_file())
+            .ok_or_else(|| format!("no {} or {} to run", ENTRY_FILES[0], ENTRY_FILES[1]))?,
+    };
+    project(root, &manifest, entry)
+}
+
+/// Reads the project in the directory to run one of its scripts, named in its `[scripts]`
+/// table, like `deploy = "scripts/deploy.lua --production"`. Returns the project with the
+/// script as its entry and the arguments that come with it, or nothing if there's no such
+/// script.
+pub fn open_script(
+    root: &Path,
+    name: &str,
+) -> std::result::Result<Option<(Project, Vec<String>)>, String> {
+    let manifest = Manifest::read(root)?;
+    let command = match manifest
+        .table("scripts")?
+        .and_then(|scripts| scripts.get(name))
+    {
+        Some(command) => command
+            .as_str()
+            .ok_or_else(|| manifest.error(&format!("scripts.{} must be a string", name)))?,
+        None => return Ok(None),
+    };
+    let mut words = command.split_whitespace().map(str::to_string);
+    let script = words
+        .next()
+        .ok_or_else(|| manifest.error(&format!("scripts.{} is empty", name)))?;
+    let project = project(root, &manifest, root.join(script))?;
+    Ok(Some((project, words.collect())))
+}
+
+fn project(
+    root: &Path,
+    manifest: &Manifest,
+    entry: PathBuf,
+) -> std::result::Result<Project, String> {
+    if !entry.is_file() {
+        return Err(format!("{} does not exist", entry.display()));
+    }
+    if let Some(constraints) = manifest.string("lua")? {
+        if !install::version_satisfies(LUA_VERSION, constraints)
+            .map_err(|error| manifest.error(&error))?
+        {
+            return Err(format!(
+                "the project needs Lua {}, rluaterm runs Lua {}",
+                constraints, LUA_VERSION
+            ));
+        }
+    }
+
+    let mut module_dirs = Vec::new();
+    if let Some(paths) = manifest.setting("path")? {
+        let list_error = || manifest.error("project.path must be a list of directories");
+        for path in paths.as_array().ok_or_else(list_error)? {
+            module_dirs.push(root.join(path.as_str().ok_or_else(list_error)?));
+        }
+    }
+    let strict = match manifest.setting("strict")? {
+        Some(strict) => strict
+            .as_bool()
+            .ok_or_else(|| manifest.error("project.strict must be true or false"))?,
+        None => false,
+    };
+    let timeout = match manifest.string("timeout")? {
+        Some(timeout) => Some(timeout::parse_duration(timeout).ok_or_else(|| {
+            manifest.error("project.timeout must be a time like 30s, 500ms or 2m")
+        })?),
+        None => None,
+    };
+    Ok(Project {
+        root: root.to_path_buf(),
+        entry,
+        module_dirs,
+        strict,
+        timeout,
+    })
+}
+
+/// The packages the project in the directory depends on, from its `[dependencies]` table.
+/// Rocks are given with version constraints and git repositories as tables:
+///
+/// ```toml
+/// [dependencies]
+/// inspect = "~> 3.1"
+/// json = { git = "https://github.com/rxi/json.lua", tag = "v0.1.2" }
+/// ```
+pub fn dependencies(root: &Path) -> std::result::Result<Vec<Spec>, String> {
+    let manifest = Manifest::read(root)?;
+    let dependencies = match manifest.table("dependencies")? {
+        Some(dependencies) => dependencies,
+        None => {
+            return Err(format!(
+                "no dependencies in {} or {}",
+                CONFIG_FILE, LUA_MANIFEST_FILE
+            ))
+        }
+    };
+    let mut specs = Vec::new();
+    for (name, value) in dependencies {
+        let spec = match value {
+            toml::Value::String(constraints) => Spec::Rock {
+                name: name.clone(),
+                constraints: constraints.clone(),
+            },
+            toml::Value::Table(table) => {
+                let field = |key| table.get(key).and_then(|value| value.as_str());
+                let url = field("git").ok_or_else(|| {
+                    manifest.error(&format!("dependencies.{} needs a git URL", name))
+                })?;
+                Spec::Git {
+                    name: Some(name.clone()),
+                    url: url.to_string(),
+                    reference: field("tag")
+                        .or(field("branch"))
+                        .or(field("rev"))
+                        .map(str::to_string),
+                }
+            }
+            _ => {
+                return Err(manifest.error(&format!(
+                    "dependencies.{} must be version constraints or a table with a git URL",
+                    name
+                )))
+            }
+        };
+        specs.push(spec);
+    }
+    Ok(specs)
+}
+
+/// Installs the dependencies of the project in the directory into its `lua_modules`, at the
+/// versions in its lockfile when they still meet the constraints, and locks what was installed.
+pub fn install_dependencies(root: &Path) -> std::result::Result<Vec<install::Installed>, String> {
+    let specs = dependencies(root)?;
+    let lock_path = root.join(LOCK_FILE);
+    let installed = install::install(&specs, root, install::read_lock(&lock_path)?)?;
+    install::write_lock(&lock_path, &installed)?;
+    Ok(installed)
+}
+
+/// Lets `require` find Lua modules, and C modules outside of the sandbox, in the directories
+/// before anywhere else, wherever rluaterm is started from.
+pub fn add_module_dirs(lua: &Lua, dirs: &[PathBuf]) -> Result<()> {
+    lua.context(|lua_ctx| {
+        let package = lua_ctx.globals().get::<_, Table>("package")?;
+        let mut path = String::new();
+        let mut cpath = String::new();
+        for dir in dirs {
+            cpath.push_str(&format!("{}/?.{};", dir.display(), C_MODULE_EXTENSION));
+        }
+        if !dirs.is_empty() {
+            path.push_str(&require::path_template(dirs));
+            path.push(';');
+        }
+        path.push_str(&package.get::<_, String>("path")?);
+        cpath.push_str(&package.get::<_, String>("cpath")?);
+        package.set("path", path)?;
+        package.set("cpath", cpath)
+    })
+}
+
+/// Module directories given with `--path`, followed by the ones in `RLUATERM_PATH`, which is a
+/// list separated like `PATH`.
+pub fn extra_module_dirs(path_flags: &[PathBuf]) -> Vec<PathBuf> {
+    let mut dirs = path_flags.to_vec();
+    if let Some(paths) = std::env::var_os(PATH_VARIABLE) {
+        dirs.extend(std::env::split_paths(&paths).filter(|dir| !dir.as_os_str().is_empty()));
+    }
+    dirs
+}
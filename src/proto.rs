@@ -0,0 +1,508 @@
+/*
+   Copyright (C) 2022  Kalka
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License, or (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use std::path::Path;
+
+use prost_reflect::prost::Message;
+use prost_reflect::{
+    DescriptorPool, DynamicMessage, FieldDescriptor, Kind, MapKey, MessageDescriptor,
+    ReflectMessage,
+};
+use rlua::{
+    Context, LightUserData, Lua, MetaMethod, Result, Table, UserData, UserDataMethods, Value,
+};
+
+use crate::error;
+use crate::help::{self, FunctionDoc};
+
+const PROTO_DOCS: &[FunctionDoc] = &[FunctionDoc {
+    name: "load",
+    signature: "proto.load(path[, options]) -> schema",
+    description: "Compiles a .proto file and the files it imports, looked up in its own directory and the directories in options.includes. schema:encode(message, table) turns a table into the named message's bytes and schema:decode(message, bytes) turns them back, with messages named in full like \"shop.v1.Order\" or by their name alone when it's unique, and schema:messages() lists their full names. Enums are written as names or numbers and read as names, repeated fields are sequences and maps tables, and 64-bit integers past what Lua holds are read as floats. Decoded tables have every field, with defaults for those not sent, except optional, oneof and message fields, which are only there when set. Raises an error saying why when the schema can't be compiled, a table doesn't fit the message or the bytes aren't valid protobuf.",
+    example: "local schema = proto.load(\"protos/shop.proto\")\nlocal body = schema:encode(\"shop.v1.Order\", { id = 42, items = { { sku = \"A-1\", quantity = 2 } } })\nlocal order = schema:decode(\"shop.v1.Order\", body)",
+}];
+
+// Deeper tables are almost certainly cycles
+const MAX_DEPTH: usize = 100;
+
+// The messages compiled from a .proto file and its imports
+struct Schema(DescriptorPool);
+
+impl Schema {
+    fn message(&self, function: &str, name: &str) -> Result<MessageDescriptor> {
+        if let Some(message) = self.0.get_message_by_name(name) {
+            return Ok(message);
+        }
+        let mut matches = self
+            .0
+            .all_messages()
+            .filter(|message| !message.is_map_entry() && message.name() == name);
+        match (matches.next(), matches.next()) {
+            (Some(message), None) => Ok(message),
+            (Some(_), Some(_)) => Err(rlua::Error::RuntimeError(format!(
+                "bad argument #1 to '{}' (more than one message is named {}, give its full name)",
+                function, name
+            ))),
+            _ => Err(rlua::Error::RuntimeError(format!(
+                "bad argument #1 to '{}' (no message named {} in the schema)",
+                function, name
+            ))),
+        }
+    }
+}
+
+impl UserData for Schema {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method(
+            "encode",
+            |lua_ctx, schema, (name, table): (String, Table)| {
+                let message = schema.message("encode", &name)?;
+                let message = to_message(&message, &message, table, "", 0)?;
+                lua_ctx.create_string(&message.encode_to_vec())
+            },
+        );
+        methods.add_method(
+            "decode",
+            |lua_ctx, schema, (name, bytes): (String, rlua::String)| {
+                let message = schema.message("decode", &name)?;
+                let message =
+                    DynamicMessage::decode(message, bytes.as_bytes()).map_err(|error| {
+                        rlua::Error::external(error::Error::Decode {
+                            format: "protobuf",
+                            message: error
+                                .to_string()
+                                .trim_start_matches("failed to decode Protobuf message: ")
+                                .to_string(),
+                        })
+                    })?;
+                from_message(lua_ctx, &message)
+            },
+        );
+        methods.add_method("messages", |lua_ctx, schema, ()| {
+            let mut names = schema
+                .0
+                .all_messages()
+                .filter(|message| !message.is_map_entry())
+                .map(|message| message.full_name().to_string())
+                .collect::<Vec<_>>();
+            names.sort();
+            lua_ctx.create_sequence_from(names)
+        });
+        methods.add_meta_method(MetaMethod::ToString, |_, schema, ()| {
+            Ok(format!(
+                "proto.schema({} messages)",
+                schema.0.all_messages().len()
+            ))
+        });
+    }
+}
+
+fn encode_error(message: &MessageDescriptor, text: String) -> rlua::Error {
+    rlua::Error::RuntimeError(format!(
+        "cannot encode to {}: {}",
+        message.full_name(),
+        text
+    ))
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::LightUserData(LightUserData(pointer)) if pointer.is_null() => "null",
+        value => value.type_name(),
+    }
+}
+
+fn to_integer(value: &Value) -> Option<i64> {
+    match *value {
+        Value::Integer(integer) => Some(integer),
+        Value::Number(number) if number.fract() == 0.0 => {
+            // i64::MAX as f64 rounds up to 2^63, which doesn't fit
+            (number >= i64::MIN as f64 && number < i64::MAX as f64).then_some(number as i64)
+        }
+        _ => None,
+    }
+}
+
+fn to_unsigned(value: &Value) -> Option<u64> {
+    match *value {
+        Value::Integer(integer) => u64::try_from(integer).ok(),
+        Value::Number(number) if number.fract() == 0.0 => {
+            (number >= 0.0 && number < u64::MAX as f64).then_some(number as u64)
+        }
+        _ => None,
+    }
+}
+
+// The value for a single field of the given kind, or one item of a repeated field
+fn to_value(
+    root: &MessageDescriptor,
+    field: &str,
+    kind: &Kind,
+    value: Value,
+    depth: usize,
+) -> Result<prost_reflect::Value> {
+    use prost_reflect::Value as Proto;
+    let mismatch = |expected: &str, value: &Value| {
+        encode_error(
+            root,
+            format!(
+                "field '{}' needs {}, got {}",
+                field,
+                expected,
+                type_name(value)
+            ),
+        )
+    };
+    let out_of_range = |expected: &str| {
+        encode_error(
+            root,
+            format!("field '{}' is out of range for {}", field, expected),
+        )
+    };
+    Ok(match kind {
+        Kind::Double | Kind::Float => match value {
+            Value::Integer(integer) if *kind == Kind::Double => Proto::F64(integer as f64),
+            Value::Number(number) if *kind == Kind::Double => Proto::F64(number),
+            Value::Integer(integer) => Proto::F32(integer as f32),
+            Value::Number(number) => Proto::F32(number as f32),
+            value => return Err(mismatch("a number", &value)),
+        },
+        Kind::Int32 | Kind::Sint32 | Kind::Sfixed32 => match to_integer(&value) {
+            Some(integer) => {
+                Proto::I32(i32::try_from(integer).map_err(|_| out_of_range("a 32-bit integer"))?)
+            }
+            None => return Err(mismatch("an integer", &value)),
+        },
+        Kind::Int64 | Kind::Sint64 | Kind::Sfixed64 => match to_integer(&value) {
+            Some(integer) => Proto::I64(integer),
+            None => return Err(mismatch("an integer", &value)),
+        },
+        Kind::Uint32 | Kind::Fixed32 => match to_unsigned(&value) {
+            Some(integer) => Proto::U32(
+                u32::try_from(integer).map_err(|_| out_of_range("an unsigned 32-bit integer"))?,
+            ),
+            None if to_integer(&value).is_some() => {
+                return Err(out_of_range("an unsigned 32-bit integer"))
+            }
+            None => return Err(mismatch("an integer", &value)),
+        },
+        Kind::Uint64 | Kind::Fixed64 => match to_unsigned(&value) {
+            Some(integer) => Proto::U64(integer),
+            None if to_integer(&value).is_some() => {
+                return Err(out_of_range("an unsigned 64-bit integer"))
+            }
+            None => return Err(mismatch("an integer", &value)),
+        },
+        Kind::Bool => match value {
+            Value::Boolean(boolean) => Proto::Bool(boolean),
+            value => return Err(mismatch("a boolean", &value)),
+        },
+        Kind::String => match value {
+            Value::String(string) => match string.to_str() {
+                Ok(string) => Proto::String(string.to_string()),
+                Err(_) => {
+                    return Err(encode_error(
+                        root,
+                        format!("field '{}' needs UTF-8 text", field),
+                    ))
+                }
+            },
+            value => return Err(mismatch("a string", &value)),
+        },
+        Kind::Bytes => match value {
+            Value::String(string) => Proto::Bytes(string.as_bytes().to_vec().into()),
+            value => return Err(mismatch("a string", &value)),
+        },
+        Kind::Enum(enum_type) => match value {
+            Value::String(ref name) => {
+                let name = name.to_str().unwrap_or_default();
+                match enum_type.get_value_by_name(name) {
+                    Some(enum_value) => Proto::EnumNumber(enum_value.number()),
+                    None => {
+                        return Err(encode_error(
+                            root,
+                            format!(
+                                "field '{}' has no value named {} in {}",
+                                field,
+                                name,
+                                enum_type.full_name()
+                            ),
+                        ))
+                    }
+                }
+            }
+            value => match to_integer(&value) {
+                Some(number) => Proto::EnumNumber(
+                    i32::try_from(number).map_err(|_| out_of_range("a 32-bit enum number"))?,
+                ),
+                None => return Err(mismatch("an enum name or number", &value)),
+            },
+        },
+        Kind::Message(message_type) => match value {
+            Value::Table(table) => {
+                Proto::Message(to_message(root, message_type, table, field, depth + 1)?)
+            }
+            value => return Err(mismatch("a table", &value)),
+        },
+    })
+}
+
+fn to_map_key(
+    root: &MessageDescriptor,
+    field: &str,
+    kind: &Kind,
+    key: Value,
+    depth: usize,
+) -> Result<MapKey> {
+    use prost_reflect::Value as Proto;
+    Ok(match to_value(root, field, kind, key, depth)? {
+        Proto::Bool(boolean) => MapKey::Bool(boolean),
+        Proto::I32(integer) => MapKey::I32(integer),
+        Proto::I64(integer) => MapKey::I64(integer),
+        Proto::U32(integer) => MapKey::U32(integer),
+        Proto::U64(integer) => MapKey::U64(integer),
+        Proto::String(string) => MapKey::String(string),
+        // Protobuf only allows integers, booleans and strings as map keys
+        _ => unreachable!("invalid map key kind"),
+    })
+}
+
+fn to_field(
+    root: &MessageDescriptor,
+    field: &FieldDescriptor,
+    path: &str,
+    value: Value,
+    depth: usize,
+) -> Result<prost_reflect::Value> {
+    if field.is_map() {
+        let table = match value {
+            Value::Table(table) => table,
+            value => {
+                return Err(encode_error(
+                    root,
+                    format!("field '{}' needs a table, got {}", path, type_name(&value)),
+                ))
+            }
+        };
+        let entry = field.kind();
+        let entry = entry.as_message().expect("map fields have entry messages");
+        let key_kind = entry.map_entry_key_field().kind();
+        let value_kind = entry.map_entry_value_field().kind();
+        let mut map = std::collections::HashMap::new();
+        for pair in table.pairs::<Value, Value>() {
+            let (key, value) = pair?;
+            let key = to_map_key(root, path, &key_kind, key, depth)?;
+            map.insert(key, to_value(root, path, &value_kind, value, depth)?);
+        }
+        Ok(prost_reflect::Value::Map(map))
+    } else if field.is_list() {
+        let table = match value {
+            Value::Table(table) => table,
+            value => {
+                return Err(encode_error(
+                    root,
+                    format!(
+                        "field '{}' needs a sequence, got {}",
+                        path,
+                        type_name(&value)
+                    ),
+                ))
+            }
+        };
+        let kind = field.kind();
+        let items = table
+            .sequence_values::<Value>()
+            .map(|item| to_value(root, path, &kind, item?, depth))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(prost_reflect::Value::List(items))
+    } else {
+        to_value(root, path, &field.kind(), value, depth)
+    }
+}
+
+// Errors name the message being encoded, and fields by their path from it, like items.sku
+fn to_message(
+    root: &MessageDescriptor,
+    message: &MessageDescriptor,
+    table: Table,
+    path: &str,
+    depth: usize,
+) -> Result<DynamicMessage> {
+    if depth > MAX_DEPTH {
+        return Err(encode_error(
+            root,
+            "tables are nested too deeply, or inside themselves".to_string(),
+        ));
+    }
+    let mut dynamic = DynamicMessage::new(message.clone());
+    for pair in table.pairs::<Value, Value>() {
+        let (key, value) = pair?;
+        let name = match &key {
+            Value::String(name) => name.to_str()?.to_string(),
+            key => {
+                return Err(encode_error(
+                    root,
+                    format!("field names must be strings, got {}", type_name(key)),
+                ))
+            }
+        };
+        let field_path = match path {
+            "" => name.clone(),
+            path => format!("{}.{}", path, name),
+        };
+        let field = message.get_field_by_name(&name).ok_or_else(|| {
+            encode_error(
+                root,
+                format!("field '{}' isn't in {}", field_path, message.full_name()),
+            )
+        })?;
+        // json.null leaves a field unset, like leaving it out
+        if let Value::LightUserData(LightUserData(pointer)) = value {
+            if pointer.is_null() {
+                continue;
+            }
+        }
+        let value = to_field(root, &field, &field_path, value, depth)?;
+        dynamic
+            .try_set_field(&field, value)
+            .map_err(|error| encode_error(root, error.to_string()))?;
+    }
+    Ok(dynamic)
+}
+
+fn from_value<'lua>(
+    lua_ctx: Context<'lua>,
+    kind: &Kind,
+    value: &prost_reflect::Value,
+) -> Result<Value<'lua>> {
+    use prost_reflect::Value as Proto;
+    Ok(match value {
+        Proto::Bool(boolean) => Value::Boolean(*boolean),
+        Proto::I32(integer) => Value::Integer(*integer as i64),
+        Proto::I64(integer) => Value::Integer(*integer),
+        Proto::U32(integer) => Value::Integer(*integer as i64),
+        Proto::U64(integer) => match i64::try_from(*integer) {
+            Ok(integer) => Value::Integer(integer),
+            Err(_) => Value::Number(*integer as f64),
+        },
+        Proto::F32(number) => Value::Number(*number as f64),
+        Proto::F64(number) => Value::Number(*number),
+        Proto::String(string) => Value::String(lua_ctx.create_string(string)?),
+        Proto::Bytes(bytes) => Value::String(lua_ctx.create_string(bytes.as_ref())?),
+        // Numbers the schema doesn't name stay numbers
+        Proto::EnumNumber(number) => match kind
+            .as_enum()
+            .and_then(|enum_type| enum_type.get_value(*number))
+        {
+            Some(enum_value) => Value::String(lua_ctx.create_string(enum_value.name())?),
+            None => Value::Integer(*number as i64),
+        },
+        Proto::Message(message) => Value::Table(from_message(lua_ctx, message)?),
+        Proto::List(items) => Value::Table(
+            lua_ctx.create_sequence_from(
+                items
+                    .iter()
+                    .map(|item| from_value(lua_ctx, kind, item))
+                    .collect::<Result<Vec<_>>>()?,
+            )?,
+        ),
+        Proto::Map(map) => {
+            let entry = kind.as_message().expect("map fields have entry messages");
+            let value_kind = entry.map_entry_value_field().kind();
+            let table = lua_ctx.create_table()?;
+            for (key, value) in map {
+                let key = match key {
+                    MapKey::Bool(boolean) => Value::Boolean(*boolean),
+                    MapKey::I32(integer) => Value::Integer(*integer as i64),
+                    MapKey::I64(integer) => Value::Integer(*integer),
+                    MapKey::U32(integer) => Value::Integer(*integer as i64),
+                    MapKey::U64(integer) => match i64::try_from(*integer) {
+                        Ok(integer) => Value::Integer(integer),
+                        Err(_) => Value::Number(*integer as f64),
+                    },
+                    MapKey::String(string) => Value::String(lua_ctx.create_string(string)?),
+                };
+                table.set(key, from_value(lua_ctx, &value_kind, value)?)?;
+            }
+            Value::Table(table)
+        }
+    })
+}
+
+fn from_message<'lua>(lua_ctx: Context<'lua>, message: &DynamicMessage) -> Result<Table<'lua>> {
+    let table = lua_ctx.create_table()?;
+    for field in message.descriptor().fields() {
+        if field.supports_presence() && !message.has_field(&field) {
+            continue;
+        }
+        let value = message.get_field(&field);
+        table.set(field.name(), from_value(lua_ctx, &field.kind(), &value)?)?;
+    }
+    Ok(table)
+}
+
+fn load(_: Context, (path, options): (String, Option<Table>)) -> Result<Schema> {
+    let mut includes = includes(&options)?;
+    // The file's own directory comes last, so imports are found there when nowhere else
+    includes.push(match Path::new(&path).parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_string_lossy().to_string(),
+        _ => ".".to_string(),
+    });
+    let schema_error = |source| {
+        rlua::Error::external(error::Error::ProtoSchema {
+            path: path.clone(),
+            source,
+        })
+    };
+    // protox reports a missing file as one outside the include paths
+    std::fs::metadata(&path).map_err(|source| {
+        rlua::Error::external(error::Error::Read {
+            path: path.clone(),
+            source,
+        })
+    })?;
+    let mut compiler = protox::Compiler::new(&includes).map_err(schema_error)?;
+    compiler.open_file(&path).map_err(schema_error)?;
+    Ok(Schema(compiler.descriptor_pool()))
+}
+
+/// The directories proto.load looks for imports in, besides the file's own.
+pub fn includes(options: &Option<Table>) -> Result<Vec<String>> {
+    Ok(match options {
+        Some(options) => options
+            .get::<_, Option<Vec<String>>>("includes")?
+            .unwrap_or_default(),
+        None => Vec::new(),
+    })
+}
+
+pub fn load_proto_library(lua: &Lua) -> Result<()> {
+    lua.context(|lua_ctx| {
+        let proto = lua_ctx.create_table()?;
+
+        proto.set("load", lua_ctx.create_function(load)?)?;
+
+        help::register_module_docs(
+            lua_ctx,
+            "proto",
+            "Protocol Buffers messages from .proto schemas",
+            &proto,
+            PROTO_DOCS,
+        )?;
+        lua_ctx.globals().set("proto", proto)?;
+        Ok(())
+    })
+}
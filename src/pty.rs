@@ -0,0 +1,411 @@
+/*
+   Copyright (C) 2022  Kalka
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License, or (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+#[cfg(unix)]
+use rlua::{AnyUserData, Function, MetaMethod, UserData, UserDataMethods};
+use rlua::{Context, Result, Table, Value};
+#[cfg(unix)]
+use std::fs::File;
+#[cfg(unix)]
+use std::io::{Read, Write};
+#[cfg(unix)]
+use std::os::fd::{AsRawFd, FromRawFd};
+#[cfg(unix)]
+use std::process::{Child, ExitStatus, Stdio};
+#[cfg(unix)]
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+#[cfg(unix)]
+use std::time::{Duration, Instant};
+
+#[cfg(unix)]
+use crate::proc::{self, POLL_INTERVAL};
+
+// Size of the pseudo-terminal when rluaterm's output isn't a terminal to take it from
+#[cfg(unix)]
+const DEFAULT_SIZE: (u16, u16) = (80, 24);
+// Most bytes read from the pseudo-terminal at once
+#[cfg(unix)]
+const READ_SIZE: usize = 4096;
+
+// The columns and rows of the terminal rluaterm's output goes to, if it's one
+#[cfg(unix)]
+fn terminal_size() -> Option<(u16, u16)> {
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    let result = unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) };
+    (result == 0 && size.ws_col > 0 && size.ws_row > 0).then_some((size.ws_col, size.ws_row))
+}
+
+// Resizing the master end sends SIGWINCH to the programs running in the pseudo-terminal
+#[cfg(unix)]
+fn set_size(master: &File, (columns, rows): (u16, u16)) -> std::io::Result<()> {
+    let size = libc::winsize {
+        ws_row: rows,
+        ws_col: columns,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+    if unsafe { libc::ioctl(master.as_raw_fd(), libc::TIOCSWINSZ, &size) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn slave_name(master: &File) -> std::io::Result<String> {
+    let mut name = [0 as libc::c_char; 128];
+    if unsafe { libc::ptsname_r(master.as_raw_fd(), name.as_mut_ptr(), name.len()) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let name = unsafe { std::ffi::CStr::from_ptr(name.as_ptr()) };
+    Ok(name.to_string_lossy().into_owned())
+}
+
+// ptsname returns a static buffer, so calls take turns
+#[cfg(all(unix, not(any(target_os = "linux", target_os = "android"))))]
+fn slave_name(master: &File) -> std::io::Result<String> {
+    static PTSNAME: std::sync::Mutex<()> = std::sync::Mutex::new(());
+    let _guard = PTSNAME
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    let name = unsafe { libc::ptsname(master.as_raw_fd()) };
+    if name.is_null() {
+        return Err(std::io::Error::last_os_error());
+    }
+    let name = unsafe { std::ffi::CStr::from_ptr(name) };
+    Ok(name.to_string_lossy().into_owned())
+}
+
+// Opens a pseudo-terminal, returning its master and slave ends
+#[cfg(unix)]
+fn open_pty() -> std::io::Result<(File, File)> {
+    let master = unsafe { libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY) };
+    if master < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let master = unsafe { File::from_raw_fd(master) };
+    let fd = master.as_raw_fd();
+    // The child keeping the master open would stop reads from ever seeing it exit
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } != 0
+        || unsafe { libc::grantpt(fd) } != 0
+        || unsafe { libc::unlockpt(fd) } != 0
+    {
+        return Err(std::io::Error::last_os_error());
+    }
+    let slave = std::os::unix::fs::OpenOptionsExt::custom_flags(
+        std::fs::OpenOptions::new().read(true).write(true),
+        libc::O_NOCTTY,
+    )
+    .open(slave_name(&master)?)?;
+    Ok((master, slave))
+}
+
+// A process started with proc.spawn_pty. Its on_output callback is kept in the userdata's user
+// value.
+#[cfg(unix)]
+struct PtyProcess {
+    name: String,
+    child: Child,
+    master: File,
+    output: Receiver<Vec<u8>>,
+    // Set once the pseudo-terminal is closed on the child's side
+    output_done: bool,
+    status: Option<ExitStatus>,
+    size: (u16, u16),
+    // Whether the size follows rluaterm's terminal, until it's set with resize
+    follow_terminal: bool,
+}
+
+#[cfg(unix)]
+impl PtyProcess {
+    fn write_error(&self, source: std::io::Error) -> rlua::Error {
+        rlua::Error::external(crate::error::Error::Write {
+            path: format!("terminal of {}", self.name),
+            source,
+        })
+    }
+
+    // Passes on a resize of rluaterm's terminal
+    fn follow_terminal_size(&mut self) {
+        if !self.follow_terminal {
+            return;
+        }
+        if let Some(size) = terminal_size().filter(|size| *size != self.size) {
+            if set_size(&self.master, size).is_ok() {
+                self.size = size;
+            }
+        }
+    }
+
+    // The bytes received so far, waiting up to the timeout for the first ones
+    fn receive(&mut self, timeout: Duration) -> Vec<u8> {
+        self.follow_terminal_size();
+        if self.output_done {
+            std::thread::sleep(timeout);
+            return Vec::new();
+        }
+        let mut bytes = match self.output.recv_timeout(timeout) {
+            Ok(bytes) => bytes,
+            Err(RecvTimeoutError::Timeout) => return Vec::new(),
+            Err(RecvTimeoutError::Disconnected) => {
+                self.output_done = true;
+                return Vec::new();
+            }
+        };
+        for more in self.output.try_iter() {
+            bytes.extend(more);
+        }
+        bytes
+    }
+
+    // The exit code once the process has exited and all of its output was received
+    fn exit_code(&mut self) -> Result<Option<i32>> {
+        if self.status.is_none() {
+            self.status = self
+                .child
+                .try_wait()
+                .map_err(|source| proc::spawn_error(&self.name, source))?;
+        }
+        Ok(match self.status {
+            Some(status) if self.output_done => Some(proc::exit_code(status)),
+            _ => None,
+        })
+    }
+
+    fn kill(&mut self) {
+        if self.status.is_none() {
+            let _ = self.child.kill();
+            self.status = self.child.wait().ok();
+        }
+    }
+
+    // The output within the timeout, all of it until the end without one. "" when the timeout
+    // passes first, nil once there's no more.
+    fn read(&mut self, lua_ctx: Context, timeout: Option<f64>) -> Result<Option<Vec<u8>>> {
+        let deadline =
+            timeout.map(|timeout| Instant::now() + Duration::from_secs_f64(timeout.max(0.0)));
+        loop {
+            let bytes = self.receive(POLL_INTERVAL);
+            if !bytes.is_empty() {
+                return Ok(Some(bytes));
+            }
+            if self.output_done {
+                return Ok(None);
+            }
+            crate::check_interrupt(lua_ctx)?;
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return Ok(Some(Vec::new()));
+            }
+        }
+    }
+}
+
+// Hangs up on the programs in the pseudo-terminal like closing a terminal window does, since
+// nothing can use it anymore
+#[cfg(unix)]
+impl Drop for PtyProcess {
+    fn drop(&mut self) {
+        if self.status.is_none() && matches!(self.child.try_wait(), Ok(None)) {
+            unsafe { libc::kill(self.child.id() as libc::pid_t, libc::SIGHUP) };
+        }
+    }
+}
+
+#[cfg(unix)]
+impl UserData for PtyProcess {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method_mut("read", |lua_ctx, process, timeout: Option<f64>| {
+            process
+                .read(lua_ctx, timeout)?
+                .map(|bytes| lua_ctx.create_string(&bytes))
+                .transpose()
+        });
+        methods.add_method_mut("write", |_, process, data: rlua::String| {
+            process
+                .master
+                .write_all(data.as_bytes())
+                .map_err(|source| process.write_error(source))
+        });
+        methods.add_method_mut("resize", |_, process, (columns, rows): (u16, u16)| {
+            set_size(&process.master, (columns, rows))
+                .map_err(|source| process.write_error(source))?;
+            process.size = (columns, rows);
+            process.follow_terminal = false;
+            Ok(())
+        });
+        methods.add_function(
+            "wait",
+            |lua_ctx, (process, timeout): (AnyUserData, Option<f64>)| {
+                wait(lua_ctx, &process, timeout)
+            },
+        );
+        methods.add_method_mut("kill", |_, process, ()| {
+            process.kill();
+            Ok(())
+        });
+        methods.add_meta_method(MetaMethod::Index, |_, process, key: String| {
+            Ok(match key.as_str() {
+                "pid" => Some(process.child.id()),
+                "columns" => Some(process.size.0.into()),
+                "rows" => Some(process.size.1.into()),
+                _ => None,
+            })
+        });
+        methods.add_meta_method(MetaMethod::ToString, |_, process, ()| {
+            Ok(format!(
+                "pty process {} ({})",
+                process.child.id(),
+                process.name
+            ))
+        });
+    }
+}
+
+// Hands the output to on_output, or straight to rluaterm's own output, until the process
+// exits. The handle isn't borrowed while on_output runs, so it can use it.
+#[cfg(unix)]
+fn wait<'lua>(
+    lua_ctx: Context<'lua>,
+    process: &AnyUserData<'lua>,
+    timeout: Option<f64>,
+) -> Result<Option<i32>> {
+    let deadline =
+        timeout.map(|timeout| Instant::now() + Duration::from_secs_f64(timeout.max(0.0)));
+    let on_output = process
+        .get_user_value::<Table>()?
+        .get::<_, Option<Function>>("on_output")?;
+    loop {
+        let (bytes, code) = {
+            let mut process = process.borrow_mut::<PtyProcess>()?;
+            let bytes = process.receive(POLL_INTERVAL);
+            (bytes, process.exit_code()?)
+        };
+        if !bytes.is_empty() {
+            match &on_output {
+                Some(on_output) => on_output.call::<_, ()>(lua_ctx.create_string(&bytes)?)?,
+                None => {
+                    let mut stdout = std::io::stdout();
+                    let _ = stdout.write_all(&bytes).and_then(|()| stdout.flush());
+                }
+            }
+        }
+        if code.is_some() {
+            return Ok(code);
+        }
+        if let Err(error) = crate::check_interrupt(lua_ctx) {
+            process.borrow_mut::<PtyProcess>()?.kill();
+            return Err(error);
+        }
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return Ok(None);
+        }
+    }
+}
+
+/// Starts the command in a pseudo-terminal of its own, for programs that only behave
+/// interactively when they're talking to a terminal.
+#[cfg(unix)]
+pub fn spawn_pty<'lua>(
+    lua_ctx: Context<'lua>,
+    command: Value<'lua>,
+    options: Option<Table<'lua>>,
+) -> Result<Value<'lua>> {
+    let (mut command, name) = proc::command_line(command, "spawn_pty")?;
+    proc::configure(&mut command, options.as_ref())?;
+    let (columns, rows) = match &options {
+        Some(options) => (
+            options.get::<_, Option<u16>>("columns")?,
+            options.get::<_, Option<u16>>("rows")?,
+        ),
+        None => (None, None),
+    };
+    let follow_terminal = columns.is_none() && rows.is_none();
+    let size = terminal_size().unwrap_or(DEFAULT_SIZE);
+    let size = (columns.unwrap_or(size.0), rows.unwrap_or(size.1));
+    let callbacks = lua_ctx.create_table()?;
+    if let Some(options) = &options {
+        callbacks.set(
+            "on_output",
+            options.get::<_, Option<Function>>("on_output")?,
+        )?;
+    }
+
+    let spawn_error = |source| proc::spawn_error(&name, source);
+    let (master, slave) = open_pty().map_err(spawn_error)?;
+    set_size(&master, size).map_err(spawn_error)?;
+    if std::env::var_os("TERM").is_none() {
+        command.env("TERM", "xterm-256color");
+    }
+    command
+        .stdin(Stdio::from(slave.try_clone().map_err(spawn_error)?))
+        .stdout(Stdio::from(slave.try_clone().map_err(spawn_error)?))
+        .stderr(Stdio::from(slave));
+    // A session of its own with the pseudo-terminal as its controlling terminal, so Ctrl+C,
+    // job control and resizes reach it like they would in a terminal window
+    unsafe {
+        std::os::unix::process::CommandExt::pre_exec(&mut command, || {
+            if libc::setsid() < 0 || libc::ioctl(0, libc::TIOCSCTTY, 0) < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let child = command.spawn().map_err(spawn_error)?;
+    // Dropping the command closes the slave end here, so reads end once the child is done
+    drop(command);
+
+    let mut reader = master.try_clone().map_err(spawn_error)?;
+    let (sender, output) = mpsc::channel();
+    std::thread::spawn(move || {
+        let mut buffer = [0; READ_SIZE];
+        loop {
+            match reader.read(&mut buffer) {
+                // Linux fails with EIO rather than returning 0 once the child side is closed
+                Ok(0) => break,
+                Ok(read) => {
+                    if sender.send(buffer[..read].to_vec()).is_err() {
+                        break;
+                    }
+                }
+                Err(error) if error.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(_) => break,
+            }
+        }
+    });
+
+    let process = lua_ctx.create_userdata(PtyProcess {
+        name,
+        child,
+        master,
+        output,
+        output_done: false,
+        status: None,
+        size,
+        follow_terminal,
+    })?;
+    process.set_user_value(callbacks)?;
+    Ok(Value::UserData(process))
+}
+
+#[cfg(not(unix))]
+pub fn spawn_pty<'lua>(
+    _lua_ctx: Context<'lua>,
+    _command: Value<'lua>,
+    _options: Option<Table<'lua>>,
+) -> Result<Value<'lua>> {
+    Err(rlua::Error::RuntimeError(
+        "proc.spawn_pty is only available on Unix".to_string(),
+    ))
+}
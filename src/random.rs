@@ -0,0 +1,162 @@
+/*
+   Copyright (C) 2022  Kalka
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License, or (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use rlua::{Lua, Result};
+
+use crate::help::{self, FunctionDoc};
+
+const RANDOM_DOCS: &[FunctionDoc] = &[
+    FunctionDoc {
+        name: "bytes",
+        signature: "random.bytes(n) -> bytes",
+        description: "n random bytes from the operating system's secure random number generator, fit for keys, salts and nonces, unlike math.random.",
+        example: "local key = random.bytes(32)",
+    },
+    FunctionDoc {
+        name: "int",
+        signature: "random.int(m[, n]) -> integer",
+        description: "A secure random integer from m to n, both included, or from 1 to m when n isn't given, like math.random. Every integer in the range is equally likely.",
+        example: "local pin = string.format(\"%06d\", random.int(0, 999999))",
+    },
+    FunctionDoc {
+        name: "uuid4",
+        signature: "random.uuid4() -> string",
+        description: "A random version 4 UUID, in lowercase like \"0b9e6a3c-5f3e-4c1b-9f0a-2d7c8e1f4a6b\".",
+        example: "local request_id = random.uuid4()",
+    },
+    FunctionDoc {
+        name: "token",
+        signature: "random.token([length]) -> string",
+        description: "A secure random string of letters and digits, 32 characters long by default, for API keys, passwords and session IDs.",
+        example: "local password = random.token(20)",
+    },
+];
+
+const TOKEN_CHARACTERS: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
+
+fn fill(bytes: &mut [u8]) -> Result<()> {
+    getrandom::fill(bytes).map_err(|error| {
+        rlua::Error::RuntimeError(format!(
+            "could not get random bytes from the system: {}",
+            error
+        ))
+    })
+}
+
+fn random_u64() -> Result<u64> {
+    let mut bytes = [0; 8];
+    fill(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+// A random number below the bound. Numbers past the last whole multiple of the bound are drawn
+// again, so the lower numbers aren't more likely than the rest.
+fn below(bound: u64) -> Result<u64> {
+    let zone = u64::MAX - u64::MAX % bound;
+    loop {
+        let number = random_u64()?;
+        if number < zone {
+            return Ok(number % bound);
+        }
+    }
+}
+
+fn int(m: i64, n: Option<i64>) -> Result<i64> {
+    let (low, high) = match n {
+        Some(n) => (m, n),
+        None => (1, m),
+    };
+    if low > high {
+        return Err(rlua::Error::RuntimeError(format!(
+            "bad argument #{} to 'int' (interval is empty)",
+            if n.is_some() { 2 } else { 1 }
+        )));
+    }
+    // The width of the range, which only overflows when it's every integer
+    let number = match (high.wrapping_sub(low) as u64).checked_add(1) {
+        Some(width) => below(width)?,
+        None => random_u64()?,
+    };
+    Ok(low.wrapping_add(number as i64))
+}
+
+fn uuid4() -> Result<String> {
+    let mut bytes = [0; 16];
+    fill(&mut bytes)?;
+    // The version, 4, and the variant bits RFC 9562 gives random UUIDs
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex = crate::hash::hex(&bytes);
+    Ok(format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    ))
+}
+
+fn token(length: Option<i64>) -> Result<String> {
+    let length = length.unwrap_or(32);
+    if length < 0 {
+        return Err(rlua::Error::RuntimeError(
+            "bad argument #1 to 'token' (length can't be negative)".to_string(),
+        ));
+    }
+    (0..length)
+        .map(|_| Ok(TOKEN_CHARACTERS[below(TOKEN_CHARACTERS.len() as u64)? as usize] as char))
+        .collect()
+}
+
+pub fn load_random_library(lua: &Lua) -> Result<()> {
+    lua.context(|lua_ctx| {
+        let random = lua_ctx.create_table()?;
+
+        random.set(
+            "bytes",
+            lua_ctx.create_function(|lua_ctx, n: i64| {
+                let length = usize::try_from(n).map_err(|_| {
+                    rlua::Error::RuntimeError(
+                        "bad argument #1 to 'bytes' (n can't be negative)".to_string(),
+                    )
+                })?;
+                let mut bytes = vec![0; length];
+                fill(&mut bytes)?;
+                lua_ctx.create_string(&bytes)
+            })?,
+        )?;
+        random.set(
+            "int",
+            lua_ctx.create_function(|_, (m, n): (i64, Option<i64>)| int(m, n))?,
+        )?;
+        random.set("uuid4", lua_ctx.create_function(|_, ()| uuid4())?)?;
+        random.set(
+            "token",
+            lua_ctx.create_function(|_, length: Option<i64>| token(length))?,
+        )?;
+
+        help::register_module_docs(
+            lua_ctx,
+            "random",
+            "Secure random numbers, bytes and identifiers",
+            &random,
+            RANDOM_DOCS,
+        )?;
+        lua_ctx.globals().set("random", random)?;
+        Ok(())
+    })
+}
@@ -0,0 +1,415 @@
+/*
+   Copyright (C) 2022  Kalka
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License, or (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+// Remote REPL over TCP.
+//
+// Clients send the token on the first line and get `ok` back, then exchange messages of any
+// number of lines terminated by a line holding a single `.`. Lines of a message starting with
+// `.` get an extra `.` prepended, like SMTP.
+//
+// A message is either Lua code, answered with its output, or `:complete <line>`, answered with
+// the offset the completions start at followed by one candidate per line, with a tab and the
+// first line of its documentation if it has any.
+use colored::Colorize;
+use cumulus::logger;
+use rlua::{Context, Function, Lua, MultiValue, Result};
+use rustyline::completion::{Completer, Pair};
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Editor, Helper};
+use std::cell::RefCell;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::rc::Rc;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Mutex;
+
+use crate::{completion, help, is_interrupted, repl};
+
+// Environment variable the token is read from when --token isn't given
+pub const TOKEN_ENV_VAR: &str = "RLUATERM_TOKEN";
+
+const CHUNK_NAME: &str = "=remote";
+
+const COMPLETE_COMMAND: &str = ":complete ";
+
+// Longest line read for the token, so clients can't send endless data before authenticating
+const MAX_TOKEN_LINE: u64 = 1024;
+
+// Code sent by a client, along with where to send the result
+struct Request {
+    code: String,
+    reply: Sender<String>,
+}
+
+// Requests waiting to be evaluated by the thread owning the Lua state
+static REQUESTS: Mutex<Option<Receiver<Request>>> = Mutex::new(None);
+
+// Output printed while evaluating a request, which goes to the client instead of stdout
+static CAPTURED_OUTPUT: Mutex<Option<String>> = Mutex::new(None);
+
+/// Captures printed text while a remote request is being evaluated. Returns false when nothing
+/// is being captured and the text should be printed as usual.
+pub fn capture_output(text: &str) -> bool {
+    match CAPTURED_OUTPUT.lock().unwrap().as_mut() {
+        Some(output) => {
+            output.push_str(text);
+            output.push('\n');
+            true
+        }
+        None => false,
+    }
+}
+
+// 128 random bits from the system, as hex
+fn generate_token() -> std::io::Result<String> {
+    let mut bytes = [0; 16];
+    getrandom::fill(&mut bytes).map_err(std::io::Error::other)?;
+    Ok(bytes.iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+// Compares every byte, so how long it takes doesn't tell how much of the token was right
+fn token_matches(given: &str, token: &str) -> bool {
+    given.len() == token.len()
+        && given
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+pub fn read_message(reader: &mut impl BufRead) -> std::io::Result<Option<String>> {
+    let mut message = Vec::new();
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end_matches(['\r', '\n']);
+        if line == "." {
+            return Ok(Some(message.join("\n")));
+        }
+        message.push(line.strip_prefix('.').unwrap_or(line).to_string());
+    }
+}
+
+pub fn write_message(writer: &mut impl Write, message: &str) -> std::io::Result<()> {
+    for line in message.lines() {
+        if line.starts_with('.') {
+            write!(writer, ".")?;
+        }
+        writeln!(writer, "{}", line)?;
+    }
+    writeln!(writer, ".")?;
+    writer.flush()
+}
+
+fn handle_client(stream: TcpStream, token: &str, requests: Sender<Request>) -> std::io::Result<()> {
+    let peer = stream.peer_addr()?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+
+    let mut client_token = String::new();
+    reader
+        .by_ref()
+        .take(MAX_TOKEN_LINE)
+        .read_line(&mut client_token)?;
+    if !token_matches(client_token.trim_end(), token) {
+        logger::warn(&format!("Rejected remote client {}: invalid token", peer));
+        writeln!(writer, "error: invalid token")?;
+        return Ok(());
+    }
+    writeln!(writer, "ok")?;
+    logger::info(&format!("Remote client {} attached", peer));
+
+    while let Some(code) = read_message(&mut reader)? {
+        let (reply, result) = mpsc::channel();
+        if requests.send(Request { code, reply }).is_err() {
+            break;
+        }
+        match result.recv() {
+            Ok(output) => write_message(&mut writer, &output)?,
+            Err(_) => break,
+        }
+    }
+    logger::info(&format!("Remote client {} detached", peer));
+    Ok(())
+}
+
+fn format_values<'lua>(lua_ctx: Context<'lua>, values: MultiValue<'lua>) -> Result<String> {
+    let tostring = lua_ctx.globals().get::<_, Function>("tostring")?;
+    let mut parts = Vec::new();
+    for value in values {
+        parts.push(tostring.call::<_, String>(value)?);
+    }
+    Ok(parts.join("\t"))
+}
+
+// Evaluate code as an expression if possible, like the reference REPL, else as a statement
+fn evaluate(lua_ctx: Context, code: &str) -> String {
+    *CAPTURED_OUTPUT.lock().unwrap() = Some(String::new());
+    let result = lua_ctx
+        .load(&format!("return {}", code))
+        .set_name(CHUNK_NAME)
+        .and_then(|chunk| chunk.into_function())
+        .or_else(|_| {
+            lua_ctx
+                .load(code)
+                .set_name(CHUNK_NAME)
+                .and_then(|chunk| chunk.into_function())
+        })
+        .and_then(|function| function.call::<_, MultiValue>(()))
+        .and_then(|values| format_values(lua_ctx, values));
+    let _ = help::attach_annotations(lua_ctx, code);
+    let mut output = CAPTURED_OUTPUT.lock().unwrap().take().unwrap_or_default();
+    match result {
+        Ok(values) => output.push_str(&values),
+        Err(error) if is_interrupted(&error) => output.push_str("interrupted"),
+        Err(error) => {
+            let message = error.to_string();
+            // The traceback would mostly show the script that happened to be running
+            let message = message.lines().next().unwrap_or_default();
+            output.push_str(&format!("error: {}", message));
+        }
+    }
+    output
+}
+
+fn complete(lua_ctx: Context, line: &str) -> String {
+    match completion::complete(lua_ctx, line) {
+        Ok((start, candidates)) => {
+            let mut output = start.to_string();
+            for candidate in candidates {
+                output.push('\n');
+                output.push_str(&candidate.name);
+                if let Some(summary) = candidate.summary {
+                    output.push('\t');
+                    output.push_str(&summary);
+                }
+            }
+            output
+        }
+        Err(_) => line.len().to_string(),
+    }
+}
+
+fn respond(lua_ctx: Context, request: Request) {
+    let output = match request.code.strip_prefix(COMPLETE_COMMAND) {
+        Some(line) => complete(lua_ctx, line),
+        None => evaluate(lua_ctx, &request.code),
+    };
+    // The client may have gone away in the meantime
+    let _ = request.reply.send(output);
+}
+
+/// Evaluates requests that arrived while a script is running. Called from the debug hook.
+pub fn serve_pending(lua_ctx: Context) {
+    // Already locked when this runs inside a request being served by `serve`
+    let requests = match REQUESTS.try_lock() {
+        Ok(requests) => requests,
+        Err(_) => return,
+    };
+    if let Some(receiver) = requests.as_ref() {
+        while let Ok(request) = receiver.try_recv() {
+            respond(lua_ctx, request);
+        }
+    }
+}
+
+/// Starts accepting remote clients in the background. Their requests are evaluated while a
+/// script runs, and by `serve` once it's done.
+pub fn listen(address: &str, token: Option<String>) -> std::io::Result<()> {
+    let listener = TcpListener::bind(address)?;
+    let token = match token.or_else(|| std::env::var(TOKEN_ENV_VAR).ok()) {
+        Some(token) => token,
+        None => {
+            let token = generate_token()?;
+            // Not logged, so it doesn't end up in the log file
+            eprintln!("Remote token: {}", token);
+            token
+        }
+    };
+    logger::info(&format!(
+        "Listening for remote clients on {}",
+        listener.local_addr()?
+    ));
+
+    let (sender, receiver) = mpsc::channel();
+    *REQUESTS.lock().unwrap() = Some(receiver);
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(error) => {
+                    logger::error(&format!("Failed to accept remote client [{}]", error));
+                    continue;
+                }
+            };
+            let token = token.clone();
+            let sender = sender.clone();
+            std::thread::spawn(move || {
+                if let Err(error) = handle_client(stream, &token, sender) {
+                    logger::error(&format!("Remote client error [{}]", error));
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+/// Evaluates remote requests until the process is stopped.
+pub fn serve(lua: &Lua) {
+    let requests = REQUESTS.lock().unwrap();
+    let receiver = match requests.as_ref() {
+        Some(receiver) => receiver,
+        None => return,
+    };
+    while let Ok(request) = receiver.recv() {
+        lua.context(|lua_ctx| respond(lua_ctx, request));
+    }
+}
+
+// Connection of an attached client, shared between the loop and the completer
+struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl Connection {
+    fn request(&mut self, message: &str) -> std::io::Result<String> {
+        write_message(&mut self.writer, message)?;
+        read_message(&mut self.reader)?.ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "connection closed")
+        })
+    }
+}
+
+// Completes names against the remote globals
+struct AttachHelper {
+    connection: Rc<RefCell<Connection>>,
+}
+
+impl Helper for AttachHelper {}
+
+impl Completer for AttachHelper {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &rustyline::Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let reply = self.connection.borrow_mut().request(&format!(
+            "{}{}",
+            COMPLETE_COMMAND,
+            &line[..pos]
+        ))?;
+        let mut lines = reply.lines();
+        let start = lines
+            .next()
+            .and_then(|start| start.parse().ok())
+            .unwrap_or(pos);
+        let candidates = lines
+            .map(|line| match line.split_once('\t') {
+                Some((name, summary)) => completion::candidate(name, Some(summary)),
+                None => completion::candidate(line, None),
+            })
+            .collect();
+        Ok((start, candidates))
+    }
+}
+
+impl Hinter for AttachHelper {
+    type Hint = String;
+}
+
+impl Highlighter for AttachHelper {}
+
+impl Validator for AttachHelper {}
+
+/// Connects to a remote REPL and forwards everything typed at the prompt to it.
+pub fn attach(address: &str, token: Option<String>) -> std::io::Result<()> {
+    let token = token
+        .or_else(|| std::env::var(TOKEN_ENV_VAR).ok())
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("no token given, use --token or {}", TOKEN_ENV_VAR),
+            )
+        })?;
+    let stream = TcpStream::connect(address)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+    writeln!(writer, "{}", token)?;
+    let mut status = String::new();
+    reader.read_line(&mut status)?;
+    if status.trim_end() != "ok" {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            status.trim_end().to_string(),
+        ));
+    }
+    println!("{}", format!("Attached to {}", address).cyan().bold());
+
+    let connection = Rc::new(RefCell::new(Connection { reader, writer }));
+    let mut editor =
+        Editor::<AttachHelper, DefaultHistory>::new().map_err(std::io::Error::other)?;
+    editor.set_helper(Some(AttachHelper {
+        connection: connection.clone(),
+    }));
+    // Only used to tell whether the code typed so far is a complete chunk
+    let syntax_lua = Lua::new();
+    let prompt = format!("{}> ", address);
+    let mut buffer = String::new();
+    loop {
+        let prompt = if buffer.is_empty() {
+            prompt.as_str()
+        } else {
+            ">> "
+        };
+        let input = match editor.readline(prompt) {
+            Ok(input) => input,
+            Err(ReadlineError::Interrupted) => {
+                buffer.clear();
+                continue;
+            }
+            Err(ReadlineError::Eof) => break,
+            Err(error) => return Err(std::io::Error::other(error)),
+        };
+        if buffer.is_empty() && input.trim().is_empty() {
+            continue;
+        }
+        if buffer.is_empty() && input.trim() == "exit" {
+            break;
+        }
+        buffer.push_str(&input);
+        buffer.push('\n');
+        if repl::is_incomplete(&syntax_lua, &buffer) {
+            continue;
+        }
+        let _ = editor.add_history_entry(buffer.trim_end());
+        let output = connection.borrow_mut().request(buffer.trim_end())?;
+        if !output.is_empty() {
+            println!("{}", output);
+        }
+        buffer.clear();
+    }
+    Ok(())
+}
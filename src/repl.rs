@@ -0,0 +1,311 @@
+/*
+   Copyright (C) 2022  Kalka
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License, or (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use colored::Colorize;
+use cumulus::logger;
+use rlua::{Lua, Result, Table, Value};
+use rustyline::completion::{Completer, Pair};
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::{Hinter, HistoryHinter};
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Editor, Helper};
+use std::borrow::Cow;
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+
+use crate::{
+    commands, completion, config, config_dir, help, is_interrupted, pager, report,
+    requested_exit_code, time, timeout, traceback, transcript, INTERRUPTED,
+};
+
+// Name REPL chunks are loaded with, so errors read "stdin:1: ..."
+const CHUNK_NAME: &str = "=stdin";
+
+const DEFAULT_PROMPT: &str = "> ";
+const DEFAULT_CONTINUATION_PROMPT: &str = ">> ";
+
+// Lines of output shown per chunk before the rest is left to :page
+const DEFAULT_MAX_OUTPUT_LINES: i64 = 200;
+
+// File in the config directory the REPL history is persisted to
+const HISTORY_FILE: &str = "history";
+
+// Suggests the most recent matching history entry as the user types, accepted with Right-arrow,
+// and completes global names with Tab, listing the documented ones with their descriptions
+struct ReplHelper<'a> {
+    lua: &'a Lua,
+    hinter: HistoryHinter,
+}
+
+impl Helper for ReplHelper<'_> {}
+
+impl Completer for ReplHelper<'_> {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &rustyline::Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let completions = self
+            .lua
+            .context(|lua_ctx| completion::complete(lua_ctx, &line[..pos]));
+        match completions {
+            Ok((start, completions)) => Ok((
+                start,
+                completions
+                    .iter()
+                    .map(|completion| {
+                        completion::candidate(&completion.name, completion.summary.as_deref())
+                    })
+                    .collect(),
+            )),
+            Err(_) => Ok((pos, Vec::new())),
+        }
+    }
+}
+
+impl Validator for ReplHelper<'_> {}
+
+impl Hinter for ReplHelper<'_> {
+    type Hint = String;
+
+    fn hint(&self, line: &str, pos: usize, ctx: &rustyline::Context<'_>) -> Option<String> {
+        self.hinter.hint(line, pos, ctx)
+    }
+}
+
+impl Highlighter for ReplHelper<'_> {
+    fn highlight_hint<'h>(&self, hint: &'h str) -> Cow<'h, str> {
+        Cow::Owned(hint.dimmed().to_string())
+    }
+}
+
+pub fn load_repl_library(lua: &Lua) -> Result<()> {
+    lua.context(|lua_ctx| {
+        let repl_lib = lua_ctx.create_table()?;
+        repl_lib.set("prompt", DEFAULT_PROMPT)?;
+        repl_lib.set("continuation_prompt", DEFAULT_CONTINUATION_PROMPT)?;
+        repl_lib.set("last_ok", true)?;
+        repl_lib.set("max_output_lines", DEFAULT_MAX_OUTPUT_LINES)?;
+        // Seconds or a duration such as "500ms", nil to run without a timeout
+        repl_lib.set(
+            "set_timeout",
+            lua_ctx.create_function(|_, timeout: Value| {
+                let duration = match timeout {
+                    Value::Nil => None,
+                    timeout => Some(time::to_duration(&timeout).ok_or_else(|| {
+                        rlua::Error::RuntimeError(
+                            "bad argument #1 to 'set_timeout' (non-negative duration expected)"
+                                .to_string(),
+                        )
+                    })?),
+                };
+                timeout::set_timeout(duration);
+                Ok(())
+            })?,
+        )?;
+        lua_ctx.globals().set("repl", repl_lib)?;
+        Ok(())
+    })
+}
+
+// Render repl.<key>, which may be a string or a function returning one
+fn repl_prompt(lua: &Lua, key: &str, default: &str) -> String {
+    lua.context(|lua_ctx| {
+        let prompt = match lua_ctx.globals().get::<_, Table>("repl") {
+            Ok(repl_lib) => repl_lib.get::<_, Value>(key),
+            Err(_) => return default.to_string(),
+        };
+        let prompt = match prompt {
+            Ok(Value::Function(function)) => function.call::<_, String>(()),
+            Ok(Value::Nil) => return default.to_string(),
+            Ok(value) => lua_ctx.unpack::<String>(value),
+            Err(error) => Err(error),
+        };
+        prompt.unwrap_or_else(|error| {
+            logger::error(&format!("Failed to render repl.{} [{}]", key, error));
+            default.to_string()
+        })
+    })
+}
+
+// Whether the code only failed to compile because it ended early, e.g. an unclosed `function`
+pub fn is_incomplete(lua: &Lua, code: &str) -> bool {
+    lua.context(|lua_ctx| {
+        matches!(
+            lua_ctx.load(code).into_function(),
+            Err(rlua::Error::SyntaxError {
+                incomplete_input: true,
+                ..
+            })
+        )
+    })
+}
+
+fn history_path() -> Option<PathBuf> {
+    let dir = config_dir()?;
+    std::fs::create_dir_all(&dir).ok()?;
+    Some(dir.join(HISTORY_FILE))
+}
+
+fn create_editor(lua: &Lua) -> rustyline::Result<Editor<ReplHelper<'_>, DefaultHistory>> {
+    let mut builder = rustyline::Config::builder();
+    if let Some(size) = config::get().history_size {
+        builder = builder.max_history_size(size)?;
+    }
+    let mut editor = Editor::with_config(builder.build())?;
+    editor.set_helper(Some(ReplHelper {
+        lua,
+        hinter: HistoryHinter::new(),
+    }));
+    if let Some(path) = history_path() {
+        // The history file doesn't exist yet on the first run
+        let _ = editor.load_history(&path);
+    }
+    Ok(editor)
+}
+
+fn save_history(editor: &mut Editor<ReplHelper<'_>, DefaultHistory>) {
+    if let Some(path) = history_path() {
+        if let Err(error) = editor.save_history(&path) {
+            logger::warn(&format!("Failed to save history [{}]", error));
+        }
+    }
+}
+
+/// Runs the REPL until the input ends or os.exit is called, returning the exit code.
+pub fn lua_interpret_loop(lua: &Lua) -> Result<i32> {
+    let mut editor = create_editor(lua).map_err(rlua::Error::external)?;
+    let mut buffer = String::new();
+    let mut exit_code = 0;
+    loop {
+        // Print the prompt, or the continuation prompt while a chunk is being continued
+        let prompt = if buffer.is_empty() {
+            repl_prompt(lua, "prompt", DEFAULT_PROMPT)
+        } else {
+            repl_prompt(lua, "continuation_prompt", DEFAULT_CONTINUATION_PROMPT)
+        };
+        let input = match editor.readline(&prompt) {
+            Ok(input) => input.trim().to_string(),
+            // Ctrl+C at the prompt discards the chunk being typed
+            Err(ReadlineError::Interrupted) => {
+                buffer.clear();
+                continue;
+            }
+            // Ctrl+D or the end of stdin
+            Err(ReadlineError::Eof) => break,
+            Err(error) => {
+                logger::error(&format!("Failed to read input [{}]", error));
+                break;
+            }
+        };
+        if buffer.is_empty() {
+            // If the input is empty, continue
+            if input.is_empty() {
+                continue;
+            }
+            // If the input is "exit", exit
+            if input == "exit" {
+                lua.context(|lua_ctx| {
+                    lua_ctx.load("log.info('Exiting Lua interpreter')").exec()?;
+                    Ok(())
+                })?;
+                break;
+            }
+            // `:time <code>` runs a line of Lua on a stopwatch
+            if let Some(code) = input.strip_prefix(":time ") {
+                let _ = editor.add_history_entry(&input);
+                transcript::record_input(code);
+                let stopwatch = time::Stopwatch::start();
+                let requested_exit_code = lua_interpret(lua, code)?;
+                println!("{}", format!("Took {:.2?}", stopwatch.elapsed()).dimmed());
+                if let Some(code) = requested_exit_code {
+                    exit_code = code;
+                    break;
+                }
+                continue;
+            }
+            // Lines starting with ':' are REPL commands rather than Lua
+            if input.starts_with(':') {
+                let _ = editor.add_history_entry(&input);
+                commands::run_command(lua, &input)?;
+                continue;
+            }
+        }
+        buffer.push_str(&input);
+        buffer.push('\n');
+        // Keep reading lines until the chunk is complete, and hold on to `---` annotations
+        // until the function they document follows
+        let annotations_only = buffer.lines().all(|line| line.starts_with("---"));
+        if annotations_only || is_incomplete(lua, &buffer) {
+            continue;
+        }
+        let _ = editor.add_history_entry(buffer.trim_end());
+        transcript::record_input(&buffer);
+        let requested_exit_code = lua_interpret(lua, &buffer)?;
+        buffer.clear();
+        if let Some(code) = requested_exit_code {
+            exit_code = code;
+            break;
+        }
+    }
+    save_history(&mut editor);
+    if let Err(error) = transcript::stop() {
+        logger::error(&format!("Failed to write transcript [{}]", error));
+    }
+    Ok(exit_code)
+}
+
+// repl.max_output_lines, where anything but a positive number turns truncation off
+fn max_output_lines(lua: &Lua) -> Option<usize> {
+    lua.context(|lua_ctx| {
+        let repl_lib = lua_ctx.globals().get::<_, Table>("repl").ok()?;
+        let limit = repl_lib.get::<_, Option<i64>>("max_output_lines").ok()??;
+        usize::try_from(limit).ok().filter(|limit| *limit > 0)
+    })
+}
+
+// Run a chunk, returning the exit code if it called os.exit
+fn lua_interpret(lua: &Lua, code: &str) -> Result<Option<i32>> {
+    // Forget any Ctrl+C pressed while waiting at the prompt
+    INTERRUPTED.store(false, Ordering::SeqCst);
+    pager::begin(max_output_lines(lua));
+    lua.context(|lua_ctx| {
+        let result =
+            traceback::exec_with_traceback(lua_ctx, lua_ctx.load(code).set_name(CHUNK_NAME)?);
+        pager::finish();
+        // Documenting what the code defined is best effort and never ends the session
+        let _ = help::attach_annotations(lua_ctx, code);
+        // Expose the outcome to prompt functions
+        if let Ok(repl_lib) = lua_ctx.globals().get::<_, Table>("repl") {
+            repl_lib.set("last_ok", result.is_ok())?;
+        }
+        if let Err(error) = result {
+            if let Some(code) = requested_exit_code(&error) {
+                return Ok(Some(code));
+            } else if is_interrupted(&error) {
+                logger::warn("Interrupted");
+            } else {
+                report::report_error("", CHUNK_NAME, code, &error);
+            }
+        }
+        Ok(None)
+    })
+}
@@ -0,0 +1,167 @@
+/*
+   Copyright (C) 2022  Kalka
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License, or (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use colored::Colorize;
+use cumulus::logger;
+
+use crate::{traceback, transcript};
+
+/// The message Lua produced, without rlua's "runtime error: " style prefixes.
+pub fn error_message(error: &rlua::Error) -> String {
+    match error {
+        rlua::Error::SyntaxError { message, .. } => message.clone(),
+        rlua::Error::RuntimeError(message) => message.clone(),
+        rlua::Error::CallbackError { traceback, cause } => {
+            format!("{}\n{}", error_message(cause), traceback)
+        }
+        error => error.to_string(),
+    }
+}
+
+// Find the first "<source>:<line>:" location in the message, skipping C frames
+fn find_location(message: &str) -> Option<(String, usize)> {
+    for line in message.lines() {
+        let line = line.trim_start();
+        for (i, _) in line.match_indices(':') {
+            let rest = &line[i + 1..];
+            let digits = rest.chars().take_while(|c| c.is_ascii_digit()).count();
+            if digits == 0 || !rest[digits..].starts_with(':') {
+                continue;
+            }
+            if let Ok(line_number) = rest[..digits].parse() {
+                return Some((line[..i].to_string(), line_number));
+            }
+        }
+    }
+    None
+}
+
+// The token a syntax error points at, e.g. `'='` in "unexpected symbol near '='"
+fn near_token(message: &str) -> Option<&str> {
+    let near = message.lines().next()?.rsplit_once(" near ")?.1;
+    if near == "<eof>" {
+        return Some(near);
+    }
+    near.strip_prefix('\'')?.strip_suffix('\'')
+}
+
+// Chunk names follow Lua's convention: "@path" for files, "=name" for anything else
+fn short_source(chunk_name: &str) -> &str {
+    chunk_name
+        .strip_prefix('@')
+        .or_else(|| chunk_name.strip_prefix('='))
+        .unwrap_or(chunk_name)
+}
+
+fn format_excerpt(message: &str, source_name: &str, line_number: usize, line: &str) -> String {
+    let (column, width) = match near_token(message) {
+        Some("<eof>") => (line.chars().count(), 1),
+        Some(token) => match line.find(token) {
+            Some(index) => (line[..index].chars().count(), token.chars().count()),
+            None => (0, line.chars().count()),
+        },
+        None => {
+            let indent = line.len() - line.trim_start().len();
+            (indent, line.trim().chars().count())
+        }
+    };
+    let gutter = " ".repeat(line_number.to_string().len());
+
+    format!(
+        "{}{} {}:{}\n{} {}\n{} {} {}\n{} {} {}{}",
+        gutter,
+        "-->".blue().bold(),
+        source_name,
+        line_number,
+        gutter,
+        "|".blue().bold(),
+        line_number.to_string().blue().bold(),
+        "|".blue().bold(),
+        line,
+        gutter,
+        "|".blue().bold(),
+        " ".repeat(column),
+        "^".repeat(width.max(1)).red().bold()
+    )
+}
+
+/// Where a syntax error in `source` points, as a line and a column counting from 1, along with
+/// its message without the location prefix.
+pub fn syntax_error_position(error: &rlua::Error, source: &str) -> Option<(usize, usize, String)> {
+    let message = error_message(error);
+    let (source_name, line_number) = find_location(&message)?;
+    let prefix = format!("{}:{}:", source_name, line_number);
+    let text = message
+        .lines()
+        .next()?
+        .split_once(&prefix)
+        .map_or(message.as_str(), |(_, text)| text)
+        .trim()
+        .to_string();
+    let line = source
+        .lines()
+        .nth(line_number.saturating_sub(1))
+        .unwrap_or("");
+    let column = match near_token(&message) {
+        Some("<eof>") => line.chars().count(),
+        Some(token) => line
+            .find(token)
+            .map_or(0, |index| line[..index].chars().count()),
+        None => line.len() - line.trim_start().len(),
+    };
+    Some((line_number, column + 1, text))
+}
+
+/// Logs an error raised while loading or running a chunk, followed by the offending source line
+/// with a caret under the error position and the traceback, if any.
+///
+/// `context` prefixes the logged message, `chunk_name` is the name the chunk was loaded with and
+/// `source` its code, used when the error points into that chunk. Errors pointing into other
+/// files are excerpted from disk.
+pub fn report_error(context: &str, chunk_name: &str, source: &str, error: &rlua::Error) {
+    let message = error_message(error);
+    let (headline, rest) = message.split_once('\n').unwrap_or((&message, ""));
+    let headline = if context.is_empty() {
+        headline.to_string()
+    } else {
+        format!("{} [{}]", context, headline)
+    };
+    logger::error(&headline);
+    transcript::record_output(&headline);
+
+    if let Some((source_name, line_number)) = find_location(&message) {
+        let contents = if source_name == short_source(chunk_name) {
+            Some(source.to_string())
+        } else {
+            std::fs::read_to_string(&source_name).ok()
+        };
+        let line = contents
+            .as_deref()
+            .and_then(|contents| contents.lines().nth(line_number.saturating_sub(1)));
+        if let Some(line) = line {
+            let excerpt = format_excerpt(&headline, &source_name, line_number, line);
+            println!("{}", excerpt);
+            transcript::record_output(&excerpt);
+        }
+    }
+
+    // Prefer the traceback taken by the message handler, which can include locals
+    let traceback = traceback::take_traceback().unwrap_or_else(|| rest.to_string());
+    if !traceback.is_empty() {
+        println!("{}", traceback);
+        transcript::record_output(&traceback);
+    }
+}
@@ -0,0 +1,243 @@
+/*
+   Copyright (C) 2022  Kalka
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License, or (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use rlua::{Context, Function, Lua, Result, Table, Value};
+use std::path::{Path, PathBuf};
+
+use crate::{help, install, traceback};
+
+// Name of the registry value holding the search path of the searcher, for finding the files
+// of required modules
+const PATH_REGISTRY_KEY: &str = "rluaterm.require.path";
+
+// Returns a package searcher finding modules on the given search path. Modules are cached by
+// file as well as by name, so a file required under two names, like `util` from the script's
+// directory and `lib.util` from the project root, only runs once. A file's module stays cached
+// only while package.loaded still holds it under one of those names, so clearing it there makes
+// the next require run the file again, as it does in plain Lua.
+const SEARCHER_SOURCE: &str = r#"
+local path, loadfile = ...
+local searchpath, error, pairs, rawequal = package.searchpath, error, pairs, rawequal
+local loaded = package.loaded
+local modules, names = {}, {}
+
+local function cached(file)
+    local module = modules[file]
+    if module == nil then
+        return nil
+    end
+    for required_name in pairs(names[file]) do
+        if rawequal(loaded[required_name], module) then
+            return module
+        end
+    end
+    modules[file], names[file] = nil, nil
+end
+
+return function(name)
+    local file, message = searchpath(name, path)
+    if not file then
+        return message
+    end
+    local module = cached(file)
+    if module ~= nil then
+        names[file][name] = true
+        return function()
+            return module
+        end, file
+    end
+    local chunk, load_error = loadfile(file)
+    if not chunk then
+        error(("error loading module '%s' from file '%s':\n\t%s"):format(name, file, load_error), 2)
+    end
+    return function(...)
+        local module = chunk(...)
+        -- A module can register itself in package.loaded instead of returning anything
+        if module == nil then
+            module = loaded[name]
+        end
+        if module == nil then
+            module = true
+        end
+        modules[file], names[file] = module, { [name] = true }
+        return module
+    end, file
+end
+"#;
+
+// Returns package.reload, which runs a module's file again and copies what it returns into the
+// table the module returned before, so everything holding on to the module sees the new code
+const RELOAD_SOURCE: &str = r#"
+local search_path = ...
+local searchpath, loaded = package.searchpath, package.loaded
+local pairs, rawget, rawset, type, error, pcall = pairs, rawget, rawset, type, error, pcall
+local getmetatable, setmetatable = getmetatable, setmetatable
+
+return function(name)
+    if type(name) ~= "string" then
+        error("bad argument #1 to 'reload' (string expected, got " .. type(name) .. ")", 2)
+    end
+    local old = loaded[name]
+    local file, message = searchpath(name, search_path())
+    if not file then
+        error(("module '%s' not found:\n\t%s"):format(name, message), 2)
+    end
+    -- Looked up on every call, so the sandbox's checks on loading files apply
+    local chunk, load_error = _G.loadfile(file)
+    if not chunk then
+        error(load_error, 2)
+    end
+    loaded[name] = nil
+    local new = chunk(name, file)
+    if new == nil then
+        new = loaded[name]
+    end
+    if new == nil then
+        new = true
+    end
+    if type(old) == "table" and type(new) == "table" and old ~= new then
+        for key in pairs(old) do
+            if rawget(new, key) == nil then
+                rawset(old, key, nil)
+            end
+        end
+        for key, value in pairs(new) do
+            rawset(old, key, value)
+        end
+        -- A protected metatable stays as it was
+        pcall(setmetatable, old, getmetatable(new))
+        new = old
+    end
+    loaded[name] = new
+    return new
+end
+"#;
+
+/// The `package.path` template finding `name.lua` and `name/init.lua` in each directory.
+pub fn path_template(dirs: &[PathBuf]) -> String {
+    dirs.iter()
+        .map(|dir| format!("{0}/?.lua;{0}/?/init.lua", dir.display()))
+        .collect::<Vec<_>>()
+        .join(";")
+}
+
+/// Directories modules are required from before `package.path`: the script's own directory and
+/// then the project root, each followed by the packages installed in it, made absolute so
+/// changing the working directory doesn't matter.
+pub fn module_dirs(script: &Path, root: Option<&Path>) -> Vec<PathBuf> {
+    let script_dir = match script.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let mut dirs = Vec::new();
+    for dir in std::iter::once(script_dir).chain(root) {
+        for dir in [dir.to_path_buf(), dir.join(install::MODULES_DIR)] {
+            if let Ok(dir) = dir.canonicalize() {
+                if !dirs.contains(&dir) {
+                    dirs.push(dir);
+                }
+            }
+        }
+    }
+    dirs
+}
+
+/// Makes `require` look for modules in the directories right after `package.preload`, before
+/// `package.path`.
+pub fn install_searcher(lua: &Lua, dirs: &[PathBuf]) -> Result<()> {
+    if dirs.is_empty() {
+        return Ok(());
+    }
+    lua.context(|lua_ctx| {
+        let globals = lua_ctx.globals();
+        let path = path_template(dirs);
+        // Looked up now, so the sandbox's checks on loading files apply
+        let searcher = lua_ctx
+            .load(SEARCHER_SOURCE)
+            .set_name("=require")?
+            .call::<_, Function>((path.as_str(), globals.get::<_, Function>("loadfile")?))?;
+        let searchers = globals
+            .get::<_, Table>("package")?
+            .get::<_, Table>("searchers")?;
+        // Shift everything after the preload searcher up by one
+        for index in (2..=searchers.raw_len()).rev() {
+            searchers.raw_set(index + 1, searchers.raw_get::<_, Function>(index)?)?;
+        }
+        searchers.raw_set(2, searcher)?;
+        lua_ctx.set_named_registry_value(PATH_REGISTRY_KEY, path)
+    })
+}
+
+/// Adds `package.reload`, which runs a module again and patches the table it returned before in
+/// place.
+pub fn attach_reload(lua: &Lua) -> Result<()> {
+    lua.context(|lua_ctx| {
+        let search_path = lua_ctx.create_function(|lua_ctx, ()| search_path(lua_ctx))?;
+        let reload = lua_ctx
+            .load(RELOAD_SOURCE)
+            .set_name("=reload")?
+            .call::<_, Function>(search_path)?;
+        help::attach_doc(
+            lua_ctx,
+            reload.clone(),
+            Some("package.reload(name)"),
+            "Runs a required module's file again and updates the table it returned in place, \
+             so code holding on to the module sees the changes",
+        )?;
+        lua_ctx
+            .globals()
+            .get::<_, Table>("package")?
+            .set("reload", reload)
+    })
+}
+
+/// Reloads every module that was required from a file, returning their names.
+pub fn reload_all(lua_ctx: Context) -> Result<Vec<String>> {
+    let package = lua_ctx.globals().get::<_, Table>("package")?;
+    let searchpath = package.get::<_, Function>("searchpath")?;
+    let reload = package.get::<_, Function>("reload")?;
+    let path = search_path(lua_ctx)?;
+    let mut names = Vec::new();
+    for pair in package.get::<_, Table>("loaded")?.pairs::<Value, Value>() {
+        if let (Value::String(name), _) = pair? {
+            let name = name.to_str()?.to_string();
+            let file = searchpath.call::<_, Option<String>>((name.as_str(), path.as_str()))?;
+            if file.is_some() {
+                names.push(name);
+            }
+        }
+    }
+    names.sort();
+    for name in &names {
+        traceback::call_with_traceback::<_, Value>(lua_ctx, reload.clone(), name.as_str())?;
+    }
+    Ok(names)
+}
+
+/// The search path `require` finds Lua modules on, the searcher's directories followed by
+/// `package.path`.
+pub fn search_path(lua_ctx: Context) -> Result<String> {
+    let package_path = lua_ctx
+        .globals()
+        .get::<_, Table>("package")?
+        .get::<_, String>("path")?;
+    Ok(
+        match lua_ctx.named_registry_value::<_, Option<String>>(PATH_REGISTRY_KEY)? {
+            Some(path) => format!("{};{}", path, package_path),
+            None => package_path,
+        },
+    )
+}
@@ -0,0 +1,519 @@
+/*
+   Copyright (C) 2022  Kalka
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License, or (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use rlua::{Context, Function, Lua, MultiValue, Result, Table, Value};
+use std::path::{Path, PathBuf};
+
+use crate::{csv, fs, proto, require, tls};
+
+// Functions that reach outside of the interpreter, disabled unless allowed
+const OS_FUNCTIONS: &[&str] = &["exec", "execute", "getenv", "remove", "rename", "tmpname"];
+const IO_FUNCTIONS: &[&str] = &[
+    "close", "input", "lines", "open", "output", "popen", "tmpfile",
+];
+const FILE_LOADERS: &[&str] = &["dofile", "loadfile"];
+
+// load for text chunks only, since crafted bytecode can break out of the VM. The mode is
+// always "t", and env is passed on only when given, as load tells nil from no env
+const TEXT_LOAD_SOURCE: &str = r#"
+local load = ...
+return function(chunk, name, _, ...)
+    return load(chunk, name, "t", ...)
+end
+"#;
+
+// loadfile and dofile for text chunks only, built on the loadfile already guarded by the
+// sandbox. As with load, env is passed on only when given
+const TEXT_FILE_LOADERS_SOURCE: &str = r#"
+local loadfile, error = ...
+local function text_loadfile(file, _, ...)
+    return loadfile(file, "t", ...)
+end
+local function text_dofile(file)
+    local chunk, message = loadfile(file, "t")
+    if not chunk then
+        error(message, 0)
+    end
+    return chunk()
+end
+return text_loadfile, text_dofile
+"#;
+
+// The only package searcher besides package.preload. package.path is read on every call, so
+// the file it resolves to is checked rather than the path, which scripts can change
+const SEARCHER_SOURCE: &str = r#"
+local package, searchpath, loadfile, check = ...
+local error = error
+return function(name)
+    local file, message = searchpath(name, package.path)
+    if not file then
+        return message
+    end
+    check(file)
+    local chunk, load_error = loadfile(file, "t")
+    if not chunk then
+        error(("error loading module '%s' from file '%s':\n\t%s"):format(name, file, load_error), 2)
+    end
+    return chunk, file
+end
+"#;
+
+// Calls the original function once the check has passed on its first argument
+const GUARD_SOURCE: &str = r#"
+local check, original = ...
+return function(first, ...)
+    check(first, ...)
+    return original(first, ...)
+end
+"#;
+
+/// What a sandboxed script may still do.
+#[derive(Default)]
+pub struct Policy {
+    // Hosts HTTP requests may go to, including their subdomains
+    pub allowed_hosts: Vec<String>,
+    // Directories files may be read from, including their subdirectories
+    pub allowed_dirs: Vec<PathBuf>,
+}
+
+fn disabled<'lua>(lua_ctx: Context<'lua>, name: &str) -> Result<Function<'lua>> {
+    let message = format!("{} is disabled in the sandbox", name);
+    lua_ctx.create_function(move |_, _: MultiValue| {
+        Err::<(), _>(rlua::Error::RuntimeError(message.clone()))
+    })
+}
+
+fn disable_all(lua_ctx: Context, table_name: &str) -> Result<()> {
+    if let Ok(table) = lua_ctx.globals().get::<_, Table>(table_name) {
+        for pair in table.clone().pairs::<String, Value>() {
+            let (name, value) = pair?;
+            if let Value::Function(_) = value {
+                let full_name = format!("{}.{}", table_name, name);
+                table.set(name, disabled(lua_ctx, &full_name)?)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn disable<'lua>(
+    lua_ctx: Context<'lua>,
+    table: &Table<'lua>,
+    table_name: &str,
+    names: &[&str],
+) -> Result<()> {
+    for name in names {
+        let full_name = match table_name {
+            "" => name.to_string(),
+            table_name => format!("{}.{}", table_name, name),
+        };
+        table.set(*name, disabled(lua_ctx, &full_name)?)?;
+    }
+    Ok(())
+}
+
+// Replace table[name] with a version that runs the check on its arguments first, if it's there
+fn guard<'lua>(
+    lua_ctx: Context<'lua>,
+    table: &Table<'lua>,
+    name: &str,
+    check: Function<'lua>,
+) -> Result<()> {
+    let Some(original) = table.get::<_, Option<Function>>(name)? else {
+        return Ok(());
+    };
+    let guarded = lua_ctx
+        .load(GUARD_SOURCE)
+        .set_name("=sandbox")?
+        .call::<_, Function>((check, original))?;
+    table.set(name, guarded)
+}
+
+fn host_allowed(url: &str, allowed_hosts: &[String]) -> bool {
+    let host = match reqwest::Url::parse(url) {
+        Ok(url) => url.host_str().unwrap_or_default().to_lowercase(),
+        Err(_) => return false,
+    };
+    allowed_hosts.iter().any(|allowed| {
+        let allowed = allowed.to_lowercase();
+        host == allowed || host.ends_with(&format!(".{}", allowed))
+    })
+}
+
+impl Policy {
+    /// Whether files under the directory can be read in the sandbox.
+    pub fn allows_dir(&self, dir: &Path) -> bool {
+        let allowed_dirs = self
+            .allowed_dirs
+            .iter()
+            .filter_map(|dir| dir.canonicalize().ok())
+            .collect::<Vec<_>>();
+        path_allowed(&dir.to_string_lossy(), &allowed_dirs)
+    }
+}
+
+fn path_allowed(path: &str, allowed_dirs: &[PathBuf]) -> bool {
+    // Resolving the path also resolves `..` and symlinks that would lead elsewhere
+    match Path::new(path).canonicalize() {
+        Ok(path) => allowed_dirs.iter().any(|dir| path.starts_with(dir)),
+        Err(_) => false,
+    }
+}
+
+/// Disables everything that reaches outside of the interpreter, except for what the policy
+/// allows: the memory library, running processes, environment variables, system information,
+/// the clipboard, the keychain, writing files, and reading files and making HTTP requests
+/// unless allowed. `load`, `loadfile` and `require` only take source code and `string.dump` is
+/// gone, so no bytecode runs, and neither `debug` nor C modules can be loaded.
+pub fn apply(lua: &Lua, policy: Policy) -> Result<()> {
+    let allowed_dirs = policy
+        .allowed_dirs
+        .iter()
+        .filter_map(|dir| dir.canonicalize().ok())
+        .collect::<Vec<_>>();
+    let allowed_hosts = policy.allowed_hosts;
+    lua.context(|lua_ctx| {
+        let globals = lua_ctx.globals();
+        let package = globals.get::<_, Option<Table>>("package")?;
+        // Taken before it's guarded, the searcher does its own checks
+        let loadfile = globals.get::<_, Option<Function>>("loadfile")?;
+        globals.set("debug", Value::Nil)?;
+        if let Some(package) = &package {
+            package
+                .get::<_, Table>("loaded")?
+                .set("debug", Value::Nil)?;
+        }
+        disable_all(lua_ctx, "memory")?;
+        disable_all(lua_ctx, "env")?;
+        disable_all(lua_ctx, "proc")?;
+        disable_all(lua_ctx, "sys")?;
+        disable_all(lua_ctx, "clipboard")?;
+        disable_all(lua_ctx, "secrets")?;
+        // Any of the globals can be left out in the config
+        if let Some(load) = globals.get::<_, Option<Function>>("load")? {
+            let text_load = lua_ctx
+                .load(TEXT_LOAD_SOURCE)
+                .set_name("=sandbox")?
+                .call::<_, Function>(load)?;
+            globals.set("load", text_load)?;
+        }
+        if let Some(string) = globals.get::<_, Option<Table>>("string")? {
+            disable(lua_ctx, &string, "string", &["dump"])?;
+        }
+        if let Some(os) = globals.get::<_, Option<Table>>("os")? {
+            disable(lua_ctx, &os, "os", OS_FUNCTIONS)?;
+            if let Some(signal) = os.get::<_, Option<Table>>("signal")? {
+                disable(lua_ctx, &signal, "os.signal", &["send"])?;
+            }
+        }
+        if let Some(archive) = globals.get::<_, Option<Table>>("archive")? {
+            let tar = archive.get::<_, Table>("tar")?;
+            disable(lua_ctx, &tar, "archive.tar", &["extract", "create"])?;
+        }
+        if let Some(compress) = globals.get::<_, Option<Table>>("compress")? {
+            disable(lua_ctx, &compress, "compress", &["stream_file"])?;
+        }
+
+        let http = globals.get::<_, Option<Table>>("http")?;
+        if allowed_hosts.is_empty() {
+            disable_all(lua_ctx, "http")?;
+            disable_all(lua_ctx, "tls")?;
+        } else {
+            if let Some(tls_module) = globals.get::<_, Option<Table>>("tls")? {
+                let hosts = allowed_hosts.clone();
+                let check_target =
+                    lua_ctx.create_function(move |_, (target, _): (String, MultiValue)| {
+                        let allowed = tls::parse_target(&target).is_some_and(|(host, _)| {
+                            host_allowed(&format!("https://{}", host), &hosts)
+                        });
+                        if allowed {
+                            Ok(())
+                        } else {
+                            Err(rlua::Error::RuntimeError(format!(
+                                "connections to {} are not allowed in the sandbox",
+                                target
+                            )))
+                        }
+                    })?;
+                guard(lua_ctx, &tls_module, "inspect", check_target)?;
+            }
+            let check_url = lua_ctx.create_function(move |_, (url, _): (String, MultiValue)| {
+                if host_allowed(&url, &allowed_hosts) {
+                    Ok(())
+                } else {
+                    Err(rlua::Error::RuntimeError(format!(
+                        "requests to {} are not allowed in the sandbox",
+                        url
+                    )))
+                }
+            })?;
+            if let Some(http) = &http {
+                guard(lua_ctx, http, "get", check_url.clone())?;
+                guard(lua_ctx, http, "json", check_url)?;
+            }
+        }
+
+        let io = globals.get::<_, Option<Table>>("io")?;
+        let fs_module = globals.get::<_, Option<Table>>("fs")?;
+        if let Some(fs_module) = &fs_module {
+            disable(lua_ctx, fs_module, "fs", fs::WRITERS)?;
+        }
+        if allowed_dirs.is_empty() {
+            if let Some(io) = &io {
+                disable(lua_ctx, io, "io", IO_FUNCTIONS)?;
+            }
+            if let Some(fs_module) = &fs_module {
+                disable(lua_ctx, fs_module, "fs", fs::READERS)?;
+            }
+            disable(lua_ctx, &globals, "", FILE_LOADERS)?;
+            if let Some(package) = &package {
+                package.set("path", "")?;
+            }
+        } else {
+            if let Some(io) = &io {
+                disable(
+                    lua_ctx,
+                    io,
+                    "io",
+                    &["close", "input", "output", "popen", "tmpfile"],
+                )?;
+            }
+            let dirs = allowed_dirs.clone();
+            let check_path =
+                lua_ctx.create_function(move |_, (path, mode): (Option<String>, Value)| {
+                    // io.lines() without a file reads stdin
+                    let path = match path {
+                        Some(path) => path,
+                        None => return Ok(()),
+                    };
+                    let writes = matches!(&mode, Value::String(mode)
+                        if mode.to_str().is_ok_and(|mode| mode.contains(['w', 'a', '+'])));
+                    if writes {
+                        return Err(rlua::Error::RuntimeError(format!(
+                            "writing {} is not allowed in the sandbox",
+                            path
+                        )));
+                    }
+                    if !path_allowed(&path, &dirs) {
+                        return Err(rlua::Error::RuntimeError(format!(
+                            "reading {} is not allowed in the sandbox",
+                            path
+                        )));
+                    }
+                    Ok(())
+                })?;
+            if let Some(io) = &io {
+                guard(lua_ctx, io, "open", check_path.clone())?;
+                guard(lua_ctx, io, "lines", check_path.clone())?;
+            }
+            for loader in FILE_LOADERS {
+                guard(lua_ctx, &globals, loader, check_path.clone())?;
+            }
+            if let Some(guarded) = globals.get::<_, Option<Function>>("loadfile")? {
+                let (text_loadfile, text_dofile) = lua_ctx
+                    .load(TEXT_FILE_LOADERS_SOURCE)
+                    .set_name("=sandbox")?
+                    .call::<_, (Function, Function)>((
+                        guarded,
+                        globals.get::<_, Function>("error")?,
+                    ))?;
+                globals.set("loadfile", text_loadfile)?;
+                globals.set("dofile", text_dofile)?;
+            }
+            if let Some(fs_module) = &fs_module {
+                for reader in fs::READERS {
+                    guard(lua_ctx, fs_module, reader, check_path.clone())?;
+                }
+            }
+            if let Some(package) = &package {
+                package.set("path", require::path_template(&allowed_dirs))?;
+            }
+        }
+        // Modules can only be required from package.preload and the allowed directories
+        if let Some(package) = &package {
+            let searchers = lua_ctx.create_table()?;
+            searchers.raw_set(
+                1,
+                package
+                    .get::<_, Table>("searchers")?
+                    .raw_get::<_, Value>(1)?,
+            )?;
+            if let Some(loadfile) = loadfile {
+                let dirs = allowed_dirs.clone();
+                let check_file = lua_ctx.create_function(move |_, file: String| {
+                    if path_allowed(&file, &dirs) {
+                        Ok(())
+                    } else {
+                        Err(rlua::Error::RuntimeError(format!(
+                            "reading {} is not allowed in the sandbox",
+                            file
+                        )))
+                    }
+                })?;
+                let searcher = lua_ctx
+                    .load(SEARCHER_SOURCE)
+                    .set_name("=sandbox")?
+                    .call::<_, Function>((
+                        package.clone(),
+                        package.get::<_, Function>("searchpath")?,
+                        loadfile,
+                        check_file,
+                    ))?;
+                searchers.raw_set(2, searcher)?;
+            }
+            package.set("searchers", searchers)?;
+            disable(lua_ctx, package, "package", &["loadlib"])?;
+        }
+        if let Some(persist_module) = globals.get::<_, Option<Table>>("persist")? {
+            disable(lua_ctx, &persist_module, "persist", &["save"])?;
+            let dirs = allowed_dirs.clone();
+            let check_path = lua_ctx.create_function(move |_, (path, _): (String, Value)| {
+                if path_allowed(&path, &dirs) {
+                    Ok(())
+                } else {
+                    Err(rlua::Error::RuntimeError(format!(
+                        "reading {} is not allowed in the sandbox",
+                        path
+                    )))
+                }
+            })?;
+            guard(lua_ctx, &persist_module, "load", check_path)?;
+        }
+        if let Some(proto_module) = globals.get::<_, Option<Table>>("proto")? {
+            // Imports can come from the include directories as well as the file's own
+            let dirs = allowed_dirs.clone();
+            let check_schema =
+                lua_ctx.create_function(move |_, (path, options): (String, Option<Table>)| {
+                    let mut paths = proto::includes(&options)?;
+                    paths.insert(0, path);
+                    match paths.iter().find(|path| !path_allowed(path, &dirs)) {
+                        Some(path) => Err(rlua::Error::RuntimeError(format!(
+                            "reading {} is not allowed in the sandbox",
+                            path
+                        ))),
+                        None => Ok(()),
+                    }
+                })?;
+            guard(lua_ctx, &proto_module, "load", check_schema)?;
+        }
+        if let Some(csv_module) = globals.get::<_, Option<Table>>("csv")? {
+            disable(lua_ctx, &csv_module, "csv", &["write"])?;
+            // csv.read reads CSV text given to it as well as files
+            let check_source =
+                lua_ctx.create_function(move |_, (source, _): (String, MultiValue)| {
+                    if csv::is_text(&source) || path_allowed(&source, &allowed_dirs) {
+                        Ok(())
+                    } else {
+                        Err(rlua::Error::RuntimeError(format!(
+                            "reading {} is not allowed in the sandbox",
+                            source
+                        )))
+                    }
+                })?;
+            guard(lua_ctx, &csv_module, "read", check_source)?;
+        }
+        if let Some(package) = &package {
+            package.set("cpath", "")?;
+        }
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    // A sandboxed state that can read the returned directory, holding `module.lua`
+    fn sandboxed() -> (Lua, TempDir) {
+        let dir = TempDir::new().unwrap();
+        std::fs::write(dir.path().join("module.lua"), "return { answer = 42 }").unwrap();
+        let lua = crate::create_lua(Some(Policy {
+            allowed_dirs: vec![dir.path().to_path_buf()],
+            ..Default::default()
+        }))
+        .unwrap();
+        (lua, dir)
+    }
+
+    fn run_error(lua: &Lua, code: &str) -> String {
+        let error = lua.context(|lua_ctx| lua_ctx.load(code).exec().unwrap_err());
+        crate::report::error_message(&error)
+    }
+
+    #[test]
+    fn requires_modules_from_allowed_dirs() {
+        let (lua, _dir) = sandboxed();
+        lua.context(|lua_ctx| {
+            let answer = lua_ctx
+                .load(r#"return require("module").answer"#)
+                .eval::<i64>()
+                .unwrap();
+            assert_eq!(answer, 42);
+        });
+    }
+
+    #[test]
+    fn denies_debug() {
+        let (lua, _dir) = sandboxed();
+        lua.context(|lua_ctx| {
+            let debug = lua_ctx.load("return debug").eval::<Value>().unwrap();
+            assert!(matches!(debug, Value::Nil));
+        });
+        assert!(run_error(&lua, r#"require("debug")"#).contains("module 'debug' not found"));
+    }
+
+    #[test]
+    fn checks_required_files_against_allowed_dirs() {
+        let (lua, _dir) = sandboxed();
+        let error = run_error(&lua, r#"package.path = "/etc/?"; require("hostname")"#);
+        assert!(error.contains("not allowed in the sandbox"), "{}", error);
+        let error = run_error(&lua, r#"package.cpath = "/usr/lib/?.so"; require("x")"#);
+        assert!(error.contains("not found"), "{}", error);
+        let error = run_error(&lua, r#"package.loadlib("/usr/lib/x.so", "*")"#);
+        assert!(error.contains("disabled in the sandbox"), "{}", error);
+    }
+
+    #[test]
+    fn opens_files_only_in_allowed_dirs() {
+        let (lua, dir) = sandboxed();
+        let path = dir.path().join("module.lua");
+        lua.context(|lua_ctx| {
+            let opened = lua_ctx
+                .load(&format!("return io.open({:?}):read('a')", path.display()))
+                .eval::<String>()
+                .unwrap();
+            assert!(opened.contains("answer"));
+        });
+        let error = run_error(&lua, r#"io.open("/etc/hostname")"#);
+        assert!(error.contains("not allowed in the sandbox"), "{}", error);
+        let error = run_error(&lua, &format!("io.open({:?}, 'w')", path.display()));
+        assert!(error.contains("not allowed in the sandbox"), "{}", error);
+    }
+
+    #[test]
+    fn runs_no_bytecode() {
+        let (lua, _dir) = sandboxed();
+        assert!(run_error(&lua, "string.dump(print)").contains("disabled in the sandbox"));
+        lua.context(|lua_ctx| {
+            let (chunk, _) = lua_ctx
+                .load(r#"return load("\27Lua", "x", "b")"#)
+                .eval::<(Value, String)>()
+                .unwrap();
+            assert!(matches!(chunk, Value::Nil));
+        });
+    }
+}
@@ -0,0 +1,233 @@
+/*
+   Copyright (C) 2022  Kalka
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License, or (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use chrono::Local;
+use croner::Cron;
+use cumulus::logger;
+use rlua::{AnyUserData, Context, Function, Lua, Result, Table};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use crate::help::{self, FunctionDoc};
+use crate::report;
+use crate::timer::{self, Repeat, Timer};
+
+// Name of the registry table mapping the timers of jobs to the functions they run
+const JOBS_REGISTRY_KEY: &str = "rluaterm.schedule.jobs";
+
+const SCHEDULE_DOCS: &[FunctionDoc] = &[
+    FunctionDoc {
+        name: "cron",
+        signature: "schedule.cron(expression, job, [options]) -> timer",
+        description: "Runs the job at the times the cron expression matches in the local time zone: minute, hour, day of the month, month and day of the week, with an optional seconds field in front, like \"*/5 * * * *\" for every five minutes or \"0 3 * * 1-5\" for 3 AM on weekdays. Each run is logged with how long it took. A job that raises an error has it logged and runs again next time. options.name names the job in the log, the expression by default. Jobs run on the timer loop, while the script waits in time.sleep or schedule.run, and the timer it returns stops them with cancel().",
+        example: "schedule.cron(\"0 * * * *\", function()\n    fs.copy(\"data.db\", \"backups/\" .. time.localtime():format(\"%H\") .. \".db\")\nend, { name = \"hourly backup\" })",
+    },
+    FunctionDoc {
+        name: "run",
+        signature: "schedule.run()",
+        description: "Logs the scheduled jobs with when they run next, then runs them, along with any other timers, until they're cancelled or Ctrl+C. Raises an error when no jobs are scheduled. `rluaterm schedule jobs.lua` runs the script and then this.",
+        example: "schedule.cron(\"*/10 * * * *\", check_disk_space)\nschedule.run()",
+    },
+    FunctionDoc {
+        name: "jobs",
+        signature: "schedule.jobs() -> table",
+        description: "The scheduled jobs, each a table with its name, its cron expression and next, when it runs next in seconds since 1970.",
+        example: "for _, job in ipairs(schedule.jobs()) do\n    print(job.name, time.format(job.next, \"%H:%M\"))\nend",
+    },
+];
+
+// Set by `rluaterm schedule`, which runs the scheduler once the script is done
+static RUN_AFTER_SCRIPT: AtomicBool = AtomicBool::new(false);
+
+struct Job {
+    name: String,
+    expression: String,
+    timer: i64,
+}
+
+static JOBS: Mutex<Vec<Job>> = Mutex::new(Vec::new());
+
+/// Makes the script's jobs run once it's done, as `rluaterm schedule` does.
+pub fn run_after_script() {
+    RUN_AFTER_SCRIPT.store(true, Ordering::SeqCst);
+}
+
+/// Whether the scheduler runs once the script is done.
+pub fn runs_after_script() -> bool {
+    RUN_AFTER_SCRIPT.load(Ordering::SeqCst)
+}
+
+fn jobs_table(lua_ctx: Context) -> Result<Table> {
+    if let Ok(table) = lua_ctx.named_registry_value::<_, Table>(JOBS_REGISTRY_KEY) {
+        return Ok(table);
+    }
+    let table = lua_ctx.create_table()?;
+    lua_ctx.set_named_registry_value(JOBS_REGISTRY_KEY, table.clone())?;
+    Ok(table)
+}
+
+// The jobs whose timers haven't been cancelled, with when they run next
+fn active_jobs<T>(f: impl Fn(&Job, chrono::DateTime<Local>) -> T) -> Vec<T> {
+    JOBS.lock()
+        .unwrap()
+        .iter()
+        .filter_map(|job| timer::next_run(job.timer).map(|next| f(job, next)))
+        .collect()
+}
+
+fn cron<'lua>(
+    lua_ctx: Context<'lua>,
+    expression: String,
+    job: Function<'lua>,
+    options: Option<Table<'lua>>,
+) -> Result<Timer> {
+    let cron = expression.parse::<Cron>().map_err(|error| {
+        rlua::Error::RuntimeError(format!(
+            "bad argument #1 to 'cron' (invalid cron expression: {})",
+            error
+        ))
+    })?;
+    let at = cron
+        .find_next_occurrence(&Local::now(), false)
+        .map_err(|error| {
+            rlua::Error::RuntimeError(format!(
+                "bad argument #1 to 'cron' ({} never matches: {})",
+                expression, error
+            ))
+        })?;
+    let name = match options {
+        Some(options) => options.get::<_, Option<String>>("name")?,
+        None => None,
+    }
+    .unwrap_or_else(|| expression.clone());
+    let due = Instant::now() + (at - Local::now()).to_std().unwrap_or_default();
+    let run = lua_ctx.create_function(|lua_ctx, handle: AnyUserData| run_job(lua_ctx, handle))?;
+    let timer = timer::start(
+        lua_ctx,
+        run,
+        due,
+        Some(Repeat::Cron {
+            cron: Box::new(cron),
+            at,
+        }),
+    )?;
+    jobs_table(lua_ctx)?.set(timer.id(), job)?;
+    JOBS.lock().unwrap().push(Job {
+        name,
+        expression,
+        timer: timer.id(),
+    });
+    Ok(timer)
+}
+
+// Runs the job with a log of how it went. Errors only fail the run, unless they stop the script.
+fn run_job<'lua>(lua_ctx: Context<'lua>, handle: AnyUserData<'lua>) -> Result<()> {
+    let id = handle.borrow::<Timer>()?.id();
+    let Some(job) = jobs_table(lua_ctx)?.get::<_, Option<Function>>(id)? else {
+        return Ok(());
+    };
+    let name = JOBS
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|job| job.timer == id)
+        .map(|job| job.name.clone())
+        .unwrap_or_default();
+    logger::info(&format!("Running {}", name));
+    let started = Instant::now();
+    match job.call::<_, ()>(handle) {
+        Ok(()) => logger::info(&format!("Finished {} in {:.2?}", name, started.elapsed())),
+        Err(error)
+            if crate::is_interrupted(&error) || crate::requested_exit_code(&error).is_some() =>
+        {
+            return Err(error)
+        }
+        Err(error) => logger::error(&format!(
+            "{} failed after {:.2?} [{}]",
+            name,
+            started.elapsed(),
+            report::error_message(&error)
+                .lines()
+                .next()
+                .unwrap_or_default()
+        )),
+    }
+    Ok(())
+}
+
+/// Logs the scheduled jobs and runs them until they're cancelled.
+pub fn run(lua_ctx: Context) -> Result<()> {
+    let jobs = active_jobs(|job, next| {
+        format!(
+            "Scheduled {} ({}), next run at {}",
+            job.name,
+            job.expression,
+            next.format("%Y-%m-%d %H:%M:%S")
+        )
+    });
+    if jobs.is_empty() {
+        return Err(rlua::Error::RuntimeError(
+            "no jobs are scheduled, add them with schedule.cron".to_string(),
+        ));
+    }
+    for job in jobs {
+        logger::info(&job);
+    }
+    timer::wait(lua_ctx, None)
+}
+
+pub fn load_schedule_library(lua: &Lua) -> Result<()> {
+    lua.context(|lua_ctx| {
+        let schedule = lua_ctx.create_table()?;
+
+        schedule.set(
+            "cron",
+            lua_ctx.create_function(
+                |lua_ctx, (expression, job, options): (String, Function, Option<Table>)| {
+                    cron(lua_ctx, expression, job, options)
+                },
+            )?,
+        )?;
+
+        schedule.set("run", lua_ctx.create_function(|lua_ctx, ()| run(lua_ctx))?)?;
+
+        schedule.set(
+            "jobs",
+            lua_ctx.create_function(|lua_ctx, ()| {
+                active_jobs(|job, next| {
+                    let table = lua_ctx.create_table()?;
+                    table.set("name", job.name.as_str())?;
+                    table.set("cron", job.expression.as_str())?;
+                    table.set("next", next.timestamp())?;
+                    Ok(table)
+                })
+                .into_iter()
+                .collect::<Result<Vec<_>>>()
+            })?,
+        )?;
+
+        help::register_module_docs(
+            lua_ctx,
+            "schedule",
+            "Running jobs on a cron schedule",
+            &schedule,
+            SCHEDULE_DOCS,
+        )?;
+        lua_ctx.globals().set("schedule", schedule)?;
+        Ok(())
+    })
+}
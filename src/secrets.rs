@@ -0,0 +1,102 @@
+/*
+   Copyright (C) 2022  Kalka
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License, or (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use rlua::{Lua, Result};
+
+use crate::error;
+use crate::help::{self, FunctionDoc};
+
+const SECRETS_DOCS: &[FunctionDoc] = &[
+    FunctionDoc {
+        name: "get",
+        signature: "secrets.get(service, account) -> string",
+        description: "The secret stored for the account under the service in the system's keychain: the Keychain on macOS, the Credential Manager on Windows, and the Secret Service (GNOME Keyring or KWallet) on Linux. nil when there's none. Raises an error saying why when the keychain can't be reached or refuses access.",
+        example: "local token = secrets.get(\"github\", \"deploy-bot\") or error(\"run setup.lua first\")",
+    },
+    FunctionDoc {
+        name: "set",
+        signature: "secrets.set(service, account, secret)",
+        description: "Stores a secret for the account under the service in the system's keychain, replacing any that was there, where other programs can't read it without asking.",
+        example: "io.write(\"GitHub token: \")\nsecrets.set(\"github\", \"deploy-bot\", io.read())",
+    },
+    FunctionDoc {
+        name: "delete",
+        signature: "secrets.delete(service, account) -> boolean",
+        description: "Removes the secret for the account under the service from the system's keychain, returning whether there was one.",
+        example: "secrets.delete(\"github\", \"deploy-bot\")",
+    },
+];
+
+fn entry(service: &str, account: &str) -> Result<keyring::Entry> {
+    keyring::Entry::new(service, account).map_err(|source| secrets_error(service, account, source))
+}
+
+fn secrets_error(service: &str, account: &str, source: keyring::Error) -> rlua::Error {
+    rlua::Error::external(error::Error::Secrets {
+        service: service.to_string(),
+        account: account.to_string(),
+        source,
+    })
+}
+
+pub fn load_secrets_library(lua: &Lua) -> Result<()> {
+    lua.context(|lua_ctx| {
+        let secrets = lua_ctx.create_table()?;
+
+        secrets.set(
+            "get",
+            lua_ctx.create_function(
+                |lua_ctx, (service, account): (String, String)| match entry(&service, &account)?
+                    .get_secret()
+                {
+                    Ok(secret) => Ok(Some(lua_ctx.create_string(&secret)?)),
+                    Err(keyring::Error::NoEntry) => Ok(None),
+                    Err(source) => Err(secrets_error(&service, &account, source)),
+                },
+            )?,
+        )?;
+        secrets.set(
+            "set",
+            lua_ctx.create_function(
+                |_, (service, account, secret): (String, String, rlua::String)| {
+                    entry(&service, &account)?
+                        .set_secret(secret.as_bytes())
+                        .map_err(|source| secrets_error(&service, &account, source))
+                },
+            )?,
+        )?;
+        secrets.set(
+            "delete",
+            lua_ctx.create_function(|_, (service, account): (String, String)| {
+                match entry(&service, &account)?.delete_credential() {
+                    Ok(()) => Ok(true),
+                    Err(keyring::Error::NoEntry) => Ok(false),
+                    Err(source) => Err(secrets_error(&service, &account, source)),
+                }
+            })?,
+        )?;
+
+        help::register_module_docs(
+            lua_ctx,
+            "secrets",
+            "Secrets kept in the system's keychain",
+            &secrets,
+            SECRETS_DOCS,
+        )?;
+        lua_ctx.globals().set("secrets", secrets)?;
+        Ok(())
+    })
+}
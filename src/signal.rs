@@ -0,0 +1,250 @@
+/*
+   Copyright (C) 2022  Kalka
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License, or (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use rlua::{Context, Function, Lua, Result, Table, Value};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::help::{self, FunctionDoc};
+use crate::traceback;
+
+// Name of the registry table mapping signal names to the functions handling them
+const HANDLERS_REGISTRY_KEY: &str = "rluaterm.signal.handlers";
+
+// Replaces the interrupt hook with a Lua hook doing the same checks, like the profiler does.
+// Handlers can't be called from the interrupt hook itself, since rlua shifts the registers of
+// the running function while it runs, which handlers reading its locals would see.
+const HOOK_SOURCE: &str = r#"
+local sethook, gethook, check, interval = ...
+if gethook() == "external hook" then
+    sethook(function()
+        check()
+    end, "", interval)
+end
+"#;
+
+// Signals received since the last check, a bit for each signal number. Set by the signal
+// handler, which can't do anything but this safely.
+static PENDING: AtomicU64 = AtomicU64::new(0);
+
+// The signals os.signal.on can handle besides INT, which Ctrl+C's handler already catches
+#[cfg(unix)]
+const HANDLED_SIGNALS: &[(&str, libc::c_int)] = &[("TERM", libc::SIGTERM), ("HUP", libc::SIGHUP)];
+
+// The signals os.signal.send knows by name
+#[cfg(unix)]
+const SIGNAL_NAMES: &[(&str, libc::c_int)] = &[
+    ("HUP", libc::SIGHUP),
+    ("INT", libc::SIGINT),
+    ("QUIT", libc::SIGQUIT),
+    ("KILL", libc::SIGKILL),
+    ("USR1", libc::SIGUSR1),
+    ("USR2", libc::SIGUSR2),
+    ("ALRM", libc::SIGALRM),
+    ("TERM", libc::SIGTERM),
+    ("CONT", libc::SIGCONT),
+    ("STOP", libc::SIGSTOP),
+    ("TSTP", libc::SIGTSTP),
+    ("WINCH", libc::SIGWINCH),
+];
+
+const SIGNAL_DOCS: &[FunctionDoc] = &[
+    FunctionDoc {
+        name: "send",
+        signature: "os.signal.send(pid, signal)",
+        description: "Sends a signal to a process, given by name like \"TERM\", \"INT\", \"HUP\", \"KILL\" or \"USR1\", with or without SIG in front, or by number. On Unix only. Raises an error saying why when it can't be sent, as when there's no such process.",
+        example: "os.signal.send(server.pid, \"TERM\")",
+    },
+    FunctionDoc {
+        name: "on",
+        signature: "os.signal.on(signal, handler)",
+        description: "Calls the handler with the signal's name when rluaterm receives \"INT\" (Ctrl+C), \"TERM\" or \"HUP\", instead of stopping. The handler runs as soon as the running Lua code gets to it, and can call os.exit to shut down once it has cleaned up. A nil handler brings back what the signal normally does. Only \"INT\" can be handled on Windows.",
+        example: "os.signal.on(\"TERM\", function()\n    save_state()\n    os.exit(0)\nend)",
+    },
+];
+
+// The name a signal is registered under: upper case without the SIG prefix
+fn normalize(name: &str) -> String {
+    let name = name.to_ascii_uppercase();
+    match name.strip_prefix("SIG") {
+        Some(stripped) => stripped.to_string(),
+        None => name,
+    }
+}
+
+#[cfg(unix)]
+extern "C" fn record_signal(signal: libc::c_int) {
+    PENDING.fetch_or(1 << signal, Ordering::SeqCst);
+}
+
+// Catches the signal, or lets it do what it normally does again when `catch` isn't set
+#[cfg(unix)]
+fn set_disposition(name: &str, catch: bool) -> Result<()> {
+    let Some((_, signal)) = HANDLED_SIGNALS.iter().find(|(handled, _)| *handled == name) else {
+        return Ok(());
+    };
+    let handler = match catch {
+        true => record_signal as extern "C" fn(libc::c_int) as libc::sighandler_t,
+        false => libc::SIG_DFL,
+    };
+    unsafe { libc::signal(*signal, handler) };
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_disposition(name: &str, _catch: bool) -> Result<()> {
+    match name {
+        "INT" => Ok(()),
+        _ => Err(rlua::Error::RuntimeError(format!(
+            "SIG{} can't be handled on this platform",
+            name
+        ))),
+    }
+}
+
+fn handlers(lua_ctx: Context) -> Result<Table> {
+    if let Ok(table) = lua_ctx.named_registry_value::<_, Table>(HANDLERS_REGISTRY_KEY) {
+        return Ok(table);
+    }
+    let table = lua_ctx.create_table()?;
+    lua_ctx.set_named_registry_value(HANDLERS_REGISTRY_KEY, table.clone())?;
+    Ok(table)
+}
+
+fn on<'lua>(lua_ctx: Context<'lua>, name: String, handler: Option<Function<'lua>>) -> Result<()> {
+    let name = normalize(&name);
+    if !["INT", "TERM", "HUP"].contains(&name.as_str()) {
+        return Err(rlua::Error::RuntimeError(format!(
+            "bad argument #1 to 'on' (can't handle SIG{}, only INT, TERM and HUP)",
+            name
+        )));
+    }
+    let catch = handler.is_some();
+    if catch {
+        let check = lua_ctx.create_function(|lua_ctx, ()| crate::check_interrupt(lua_ctx))?;
+        lua_ctx
+            .load(HOOK_SOURCE)
+            .set_name("=signal")?
+            .call::<_, ()>((
+                traceback::sethook(lua_ctx)?,
+                traceback::gethook(lua_ctx)?,
+                check,
+                crate::INTERRUPT_CHECK_INTERVAL,
+            ))?;
+    }
+    handlers(lua_ctx)?.set(name.as_str(), handler)?;
+    set_disposition(&name, catch)
+}
+
+#[cfg(unix)]
+fn send(pid: i64, signal: Value) -> Result<()> {
+    let (name, number) = match signal {
+        Value::Integer(number) => (number.to_string(), number as libc::c_int),
+        Value::String(name) => {
+            let name = normalize(name.to_str()?);
+            let number = SIGNAL_NAMES
+                .iter()
+                .find(|(known, _)| *known == name)
+                .map(|(_, number)| *number)
+                .ok_or_else(|| {
+                    rlua::Error::RuntimeError(format!(
+                        "bad argument #2 to 'send' (unknown signal '{}')",
+                        name
+                    ))
+                })?;
+            (format!("SIG{}", name), number)
+        }
+        signal => {
+            return Err(rlua::Error::RuntimeError(format!(
+                "bad argument #2 to 'send' (signal name or number expected, got {})",
+                signal.type_name()
+            )))
+        }
+    };
+    if unsafe { libc::kill(pid as libc::pid_t, number) } != 0 {
+        return Err(rlua::Error::external(crate::error::Error::Signal {
+            signal: name,
+            pid,
+            source: std::io::Error::last_os_error(),
+        }));
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn send(_pid: i64, _signal: Value) -> Result<()> {
+    Err(rlua::Error::RuntimeError(
+        "os.signal.send is only available on Unix".to_string(),
+    ))
+}
+
+// Calls the handler of the signal, returning whether there was one
+fn call_handler(lua_ctx: Context, name: &str) -> Result<bool> {
+    match handlers(lua_ctx)?.get::<_, Option<Function>>(name)? {
+        Some(handler) => {
+            handler.call::<_, ()>(name)?;
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+/// Hands Ctrl+C to the script's INT handler, returning false when there's none and the running
+/// code should be interrupted.
+pub fn handle_interrupt(lua_ctx: Context) -> Result<bool> {
+    call_handler(lua_ctx, "INT")
+}
+
+/// Calls the handlers of the signals received since the last call.
+pub fn dispatch_pending(lua_ctx: Context) -> Result<()> {
+    if PENDING.load(Ordering::Relaxed) == 0 {
+        return Ok(());
+    }
+    #[cfg(unix)]
+    {
+        let pending = PENDING.swap(0, Ordering::SeqCst);
+        for (name, signal) in HANDLED_SIGNALS {
+            if pending & (1 << signal) != 0 {
+                call_handler(lua_ctx, name)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Adds `os.signal` to the os library.
+pub fn attach_signal_library(lua: &Lua) -> Result<()> {
+    lua.context(|lua_ctx| {
+        let signal = lua_ctx.create_table()?;
+
+        signal.set(
+            "send",
+            lua_ctx.create_function(|_, (pid, signal): (i64, Value)| send(pid, signal))?,
+        )?;
+
+        signal.set(
+            "on",
+            lua_ctx.create_function(|lua_ctx, (name, handler): (String, Option<Function>)| {
+                on(lua_ctx, name, handler)
+            })?,
+        )?;
+
+        help::register_module_docs(lua_ctx, "os.signal", "Signals", &signal, SIGNAL_DOCS)?;
+        lua_ctx
+            .globals()
+            .get::<_, Table>("os")?
+            .set("signal", signal)
+    })
+}
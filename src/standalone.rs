@@ -0,0 +1,89 @@
+/*
+   Copyright (C) 2022  Kalka
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License, or (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+// Executables built with `rluaterm build` are a copy of rluaterm with the compiled script
+// appended, followed by the script's length as 8 little-endian bytes and a marker:
+//
+//   [rluaterm][bytecode][length][MARKER]
+use rlua::Result;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::compile;
+
+const MARKER: &[u8; 8] = b"RLUATERM";
+const TRAILER_LENGTH: u64 = 16;
+
+/// Where `rluaterm build` writes to without `-o`: the script path without its extension.
+pub fn default_output(script: &Path) -> PathBuf {
+    script.with_extension(std::env::consts::EXE_EXTENSION)
+}
+
+/// The script built into this executable, if it was made with `rluaterm build`.
+pub fn embedded_script() -> Option<Vec<u8>> {
+    let mut file = File::open(std::env::current_exe().ok()?).ok()?;
+    let size = file.metadata().ok()?.len();
+    if size < TRAILER_LENGTH {
+        return None;
+    }
+    file.seek(SeekFrom::End(-(TRAILER_LENGTH as i64))).ok()?;
+    let mut trailer = [0; TRAILER_LENGTH as usize];
+    file.read_exact(&mut trailer).ok()?;
+    if &trailer[8..] != MARKER {
+        return None;
+    }
+    let length = u64::from_le_bytes(trailer[..8].try_into().ok()?);
+    if length > size - TRAILER_LENGTH {
+        return None;
+    }
+    file.seek(SeekFrom::End(-((TRAILER_LENGTH + length) as i64)))
+        .ok()?;
+    let mut script = vec![0; length as usize];
+    file.read_exact(&mut script).ok()?;
+    Some(script)
+}
+
+/// Builds an executable that runs the script, from a copy of this one. Returns whether the
+/// script compiled, after reporting why it didn't otherwise.
+pub fn build(script: &Path, output: &Path) -> Result<bool> {
+    // Debug information is kept so errors still point at lines of the script
+    let bytecode = match compile::compile_script(script, false)? {
+        Some(bytecode) => bytecode,
+        None => return Ok(false),
+    };
+    let write = || -> std::io::Result<()> {
+        let executable = std::env::current_exe()?;
+        // Building from a built executable would otherwise stack the scripts
+        let own_length = match embedded_script() {
+            Some(embedded) => {
+                std::fs::metadata(&executable)?.len() - embedded.len() as u64 - TRAILER_LENGTH
+            }
+            None => std::fs::metadata(&executable)?.len(),
+        };
+        // Copying keeps the permissions, so the result is executable too
+        std::fs::copy(&executable, output)?;
+        let mut file = OpenOptions::new().write(true).open(output)?;
+        file.set_len(own_length)?;
+        file.seek(SeekFrom::End(0))?;
+        file.write_all(&bytecode)?;
+        file.write_all(&(bytecode.len() as u64).to_le_bytes())?;
+        file.write_all(MARKER)?;
+        Ok(())
+    };
+    write().map_err(rlua::Error::external)?;
+    Ok(true)
+}
@@ -0,0 +1,112 @@
+/*
+   Copyright (C) 2022  Kalka
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License, or (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use cumulus::logger;
+use rlua::{Function, Lua, Result};
+
+use crate::help::{self, FunctionDoc};
+use crate::traceback;
+
+// Returns `on` and `off` functions that set and remove a metatable on _G raising on reads of
+// undefined globals. Globals are declared by assigning them, which warns when it happens
+// inside a function rather than at the top level of a chunk, as it's usually a missing `local`
+const STRICT_SOURCE: &str = r#"
+local warn, getinfo = ...
+local getmetatable, setmetatable = getmetatable, setmetatable
+local rawset, tostring, error = rawset, tostring, error
+local declared = {}
+local metatable = {}
+
+function metatable.__index(_, name)
+    if not declared[name] then
+        error("undefined global '" .. tostring(name) .. "'", 2)
+    end
+end
+
+function metatable.__newindex(globals, name, value)
+    if not declared[name] then
+        local info = getinfo(2, "Sl")
+        if info and info.what ~= "main" and info.what ~= "C" then
+            warn(("assignment to undeclared global '%s' at %s:%d"):format(
+                tostring(name), info.short_src, info.currentline))
+        end
+        declared[name] = true
+    end
+    rawset(globals, name, value)
+end
+
+local function on()
+    setmetatable(_G, metatable)
+end
+
+local function off()
+    if getmetatable(_G) == metatable then
+        setmetatable(_G, nil)
+    end
+end
+
+return on, off
+"#;
+
+const STRICT_DOCS: &[FunctionDoc] = &[
+    FunctionDoc {
+        name: "on",
+        signature: "strict.on()",
+        description:
+            "Raises on reads of undefined globals and warns on globals assigned inside functions",
+        example: "strict.on()\nprint(undefined_name) -- error: undefined global 'undefined_name'",
+    },
+    FunctionDoc {
+        name: "off",
+        signature: "strict.off()",
+        description: "Lets undefined globals read as nil again",
+        example: "strict.off()",
+    },
+];
+
+pub fn load_strict_library(lua: &Lua) -> Result<()> {
+    lua.context(|lua_ctx| {
+        let warn = lua_ctx.create_function(|_, message: String| {
+            logger::warn(&format!("strict: {}", message));
+            Ok(())
+        })?;
+        let (on, off) = lua_ctx
+            .load(STRICT_SOURCE)
+            .set_name("=strict")?
+            .call::<_, (Function, Function)>((warn, traceback::getinfo(lua_ctx)?))?;
+
+        let strict_lib = lua_ctx.create_table()?;
+        strict_lib.set("on", on)?;
+        strict_lib.set("off", off)?;
+        help::register_module_docs(
+            lua_ctx,
+            "strict",
+            "Catching undefined globals",
+            &strict_lib,
+            STRICT_DOCS,
+        )?;
+        lua_ctx.globals().set("strict", strict_lib)?;
+        Ok(())
+    })
+}
+
+/// Turns strict mode on, as `strict.on()` does.
+pub fn enable(lua: &Lua) -> Result<()> {
+    lua.context(|lua_ctx| {
+        let strict_lib = lua_ctx.globals().get::<_, rlua::Table>("strict")?;
+        strict_lib.get::<_, Function>("on")?.call(())
+    })
+}
@@ -0,0 +1,390 @@
+/*
+   Copyright (C) 2022  Kalka
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License, or (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use rlua::{Context, Lua, Result, Table};
+use std::io;
+use std::path::Path;
+use std::sync::{Mutex, OnceLock};
+use std::time::Instant;
+use sysinfo::{
+    CpuRefreshKind, Disks, Pid, Process, ProcessRefreshKind, ProcessesToUpdate, System, UpdateKind,
+};
+
+use crate::error;
+use crate::help::{self, FunctionDoc};
+
+const SYS_DOCS: &[FunctionDoc] = &[
+    FunctionDoc {
+        name: "processes",
+        signature: "sys.processes([name]) -> table",
+        description: "The running processes, or only the ones with the name, sorted by pid. Each is a table with its pid, parent pid, name, cmdline, exe, status, cpu usage in percent of one core since the last look, memory in bytes, and start_time in seconds since 1970. The first call waits a moment to measure the cpu usage.",
+        example: "if #sys.processes(\"nginx\") == 0 then\n    print(\"nginx is not running\")\nend",
+    },
+    FunctionDoc {
+        name: "process",
+        signature: "sys.process(pid) -> table",
+        description: "The process with the pid, as sys.processes describes it, or nil when there's none.",
+        example: "local me = sys.process(server.pid)\nprint(me.memory / 1024 / 1024 .. \" MiB\")",
+    },
+    FunctionDoc {
+        name: "info",
+        signature: "sys.info() -> table",
+        description: "Describes the system: os, os_version, kernel and arch, cpu with its model, cores, physical_cores and frequency in MHz, load with the one, five and fifteen minute load averages, memory with its total, used and available bytes and swap_total and swap_used, the mounted disks with their name, mount, filesystem, total, used and available bytes and whether they're removable, uptime and boot_time in seconds, and battery with its percent and status where there is one. What the system doesn't report is nil.",
+        example: "local info = sys.info()\nprint(info.os, info.os_version, info.cpu.model)\nfor _, disk in ipairs(info.disks) do\n    print(disk.mount, disk.used * 100 // disk.total .. \"%\")\nend",
+    },
+    FunctionDoc {
+        name: "user",
+        signature: "sys.user() -> table",
+        description: "The user rluaterm runs as, with its name and home directory, and its uid and gid on Unix.",
+        example: "if sys.user().uid ~= 0 then\n    error(\"run this as root\")\nend",
+    },
+    FunctionDoc {
+        name: "hostname",
+        signature: "sys.hostname() -> string",
+        description: "The name of the machine, or nil when the system doesn't report one.",
+        example: "log.info(\"deploying from \" .. sys.hostname())",
+    },
+    FunctionDoc {
+        name: "dirs",
+        signature: "sys.dirs() -> table",
+        description: "The directories where things go on this platform: home, config, cache, data, downloads, documents, desktop and temp. Those the platform doesn't have are nil.",
+        example: "local state = path.join(sys.dirs().data, \"mytool\", \"state.json\")",
+    },
+    FunctionDoc {
+        name: "open",
+        signature: "sys.open(target)",
+        description: "Opens a URL in the browser, or a file or directory in the application the desktop uses for it, without waiting for it to close. Raises an error when the file doesn't exist or there's nothing to open it with.",
+        example: "fs.write(\"report.html\", html)\nsys.open(\"report.html\")",
+    },
+    FunctionDoc {
+        name: "which",
+        signature: "sys.which(name) -> string",
+        description: "The full path of the executable that running the name would start, looked up on PATH like the shell does (with PATHEXT on Windows), or nil when there's none.",
+        example: "if not sys.which(\"ffmpeg\") then\n    error(\"ffmpeg is not installed\")\nend",
+    },
+    FunctionDoc {
+        name: "shell_quote",
+        signature: "sys.shell_quote(arg) -> string",
+        description: "Quotes the argument for sh so it's passed on as it is, spaces, quotes and $ included. Only needed when building a command string, since os.exec and proc.spawn take a table of arguments that need no quoting.",
+        example: "os.exec(\"tar czf backup.tgz \" .. sys.shell_quote(dir) .. \" | ssh host 'cat > backup.tgz'\")",
+    },
+];
+
+// Kept between calls, since cpu usage is measured from one refresh to the next
+static SYSTEM: OnceLock<Mutex<(System, Option<Instant>)>> = OnceLock::new();
+
+fn refresh_kind() -> ProcessRefreshKind {
+    ProcessRefreshKind::nothing()
+        .with_cpu()
+        .with_memory()
+        .with_cmd(UpdateKind::OnlyIfNotSet)
+        .with_exe(UpdateKind::OnlyIfNotSet)
+}
+
+// Runs `f` on the processes, refreshed. The first refresh is repeated after a moment, since cpu
+// usage needs two to be measured.
+fn with_processes<T>(processes: ProcessesToUpdate, f: impl FnOnce(&System) -> T) -> T {
+    let system = SYSTEM.get_or_init(|| Mutex::new((System::new(), None)));
+    let mut system = system
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    let (system, last_refresh) = &mut *system;
+    if last_refresh.is_none() {
+        system.refresh_processes_specifics(ProcessesToUpdate::All, true, refresh_kind());
+        std::thread::sleep(sysinfo::MINIMUM_CPU_UPDATE_INTERVAL);
+    }
+    system.refresh_processes_specifics(processes, true, refresh_kind());
+    *last_refresh = Some(Instant::now());
+    f(system)
+}
+
+fn process_table<'lua>(lua_ctx: Context<'lua>, process: &Process) -> Result<Table<'lua>> {
+    let table = lua_ctx.create_table()?;
+    table.set("pid", process.pid().as_u32())?;
+    table.set("parent", process.parent().map(Pid::as_u32))?;
+    table.set("name", process.name().to_string_lossy().into_owned())?;
+    let cmdline = process
+        .cmd()
+        .iter()
+        .map(|arg| arg.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join(" ");
+    table.set("cmdline", cmdline)?;
+    table.set(
+        "exe",
+        process.exe().map(|exe| exe.to_string_lossy().into_owned()),
+    )?;
+    table.set("status", process.status().to_string().to_lowercase())?;
+    table.set("cpu", process.cpu_usage())?;
+    table.set("memory", process.memory())?;
+    table.set("start_time", process.start_time())?;
+    Ok(table)
+}
+
+// The first battery in /sys/class/power_supply, with its charge in percent and status
+#[cfg(target_os = "linux")]
+fn battery(lua_ctx: Context) -> Result<Option<Table>> {
+    let Ok(supplies) = std::fs::read_dir("/sys/class/power_supply") else {
+        return Ok(None);
+    };
+    let mut supplies = supplies
+        .flatten()
+        .map(|entry| entry.path())
+        .collect::<Vec<_>>();
+    supplies.sort();
+    let read = |path: &std::path::Path, name: &str| {
+        std::fs::read_to_string(path.join(name)).map(|value| value.trim().to_string())
+    };
+    for supply in supplies {
+        if read(&supply, "type").is_ok_and(|kind| kind == "Battery") {
+            let table = lua_ctx.create_table()?;
+            table.set(
+                "percent",
+                read(&supply, "capacity")
+                    .ok()
+                    .and_then(|capacity| capacity.parse::<u8>().ok()),
+            )?;
+            table.set(
+                "status",
+                read(&supply, "status")
+                    .ok()
+                    .map(|status| status.to_lowercase()),
+            )?;
+            return Ok(Some(table));
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn battery(_lua_ctx: Context) -> Result<Option<Table>> {
+    Ok(None)
+}
+
+fn info(lua_ctx: Context) -> Result<Table> {
+    let mut system = System::new();
+    system.refresh_memory();
+    system.refresh_cpu_list(CpuRefreshKind::nothing().with_frequency());
+    let info = lua_ctx.create_table()?;
+    info.set("os", System::name())?;
+    info.set("os_version", System::os_version())?;
+    info.set("kernel", System::kernel_version())?;
+    info.set("arch", System::cpu_arch())?;
+
+    let cpu = lua_ctx.create_table()?;
+    let cpus = system.cpus();
+    cpu.set(
+        "model",
+        cpus.first().map(|cpu| cpu.brand().trim().to_string()),
+    )?;
+    cpu.set("cores", cpus.len())?;
+    cpu.set("physical_cores", System::physical_core_count())?;
+    cpu.set("frequency", cpus.first().map(|cpu| cpu.frequency()))?;
+    info.set("cpu", cpu)?;
+
+    let load_average = System::load_average();
+    let load = lua_ctx.create_table()?;
+    load.set("one", load_average.one)?;
+    load.set("five", load_average.five)?;
+    load.set("fifteen", load_average.fifteen)?;
+    info.set("load", load)?;
+
+    let memory = lua_ctx.create_table()?;
+    memory.set("total", system.total_memory())?;
+    memory.set("used", system.used_memory())?;
+    memory.set("available", system.available_memory())?;
+    memory.set("swap_total", system.total_swap())?;
+    memory.set("swap_used", system.used_swap())?;
+    info.set("memory", memory)?;
+
+    let disks = lua_ctx.create_table()?;
+    for (index, disk) in Disks::new_with_refreshed_list().list().iter().enumerate() {
+        let table = lua_ctx.create_table()?;
+        table.set("name", disk.name().to_string_lossy().into_owned())?;
+        table.set("mount", disk.mount_point().to_string_lossy().into_owned())?;
+        table.set(
+            "filesystem",
+            disk.file_system().to_string_lossy().into_owned(),
+        )?;
+        table.set("total", disk.total_space())?;
+        table.set(
+            "used",
+            disk.total_space().saturating_sub(disk.available_space()),
+        )?;
+        table.set("available", disk.available_space())?;
+        table.set("removable", disk.is_removable())?;
+        disks.set(index + 1, table)?;
+    }
+    info.set("disks", disks)?;
+
+    info.set("uptime", System::uptime())?;
+    info.set("boot_time", System::boot_time())?;
+    info.set("battery", battery(lua_ctx)?)?;
+    Ok(info)
+}
+
+fn user(lua_ctx: Context) -> Result<Table> {
+    let user = lua_ctx.create_table()?;
+    #[cfg(unix)]
+    {
+        let uid = unsafe { libc::getuid() };
+        user.set("name", crate::fs::user_name(uid))?;
+        user.set("uid", uid)?;
+        user.set("gid", unsafe { libc::getgid() })?;
+    }
+    #[cfg(not(unix))]
+    user.set("name", std::env::var("USERNAME").ok())?;
+    user.set(
+        "home",
+        dirs::home_dir().map(|home| home.to_string_lossy().into_owned()),
+    )?;
+    Ok(user)
+}
+
+fn dirs(lua_ctx: Context) -> Result<Table> {
+    let dirs = lua_ctx.create_table()?;
+    for (name, dir) in [
+        ("home", dirs::home_dir()),
+        ("config", dirs::config_dir()),
+        ("cache", dirs::cache_dir()),
+        ("data", dirs::data_dir()),
+        ("downloads", dirs::download_dir()),
+        ("documents", dirs::document_dir()),
+        ("desktop", dirs::desktop_dir()),
+        ("temp", Some(std::env::temp_dir())),
+    ] {
+        dirs.set(name, dir.map(|dir| dir.to_string_lossy().into_owned()))?;
+    }
+    Ok(dirs)
+}
+
+// Whether the target starts with a URL scheme like `https:` or `mailto:`, which Windows drive
+// letters don't count as
+fn is_url(target: &str) -> bool {
+    target.split_once(':').is_some_and(|(scheme, _)| {
+        scheme.len() > 1
+            && scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+            && scheme
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.'))
+    })
+}
+
+fn open(target: String) -> Result<()> {
+    let result = if is_url(&target) || Path::new(&target).exists() {
+        open::that_detached(&target)
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "no such file or directory",
+        ))
+    };
+    result.map_err(|source| rlua::Error::external(error::Error::Open { target, source }))
+}
+
+pub fn load_sys_library(lua: &Lua) -> Result<()> {
+    lua.context(|lua_ctx| {
+        let sys = lua_ctx.create_table()?;
+
+        sys.set(
+            "processes",
+            lua_ctx.create_function(|lua_ctx, name: Option<String>| {
+                with_processes(ProcessesToUpdate::All, |system| {
+                    let mut processes = system
+                        .processes()
+                        .values()
+                        .filter(|process| {
+                            // Threads are listed as processes of their own on Linux
+                            process.thread_kind().is_none()
+                                && name
+                                    .as_deref()
+                                    .is_none_or(|name| process.name().to_string_lossy() == name)
+                        })
+                        .collect::<Vec<_>>();
+                    processes.sort_by_key(|process| process.pid());
+                    processes
+                        .into_iter()
+                        .map(|process| process_table(lua_ctx, process))
+                        .collect::<Result<Vec<_>>>()
+                })
+            })?,
+        )?;
+
+        sys.set(
+            "process",
+            lua_ctx.create_function(|lua_ctx, pid: u32| {
+                let pid = Pid::from_u32(pid);
+                with_processes(ProcessesToUpdate::Some(&[pid]), |system| {
+                    system
+                        .process(pid)
+                        .map(|process| process_table(lua_ctx, process))
+                        .transpose()
+                })
+            })?,
+        )?;
+
+        sys.set(
+            "info",
+            lua_ctx.create_function(|lua_ctx, ()| info(lua_ctx))?,
+        )?;
+
+        sys.set(
+            "user",
+            lua_ctx.create_function(|lua_ctx, ()| user(lua_ctx))?,
+        )?;
+
+        sys.set(
+            "hostname",
+            lua_ctx.create_function(|_, ()| Ok(System::host_name()))?,
+        )?;
+
+        sys.set(
+            "dirs",
+            lua_ctx.create_function(|lua_ctx, ()| dirs(lua_ctx))?,
+        )?;
+
+        sys.set("open", lua_ctx.create_function(|_, target| open(target))?)?;
+
+        sys.set(
+            "which",
+            lua_ctx.create_function(|_, name: String| {
+                Ok(which::which(name)
+                    .ok()
+                    .map(|path| path.to_string_lossy().into_owned()))
+            })?,
+        )?;
+
+        sys.set(
+            "shell_quote",
+            lua_ctx.create_function(|_, arg: String| match shlex::try_quote(&arg) {
+                Ok(quoted) => Ok(quoted.into_owned()),
+                Err(_) => Err(rlua::Error::RuntimeError(
+                    "bad argument #1 to 'shell_quote' (string contains a nul byte)".to_string(),
+                )),
+            })?,
+        )?;
+
+        help::register_module_docs(
+            lua_ctx,
+            "sys",
+            "The system rluaterm runs on",
+            &sys,
+            SYS_DOCS,
+        )?;
+        lua_ctx.globals().set("sys", sys)?;
+        Ok(())
+    })
+}
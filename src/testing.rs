@@ -0,0 +1,535 @@
+/*
+   Copyright (C) 2022  Kalka
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License, or (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use colored::Colorize;
+use cumulus::logger;
+use rlua::{Function, Lua, Result, Table};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+use crate::help::{self, FunctionDoc};
+use crate::{error, install, is_interrupted, report, require, traceback};
+
+// Directories never searched for tests, besides hidden ones
+const SKIPPED_DIRS: &[&str] = &[install::MODULES_DIR, "target"];
+
+// Name of the registry value holding the tests added with test.case
+const CASES_REGISTRY_KEY: &str = "rluaterm.testing.cases";
+
+// Returns the `test` and `assert` tables and the list `test.case` adds to. `assert` can still be
+// called like the builtin. Failed assertions raise an error pointing at the line that made them.
+const TESTING_SOURCE: &str = r#"
+local type, tostring, pairs, ipairs, error, pcall = type, tostring, pairs, ipairs, error, pcall
+local format, sort, concat, max = string.format, table.sort, table.concat, math.max
+
+local cases = {}
+local test = {}
+local assert = setmetatable({}, {
+    __call = function(_, value, message, ...)
+        if not value then
+            error(message == nil and "assertion failed!" or message, 2)
+        end
+        return value, message, ...
+    end,
+})
+
+function test.case(name, fn)
+    if type(name) ~= "string" then
+        error(format("bad argument #1 to 'case' (string expected, got %s)", type(name)), 2)
+    end
+    if type(fn) ~= "function" then
+        error(format("bad argument #2 to 'case' (function expected, got %s)", type(fn)), 2)
+    end
+    cases[#cases + 1] = { name = name, fn = fn }
+end
+
+local function equal(a, b, seen)
+    if a == b then
+        return true
+    end
+    if type(a) ~= "table" or type(b) ~= "table" then
+        return false
+    end
+    if seen[a] == b then
+        return true
+    end
+    seen[a] = b
+    for key, value in pairs(a) do
+        if not equal(value, b[key], seen) then
+            return false
+        end
+    end
+    for key in pairs(b) do
+        if a[key] == nil then
+            return false
+        end
+    end
+    return true
+end
+
+local function key_order(a, b)
+    local ta, tb = type(a), type(b)
+    if ta ~= tb then
+        return ta < tb
+    elseif ta == "number" or ta == "string" then
+        return a < b
+    end
+    return tostring(a) < tostring(b)
+end
+
+-- Tables are written one field per line with their keys sorted, so they can be diffed
+local function render(value, indent, seen)
+    if type(value) == "string" then
+        return format("%q", value)
+    elseif type(value) ~= "table" then
+        return tostring(value)
+    elseif seen[value] then
+        return "<cycle>"
+    end
+    local keys = {}
+    for key in pairs(value) do
+        keys[#keys + 1] = key
+    end
+    if #keys == 0 then
+        return "{}"
+    end
+    sort(keys, key_order)
+    seen[value] = true
+    local inner = indent .. "    "
+    local lines = { "{" }
+    for _, key in ipairs(keys) do
+        local name = key
+        if type(key) ~= "string" or not key:match("^[%a_][%w_]*$") then
+            name = "[" .. render(key, inner, seen) .. "]"
+        end
+        lines[#lines + 1] = format("%s%s = %s,", inner, name, render(value[key], inner, seen))
+    end
+    seen[value] = nil
+    lines[#lines + 1] = indent .. "}"
+    return concat(lines, "\n")
+end
+
+local function split_lines(text)
+    local lines = {}
+    for line in (text .. "\n"):gmatch("(.-)\n") do
+        lines[#lines + 1] = line
+    end
+    return lines
+end
+
+-- Line diff from the longest common subsequence, with removed lines marked "-" and added "+"
+local function diff(old, new)
+    local a, b = split_lines(old), split_lines(new)
+    local n, m = #a, #b
+    if n * m > 1000000 then
+        return "- " .. old:gsub("\n", "\n- ") .. "\n+ " .. new:gsub("\n", "\n+ ")
+    end
+    local common = {}
+    for i = n + 1, 1, -1 do
+        common[i] = {}
+        for j = m + 1, 1, -1 do
+            if i > n or j > m then
+                common[i][j] = 0
+            elseif a[i] == b[j] then
+                common[i][j] = common[i + 1][j + 1] + 1
+            else
+                common[i][j] = max(common[i + 1][j], common[i][j + 1])
+            end
+        end
+    end
+    local lines, i, j = {}, 1, 1
+    while i <= n or j <= m do
+        if i <= n and j <= m and a[i] == b[j] then
+            lines[#lines + 1] = "  " .. a[i]
+            i, j = i + 1, j + 1
+        elseif i <= n and (j > m or common[i + 1][j] >= common[i][j + 1]) then
+            lines[#lines + 1] = "- " .. a[i]
+            i = i + 1
+        else
+            lines[#lines + 1] = "+ " .. b[j]
+            j = j + 1
+        end
+    end
+    return concat(lines, "\n")
+end
+
+-- Raises the failure at the line of the test that called the assertion
+local function fail(message, detail)
+    if message ~= nil then
+        detail = tostring(message) .. "\n" .. detail
+    end
+    error(detail, 3)
+end
+
+function assert.eq(actual, expected, message)
+    if equal(actual, expected, {}) then
+        return actual
+    end
+    local old, new
+    if type(actual) == "string" and type(expected) == "string"
+        and (actual:find("\n") or expected:find("\n")) then
+        old, new = expected, actual
+    else
+        old, new = render(expected, "", {}), render(actual, "", {})
+    end
+    if old:find("\n") or new:find("\n") then
+        fail(message, "values differ (- expected, + actual):\n" .. diff(old, new))
+    end
+    fail(message, format("expected: %s\n  actual: %s", old, new))
+end
+
+function assert.ne(actual, unexpected, message)
+    if not equal(actual, unexpected, {}) then
+        return actual
+    end
+    fail(message, format("expected a value other than %s", render(unexpected, "", {})))
+end
+
+function assert.matches(text, pattern, message)
+    if type(text) ~= "string" then
+        fail(message, format("expected a string matching %q, got %s", pattern, render(text, "", {})))
+    end
+    local result = { text:find(pattern) }
+    if #result == 0 then
+        fail(message, format("%q does not match %q", text, pattern))
+    end
+    if #result > 2 then
+        return table.unpack(result, 3)
+    end
+    return text:sub(result[1], result[2])
+end
+
+function assert.error(fn, pattern, message)
+    local ok, err = pcall(fn)
+    if ok then
+        fail(message, "expected an error, but the function returned")
+    end
+    if pattern ~= nil and not (type(err) == "string" and err:find(pattern)) then
+        fail(message, format("error %s does not match %q", render(err, "", {}), pattern))
+    end
+    return err
+end
+
+return test, assert, cases
+"#;
+
+const TEST_DOCS: &[FunctionDoc] = &[FunctionDoc {
+    name: "case",
+    signature: "test.case(name, fn)",
+    description:
+        "Adds a test, run by `rluaterm test` once the file has run. It fails if fn raises an error",
+    example: "test.case(\"adds numbers\", function()\n    assert.eq(1 + 1, 2)\nend)",
+}];
+
+const ASSERT_DOCS: &[FunctionDoc] = &[
+    FunctionDoc {
+        name: "eq",
+        signature: "assert.eq(actual, expected[, message]) -> actual",
+        description: "Fails unless the values are equal, comparing tables field by field, and shows a diff of tables and multi-line strings that differ",
+        example: "assert.eq(split(\"a,b\"), { \"a\", \"b\" })",
+    },
+    FunctionDoc {
+        name: "ne",
+        signature: "assert.ne(actual, unexpected[, message]) -> actual",
+        description: "Fails if the values are equal, comparing tables field by field",
+        example: "assert.ne(os.time(), 0)",
+    },
+    FunctionDoc {
+        name: "matches",
+        signature: "assert.matches(text, pattern[, message]) -> captures",
+        description: "Fails unless the string matches the Lua pattern, and returns its captures or the matched text",
+        example: "local year = assert.matches(os.date(), \"(%d%d%d%d)\")",
+    },
+    FunctionDoc {
+        name: "error",
+        signature: "assert.error(fn[, pattern[, message]]) -> error",
+        description: "Fails unless calling fn raises an error, matching the Lua pattern if given, and returns the error",
+        example: "assert.error(function() parse(\"{\") end, \"unexpected end\")",
+    },
+];
+
+/// Whether the file is a test, named `name_test.lua` or `test_name.lua`.
+pub fn is_test_file(path: &Path) -> bool {
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or("");
+    name.ends_with("_test.lua") || (name.starts_with("test_") && name.ends_with(".lua"))
+}
+
+/// The test files under the directories, in order, along with the files given directly.
+pub fn test_files(paths: &[String]) -> Vec<PathBuf> {
+    fn walk(path: &Path, files: &mut Vec<PathBuf>) {
+        let Ok(entries) = std::fs::read_dir(path) else {
+            return;
+        };
+        let mut entries: Vec<PathBuf> = entries.flatten().map(|entry| entry.path()).collect();
+        entries.sort();
+        for entry in entries {
+            let name = entry
+                .file_name()
+                .and_then(|name| name.to_str())
+                .unwrap_or("");
+            if entry.is_dir() {
+                if !name.starts_with('.') && !SKIPPED_DIRS.contains(&name) {
+                    walk(&entry, files);
+                }
+            } else if is_test_file(&entry) {
+                files.push(entry);
+            }
+        }
+    }
+
+    let mut files = Vec::new();
+    for path in paths {
+        let path = Path::new(path);
+        if path.is_dir() {
+            walk(path, &mut files);
+        } else {
+            files.push(path.to_path_buf());
+        }
+    }
+    files
+}
+
+/// Adds the `test` and `assert` libraries, returning the list `test.case` adds to.
+pub fn load_testing_library(lua: &Lua) -> Result<()> {
+    lua.context(|lua_ctx| {
+        let globals = lua_ctx.globals();
+        let (test, assert, cases) = lua_ctx
+            .load(TESTING_SOURCE)
+            .set_name("=testing")?
+            .call::<_, (Table, Table, Table)>(())?;
+        help::register_module_docs(lua_ctx, "test", "Defining tests", &test, TEST_DOCS)?;
+        help::register_module_docs(
+            lua_ctx,
+            "assert",
+            "Checks for tests, besides assert(value, message) itself",
+            &assert,
+            ASSERT_DOCS,
+        )?;
+        lua_ctx.set_named_registry_value(CASES_REGISTRY_KEY, cases)?;
+        globals.set("test", test)?;
+        globals.set("assert", assert)
+    })
+}
+
+// The error without the traceback rlua adds, which only shows rluaterm's own frames
+fn failure_message(error: &rlua::Error) -> String {
+    let message = report::error_message(error);
+    match message.split_once("\nstack traceback:") {
+        Some((message, _)) => message.to_string(),
+        None => message,
+    }
+}
+
+enum Outcome {
+    Passed,
+    Failed(String),
+}
+
+struct CaseResult {
+    name: String,
+    outcome: Outcome,
+    duration: Duration,
+}
+
+// What running one test file came to: its tests, or the error it failed to load with
+struct FileResult {
+    cases: Vec<CaseResult>,
+    filtered: usize,
+    error: Option<String>,
+    interrupted: bool,
+}
+
+// Runs the file in an interpreter of its own and then every test it added whose name contains
+// the filter
+fn run_file(path: &Path, filter: Option<&str>, stop: &AtomicBool) -> Result<FileResult> {
+    let lua = crate::create_lua(None)?;
+    let root = std::env::current_dir().ok();
+    require::install_searcher(&lua, &require::module_dirs(path, root.as_deref()))?;
+    load_testing_library(&lua)?;
+    lua.context(|lua_ctx| {
+        let mut result = FileResult {
+            cases: Vec::new(),
+            filtered: 0,
+            error: None,
+            interrupted: false,
+        };
+        let loaded = std::fs::read(path)
+            .map_err(|source| {
+                rlua::Error::external(error::Error::Read {
+                    path: path.display().to_string(),
+                    source,
+                })
+            })
+            .and_then(|source| {
+                lua_ctx
+                    .load(&source)
+                    .set_name(&format!("@{}", path.display()))?
+                    .into_function()
+            })
+            .and_then(|chunk| traceback::call_with_traceback::<_, ()>(lua_ctx, chunk, ()));
+        if let Err(error) = loaded {
+            result.interrupted = is_interrupted(&error);
+            result.error = Some(failure_message(&error));
+            return Ok(result);
+        }
+
+        let cases = lua_ctx.named_registry_value::<_, Table>(CASES_REGISTRY_KEY)?;
+        for case in cases.sequence_values::<Table>() {
+            if stop.load(Ordering::SeqCst) {
+                break;
+            }
+            let case = case?;
+            let name = case.get::<_, String>("name")?;
+            if filter.is_some_and(|filter| !name.contains(filter)) {
+                result.filtered += 1;
+                continue;
+            }
+            let started = Instant::now();
+            let outcome = match traceback::call_with_traceback::<_, ()>(
+                lua_ctx,
+                case.get::<_, Function>("fn")?,
+                (),
+            ) {
+                Ok(()) => Outcome::Passed,
+                Err(error) => {
+                    if is_interrupted(&error) {
+                        result.interrupted = true;
+                        break;
+                    }
+                    Outcome::Failed(failure_message(&error))
+                }
+            };
+            result.cases.push(CaseResult {
+                name,
+                outcome,
+                duration: started.elapsed(),
+            });
+        }
+        Ok(result)
+    })
+}
+
+fn print_file_result(path: &Path, result: &FileResult) {
+    println!("{}", path.display().to_string().bold());
+    if let Some(error) = &result.error {
+        println!("  {} failed to run", "✗".red());
+        println!("    {}", error.replace('\n', "\n    "));
+    }
+    for case in &result.cases {
+        let duration = format!("({:.2}ms)", case.duration.as_secs_f64() * 1000.0).dimmed();
+        match &case.outcome {
+            Outcome::Passed => println!("  {} {} {}", "✓".green(), case.name, duration),
+            Outcome::Failed(message) => {
+                println!("  {} {} {}", "✗".red(), case.name, duration);
+                println!("    {}", message.replace('\n', "\n    "));
+            }
+        }
+    }
+}
+
+/// Runs the test files on up to `jobs` threads, each file in an interpreter of its own, and
+/// prints the results of each file in order followed by a summary. Only tests whose name
+/// contains the filter run. Returns the exit code: 0 when every test passed, 1 otherwise.
+pub fn run_tests(files: &[PathBuf], filter: Option<&str>, jobs: usize) -> i32 {
+    let started = Instant::now();
+    let next = AtomicUsize::new(0);
+    let stop = AtomicBool::new(false);
+    let (sender, receiver) = mpsc::channel();
+    let (mut passed, mut failed, mut filtered, mut interrupted) = (0, 0, 0, false);
+
+    std::thread::scope(|scope| {
+        for _ in 0..jobs.clamp(1, files.len().max(1)) {
+            let sender = sender.clone();
+            let (next, stop) = (&next, &stop);
+            scope.spawn(move || loop {
+                let index = next.fetch_add(1, Ordering::SeqCst);
+                if index >= files.len() || stop.load(Ordering::SeqCst) {
+                    break;
+                }
+                let result =
+                    run_file(&files[index], filter, stop).unwrap_or_else(|error| FileResult {
+                        cases: Vec::new(),
+                        filtered: 0,
+                        error: Some(failure_message(&error)),
+                        interrupted: false,
+                    });
+                if result.interrupted {
+                    stop.store(true, Ordering::SeqCst);
+                }
+                if sender.send((index, result)).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(sender);
+
+        // Results arrive as files finish, and are printed in the order the files were found
+        let mut pending = BTreeMap::new();
+        let mut printed = 0;
+        for (index, result) in receiver {
+            pending.insert(index, result);
+            while let Some(result) = pending.remove(&printed) {
+                interrupted |= result.interrupted;
+                filtered += result.filtered;
+                if result.error.is_some() && !result.interrupted {
+                    failed += 1;
+                }
+                for case in &result.cases {
+                    match case.outcome {
+                        Outcome::Passed => passed += 1,
+                        Outcome::Failed(_) => failed += 1,
+                    }
+                }
+                if !result.cases.is_empty() || result.error.is_some() {
+                    print_file_result(&files[printed], &result);
+                }
+                printed += 1;
+            }
+        }
+    });
+
+    if interrupted {
+        logger::warn("Interrupted");
+        return crate::INTERRUPTED_EXIT_CODE;
+    }
+    let mut summary = vec![
+        format!("{} passed", passed).green().to_string(),
+        if failed > 0 {
+            format!("{} failed", failed).red().to_string()
+        } else {
+            format!("{} failed", failed)
+        },
+    ];
+    if filtered > 0 {
+        summary.push(format!("{} filtered out", filtered));
+    }
+    println!(
+        "\n{} ({:.2}s)",
+        summary.join(", "),
+        started.elapsed().as_secs_f64()
+    );
+    if passed + failed == 0 {
+        logger::error("No tests ran");
+        return 1;
+    }
+    i32::from(failed > 0)
+}
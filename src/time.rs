@@ -0,0 +1,837 @@
+/*
+   Copyright (C) 2022  Kalka
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License, or (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use chrono::format::StrftimeItems;
+use chrono::{
+    DateTime, Datelike, FixedOffset, Local, Months, NaiveDate, NaiveDateTime, NaiveTime,
+    SecondsFormat, TimeDelta, TimeZone, Timelike, Utc,
+};
+use chrono_tz::Tz;
+use rlua::{
+    AnyUserData, Context, Lua, MetaMethod, Result, Table, UserData, UserDataMethods, Value,
+};
+use std::fmt;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::help::{self, FunctionDoc};
+use crate::{timeout, timer};
+
+const TIME_DOCS: &[FunctionDoc] = &[
+    FunctionDoc {
+        name: "now",
+        signature: "time.now() -> number",
+        description: "The current time in seconds since 1970, with the fraction down to the microsecond. It follows the system clock, so it can jump when the clock is set; use time.monotonic to measure durations.",
+        example: "log.info(\"started at \" .. time.now())",
+    },
+    FunctionDoc {
+        name: "monotonic",
+        signature: "time.monotonic() -> number",
+        description: "Seconds since rluaterm started, from a clock that only moves forward, for measuring how long things take.",
+        example: "local start = time.monotonic()\nhttp.get(url)\nprint(string.format(\"%.3fs\", time.monotonic() - start))",
+    },
+    FunctionDoc {
+        name: "hrtime",
+        signature: "time.hrtime() -> integer",
+        description: "Nanoseconds since rluaterm started, from the same clock as time.monotonic, as an integer for timing things that take less than a microsecond.",
+        example: "local start = time.hrtime()\nparse(line)\nprint(time.hrtime() - start .. \"ns\")",
+    },
+    FunctionDoc {
+        name: "sleep",
+        signature: "time.sleep(seconds)",
+        description: "Waits for the seconds, which can have a fraction, or for a duration. Timers, Ctrl+C, signal handlers and the script's timeout still get their turn while it waits. Called from a timer's callback, it only pauses that callback, and other timers keep running.",
+        example: "while not fs.exists(\"ready\") do\n    time.sleep(0.5)\nend",
+    },
+    FunctionDoc {
+        name: "parse",
+        signature: "time.parse(text, [format]) -> time",
+        description: "Reads a date and time, by default as RFC 3339 (\"2024-05-01T10:00:00Z\"), RFC 2822 (\"Wed, 01 May 2024 10:00:00 +0000\"), \"2024-05-01 10:00:00\" or \"2024-05-01\", or else in the strftime format given. Times without an offset are taken as UTC. The time it returns has the fields year, month, day, hour, minute, second, nanosecond, weekday (1 for Monday to 7 for Sunday), yday, offset in seconds east of UTC, zone and timestamp in seconds since 1970, the methods format(format), rfc3339() and rfc2822(), and compares with other times. Raises an error when the text isn't a time.",
+        example: "local t = time.parse(response.created_at)\nprint(t.year, t.month, t.day)",
+    },
+    FunctionDoc {
+        name: "format",
+        signature: "time.format(t, format) -> string",
+        description: "Writes the time, or a number of seconds since 1970 as UTC, with a strftime format like \"%Y-%m-%d %H:%M:%S\".",
+        example: "time.format(time.now(), \"%Y-%m-%d\") --> \"2024-05-01\"",
+    },
+    FunctionDoc {
+        name: "rfc3339",
+        signature: "time.rfc3339(t) -> string",
+        description: "Writes the time, or a number of seconds since 1970 as UTC, as RFC 3339, the format of most APIs.",
+        example: "time.rfc3339(0) --> \"1970-01-01T00:00:00Z\"",
+    },
+    FunctionDoc {
+        name: "rfc2822",
+        signature: "time.rfc2822(t) -> string",
+        description: "Writes the time, or a number of seconds since 1970 as UTC, as RFC 2822, the format of email and HTTP headers.",
+        example: "time.rfc2822(0) --> \"Thu, 1 Jan 1970 00:00:00 +0000\"",
+    },
+    FunctionDoc {
+        name: "to_zone",
+        signature: "time.to_zone(t, zone) -> time",
+        description: "The same moment shown in another zone: an IANA name like \"Europe/Berlin\", \"UTC\", \"local\" or an offset like \"+05:30\". The fields, format and add then follow the zone's daylight saving time, and t.zone says which it is.",
+        example: "local t = time.to_zone(time.parse(event.start), \"America/New_York\")\nprint(t:format(\"%H:%M %Z\"))",
+    },
+    FunctionDoc {
+        name: "localtime",
+        signature: "time.localtime([t]) -> time",
+        description: "The time, or now without one, in the system's time zone. It isn't time.local since local is a keyword in Lua.",
+        example: "print(time.localtime():format(\"%A %H:%M\"))",
+    },
+    FunctionDoc {
+        name: "utc",
+        signature: "time.utc([t]) -> time",
+        description: "The time, or now without one, in UTC.",
+        example: "print(time.utc():rfc3339())",
+    },
+    FunctionDoc {
+        name: "add",
+        signature: "time.add(t, changes) -> time",
+        description: "The time moved by the years, months, weeks, days, hours, minutes and seconds in changes, any of which can be negative or left out. Years, months, weeks and days move the date on the calendar of the time's zone and keep the time of day, across daylight saving changes too, landing on the last day when the month is shorter. Hours, minutes and seconds are added as they pass. Also a method of times.",
+        example: "local tomorrow = time.localtime():add({ days = 1 })\nlocal next_month = time.add(time.now(), { months = 1 })",
+    },
+    FunctionDoc {
+        name: "duration",
+        signature: "time.duration(length) -> duration",
+        description: "A length of time, from seconds or text like \"1h30m\", \"90s\", \"500ms\" or \"2d\". Subtracting times gives one too. Durations add to and subtract from times and each other, multiply and divide by numbers, compare, and have the methods as_seconds(), as_milliseconds(), as_minutes(), as_hours() and as_days(). time.sleep, timers and timeouts take them wherever they take seconds.",
+        example: "local elapsed = time.utc() - started\nif elapsed > time.duration(\"5m\") then\n    log.warn(\"slow run: \" .. tostring(elapsed))\nend",
+    },
+    FunctionDoc {
+        name: "human",
+        signature: "time.human(t) -> string",
+        description: "Says roughly how long ago a time was, or will be, in words like \"2 hours ago\" or \"in 3 days\". Given a duration, it's taken as how long ago something was.",
+        example: "print(\"last deploy: \" .. time.human(time.parse(deploy.finished_at)))",
+    },
+    FunctionDoc {
+        name: "stopwatch",
+        signature: "time.stopwatch() -> stopwatch",
+        description: "Starts a stopwatch on the monotonic clock. stopwatch:elapsed() is the duration since it started, stopwatch:lap([label]) ends a lap and returns its duration, and stopwatch:report() writes a table of the laps with how long each took and the time since the start, ending with the total.",
+        example: "local watch = time.stopwatch()\nlocal rows = load_rows()\nwatch:lap(\"load\")\nsave(transform(rows))\nwatch:lap(\"transform and save\")\nprint(watch:report())",
+    },
+];
+
+/// Measures how long something takes, in laps. Backs `time.stopwatch()` and the REPL's `:time`.
+pub struct Stopwatch {
+    started: Instant,
+    // Each lap's label and when it ended, counted from the start
+    laps: Vec<(String, Duration)>,
+}
+
+impl Stopwatch {
+    pub fn start() -> Stopwatch {
+        Stopwatch {
+            started: Instant::now(),
+            laps: Vec::new(),
+        }
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    // Ends a lap, returning how long it took
+    fn lap(&mut self, label: Option<String>) -> Duration {
+        let elapsed = self.elapsed();
+        let previous = self.laps.last().map_or(Duration::ZERO, |(_, end)| *end);
+        let label = label.unwrap_or_else(|| format!("lap {}", self.laps.len() + 1));
+        self.laps.push((label, elapsed));
+        elapsed - previous
+    }
+
+    fn report(&self) -> String {
+        let total = self.elapsed();
+        let width = self
+            .laps
+            .iter()
+            .map(|(label, _)| label.chars().count())
+            .chain(["total".len()])
+            .max()
+            .unwrap_or_default();
+        let mut report = String::new();
+        let mut previous = Duration::ZERO;
+        for (label, end) in &self.laps {
+            let lap = format!("{:.2?}", *end - previous);
+            let since_start = format!("{:.2?}", end);
+            report.push_str(&format!(
+                "{:<width$}  {:>10}  {:>10}\n",
+                label, lap, since_start
+            ));
+            previous = *end;
+        }
+        let total = format!("{:.2?}", total);
+        report.push_str(&format!("{:<width$}  {:>10}  {:>10}", "total", "", total));
+        report
+    }
+}
+
+impl UserData for Stopwatch {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("elapsed", |_, stopwatch, ()| {
+            Span::from_std(stopwatch.elapsed())
+        });
+        methods.add_method_mut("lap", |_, stopwatch, label: Option<String>| {
+            Span::from_std(stopwatch.lap(label))
+        });
+        methods.add_method("report", |_, stopwatch, ()| Ok(stopwatch.report()));
+    }
+}
+
+// What time.monotonic and time.hrtime count from
+static START: OnceLock<Instant> = OnceLock::new();
+
+fn since_start() -> Duration {
+    START.get_or_init(Instant::now).elapsed()
+}
+
+/// How long a duration, a number of seconds or text like `1h30m` stands for, or `None` when it
+/// isn't one of those or is negative.
+pub fn to_duration(value: &Value) -> Option<Duration> {
+    match value {
+        Value::Integer(seconds) => Duration::try_from_secs_f64(*seconds as f64).ok(),
+        Value::Number(seconds) => Duration::try_from_secs_f64(*seconds).ok(),
+        Value::String(text) => timeout::parse_duration(text.to_str().ok()?),
+        Value::UserData(span) => span.borrow::<Span>().ok()?.0.to_std().ok(),
+        _ => None,
+    }
+}
+
+fn sleep_length(length: &Value) -> Result<Duration> {
+    to_duration(length).ok_or_else(|| {
+        rlua::Error::RuntimeError(
+            "bad argument #1 to 'sleep' (non-negative duration expected)".to_string(),
+        )
+    })
+}
+
+// Sleeps in short steps, so the wait can be interrupted like running Lua code can, running timers
+// as they come due
+fn sleep(lua_ctx: Context, length: Value) -> Result<()> {
+    let duration = sleep_length(&length)?;
+    timer::wait(lua_ctx, Some(Instant::now() + duration))
+}
+
+// The offset a time is shown with. Numbers of seconds since 1970 are in UTC, and parsed times keep
+// the offset they were written with.
+#[derive(Clone, Copy)]
+enum Zone {
+    Utc,
+    Local,
+    Fixed(FixedOffset),
+    Named(Tz),
+}
+
+impl Zone {
+    fn parse(name: &str) -> Result<Zone> {
+        if name.eq_ignore_ascii_case("utc") {
+            return Ok(Zone::Utc);
+        }
+        if name == "local" {
+            return Ok(Zone::Local);
+        }
+        if let Ok(offset) = name.parse::<FixedOffset>() {
+            return Ok(Zone::Fixed(offset));
+        }
+        name.parse::<Tz>()
+            .map(Zone::Named)
+            .map_err(|_| rlua::Error::RuntimeError(format!("unknown time zone {:?}", name)))
+    }
+
+    fn name(&self) -> String {
+        match self {
+            Zone::Utc => "UTC".to_string(),
+            Zone::Local => "local".to_string(),
+            Zone::Fixed(offset) => offset.to_string(),
+            Zone::Named(tz) => tz.name().to_string(),
+        }
+    }
+
+    // The moment the date and time on the wall clock of the zone stand for. Times that are
+    // skipped when the clocks go forward are moved past the gap, and the earlier of the two
+    // times that happen twice when they go back is taken.
+    fn at(&self, naive: NaiveDateTime) -> Option<DateTime<Utc>> {
+        fn resolve<T: TimeZone>(zone: &T, naive: NaiveDateTime) -> Option<DateTime<Utc>> {
+            zone.from_local_datetime(&naive)
+                .earliest()
+                .or_else(|| {
+                    zone.from_local_datetime(&(naive + TimeDelta::hours(1)))
+                        .earliest()
+                })
+                .map(|time| time.to_utc())
+        }
+        match self {
+            Zone::Utc => Some(naive.and_utc()),
+            Zone::Local => resolve(&Local, naive),
+            Zone::Fixed(offset) => resolve(offset, naive),
+            Zone::Named(tz) => resolve(tz, naive),
+        }
+    }
+}
+
+// A point in time along with the zone it's shown in, which is what time.parse returns
+#[derive(Clone, Copy)]
+struct Time {
+    instant: DateTime<Utc>,
+    zone: Zone,
+}
+
+impl Time {
+    fn from_timestamp(seconds: f64) -> Option<Time> {
+        let whole = seconds.floor();
+        let instant =
+            DateTime::from_timestamp(whole as i64, ((seconds - whole) * 1e9).round() as u32)?;
+        Some(Time {
+            instant,
+            zone: Zone::Utc,
+        })
+    }
+
+    // The time as it reads in its zone
+    fn local(&self) -> DateTime<FixedOffset> {
+        match self.zone {
+            Zone::Utc => self.instant.fixed_offset(),
+            Zone::Local => self.instant.with_timezone(&Local).fixed_offset(),
+            Zone::Fixed(offset) => self.instant.with_timezone(&offset),
+            Zone::Named(tz) => self.instant.with_timezone(&tz).fixed_offset(),
+        }
+    }
+
+    fn in_zone(&self, zone: Zone) -> Time {
+        Time {
+            instant: self.instant,
+            zone,
+        }
+    }
+
+    fn add(&self, changes: Table) -> Result<Time> {
+        let get =
+            |name: &str| -> Result<i64> { Ok(changes.get::<_, Option<i64>>(name)?.unwrap_or(0)) };
+        let out_of_range = || rlua::Error::RuntimeError("time out of range".to_string());
+        let months = get("years")? * 12 + get("months")?;
+        let days = get("weeks")? * 7 + get("days")?;
+        let mut time = *self;
+        if months != 0 || days != 0 {
+            let naive = self.local().naive_local();
+            let naive = if months >= 0 {
+                naive
+                    .checked_add_months(Months::new(months.try_into().map_err(|_| out_of_range())?))
+            } else {
+                naive.checked_sub_months(Months::new(
+                    months
+                        .unsigned_abs()
+                        .try_into()
+                        .map_err(|_| out_of_range())?,
+                ))
+            }
+            .and_then(|naive| naive.checked_add_signed(TimeDelta::try_days(days)?))
+            .ok_or_else(out_of_range)?;
+            time.instant = self.zone.at(naive).ok_or_else(out_of_range)?;
+        }
+        let seconds = changes.get::<_, Option<f64>>("seconds")?.unwrap_or(0.0)
+            + get("minutes")? as f64 * 60.0
+            + get("hours")? as f64 * 3600.0;
+        let delta = TimeDelta::from_std(Duration::from_secs_f64(seconds.abs()))
+            .map_err(|_| out_of_range())?;
+        time.instant = if seconds >= 0.0 {
+            time.instant.checked_add_signed(delta)
+        } else {
+            time.instant.checked_sub_signed(delta)
+        }
+        .ok_or_else(out_of_range)?;
+        Ok(time)
+    }
+
+    fn format(&self, format: &str) -> Result<String> {
+        let items = StrftimeItems::new(format)
+            .parse()
+            .map_err(|_| rlua::Error::RuntimeError(format!("invalid time format {:?}", format)))?;
+        Ok(match self.zone {
+            // Formatted in the zone itself, so %Z gives its abbreviation like CET
+            Zone::Named(tz) => self
+                .instant
+                .with_timezone(&tz)
+                .format_with_items(items.iter())
+                .to_string(),
+            _ => self.local().format_with_items(items.iter()).to_string(),
+        })
+    }
+
+    fn rfc3339(&self) -> String {
+        self.local().to_rfc3339_opts(SecondsFormat::AutoSi, true)
+    }
+
+    fn timestamp<'lua>(&self) -> Value<'lua> {
+        match self.instant.timestamp_subsec_nanos() {
+            0 => Value::Integer(self.instant.timestamp()),
+            nanos => Value::Number(self.instant.timestamp() as f64 + nanos as f64 / 1e9),
+        }
+    }
+}
+
+impl UserData for Time {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("format", |_, time, format: String| time.format(&format));
+        methods.add_method("rfc3339", |_, time, ()| Ok(time.rfc3339()));
+        methods.add_method("rfc2822", |_, time, ()| Ok(time.local().to_rfc2822()));
+        methods.add_method("add", |_, time, changes: Table| time.add(changes));
+        methods.add_meta_method(MetaMethod::Index, |lua_ctx, time, key: String| {
+            let local = time.local();
+            Ok(match key.as_str() {
+                "year" => Value::Integer(local.year() as i64),
+                "month" => Value::Integer(local.month() as i64),
+                "day" => Value::Integer(local.day() as i64),
+                "hour" => Value::Integer(local.hour() as i64),
+                "minute" => Value::Integer(local.minute() as i64),
+                "second" => Value::Integer(local.second() as i64),
+                "nanosecond" => Value::Integer(local.nanosecond() as i64),
+                "weekday" => Value::Integer(local.weekday().number_from_monday() as i64),
+                "yday" => Value::Integer(local.ordinal() as i64),
+                "offset" => Value::Integer(local.offset().local_minus_utc() as i64),
+                "timestamp" => time.timestamp(),
+                "zone" => Value::String(lua_ctx.create_string(&time.zone.name())?),
+                _ => Value::Nil,
+            })
+        });
+        methods.add_meta_method(MetaMethod::ToString, |_, time, ()| Ok(time.rfc3339()));
+        methods.add_meta_method(MetaMethod::Eq, |_, time, other: AnyUserData| {
+            Ok(other
+                .borrow::<Time>()
+                .is_ok_and(|other| time.instant == other.instant))
+        });
+        methods.add_meta_method(MetaMethod::Lt, |_, time, other: Time| {
+            Ok(time.instant < other.instant)
+        });
+        methods.add_meta_method(MetaMethod::Le, |_, time, other: Time| {
+            Ok(time.instant <= other.instant)
+        });
+        add_arithmetic(methods);
+    }
+}
+
+// A length of time, which is what time.duration returns and subtracting times gives. Unlike
+// std's Duration, it can be negative.
+#[derive(Clone, Copy)]
+struct Span(TimeDelta);
+
+impl Span {
+    fn from_seconds(seconds: f64) -> Result<Span> {
+        // TimeDelta counts nanoseconds in an i64, which lasts for about 292 years
+        if seconds.is_nan() || seconds.abs() >= 9e9 {
+            return Err(rlua::Error::RuntimeError(
+                "duration out of range".to_string(),
+            ));
+        }
+        Ok(Span(TimeDelta::nanoseconds((seconds * 1e9).round() as i64)))
+    }
+
+    fn from_std(duration: Duration) -> Result<Span> {
+        TimeDelta::from_std(duration)
+            .map(Span)
+            .map_err(|_| rlua::Error::RuntimeError("duration out of range".to_string()))
+    }
+
+    fn seconds(&self) -> f64 {
+        self.0.num_nanoseconds().map_or_else(
+            || self.0.num_milliseconds() as f64 / 1e3,
+            |nanos| nanos as f64 / 1e9,
+        )
+    }
+}
+
+// Written like time.duration reads it, such as 1h30m, 2.5s or 250ms
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0 < TimeDelta::zero() {
+            write!(f, "-")?;
+        }
+        let delta = self.0.abs();
+        if delta.is_zero() {
+            return write!(f, "0s");
+        }
+        let nanos = delta.subsec_nanos();
+        if delta.num_seconds() == 0 && nanos % 1_000_000 == 0 {
+            return write!(f, "{}ms", nanos / 1_000_000);
+        }
+        let mut seconds = delta.num_seconds();
+        for (unit, length) in [("d", 86400), ("h", 3600), ("m", 60)] {
+            if seconds >= length {
+                write!(f, "{}{}", seconds / length, unit)?;
+                seconds %= length;
+            }
+        }
+        match nanos {
+            0 if seconds == 0 => Ok(()),
+            0 => write!(f, "{}s", seconds),
+            _ => {
+                let fraction = format!("{:09}", nanos);
+                write!(f, "{}.{}s", seconds, fraction.trim_end_matches('0'))
+            }
+        }
+    }
+}
+
+impl UserData for Span {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("as_seconds", |_, span, ()| Ok(span.seconds()));
+        methods.add_method("as_milliseconds", |_, span, ()| Ok(span.seconds() * 1e3));
+        methods.add_method("as_minutes", |_, span, ()| Ok(span.seconds() / 60.0));
+        methods.add_method("as_hours", |_, span, ()| Ok(span.seconds() / 3600.0));
+        methods.add_method("as_days", |_, span, ()| Ok(span.seconds() / 86400.0));
+        methods.add_meta_method(MetaMethod::ToString, |_, span, ()| Ok(span.to_string()));
+        methods.add_meta_method(MetaMethod::Unm, |_, span, ()| Ok(Span(-span.0)));
+        methods.add_meta_method(MetaMethod::Eq, |_, span, other: AnyUserData| {
+            Ok(other.borrow::<Span>().is_ok_and(|other| span.0 == other.0))
+        });
+        methods.add_meta_method(MetaMethod::Lt, |_, span, other: Span| Ok(span.0 < other.0));
+        methods.add_meta_method(MetaMethod::Le, |_, span, other: Span| Ok(span.0 <= other.0));
+        add_arithmetic(methods);
+    }
+}
+
+// What arithmetic on times and durations is done with
+enum Operand {
+    Time(Time),
+    Span(Span),
+    Number(f64),
+}
+
+impl Operand {
+    fn from(value: &Value) -> Option<Operand> {
+        match value {
+            Value::Integer(number) => Some(Operand::Number(*number as f64)),
+            Value::Number(number) => Some(Operand::Number(*number)),
+            Value::UserData(userdata) => {
+                if let Ok(time) = userdata.borrow::<Time>() {
+                    Some(Operand::Time(*time))
+                } else {
+                    userdata
+                        .borrow::<Span>()
+                        .ok()
+                        .map(|span| Operand::Span(*span))
+                }
+            }
+            _ => None,
+        }
+    }
+
+    fn name(value: &Value) -> &'static str {
+        match Operand::from(value) {
+            Some(Operand::Time(_)) => "a time",
+            Some(Operand::Span(_)) => "a duration",
+            Some(Operand::Number(_)) => "a number",
+            None => "a value",
+        }
+    }
+}
+
+// Times move by durations, or numbers of seconds, and subtracting them gives the duration
+// between. Durations add up and scale by numbers.
+fn arithmetic<'lua>(
+    lua_ctx: Context<'lua>,
+    operation: MetaMethod,
+    a: Value<'lua>,
+    b: Value<'lua>,
+) -> Result<Value<'lua>> {
+    let shift = |time: Time, delta: TimeDelta| -> Result<Value<'lua>> {
+        let instant = time
+            .instant
+            .checked_add_signed(delta)
+            .ok_or_else(|| rlua::Error::RuntimeError("time out of range".to_string()))?;
+        Ok(Value::UserData(
+            lua_ctx.create_userdata(Time { instant, ..time })?,
+        ))
+    };
+    let span =
+        |span: Span| -> Result<Value<'lua>> { Ok(Value::UserData(lua_ctx.create_userdata(span)?)) };
+    match (operation, Operand::from(&a), Operand::from(&b)) {
+        (MetaMethod::Add, Some(Operand::Time(time)), Some(Operand::Span(by)))
+        | (MetaMethod::Add, Some(Operand::Span(by)), Some(Operand::Time(time))) => {
+            shift(time, by.0)
+        }
+        (MetaMethod::Add, Some(Operand::Time(time)), Some(Operand::Number(seconds)))
+        | (MetaMethod::Add, Some(Operand::Number(seconds)), Some(Operand::Time(time))) => {
+            shift(time, Span::from_seconds(seconds)?.0)
+        }
+        (MetaMethod::Sub, Some(Operand::Time(time)), Some(Operand::Span(by))) => shift(time, -by.0),
+        (MetaMethod::Sub, Some(Operand::Time(time)), Some(Operand::Number(seconds))) => {
+            shift(time, -Span::from_seconds(seconds)?.0)
+        }
+        (MetaMethod::Sub, Some(Operand::Time(a)), Some(Operand::Time(b))) => {
+            span(Span(a.instant - b.instant))
+        }
+        (MetaMethod::Add, Some(Operand::Span(a)), Some(Operand::Span(b))) => span(Span(a.0 + b.0)),
+        (MetaMethod::Sub, Some(Operand::Span(a)), Some(Operand::Span(b))) => span(Span(a.0 - b.0)),
+        (MetaMethod::Mul, Some(Operand::Span(a)), Some(Operand::Number(b)))
+        | (MetaMethod::Mul, Some(Operand::Number(b)), Some(Operand::Span(a))) => {
+            span(Span::from_seconds(a.seconds() * b)?)
+        }
+        (MetaMethod::Div, Some(Operand::Span(a)), Some(Operand::Number(b))) => {
+            span(Span::from_seconds(a.seconds() / b)?)
+        }
+        (MetaMethod::Div, Some(Operand::Span(a)), Some(Operand::Span(b))) => {
+            Ok(Value::Number(a.seconds() / b.seconds()))
+        }
+        _ => {
+            let verb = match operation {
+                MetaMethod::Add => "add",
+                MetaMethod::Sub => "subtract",
+                MetaMethod::Mul => "multiply",
+                _ => "divide",
+            };
+            Err(rlua::Error::RuntimeError(format!(
+                "attempt to {} {} and {}",
+                verb,
+                Operand::name(&a),
+                Operand::name(&b)
+            )))
+        }
+    }
+}
+
+fn add_arithmetic<'lua, T: UserData, M: UserDataMethods<'lua, T>>(methods: &mut M) {
+    for operation in [
+        MetaMethod::Add,
+        MetaMethod::Sub,
+        MetaMethod::Mul,
+        MetaMethod::Div,
+    ] {
+        methods.add_meta_function(operation, move |lua_ctx, (a, b): (Value, Value)| {
+            arithmetic(lua_ctx, operation, a, b)
+        });
+    }
+}
+
+// Roughly how long ago the length of time was, or how far off when it's negative
+fn human(delta: TimeDelta) -> String {
+    let seconds = delta.num_seconds().abs();
+    let (count, unit) = match seconds {
+        0 => return "just now".to_string(),
+        1..=59 => (seconds, "second"),
+        60..=3599 => (seconds / 60, "minute"),
+        3600..=86399 => (seconds / 3600, "hour"),
+        86400..=2591999 => (seconds / 86400, "day"),
+        2592000..=31535999 => (seconds / 2592000, "month"),
+        _ => (seconds / 31536000, "year"),
+    };
+    let length = format!("{} {}{}", count, unit, if count == 1 { "" } else { "s" });
+    if delta > TimeDelta::zero() {
+        format!("{} ago", length)
+    } else {
+        format!("in {}", length)
+    }
+}
+
+// A time, or a number of seconds since 1970
+fn to_time(value: Value, position: usize, function: &str) -> Result<Time> {
+    let time = match value {
+        Value::Integer(seconds) => Time::from_timestamp(seconds as f64),
+        Value::Number(seconds) => Time::from_timestamp(seconds),
+        Value::UserData(time) => time.borrow::<Time>().ok().map(|time| *time),
+        _ => None,
+    };
+    time.ok_or_else(|| {
+        rlua::Error::RuntimeError(format!(
+            "bad argument #{} to '{}' (time expected)",
+            position, function
+        ))
+    })
+}
+
+// The time, or now when there's none
+fn now_or(value: Value, function: &str) -> Result<Time> {
+    match value {
+        Value::Nil => Ok(Time {
+            instant: Utc::now(),
+            zone: Zone::Utc,
+        }),
+        value => to_time(value, 1, function),
+    }
+}
+
+fn fixed(time: DateTime<FixedOffset>) -> Time {
+    Time {
+        instant: time.to_utc(),
+        zone: Zone::Fixed(*time.offset()),
+    }
+}
+
+fn utc(time: NaiveDateTime) -> Time {
+    Time {
+        instant: time.and_utc(),
+        zone: Zone::Utc,
+    }
+}
+
+fn parse(text: &str, format: Option<&str>) -> Result<Time> {
+    let time = match format {
+        Some(format) => DateTime::parse_from_str(text, format)
+            .map(fixed)
+            .or_else(|_| NaiveDateTime::parse_from_str(text, format).map(utc))
+            .or_else(|_| {
+                NaiveDate::parse_from_str(text, format)
+                    .map(|date| utc(date.and_time(NaiveTime::MIN)))
+            })
+            .ok(),
+        None => DateTime::parse_from_rfc3339(text)
+            .or_else(|_| DateTime::parse_from_rfc2822(text))
+            .map(fixed)
+            .or_else(|_| NaiveDateTime::parse_from_str(text, "%Y-%m-%d %H:%M:%S%.f").map(utc))
+            .or_else(|_| NaiveDateTime::parse_from_str(text, "%Y-%m-%dT%H:%M:%S%.f").map(utc))
+            .or_else(|_| {
+                NaiveDate::parse_from_str(text, "%Y-%m-%d")
+                    .map(|date| utc(date.and_time(NaiveTime::MIN)))
+            })
+            .ok(),
+    };
+    time.ok_or_else(|| match format {
+        Some(format) => rlua::Error::RuntimeError(format!(
+            "cannot parse {:?} as a time in the format {:?}",
+            text, format
+        )),
+        None => rlua::Error::RuntimeError(format!("cannot parse {:?} as a time", text)),
+    })
+}
+
+pub fn load_time_library(lua: &Lua) -> Result<()> {
+    START.get_or_init(Instant::now);
+    lua.context(|lua_ctx| {
+        let time = lua_ctx.create_table()?;
+
+        time.set(
+            "now",
+            lua_ctx.create_function(|_, ()| {
+                // A clock set before 1970 gives negative times
+                Ok(match SystemTime::now().duration_since(UNIX_EPOCH) {
+                    Ok(since) => since.as_secs_f64(),
+                    Err(err) => -err.duration().as_secs_f64(),
+                })
+            })?,
+        )?;
+
+        time.set(
+            "monotonic",
+            lua_ctx.create_function(|_, ()| Ok(since_start().as_secs_f64()))?,
+        )?;
+
+        time.set(
+            "hrtime",
+            lua_ctx.create_function(|_, ()| Ok(since_start().as_nanos() as i64))?,
+        )?;
+
+        time.set(
+            "sleep",
+            timer::yielding_sleep(
+                lua_ctx,
+                lua_ctx.create_function(|lua_ctx, length: Value| sleep(lua_ctx, length))?,
+                lua_ctx.create_function(|_, length: Value| sleep_length(&length).map(|_| ()))?,
+            )?,
+        )?;
+
+        time.set(
+            "parse",
+            lua_ctx.create_function(|_, (text, format): (String, Option<String>)| {
+                parse(&text, format.as_deref())
+            })?,
+        )?;
+
+        time.set(
+            "format",
+            lua_ctx.create_function(|_, (time, format): (Value, String)| {
+                to_time(time, 1, "format")?.format(&format)
+            })?,
+        )?;
+
+        time.set(
+            "rfc3339",
+            lua_ctx.create_function(|_, time: Value| Ok(to_time(time, 1, "rfc3339")?.rfc3339()))?,
+        )?;
+
+        time.set(
+            "rfc2822",
+            lua_ctx.create_function(|_, time: Value| {
+                Ok(to_time(time, 1, "rfc2822")?.local().to_rfc2822())
+            })?,
+        )?;
+
+        time.set(
+            "duration",
+            lua_ctx.create_function(|_, length: Value| match &length {
+                Value::Integer(seconds) => Span::from_seconds(*seconds as f64),
+                Value::Number(seconds) => Span::from_seconds(*seconds),
+                _ => to_duration(&length)
+                    .and_then(|duration| TimeDelta::from_std(duration).ok())
+                    .map(Span)
+                    .ok_or_else(|| {
+                        rlua::Error::RuntimeError(
+                            "bad argument #1 to 'duration' (duration expected)".to_string(),
+                        )
+                    }),
+            })?,
+        )?;
+
+        time.set(
+            "human",
+            lua_ctx.create_function(|_, value: Value| {
+                let delta = match Operand::from(&value) {
+                    Some(Operand::Time(time)) => Utc::now() - time.instant,
+                    Some(Operand::Span(span)) => span.0,
+                    Some(Operand::Number(seconds)) => Span::from_seconds(seconds)?.0,
+                    None => {
+                        return Err(rlua::Error::RuntimeError(
+                            "bad argument #1 to 'human' (time or duration expected)".to_string(),
+                        ))
+                    }
+                };
+                Ok(human(delta))
+            })?,
+        )?;
+
+        time.set(
+            "stopwatch",
+            lua_ctx.create_function(|_, ()| Ok(Stopwatch::start()))?,
+        )?;
+
+        time.set(
+            "to_zone",
+            lua_ctx.create_function(|_, (time, zone): (Value, String)| {
+                Ok(to_time(time, 1, "to_zone")?.in_zone(Zone::parse(&zone)?))
+            })?,
+        )?;
+
+        time.set(
+            "localtime",
+            lua_ctx.create_function(|_, time: Value| {
+                Ok(now_or(time, "localtime")?.in_zone(Zone::Local))
+            })?,
+        )?;
+
+        time.set(
+            "utc",
+            lua_ctx
+                .create_function(|_, time: Value| Ok(now_or(time, "utc")?.in_zone(Zone::Utc)))?,
+        )?;
+
+        time.set(
+            "add",
+            lua_ctx.create_function(|_, (time, changes): (Value, Table)| {
+                to_time(time, 1, "add")?.add(changes)
+            })?,
+        )?;
+
+        help::register_module_docs(lua_ctx, "time", "Clocks and timing", &time, TIME_DOCS)?;
+        lua_ctx.globals().set("time", time)?;
+        Ok(())
+    })
+}
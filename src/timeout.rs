@@ -0,0 +1,106 @@
+/*
+   Copyright (C) 2022  Kalka
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License, or (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// How long a chunk may run, and when the running one has to be done by
+static TIMEOUT: Mutex<Option<Duration>> = Mutex::new(None);
+static DEADLINE: Mutex<Option<Instant>> = Mutex::new(None);
+
+/// Parses durations such as `30s`, `500ms`, `2m`, `1h30m` and `1d`, a bare number being seconds.
+pub fn parse_duration(text: &str) -> Option<Duration> {
+    let mut rest = text.trim();
+    if let Ok(seconds) = rest.parse::<f64>() {
+        return Duration::try_from_secs_f64(seconds).ok();
+    }
+    if rest.is_empty() {
+        return None;
+    }
+    let mut seconds = 0.0;
+    while !rest.is_empty() {
+        let split = rest
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(rest.len());
+        let (value, tail) = rest.split_at(split);
+        let value = value.parse::<f64>().ok()?;
+        let split = tail
+            .find(|c: char| c.is_ascii_digit() || c == '.')
+            .unwrap_or(tail.len());
+        let (unit, tail) = tail.split_at(split);
+        seconds += match unit {
+            "ms" => value / 1000.0,
+            "s" => value,
+            "m" => value * 60.0,
+            "h" => value * 3600.0,
+            "d" => value * 86400.0,
+            _ => return None,
+        };
+        rest = tail;
+    }
+    Duration::try_from_secs_f64(seconds).ok()
+}
+
+/// Sets how long chunks may run from now on, restarting the clock of the running one.
+pub fn set_timeout(timeout: Option<Duration>) {
+    *TIMEOUT.lock().unwrap() = timeout;
+    start();
+}
+
+/// Starts the clock for a chunk that's about to run.
+pub fn start() {
+    let timeout = *TIMEOUT.lock().unwrap();
+    *DEADLINE.lock().unwrap() = timeout.map(|timeout| Instant::now() + timeout);
+}
+
+/// The timeout, once the running chunk has gone past it.
+pub fn expired() -> Option<Duration> {
+    let deadline = (*DEADLINE.lock().unwrap())?;
+    if Instant::now() < deadline {
+        return None;
+    }
+    *TIMEOUT.lock().unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_plain_seconds() {
+        assert_eq!(parse_duration("30"), Some(Duration::from_secs(30)));
+        assert_eq!(parse_duration(" 1.5 "), Some(Duration::from_millis(1500)));
+    }
+
+    #[test]
+    fn parses_units() {
+        assert_eq!(parse_duration("500ms"), Some(Duration::from_millis(500)));
+        assert_eq!(parse_duration("30s"), Some(Duration::from_secs(30)));
+        assert_eq!(parse_duration("1h30m"), Some(Duration::from_secs(5400)));
+        assert_eq!(parse_duration("2d"), Some(Duration::from_secs(172_800)));
+        assert_eq!(parse_duration("1.5m"), Some(Duration::from_secs(90)));
+    }
+
+    #[test]
+    fn rejects_invalid_durations() {
+        assert_eq!(parse_duration(""), None);
+        assert_eq!(parse_duration("-1"), None);
+        assert_eq!(parse_duration("10x"), None);
+        assert_eq!(parse_duration("1m30"), None);
+        assert_eq!(parse_duration("ms"), None);
+        assert_eq!(parse_duration("bogus"), None);
+    }
+}
@@ -0,0 +1,411 @@
+/*
+   Copyright (C) 2022  Kalka
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License, or (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use chrono::{DateTime, Local, TimeDelta};
+use croner::Cron;
+use rlua::{
+    Context, Function, Lua, MetaMethod, MultiValue, Result, Table, Thread, ThreadStatus, UserData,
+    UserDataMethods, Value,
+};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::help::{self, FunctionDoc};
+use crate::proc::POLL_INTERVAL;
+use crate::time;
+
+// Name of the registry table mapping timer ids to their callbacks
+const CALLBACKS_REGISTRY_KEY: &str = "rluaterm.timer.callbacks";
+// Name of the registry value telling apart the Lua states timers belong to
+const STATE_REGISTRY_KEY: &str = "rluaterm.timer.state";
+// Name of the registry value holding the coroutine the loop is running a callback in
+const CURRENT_REGISTRY_KEY: &str = "rluaterm.timer.current";
+// Name of the registry value holding how long the callback that just yielded sleeps for, which
+// tells yields from time.sleep apart
+const SLEEP_REGISTRY_KEY: &str = "rluaterm.timer.sleep";
+
+// time.sleep, which yields back to the loop when called from the coroutine a callback runs in,
+// so other timers run while it sleeps, and blocks otherwise. Coroutines the callback starts
+// itself still block, since their yields go to the callback
+const SLEEP_SOURCE: &str = r#"
+local sleep, check, current, request = ...
+local isyieldable, running, yield = coroutine.isyieldable, coroutine.running, coroutine.yield
+return function(length, ...)
+    if isyieldable() and running() == current() then
+        check(length)
+        request(length)
+        yield()
+        return
+    end
+    return sleep(length, ...)
+end
+"#;
+
+const TIMER_DOCS: &[FunctionDoc] = &[
+    FunctionDoc {
+        name: "after",
+        signature: "timer.after(delay, callback) -> timer",
+        description: "Calls the callback with the timer once the delay, in seconds or a duration, has passed. Timers run while the script waits in time.sleep or timer.run, and once a script file is done, rluaterm keeps running until none are left. The timer has a cancel() method and an active field.",
+        example: "local warning = timer.after(30, function()\n    log.warn(\"still waiting for the build\")\nend)\nwait_for_build()\nwarning:cancel()",
+    },
+    FunctionDoc {
+        name: "every",
+        signature: "timer.every(interval, callback) -> timer",
+        description: "Calls the callback with the timer every interval, in seconds or a duration, starting one interval from now, until it's cancelled. Calls that were missed while something else ran are skipped rather than made up for.",
+        example: "timer.every(\"10s\", function(t)\n    if http.get(url).status == 200 then\n        log.info(\"up\")\n        t:cancel()\n    end\nend)",
+    },
+    FunctionDoc {
+        name: "run",
+        signature: "timer.run()",
+        description: "Runs timers as they come due until none are left, or until Ctrl+C.",
+        example: "timer.every(60, poll)\ntimer.run()",
+    },
+];
+
+/// How a timer comes due again once it has run.
+pub enum Repeat {
+    Every(Duration),
+    /// On the times a cron expression matches in the local time zone, `at` being the one the
+    /// timer is due for
+    Cron {
+        cron: Box<Cron>,
+        at: DateTime<Local>,
+    },
+}
+
+impl Repeat {
+    // When the timer is due again after coming due at `due`, skipping the times that were
+    // missed by `now`, or None when it never is
+    fn next(&mut self, due: Instant, now: Instant) -> Option<Instant> {
+        match self {
+            Repeat::Every(interval) => {
+                let next = due + *interval;
+                Some(if next <= now { now + *interval } else { next })
+            }
+            Repeat::Cron { cron, at } => {
+                let local = Local::now();
+                *at = cron.find_next_occurrence(&local.max(*at), false).ok()?;
+                Some(now + (*at - local).to_std().unwrap_or_default())
+            }
+        }
+    }
+}
+
+// A timer waiting to run, in the Lua state its callback is in
+struct Scheduled {
+    state: i64,
+    due: Instant,
+    repeat: Option<Repeat>,
+    // Whether its callback is being called or is sleeping, so it isn't called again meanwhile
+    running: bool,
+    // For a callback sleeping in time.sleep, the timer it's the callback of
+    wakes: Option<i64>,
+}
+
+// Every state's timers, as test files run side by side in states of their own
+static SCHEDULE: Mutex<BTreeMap<i64, Scheduled>> = Mutex::new(BTreeMap::new());
+static NEXT_ID: AtomicI64 = AtomicI64::new(1);
+static NEXT_STATE: AtomicI64 = AtomicI64::new(1);
+
+fn state(lua_ctx: Context) -> Result<i64> {
+    if let Ok(state) = lua_ctx.named_registry_value::<_, i64>(STATE_REGISTRY_KEY) {
+        return Ok(state);
+    }
+    let state = NEXT_STATE.fetch_add(1, Ordering::Relaxed);
+    lua_ctx.set_named_registry_value(STATE_REGISTRY_KEY, state)?;
+    Ok(state)
+}
+
+fn callbacks(lua_ctx: Context) -> Result<Table> {
+    if let Ok(table) = lua_ctx.named_registry_value::<_, Table>(CALLBACKS_REGISTRY_KEY) {
+        return Ok(table);
+    }
+    let table = lua_ctx.create_table()?;
+    lua_ctx.set_named_registry_value(CALLBACKS_REGISTRY_KEY, table.clone())?;
+    Ok(table)
+}
+
+/// A handle on a timer, which is what `timer.after` and `timer.every` return.
+pub struct Timer {
+    id: i64,
+}
+
+impl Timer {
+    pub fn id(&self) -> i64 {
+        self.id
+    }
+}
+
+impl UserData for Timer {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("cancel", |lua_ctx, timer, ()| {
+            SCHEDULE.lock().unwrap().remove(&timer.id);
+            callbacks(lua_ctx)?.set(timer.id, Value::Nil)
+        });
+        methods.add_meta_method(MetaMethod::Index, |_, timer, key: String| {
+            Ok((key == "active").then(|| SCHEDULE.lock().unwrap().contains_key(&timer.id)))
+        });
+        methods.add_meta_method(MetaMethod::ToString, |_, timer, ()| {
+            Ok(format!("timer {}", timer.id))
+        });
+    }
+}
+
+fn schedule<'lua>(
+    lua_ctx: Context<'lua>,
+    delay: Value<'lua>,
+    callback: Function<'lua>,
+    repeat: bool,
+) -> Result<Timer> {
+    let function = if repeat { "every" } else { "after" };
+    let delay = time::to_duration(&delay)
+        .filter(|delay| !repeat || !delay.is_zero())
+        .ok_or_else(|| {
+            rlua::Error::RuntimeError(format!(
+                "bad argument #1 to '{}' ({} duration expected)",
+                function,
+                if repeat { "positive" } else { "non-negative" }
+            ))
+        })?;
+    start(
+        lua_ctx,
+        callback,
+        Instant::now() + delay,
+        repeat.then_some(Repeat::Every(delay)),
+    )
+}
+
+/// Starts a timer calling the callback with its handle once it's due, and again as often as it
+/// repeats.
+pub fn start<'lua>(
+    lua_ctx: Context<'lua>,
+    callback: Function<'lua>,
+    due: Instant,
+    repeat: Option<Repeat>,
+) -> Result<Timer> {
+    let id = insert(lua_ctx, Value::Function(callback), due, repeat, None)?;
+    Ok(Timer { id })
+}
+
+// Schedules the callback, or the coroutine of a sleeping one, returning its id
+fn insert<'lua>(
+    lua_ctx: Context<'lua>,
+    callback: Value<'lua>,
+    due: Instant,
+    repeat: Option<Repeat>,
+    wakes: Option<i64>,
+) -> Result<i64> {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    callbacks(lua_ctx)?.set(id, callback)?;
+    let state = state(lua_ctx)?;
+    SCHEDULE.lock().unwrap().insert(
+        id,
+        Scheduled {
+            state,
+            due,
+            repeat,
+            running: false,
+            wakes,
+        },
+    );
+    Ok(id)
+}
+
+// Runs a callback, or the rest of one after a sleep, in its coroutine, returning how long it
+// sleeps for when it yields from time.sleep
+fn resume<'lua>(
+    lua_ctx: Context<'lua>,
+    thread: &Thread<'lua>,
+    args: MultiValue<'lua>,
+) -> Result<Option<Duration>> {
+    // Restored afterwards, as a callback can wait for timers without yielding
+    let previous = lua_ctx.named_registry_value::<_, Value>(CURRENT_REGISTRY_KEY)?;
+    lua_ctx.set_named_registry_value(CURRENT_REGISTRY_KEY, thread.clone())?;
+    lua_ctx.set_named_registry_value(SLEEP_REGISTRY_KEY, Value::Nil)?;
+    let result = thread.resume::<_, MultiValue>(args);
+    lua_ctx.set_named_registry_value(CURRENT_REGISTRY_KEY, previous)?;
+    result?;
+    if thread.status() != ThreadStatus::Resumable {
+        return Ok(None);
+    }
+    let length = lua_ctx.named_registry_value::<_, Value>(SLEEP_REGISTRY_KEY)?;
+    match time::to_duration(&length) {
+        Some(length) => Ok(Some(length)),
+        None => Err(rlua::Error::RuntimeError(
+            "timer callbacks can only yield from time.sleep".to_string(),
+        )),
+    }
+}
+
+/// When the timer runs next by the system clock, or `None` once it's done or cancelled.
+pub fn next_run(id: i64) -> Option<DateTime<Local>> {
+    let due = SCHEDULE.lock().unwrap().get(&id)?.due;
+    let wait = due.saturating_duration_since(Instant::now());
+    Some(Local::now() + TimeDelta::from_std(wait).ok()?)
+}
+
+/// Calls the callbacks of the timers that are due, returning when the next one is.
+pub fn run_due(lua_ctx: Context) -> Result<Option<Instant>> {
+    let now = Instant::now();
+    let state = state(lua_ctx)?;
+    loop {
+        // Timers that come due while the callbacks run wait for the next round, so one that's
+        // always due can't keep the others from running
+        let (id, repeats, wakes) = {
+            let mut schedule = SCHEDULE.lock().unwrap();
+            let own = schedule
+                .iter()
+                .filter(|(_, scheduled)| scheduled.state == state && !scheduled.running);
+            let Some((&id, _)) = own
+                .clone()
+                .filter(|(_, scheduled)| scheduled.due <= now)
+                .min_by_key(|(_, scheduled)| scheduled.due)
+            else {
+                return Ok(own.map(|(_, scheduled)| scheduled.due).min());
+            };
+            let scheduled = schedule.get_mut(&id).unwrap();
+            let (due, wakes) = (scheduled.due, scheduled.wakes);
+            match scheduled
+                .repeat
+                .as_mut()
+                .and_then(|repeat| repeat.next(due, now))
+            {
+                Some(next) => {
+                    scheduled.due = next;
+                    scheduled.running = true;
+                }
+                None => {
+                    schedule.remove(&id);
+                }
+            }
+            (id, schedule.contains_key(&id), wakes)
+        };
+        let callbacks = callbacks(lua_ctx)?;
+        // Callbacks run in a coroutine of their own, so they can sleep without blocking the rest
+        let (thread, args) = match callbacks.get::<_, Value>(id)? {
+            Value::Function(callback) => (
+                lua_ctx.create_thread(callback)?,
+                lua_ctx.pack_multi(Timer { id })?,
+            ),
+            Value::Thread(thread) => (thread, MultiValue::new()),
+            _ => continue,
+        };
+        if !repeats {
+            callbacks.set(id, Value::Nil)?;
+        }
+        let timer = wakes.unwrap_or(id);
+        let result = resume(lua_ctx, &thread, args);
+        if let Ok(Some(length)) = result {
+            insert(
+                lua_ctx,
+                Value::Thread(thread),
+                Instant::now() + length,
+                None,
+                Some(timer),
+            )?;
+            continue;
+        }
+        if let Some(scheduled) = SCHEDULE.lock().unwrap().get_mut(&timer) {
+            scheduled.running = false;
+        }
+        result?;
+    }
+}
+
+/// Waits until the deadline, or without one until no timers are left, running timers as they
+/// come due.
+pub fn wait(lua_ctx: Context, deadline: Option<Instant>) -> Result<()> {
+    loop {
+        crate::check_interrupt(lua_ctx)?;
+        let next = run_due(lua_ctx)?;
+        let now = Instant::now();
+        let until = match (deadline, next) {
+            (Some(deadline), _) if deadline <= now => return Ok(()),
+            (Some(deadline), Some(next)) => deadline.min(next),
+            (Some(deadline), None) => deadline,
+            (None, Some(next)) => next,
+            (None, None) => return Ok(()),
+        };
+        thread::sleep(until.saturating_duration_since(now).min(POLL_INTERVAL));
+    }
+}
+
+/// Whether any of this Lua state's timers are waiting to run.
+pub fn pending(lua_ctx: Context) -> Result<bool> {
+    let state = state(lua_ctx)?;
+    Ok(SCHEDULE
+        .lock()
+        .unwrap()
+        .values()
+        .any(|scheduled| scheduled.state == state))
+}
+
+/// Wraps the blocking `sleep` in time.sleep, which yields instead when called from a timer's
+/// callback. `check` raises the error `sleep` would for a length that isn't a duration.
+pub fn yielding_sleep<'lua>(
+    lua_ctx: Context<'lua>,
+    sleep: Function<'lua>,
+    check: Function<'lua>,
+) -> Result<Function<'lua>> {
+    let current = lua_ctx.create_function(|lua_ctx, ()| {
+        lua_ctx.named_registry_value::<_, Value>(CURRENT_REGISTRY_KEY)
+    })?;
+    let request = lua_ctx.create_function(|lua_ctx, length: Value| {
+        lua_ctx.set_named_registry_value(SLEEP_REGISTRY_KEY, length)
+    })?;
+    lua_ctx
+        .load(SLEEP_SOURCE)
+        .set_name("=time.sleep")?
+        .call((sleep, check, current, request))
+}
+
+pub fn load_timer_library(lua: &Lua) -> Result<()> {
+    lua.context(|lua_ctx| {
+        let timer = lua_ctx.create_table()?;
+
+        timer.set(
+            "after",
+            lua_ctx.create_function(|lua_ctx, (delay, callback): (Value, Function)| {
+                schedule(lua_ctx, delay, callback, false)
+            })?,
+        )?;
+
+        timer.set(
+            "every",
+            lua_ctx.create_function(|lua_ctx, (interval, callback): (Value, Function)| {
+                schedule(lua_ctx, interval, callback, true)
+            })?,
+        )?;
+
+        timer.set(
+            "run",
+            lua_ctx.create_function(|lua_ctx, ()| wait(lua_ctx, None))?,
+        )?;
+
+        help::register_module_docs(
+            lua_ctx,
+            "timer",
+            "Calling functions later or repeatedly",
+            &timer,
+            TIMER_DOCS,
+        )?;
+        lua_ctx.globals().set("timer", timer)?;
+        Ok(())
+    })
+}
@@ -0,0 +1,292 @@
+/*
+   Copyright (C) 2022  Kalka
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License, or (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use std::io::Write;
+use std::net::{Ipv4Addr, Ipv6Addr, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use rlua::{Context, Lua, Result, Table};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{
+    ClientConfig, ClientConnection, DigitallySignedStruct, RootCertStore, SignatureScheme,
+};
+use sha2::Digest;
+use x509_parser::extensions::GeneralName;
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+use crate::error;
+use crate::hash::hex;
+use crate::help::{self, FunctionDoc};
+
+const TLS_DOCS: &[FunctionDoc] = &[FunctionDoc {
+    name: "inspect",
+    signature: "tls.inspect(target[, options]) -> info",
+    description: "Connects to a TLS server, given as \"host:port\", \"host\" for port 443 or an https URL, and describes it without sending anything: info.protocol and info.cipher that were agreed on, info.trusted, whether the certificates check out against the Mozilla roots for the host, with info.error saying why not, and info.certificates, the chain the server sent, leaf first. Each certificate has subject, issuer, common_name, sans (its DNS names and IP addresses), serial, fingerprint (SHA-256 in hex), not_before and not_after as seconds since 1970, and days_remaining, negative once it has expired. Expired and untrusted certificates are described too. options.timeout is how many seconds to wait for the server (10 by default). Raises an error saying why when the server can't be reached or doesn't speak TLS.",
+    example: "for _, host in ipairs({ \"example.com\", \"api.example.com:8443\" }) do\n    local leaf = tls.inspect(host).certificates[1]\n    if leaf.days_remaining < 14 then\n        print(host .. \" expires in \" .. leaf.days_remaining .. \" days\")\n    end\nend",
+}];
+
+const DEFAULT_TIMEOUT: f64 = 10.0;
+
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+// Checks certificates the way clients do, but records why they don't check out instead of
+// ending the handshake, so expired and self-signed certificates can still be described
+#[derive(Debug)]
+struct RecordingVerifier {
+    inner: Arc<WebPkiServerVerifier>,
+    error: Mutex<Option<String>>,
+}
+
+impl ServerCertVerifier for RecordingVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        if let Err(error) = self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            ocsp_response,
+            now,
+        ) {
+            *self.error.lock().unwrap() = Some(error.to_string());
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+fn tls_error(target: &str, message: impl ToString) -> rlua::Error {
+    rlua::Error::external(error::Error::Tls {
+        target: target.to_string(),
+        message: message.to_string(),
+    })
+}
+
+/// The host and port of a target like "host:port", "host" or "https://host:port/path".
+pub fn parse_target(target: &str) -> Option<(String, u16)> {
+    let url = match target.contains("://") {
+        true => reqwest::Url::parse(target).ok()?,
+        false => reqwest::Url::parse(&format!("https://{}", target)).ok()?,
+    };
+    let host = url
+        .host_str()?
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+    Some((host.to_string(), url.port().unwrap_or(443)))
+}
+
+fn describe<'lua>(lua_ctx: Context<'lua>, der: &CertificateDer, now: i64) -> Result<Table<'lua>> {
+    let table = lua_ctx.create_table()?;
+    table.set("fingerprint", hex(&sha2::Sha256::digest(der.as_ref())))?;
+    let certificate = match X509Certificate::from_der(der.as_ref()) {
+        Ok((_, certificate)) => certificate,
+        // The fingerprint is all there is to say about a certificate that can't be read
+        Err(_) => return Ok(table),
+    };
+    table.set("subject", certificate.subject().to_string())?;
+    table.set("issuer", certificate.issuer().to_string())?;
+    table.set(
+        "common_name",
+        certificate
+            .subject()
+            .iter_common_name()
+            .next()
+            .and_then(|name| name.as_str().ok()),
+    )?;
+    let mut sans = Vec::new();
+    if let Ok(Some(extension)) = certificate.subject_alternative_name() {
+        for name in &extension.value.general_names {
+            match name {
+                GeneralName::DNSName(name) => sans.push(name.to_string()),
+                GeneralName::IPAddress(bytes) => {
+                    if let Ok(octets) = <[u8; 4]>::try_from(*bytes) {
+                        sans.push(Ipv4Addr::from(octets).to_string());
+                    } else if let Ok(octets) = <[u8; 16]>::try_from(*bytes) {
+                        sans.push(Ipv6Addr::from(octets).to_string());
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    table.set("sans", lua_ctx.create_sequence_from(sans)?)?;
+    table.set("serial", certificate.raw_serial_as_string())?;
+    let validity = certificate.validity();
+    let not_after = validity.not_after.timestamp();
+    table.set("not_before", validity.not_before.timestamp())?;
+    table.set("not_after", not_after)?;
+    table.set(
+        "days_remaining",
+        (not_after - now).div_euclid(SECONDS_PER_DAY),
+    )?;
+    Ok(table)
+}
+
+fn inspect<'lua>(
+    lua_ctx: Context<'lua>,
+    (target, options): (String, Option<Table<'lua>>),
+) -> Result<Table<'lua>> {
+    let timeout = match &options {
+        Some(options) => options.get::<_, Option<f64>>("timeout")?,
+        None => None,
+    }
+    .unwrap_or(DEFAULT_TIMEOUT);
+    let timeout = Duration::try_from_secs_f64(timeout)
+        .ok()
+        .filter(|timeout| !timeout.is_zero())
+        .ok_or_else(|| {
+            rlua::Error::RuntimeError(format!(
+                "bad option 'timeout' to 'inspect' (positive number expected, got {})",
+                timeout
+            ))
+        })?;
+    let (host, port) = parse_target(&target).ok_or_else(|| {
+        rlua::Error::RuntimeError(format!(
+            "bad argument #1 to 'inspect' (host, host:port or https URL expected, got \"{}\")",
+            target
+        ))
+    })?;
+    let server_name = ServerName::try_from(host.clone())
+        .map_err(|_| tls_error(&target, format!("{} isn't a valid host name", host)))?;
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let mut roots = RootCertStore::empty();
+    roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    let inner = WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider.clone())
+        .build()
+        .map_err(|error| tls_error(&target, error))?;
+    let verifier = Arc::new(RecordingVerifier {
+        inner,
+        error: Mutex::new(None),
+    });
+    let config = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(|error| tls_error(&target, error))?
+        .dangerous()
+        .with_custom_certificate_verifier(verifier.clone())
+        .with_no_client_auth();
+
+    let address = (host.as_str(), port)
+        .to_socket_addrs()
+        .map_err(|error| tls_error(&target, error))?
+        .next()
+        .ok_or_else(|| tls_error(&target, format!("{} has no addresses", host)))?;
+    let mut socket =
+        TcpStream::connect_timeout(&address, timeout).map_err(|error| tls_error(&target, error))?;
+    socket
+        .set_read_timeout(Some(timeout))
+        .and_then(|_| socket.set_write_timeout(Some(timeout)))
+        .map_err(|error| tls_error(&target, error))?;
+    let mut connection = ClientConnection::new(Arc::new(config), server_name)
+        .map_err(|error| tls_error(&target, error))?;
+    while connection.is_handshaking() {
+        connection
+            .complete_io(&mut socket)
+            .map_err(|error| match error.kind() {
+                std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut => {
+                    tls_error(&target, "the server didn't finish the handshake in time")
+                }
+                _ => tls_error(&target, error),
+            })?;
+    }
+    // Closing politely is a courtesy, so it failing doesn't matter
+    connection.send_close_notify();
+    let _ = connection.write_tls(&mut socket);
+    let _ = socket.flush();
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64;
+    let info = lua_ctx.create_table()?;
+    info.set("host", host.as_str())?;
+    info.set("port", port)?;
+    info.set(
+        "protocol",
+        connection.protocol_version().map(|version| match version {
+            rustls::ProtocolVersion::TLSv1_2 => "TLSv1.2".to_string(),
+            rustls::ProtocolVersion::TLSv1_3 => "TLSv1.3".to_string(),
+            version => format!("{:?}", version),
+        }),
+    )?;
+    info.set(
+        "cipher",
+        connection
+            .negotiated_cipher_suite()
+            .and_then(|suite| suite.suite().as_str().map(str::to_string)),
+    )?;
+    let error = verifier.error.lock().unwrap().take();
+    info.set("trusted", error.is_none())?;
+    info.set("error", error)?;
+    let certificates = lua_ctx.create_table()?;
+    for (index, der) in connection
+        .peer_certificates()
+        .unwrap_or_default()
+        .iter()
+        .enumerate()
+    {
+        certificates.set(index + 1, describe(lua_ctx, der, now)?)?;
+    }
+    info.set("certificates", certificates)?;
+    Ok(info)
+}
+
+pub fn load_tls_library(lua: &Lua) -> Result<()> {
+    lua.context(|lua_ctx| {
+        let tls = lua_ctx.create_table()?;
+
+        tls.set("inspect", lua_ctx.create_function(inspect)?)?;
+
+        help::register_module_docs(
+            lua_ctx,
+            "tls",
+            "Inspecting TLS servers and their certificates",
+            &tls,
+            TLS_DOCS,
+        )?;
+        lua_ctx.globals().set("tls", tls)?;
+        Ok(())
+    })
+}
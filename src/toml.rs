@@ -0,0 +1,258 @@
+/*
+   Copyright (C) 2022  Kalka
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License, or (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use rlua::{Context, Function, Lua, Result, Table, Value};
+
+use crate::error;
+use crate::help::{self, FunctionDoc};
+
+const TOML_DOCS: &[FunctionDoc] = &[
+    FunctionDoc {
+        name: "decode",
+        signature: "toml.decode(text[, options]) -> table",
+        description: "Parses TOML into a table. Dates and times become strings as they're written in RFC 3339. With options.ordered, tables remember the order of their keys in the text: pairs goes through them in that order, followed by any keys added since, and toml.encode writes them back in it. Raises an error saying where the TOML is invalid.",
+        example: "local manifest = toml.decode(fs.read(\"Cargo.toml\"))\nprint(manifest.package.name, manifest.package.version)",
+    },
+    FunctionDoc {
+        name: "encode",
+        signature: "toml.encode(table[, options]) -> string",
+        description: "Turns a table into TOML. Tables with nothing but the keys 1 to n become arrays and other tables become tables, with their keys in the order toml.decode read them for ordered tables, and in the order the table holds them in otherwise. options.sort_keys writes the keys in order instead. Raises an error for values TOML can't hold, like functions and json.null, and for tables nested inside themselves.",
+        example: "local manifest = toml.decode(fs.read(\"Cargo.toml\"), { ordered = true })\nmanifest.package.version = \"0.2.0\"\nfs.write(\"Cargo.toml\", toml.encode(manifest))",
+    },
+];
+
+// __pairs for tables decoded with options.ordered, going through the keys in the order they
+// were read and then any added since
+const ORDERED_PAIRS_SOURCE: &str = r#"
+local getmetatable, next, rawget = getmetatable, next, rawget
+
+return function(t)
+    local order = getmetatable(t).__order
+    local read = {}
+    for _, key in ipairs(order) do
+        read[key] = true
+    end
+    local index, key = 0, nil
+    return function()
+        while index < #order do
+            index = index + 1
+            local value = rawget(t, order[index])
+            if value ~= nil then
+                return order[index], value
+            end
+        end
+        local value
+        repeat
+            key, value = next(t, key)
+        until key == nil or not read[key]
+        return key, value
+    end, t, nil
+end
+"#;
+
+const ORDERED_PAIRS_REGISTRY_KEY: &str = "rluaterm.toml.ordered_pairs";
+
+// Deeper tables are almost certainly cycles
+const MAX_DEPTH: usize = 100;
+
+fn encode_error(message: String) -> rlua::Error {
+    rlua::Error::RuntimeError(format!("cannot encode to TOML: {}", message))
+}
+
+fn to_lua<'lua>(lua_ctx: Context<'lua>, value: toml::Value, ordered: bool) -> Result<Value<'lua>> {
+    Ok(match value {
+        toml::Value::String(string) => Value::String(lua_ctx.create_string(&string)?),
+        toml::Value::Integer(integer) => Value::Integer(integer),
+        toml::Value::Float(float) => Value::Number(float),
+        toml::Value::Boolean(boolean) => Value::Boolean(boolean),
+        toml::Value::Datetime(datetime) => {
+            Value::String(lua_ctx.create_string(&datetime.to_string())?)
+        }
+        toml::Value::Array(array) => {
+            let table = lua_ctx.create_table()?;
+            for (index, value) in array.into_iter().enumerate() {
+                table.raw_set(index as i64 + 1, to_lua(lua_ctx, value, ordered)?)?;
+            }
+            Value::Table(table)
+        }
+        toml::Value::Table(map) => Value::Table(table_to_lua(lua_ctx, map, ordered)?),
+    })
+}
+
+fn table_to_lua<'lua>(
+    lua_ctx: Context<'lua>,
+    map: toml::Table,
+    ordered: bool,
+) -> Result<Table<'lua>> {
+    let table = lua_ctx.create_table()?;
+    let order = lua_ctx.create_table()?;
+    for (index, (key, value)) in map.into_iter().enumerate() {
+        order.raw_set(index as i64 + 1, key.as_str())?;
+        table.raw_set(key, to_lua(lua_ctx, value, ordered)?)?;
+    }
+    if ordered {
+        let metatable = lua_ctx.create_table()?;
+        metatable.set("__order", order)?;
+        metatable.set(
+            "__pairs",
+            lua_ctx.named_registry_value::<_, Function>(ORDERED_PAIRS_REGISTRY_KEY)?,
+        )?;
+        table.set_metatable(Some(metatable));
+    }
+    Ok(table)
+}
+
+// The entries of a table, in the order toml.decode read them first for ordered tables
+fn ordered_pairs(table: Table) -> Result<Vec<(String, Value)>> {
+    let mut pairs = Vec::new();
+    for pair in table.clone().pairs::<Value, Value>() {
+        let (key, value) = pair?;
+        let key = match key {
+            Value::String(key) => key.to_str()?.to_string(),
+            Value::Integer(key) => key.to_string(),
+            Value::Number(key) => key.to_string(),
+            key => return Err(encode_error(format!("{} keys", key.type_name()))),
+        };
+        pairs.push((key, value));
+    }
+    let order = match table.get_metatable() {
+        Some(metatable) => metatable.raw_get::<_, Option<Table>>("__order")?,
+        None => None,
+    };
+    let Some(order) = order else {
+        return Ok(pairs);
+    };
+    let mut ordered = Vec::with_capacity(pairs.len());
+    for key in order.sequence_values::<String>() {
+        let key = key?;
+        if let Some(position) = pairs.iter().position(|(name, _)| *name == key) {
+            ordered.push(pairs.remove(position));
+        }
+    }
+    ordered.append(&mut pairs);
+    Ok(ordered)
+}
+
+fn to_toml(value: Value, sort_keys: bool, depth: usize) -> Result<toml::Value> {
+    Ok(match value {
+        Value::Boolean(boolean) => toml::Value::Boolean(boolean),
+        Value::Integer(integer) => toml::Value::Integer(integer),
+        Value::Number(number) => toml::Value::Float(number),
+        Value::String(string) => toml::Value::String(string.to_str()?.to_string()),
+        Value::Table(table) => {
+            if depth >= MAX_DEPTH {
+                return Err(encode_error("tables are nested too deeply".to_string()));
+            }
+            let length = table.raw_len() as usize;
+            let pairs = ordered_pairs(table.clone())?;
+            if length > 0 && pairs.len() == length {
+                let mut array = Vec::with_capacity(length);
+                for index in 1..=length {
+                    array.push(to_toml(table.raw_get(index as i64)?, sort_keys, depth + 1)?);
+                }
+                toml::Value::Array(array)
+            } else {
+                toml::Value::Table(table_to_toml(pairs, sort_keys, depth)?)
+            }
+        }
+        value => {
+            let name = match value {
+                Value::Nil | Value::LightUserData(_) => "null",
+                value => value.type_name(),
+            };
+            return Err(encode_error(format!("{} values", name)));
+        }
+    })
+}
+
+fn table_to_toml(
+    pairs: Vec<(String, Value)>,
+    sort_keys: bool,
+    depth: usize,
+) -> Result<toml::Table> {
+    let mut entries = Vec::with_capacity(pairs.len());
+    for (key, value) in pairs {
+        entries.push((key, to_toml(value, sort_keys, depth + 1)?));
+    }
+    if sort_keys {
+        entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+    }
+    Ok(entries.into_iter().collect())
+}
+
+fn encode(table: Value, options: Option<Table>) -> Result<String> {
+    let sort_keys = match options {
+        Some(options) => options
+            .get::<_, Option<bool>>("sort_keys")?
+            .unwrap_or(false),
+        None => false,
+    };
+    let table = match table {
+        Value::Table(table) => table,
+        value => {
+            return Err(rlua::Error::RuntimeError(format!(
+                "bad argument #1 to 'encode' (table expected, got {})",
+                value.type_name()
+            )))
+        }
+    };
+    let toml = table_to_toml(ordered_pairs(table)?, sort_keys, 0)?;
+    toml::to_string(&toml).map_err(|error| encode_error(error.to_string()))
+}
+
+pub fn load_toml_library(lua: &Lua) -> Result<()> {
+    lua.context(|lua_ctx| {
+        let ordered_pairs = lua_ctx
+            .load(ORDERED_PAIRS_SOURCE)
+            .set_name("=toml")?
+            .call::<_, Function>(())?;
+        lua_ctx.set_named_registry_value(ORDERED_PAIRS_REGISTRY_KEY, ordered_pairs)?;
+
+        let toml = lua_ctx.create_table()?;
+
+        toml.set(
+            "decode",
+            lua_ctx.create_function(|lua_ctx, (text, options): (String, Option<Table>)| {
+                let ordered = match options {
+                    Some(options) => options.get::<_, Option<bool>>("ordered")?.unwrap_or(false),
+                    None => false,
+                };
+                let map = text
+                    .parse::<toml::Table>()
+                    .map_err(|source| rlua::Error::external(error::Error::Toml { source }))?;
+                table_to_lua(lua_ctx, map, ordered)
+            })?,
+        )?;
+
+        toml.set(
+            "encode",
+            lua_ctx.create_function(|_, (table, options): (Value, Option<Table>)| {
+                encode(table, options)
+            })?,
+        )?;
+
+        help::register_module_docs(
+            lua_ctx,
+            "toml",
+            "TOML encoding and decoding",
+            &toml,
+            TOML_DOCS,
+        )?;
+        lua_ctx.globals().set("toml", toml)?;
+
+        Ok(())
+    })
+}
@@ -0,0 +1,224 @@
+/*
+   Copyright (C) 2022  Kalka
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License, or (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use rlua::{
+    Chunk, Context, FromLuaMulti, Function, Lua, MultiValue, Result, StdLib, Table, ToLuaMulti,
+};
+use std::sync::Mutex;
+
+use crate::timeout;
+
+// Name of the registry value holding the function chunks are run through
+const RUNNER_REGISTRY_KEY: &str = "rluaterm.traceback.runner";
+
+// Name of the registry value holding debug.getinfo, for looking up where functions are defined
+const GETINFO_REGISTRY_KEY: &str = "rluaterm.traceback.getinfo";
+
+// Names of the registry values holding debug.sethook and debug.gethook, for the profiler and
+// signal handlers
+const SETHOOK_REGISTRY_KEY: &str = "rluaterm.traceback.sethook";
+const GETHOOK_REGISTRY_KEY: &str = "rluaterm.traceback.gethook";
+
+// Traceback produced by the message handler for the last failed chunk
+static LAST_TRACEBACK: Mutex<Option<String>> = Mutex::new(None);
+
+// Runs a function under xpcall with a message handler that walks the stack while it is still
+// intact, optionally listing each frame's locals when `repl.show_locals` is set. The handler hands
+// the traceback to Rust and passes the error through untouched so it can still be matched on.
+const RUNNER_SOURCE: &str = r#"
+local getinfo, getlocal, stash = ...
+local globals, xpcall, error, rawget = _G, xpcall, error, rawget
+local pack, unpack, concat = table.pack, table.unpack, table.concat
+local format, tostring, type = string.format, tostring, type
+
+local function describe(value)
+    if type(value) == "string" then
+        if #value > 60 then
+            value = value:sub(1, 57) .. "..."
+        end
+        return format("%q", value)
+    end
+    return tostring(value)
+end
+
+local function frame_name(info)
+    if info.what == "main" then
+        return "main chunk"
+    elseif info.name then
+        return format("%s '%s'", info.namewhat ~= "" and info.namewhat or "function", info.name)
+    elseif info.what == "C" then
+        return "?"
+    end
+    return format("function <%s:%d>", info.short_src, info.linedefined)
+end
+
+local function handler(err)
+    local repl = rawget(globals, "repl")
+    local show_locals = type(repl) == "table" and rawget(repl, "show_locals")
+    local lines = { "stack traceback:" }
+    local level = 2
+    while true do
+        local info = getinfo(level, "Slnf")
+        if info == nil or info.func == xpcall then
+            break
+        end
+        if info.currentline > 0 then
+            lines[#lines + 1] = format("\t%s:%d: in %s", info.short_src, info.currentline, frame_name(info))
+        else
+            lines[#lines + 1] = format("\t%s: in %s", info.short_src, frame_name(info))
+        end
+        if show_locals and info.what ~= "C" then
+            local index = 1
+            while true do
+                local name, value = getlocal(level, index)
+                if name == nil then
+                    break
+                end
+                if name:sub(1, 1) ~= "(" then
+                    lines[#lines + 1] = format("\t\t%s = %s", name, describe(value))
+                end
+                index = index + 1
+            end
+        end
+        level = level + 1
+    end
+    stash(concat(lines, "\n"))
+    return err
+end
+
+return function(f, ...)
+    local result = pack(xpcall(f, handler, ...))
+    if not result[1] then
+        error(result[2], 0)
+    end
+    return unpack(result, 2, result.n)
+end
+"#;
+
+pub fn attach_traceback_handler(lua: &Lua) -> Result<()> {
+    // The debug library is only used by the message handler and never exposed to scripts,
+    // since it can break the guarantees rlua relies on. Loading it also registers it in
+    // package.loaded, where require would find it
+    unsafe {
+        lua.unsafe_load_from_std_lib(StdLib::DEBUG)?;
+    }
+    lua.context(|lua_ctx| {
+        let globals = lua_ctx.globals();
+        let debug = globals.get::<_, Table>("debug")?;
+        globals.set("debug", rlua::Nil)?;
+        if let Some(package) = globals.get::<_, Option<Table>>("package")? {
+            package.get::<_, Table>("loaded")?.set("debug", rlua::Nil)?;
+        }
+
+        let getinfo = debug.get::<_, Function>("getinfo")?;
+        lua_ctx.set_named_registry_value(GETINFO_REGISTRY_KEY, getinfo.clone())?;
+        lua_ctx
+            .set_named_registry_value(SETHOOK_REGISTRY_KEY, debug.get::<_, Function>("sethook")?)?;
+        lua_ctx
+            .set_named_registry_value(GETHOOK_REGISTRY_KEY, debug.get::<_, Function>("gethook")?)?;
+
+        let stash = lua_ctx.create_function(|_, traceback: String| {
+            *LAST_TRACEBACK.lock().unwrap() = Some(traceback);
+            Ok(())
+        })?;
+        let runner = lua_ctx
+            .load(RUNNER_SOURCE)
+            .set_name("=traceback")?
+            .call::<_, Function>((getinfo, debug.get::<_, Function>("getlocal")?, stash))?;
+        lua_ctx.set_named_registry_value(RUNNER_REGISTRY_KEY, runner)?;
+        Ok(())
+    })
+}
+
+/// Calls the function so that, if it fails, `take_traceback` returns the stack at the point of
+/// the error, and so that it fails once it runs past the timeout.
+pub fn call_with_traceback<'lua, A, R>(
+    lua_ctx: Context<'lua>,
+    function: Function<'lua>,
+    args: A,
+) -> Result<R>
+where
+    A: ToLuaMulti<'lua>,
+    R: FromLuaMulti<'lua>,
+{
+    *LAST_TRACEBACK.lock().unwrap() = None;
+    // Every chunk gets the whole timeout to itself
+    timeout::start();
+    let runner = lua_ctx.named_registry_value::<_, Function>(RUNNER_REGISTRY_KEY)?;
+    let mut args = lua_ctx.pack_multi(args)?.into_vec();
+    args.insert(0, rlua::Value::Function(function));
+    runner.call(MultiValue::from_vec(args))
+}
+
+/// Compiles and runs the chunk through `call_with_traceback`.
+pub fn exec_with_traceback<'lua>(lua_ctx: Context<'lua>, chunk: Chunk<'lua, '_>) -> Result<()> {
+    let function = chunk.into_function()?;
+    call_with_traceback(lua_ctx, function, ())
+}
+
+/// Takes the traceback recorded for the last failed `call_with_traceback`.
+pub fn take_traceback() -> Option<String> {
+    LAST_TRACEBACK.lock().unwrap().take()
+}
+
+/// `debug.getinfo`, kept around after the debug library is removed from the globals.
+pub fn getinfo(lua_ctx: Context) -> Result<Function> {
+    lua_ctx.named_registry_value(GETINFO_REGISTRY_KEY)
+}
+
+/// The source a Lua function was loaded from and the line it is defined on. The source is `@path`
+/// for files, `=name` for chunks whose code isn't kept, and the code itself otherwise.
+pub fn function_source<'lua>(
+    lua_ctx: Context<'lua>,
+    function: Function<'lua>,
+) -> Result<Option<(String, usize)>> {
+    let info = getinfo(lua_ctx)?.call::<_, Table>((function, "S"))?;
+    if info.get::<_, String>("what")? == "C" {
+        return Ok(None);
+    }
+    let line = info.get::<_, i64>("linedefined")?;
+    Ok(Some((info.get("source")?, line.max(0) as usize)))
+}
+
+/// `debug.sethook`, kept around after the debug library is removed from the globals. Setting a
+/// hook with it replaces the interrupt hook, so the new hook has to do its checks.
+pub fn sethook(lua_ctx: Context) -> Result<Function> {
+    lua_ctx.named_registry_value(SETHOOK_REGISTRY_KEY)
+}
+
+/// `debug.gethook`, which returns "external hook" while the interrupt hook is set.
+pub fn gethook(lua_ctx: Context) -> Result<Function> {
+    lua_ctx.named_registry_value(GETHOOK_REGISTRY_KEY)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn debug_library_is_not_reachable() {
+        let lua = Lua::new();
+        attach_traceback_handler(&lua).unwrap();
+        lua.context(|lua_ctx| {
+            let globals = lua_ctx.globals();
+            assert!(globals.get::<_, Option<Table>>("debug").unwrap().is_none());
+            let require = globals.get::<_, Function>("require").unwrap();
+            assert!(require.call::<_, Table>("debug").is_err());
+            // The handler keeps working without it
+            assert!(getinfo(lua_ctx).is_ok());
+        });
+    }
+}
@@ -0,0 +1,155 @@
+/*
+   Copyright (C) 2022  Kalka
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License, or (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use rlua::{Function, Lua, Result, Variadic};
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::{pager, remote};
+
+struct Transcript {
+    path: PathBuf,
+    file: File,
+    strip_ansi: bool,
+    // Output produced since the last input, written out as one block
+    pending_output: String,
+}
+
+static TRANSCRIPT: Mutex<Option<Transcript>> = Mutex::new(None);
+
+// Remove terminal escape sequences such as the ones `colored` produces
+fn strip_ansi(text: &str) -> String {
+    let mut stripped = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\x1b' {
+            stripped.push(c);
+            continue;
+        }
+        if chars.next() == Some('[') {
+            for c in chars.by_ref() {
+                if ('@'..='~').contains(&c) {
+                    break;
+                }
+            }
+        }
+    }
+    stripped
+}
+
+impl Transcript {
+    fn flush_output(&mut self) -> std::io::Result<()> {
+        if self.pending_output.is_empty() {
+            return Ok(());
+        }
+        let output = std::mem::take(&mut self.pending_output);
+        let output = if self.strip_ansi {
+            strip_ansi(&output)
+        } else {
+            output
+        };
+        writeln!(self.file, "```\n{}\n```\n", output.trim_end())?;
+        self.file.flush()
+    }
+}
+
+/// Starts recording the session into a Markdown file, replacing any transcript in progress.
+pub fn start(path: &Path, strip_ansi: bool) -> std::io::Result<()> {
+    stop()?;
+    let mut file = File::create(path)?;
+    writeln!(file, "# rluaterm transcript\n")?;
+    *TRANSCRIPT.lock().unwrap() = Some(Transcript {
+        path: path.to_path_buf(),
+        file,
+        strip_ansi,
+        pending_output: String::new(),
+    });
+    Ok(())
+}
+
+/// Stops recording, returning the path of the finished transcript if one was in progress.
+pub fn stop() -> std::io::Result<Option<PathBuf>> {
+    match TRANSCRIPT.lock().unwrap().take() {
+        Some(mut transcript) => {
+            transcript.flush_output()?;
+            Ok(Some(transcript.path))
+        }
+        None => Ok(None),
+    }
+}
+
+/// The path of the transcript being recorded, if any.
+pub fn active_path() -> Option<PathBuf> {
+    TRANSCRIPT
+        .lock()
+        .unwrap()
+        .as_ref()
+        .map(|transcript| transcript.path.clone())
+}
+
+/// Records code entered at the prompt.
+pub fn record_input(code: &str) {
+    if let Some(transcript) = TRANSCRIPT.lock().unwrap().as_mut() {
+        let result = transcript.flush_output().and_then(|_| {
+            writeln!(transcript.file, "```lua\n{}\n```\n", code.trim_end())?;
+            transcript.file.flush()
+        });
+        if result.is_err() {
+            // Don't interrupt the session over a transcript that can't be written
+            transcript.pending_output.clear();
+        }
+    }
+}
+
+/// Records output shown to the user.
+pub fn record_output(text: &str) {
+    if let Some(transcript) = TRANSCRIPT.lock().unwrap().as_mut() {
+        transcript.pending_output.push_str(text);
+        if !text.ends_with('\n') {
+            transcript.pending_output.push('\n');
+        }
+    }
+}
+
+/// Replaces `print` with a version that also records its output into the transcript, or sends it
+/// to the remote client whose request is being evaluated.
+pub fn attach_print_recorder(lua: &Lua) -> Result<()> {
+    lua.context(|lua_ctx| {
+        lua_ctx.globals().set(
+            "print",
+            lua_ctx.create_function(|ctx, args: Variadic<rlua::Value>| {
+                let tostring = ctx.globals().get::<_, Function>("tostring")?;
+                let mut parts = Vec::with_capacity(args.len());
+                for arg in args.into_iter() {
+                    parts.push(tostring.call::<_, String>(arg)?);
+                }
+                let line = parts.join("\t");
+                // Output of remote requests goes to the client, output past the REPL's limit is
+                // only kept for :page
+                if !remote::capture_output(&line) {
+                    if let Some(shown) = pager::show(&line) {
+                        println!("{}", shown);
+                        record_output(&shown);
+                    }
+                }
+                Ok(())
+            })?,
+        )?;
+        Ok(())
+    })
+}
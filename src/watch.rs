@@ -0,0 +1,124 @@
+/*
+   Copyright (C) 2022  Kalka
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License, or (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use colored::Colorize;
+use cumulus::logger;
+use notify::{RecursiveMode, Watcher};
+use rlua::{Function, Lua, Result, Table, Value};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::mpsc;
+use std::time::Duration;
+
+use crate::{require, INTERRUPTED};
+
+// How often to check for Ctrl+C while waiting for changes
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+// Editors often write a file in several steps, wait for them to settle before rerunning
+const SETTLE_DELAY: Duration = Duration::from_millis(100);
+
+const CLEAR_SCREEN: &str = "\x1b[2J\x1b[H";
+
+// Files of the modules the script required, found the same way `require` finds them
+fn required_files(lua: &Lua) -> Result<Vec<PathBuf>> {
+    lua.context(|lua_ctx| {
+        let package = lua_ctx.globals().get::<_, Table>("package")?;
+        let search_path = require::search_path(lua_ctx)?;
+        let searchpath = package.get::<_, Function>("searchpath")?;
+        let mut files = Vec::new();
+        for pair in package.get::<_, Table>("loaded")?.pairs::<Value, Value>() {
+            if let (Value::String(name), _) = pair? {
+                let file = searchpath.call::<_, Option<String>>((name, search_path.as_str()))?;
+                files.extend(file.map(PathBuf::from));
+            }
+        }
+        Ok(files)
+    })
+}
+
+// Absolute path, so it can be compared with the paths in change events
+fn absolute(path: &Path) -> Option<PathBuf> {
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    Some(parent.canonicalize().ok()?.join(path.file_name()?))
+}
+
+/// Runs the script with `run` and again whenever it or one of the modules it required changes,
+/// until Ctrl+C. `run` returns the state the script ran in and its exit code.
+pub fn watch(script: &Path, mut run: impl FnMut() -> Result<(Lua, i32)>) -> Result<()> {
+    let (sender, receiver) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(sender).map_err(rlua::Error::external)?;
+    let mut watched_dirs = HashSet::new();
+    loop {
+        print!("{}", CLEAR_SCREEN);
+        INTERRUPTED.store(false, Ordering::SeqCst);
+        let (lua, exit_code) = run()?;
+
+        let mut files = required_files(&lua)?;
+        files.push(script.to_path_buf());
+        let files = files
+            .iter()
+            .filter_map(|file| absolute(file))
+            .collect::<HashSet<_>>();
+        // Watch directories rather than files, since saving often replaces the file
+        for dir in files.iter().filter_map(|file| file.parent()) {
+            if watched_dirs.insert(dir.to_path_buf()) {
+                if let Err(error) = watcher.watch(dir, RecursiveMode::NonRecursive) {
+                    logger::warn(&format!("Failed to watch {} [{}]", dir.display(), error));
+                }
+            }
+        }
+        let status = if exit_code == 0 {
+            String::new()
+        } else {
+            format!("Exited with code {}. ", exit_code)
+        };
+        println!(
+            "{}",
+            format!(
+                "{}Watching {} files for changes, Ctrl+C to stop",
+                status,
+                files.len()
+            )
+            .dimmed()
+        );
+
+        loop {
+            if INTERRUPTED.swap(false, Ordering::SeqCst) {
+                return Ok(());
+            }
+            let event = match receiver.recv_timeout(POLL_INTERVAL) {
+                Ok(Ok(event)) => event,
+                Ok(Err(error)) => {
+                    logger::warn(&format!("Failed to watch files [{}]", error));
+                    continue;
+                }
+                Err(mpsc::RecvTimeoutError::Timeout) => continue,
+                Err(mpsc::RecvTimeoutError::Disconnected) => return Ok(()),
+            };
+            let changed =
+                !event.kind.is_access() && event.paths.iter().any(|path| files.contains(path));
+            if changed {
+                break;
+            }
+        }
+        std::thread::sleep(SETTLE_DELAY);
+        while receiver.try_recv().is_ok() {}
+    }
+}
@@ -0,0 +1,600 @@
+/*
+   Copyright (C) 2022  Kalka
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License, or (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use quick_xml::events::{BytesEnd, BytesStart, BytesText, Event};
+use quick_xml::{Reader, Writer, XmlVersion};
+use rlua::{Context, Function, Lua, Result, Table, Value};
+use std::collections::HashSet;
+
+use crate::error;
+use crate::help::{self, FunctionDoc};
+
+const XML_DOCS: &[FunctionDoc] = &[
+    FunctionDoc {
+        name: "decode",
+        signature: "xml.decode(text) -> element",
+        description: "Parses XML into a tree of elements, returning the root element. Each element is a table with its tag, its attributes in attrs and its children in order in children, where text is a string and elements are tables. Text that is only whitespace is left out, comments and processing instructions are dropped, and entities are resolved. Elements have query, first and text methods, the same as xml.query, xml.first and xml.text. Raises an error saying where the XML is invalid.",
+        example: "local feed = xml.decode(http.get(\"https://example.com/feed.xml\").text)\nprint(feed:first(\"channel/title/text()\"))",
+    },
+    FunctionDoc {
+        name: "encode",
+        signature: "xml.encode(element[, options]) -> string",
+        description: "Turns a tree of elements like xml.decode's back into XML, escaping text and attributes. Elements need a tag, and attrs and children are optional, where children can hold strings, numbers and elements. options.pretty indents the XML over several lines and options.declaration starts it with an <?xml?> declaration.",
+        example: "print(xml.encode({ tag = \"url\", children = {\n    { tag = \"loc\", children = { \"https://example.com/\" } },\n} }, { declaration = true }))",
+    },
+    FunctionDoc {
+        name: "query",
+        signature: "xml.query(element, path) -> list",
+        description: "Finds the elements under an element along a path like XPath's: tags separated by /, where // goes through every level below, * matches any tag, and a tag without a namespace prefix matches it with any prefix. Steps can end in predicates: [n] for the nth match, [@name] for elements with the attribute and [@name='value'] for elements where it has the value. A path ending in /@name gives those attributes and one ending in /text() the text of the elements. A path starting with / starts at the element itself rather than its children.",
+        example: "for _, link in ipairs(xml.query(page, \"//a[@rel='next']/@href\")) do\n    print(link)\nend",
+    },
+    FunctionDoc {
+        name: "first",
+        signature: "xml.first(element, path) -> value",
+        description: "The first of what xml.query finds along the path, or nil if nothing is found.",
+        example: "local total = tonumber(xml.first(response, \"//soap:Body//Total/text()\"))",
+    },
+    FunctionDoc {
+        name: "text",
+        signature: "xml.text(element) -> string",
+        description: "All the text in an element and the elements in it, joined together.",
+        example: "print(xml.text(xml.decode(\"<p>Hello <b>there</b></p>\")))  --> Hello there",
+    },
+];
+
+// The metatable of the elements xml.decode returns, which gives them query, first and text
+const ELEMENT_REGISTRY_KEY: &str = "rluaterm.xml.element";
+
+// Deeper trees are almost certainly cycles
+const MAX_DEPTH: usize = 100;
+
+fn xml_error(position: u64, message: impl ToString) -> rlua::Error {
+    rlua::Error::external(error::Error::Xml {
+        position,
+        message: message.to_string(),
+    })
+}
+
+fn new_element<'lua>(
+    lua_ctx: Context<'lua>,
+    start: &BytesStart,
+    position: u64,
+) -> Result<Table<'lua>> {
+    let element = lua_ctx.create_table()?;
+    element.set("tag", start.name().as_ref())?;
+    let attrs = lua_ctx.create_table()?;
+    for attribute in start.attributes() {
+        let attribute = attribute.map_err(|error| xml_error(position, error))?;
+        let value = attribute
+            .normalized_value(XmlVersion::Implicit1_0)
+            .map_err(|error| xml_error(position, error))?;
+        attrs.set(attribute.key.as_ref(), value.as_ref())?;
+    }
+    element.set("attrs", attrs)?;
+    element.set("children", lua_ctx.create_table()?)?;
+    element.set_metatable(Some(
+        lua_ctx.named_registry_value::<_, Table>(ELEMENT_REGISTRY_KEY)?,
+    ));
+    Ok(element)
+}
+
+fn decode(lua_ctx: Context, text: String) -> Result<Table> {
+    let mut reader = Reader::from_str(&text);
+    // Elements still open, the innermost last
+    let mut open: Vec<Table> = Vec::new();
+    let mut root = None;
+    let mut pending_text = String::new();
+    loop {
+        let event = reader
+            .read_event()
+            .map_err(|error| xml_error(reader.error_position(), error))?;
+        let position = reader.buffer_position();
+        // Text ends where an element starts or ends
+        if matches!(
+            event,
+            Event::Start(_) | Event::End(_) | Event::Empty(_) | Event::Eof
+        ) {
+            let text = std::mem::take(&mut pending_text);
+            if let Some(parent) = open.last() {
+                if !text.trim().is_empty() {
+                    let children = parent.get::<_, Table>("children")?;
+                    children.raw_set(children.raw_len() + 1, text)?;
+                }
+            }
+        }
+        match event {
+            Event::Start(_) | Event::Empty(_) if open.is_empty() && root.is_some() => {
+                return Err(xml_error(position, "there is more than one root element"));
+            }
+            Event::Start(start) => {
+                open.push(new_element(lua_ctx, &start, position)?);
+            }
+            Event::Empty(start) => {
+                let element = new_element(lua_ctx, &start, position)?;
+                match open.last() {
+                    Some(parent) => {
+                        let children = parent.get::<_, Table>("children")?;
+                        children.raw_set(children.raw_len() + 1, element)?;
+                    }
+                    None => root = Some(element),
+                }
+            }
+            Event::End(_) => {
+                let element = open.pop().expect("the reader checks end tags match");
+                match open.last() {
+                    Some(parent) => {
+                        let children = parent.get::<_, Table>("children")?;
+                        children.raw_set(children.raw_len() + 1, element)?;
+                    }
+                    None => root = Some(element),
+                }
+            }
+            Event::Text(text) => pending_text.push_str(&text.xml10_content()),
+            Event::CData(data) => pending_text.push_str(&data.xml10_content()),
+            Event::GeneralRef(reference) => {
+                let name = reference.xml10_content();
+                if let Some(character) = reference
+                    .resolve_char_ref()
+                    .map_err(|error| xml_error(position, error))?
+                {
+                    pending_text.push(character);
+                } else if let Some(entity) = quick_xml::escape::resolve_predefined_entity(&name) {
+                    pending_text.push_str(entity);
+                } else {
+                    return Err(xml_error(position, format!("unknown entity &{};", name)));
+                }
+            }
+            Event::Eof => break,
+            Event::Comment(_) | Event::Decl(_) | Event::PI(_) | Event::DocType(_) => {}
+        }
+    }
+    if let Some(element) = open.last() {
+        let tag = element.get::<_, String>("tag")?;
+        return Err(xml_error(
+            reader.buffer_position(),
+            format!("<{}> is never closed", tag),
+        ));
+    }
+    root.ok_or_else(|| xml_error(0, "there is no root element"))
+}
+
+fn encode_error(message: String) -> rlua::Error {
+    rlua::Error::RuntimeError(format!("cannot encode to XML: {}", message))
+}
+
+fn write_element(writer: &mut Writer<Vec<u8>>, element: Table, depth: usize) -> Result<()> {
+    if depth >= MAX_DEPTH {
+        return Err(encode_error("elements are nested too deeply".to_string()));
+    }
+    let tag = match element.get::<_, Value>("tag")? {
+        Value::String(tag) => tag.to_str()?.to_string(),
+        tag => {
+            return Err(encode_error(format!(
+                "elements need a string tag, got {}",
+                tag.type_name()
+            )))
+        }
+    };
+    let mut start = BytesStart::new(tag.as_str());
+    if let Some(attrs) = element.get::<_, Option<Table>>("attrs")? {
+        let mut attrs = attrs
+            .pairs::<String, String>()
+            .collect::<Result<Vec<_>>>()?;
+        attrs.sort();
+        for (name, value) in &attrs {
+            start.push_attribute((name.as_str(), value.as_str()));
+        }
+    }
+    let children = match element.get::<_, Option<Table>>("children")? {
+        Some(children) => children
+            .sequence_values::<Value>()
+            .collect::<Result<Vec<_>>>()?,
+        None => Vec::new(),
+    };
+    let write_error = |error: std::io::Error| encode_error(error.to_string());
+    if children.is_empty() {
+        return writer.write_event(Event::Empty(start)).map_err(write_error);
+    }
+    writer
+        .write_event(Event::Start(start))
+        .map_err(write_error)?;
+    for child in children {
+        match child {
+            Value::String(text) => writer
+                .write_event(Event::Text(BytesText::new(text.to_str()?)))
+                .map_err(write_error)?,
+            Value::Integer(_) | Value::Number(_) => {
+                let text = child_text(child);
+                writer
+                    .write_event(Event::Text(BytesText::new(&text)))
+                    .map_err(write_error)?
+            }
+            Value::Table(child) => write_element(writer, child, depth + 1)?,
+            child => {
+                return Err(encode_error(format!(
+                    "children can't be {} values",
+                    child.type_name()
+                )))
+            }
+        }
+    }
+    writer
+        .write_event(Event::End(BytesEnd::new(tag.as_str())))
+        .map_err(write_error)
+}
+
+fn child_text(value: Value) -> String {
+    match value {
+        Value::Integer(integer) => integer.to_string(),
+        Value::Number(number) => number.to_string(),
+        _ => String::new(),
+    }
+}
+
+fn encode(element: Table, options: Option<Table>) -> Result<String> {
+    let (pretty, declaration) = match options {
+        Some(options) => (
+            options.get::<_, Option<bool>>("pretty")?.unwrap_or(false),
+            options
+                .get::<_, Option<bool>>("declaration")?
+                .unwrap_or(false),
+        ),
+        None => (false, false),
+    };
+    let mut writer = match pretty {
+        true => Writer::new_with_indent(Vec::new(), b' ', 2),
+        false => Writer::new(Vec::new()),
+    };
+    if declaration {
+        writer
+            .write_event(Event::Decl(quick_xml::events::BytesDecl::new(
+                "1.0",
+                Some("UTF-8"),
+                None,
+            )))
+            .map_err(|error| encode_error(error.to_string()))?;
+    }
+    write_element(&mut writer, element, 0)?;
+    String::from_utf8(writer.into_inner()).map_err(|error| encode_error(error.to_string()))
+}
+
+// Whether an element's tag passes a step's test, where a name without a prefix matches any
+fn tag_matches(tag: &str, test: &str) -> bool {
+    test == "*"
+        || tag == test
+        || (!test.contains(':') && tag.rsplit_once(':').is_some_and(|(_, local)| local == test))
+}
+
+enum Predicate {
+    Position(usize),
+    HasAttribute(String),
+    AttributeEquals(String, String),
+}
+
+struct Step {
+    // Whether the step goes through every level below rather than just the children
+    descendants: bool,
+    test: String,
+    predicates: Vec<Predicate>,
+}
+
+enum Select {
+    Elements,
+    Attribute(String),
+    Text,
+}
+
+struct Query {
+    // Whether the first step starts at the element itself
+    absolute: bool,
+    steps: Vec<Step>,
+    select: Select,
+}
+
+fn parse_predicate(predicate: &str) -> Option<Predicate> {
+    if let Ok(position) = predicate.parse::<usize>() {
+        return (position > 0).then_some(Predicate::Position(position));
+    }
+    let attribute = predicate.strip_prefix('@')?;
+    match attribute.split_once('=') {
+        None => Some(Predicate::HasAttribute(attribute.trim().to_string())),
+        Some((name, value)) => {
+            let value = value.trim();
+            let unquoted = value
+                .strip_prefix('\'')
+                .and_then(|value| value.strip_suffix('\''))
+                .or_else(|| {
+                    value
+                        .strip_prefix('"')
+                        .and_then(|value| value.strip_suffix('"'))
+                })?;
+            Some(Predicate::AttributeEquals(
+                name.trim().to_string(),
+                unquoted.to_string(),
+            ))
+        }
+    }
+}
+
+fn parse_step(segment: &str, descendants: bool) -> Option<Step> {
+    let (test, mut rest) = match segment.find('[') {
+        Some(index) => segment.split_at(index),
+        None => (segment, ""),
+    };
+    if test.is_empty() || test.contains([']', '@', '(']) {
+        return None;
+    }
+    let mut predicates = Vec::new();
+    while !rest.is_empty() {
+        let end = rest.find(']')?;
+        predicates.push(parse_predicate(rest.get(1..end)?)?);
+        rest = &rest[end + 1..];
+        if !rest.is_empty() && !rest.starts_with('[') {
+            return None;
+        }
+    }
+    Some(Step {
+        descendants,
+        test: test.to_string(),
+        predicates,
+    })
+}
+
+fn parse_query(path: &str, function: &str) -> Result<Query> {
+    let invalid = || {
+        rlua::Error::RuntimeError(format!(
+            "bad argument #2 to '{}' (invalid path \"{}\")",
+            function, path
+        ))
+    };
+    let (absolute, rest) = match path.strip_prefix('/') {
+        Some(rest) => (true, rest),
+        None => (false, path),
+    };
+    let mut segments = rest.split('/').collect::<Vec<_>>();
+    let select = match segments.last() {
+        Some(&"text()") => Select::Text,
+        Some(last) if last.starts_with('@') => Select::Attribute(last[1..].to_string()),
+        _ => Select::Elements,
+    };
+    if !matches!(select, Select::Elements) {
+        segments.pop();
+    }
+    let mut steps = Vec::new();
+    let mut descendants = false;
+    for segment in segments {
+        if segment.is_empty() {
+            // The gap in `//`, which goes through every level for the next step
+            if descendants {
+                return Err(invalid());
+            }
+            descendants = true;
+            continue;
+        }
+        steps.push(parse_step(segment, descendants).ok_or_else(invalid)?);
+        descendants = false;
+    }
+    if descendants || (steps.is_empty() && matches!(select, Select::Elements)) {
+        return Err(invalid());
+    }
+    Ok(Query {
+        absolute,
+        steps,
+        select,
+    })
+}
+
+// The elements among an element's children
+fn child_elements<'lua>(element: &Table<'lua>) -> Result<Vec<Table<'lua>>> {
+    let Some(children) = element.get::<_, Option<Table>>("children")? else {
+        return Ok(Vec::new());
+    };
+    children
+        .sequence_values::<Value>()
+        .filter_map(|child| match child {
+            Ok(Value::Table(child)) => Some(Ok(child)),
+            Ok(_) => None,
+            Err(error) => Some(Err(error)),
+        })
+        .collect()
+}
+
+// Elements are told apart by the positions of the children leading to them from where the
+// query started, which also puts them in the order they're written in
+type Found<'lua> = (Vec<usize>, Table<'lua>);
+
+fn collect_descendants<'lua>(
+    found: &Found<'lua>,
+    into: &mut Vec<Found<'lua>>,
+    depth: usize,
+) -> Result<()> {
+    if depth >= MAX_DEPTH {
+        return Err(rlua::Error::RuntimeError(
+            "elements are nested too deeply".to_string(),
+        ));
+    }
+    for (index, child) in child_elements(&found.1)?.into_iter().enumerate() {
+        let mut path = found.0.clone();
+        path.push(index);
+        let child = (path, child);
+        into.push((child.0.clone(), child.1.clone()));
+        collect_descendants(&child, into, depth + 1)?;
+    }
+    Ok(())
+}
+
+fn attribute(element: &Table, name: &str) -> Result<Option<String>> {
+    match element.get::<_, Option<Table>>("attrs")? {
+        Some(attrs) => attrs.get(name),
+        None => Ok(None),
+    }
+}
+
+fn apply_step<'lua>(step: &Step, candidates: Vec<Found<'lua>>) -> Result<Vec<Found<'lua>>> {
+    let mut matches = Vec::new();
+    for candidate in candidates {
+        if tag_matches(&candidate.1.get::<_, String>("tag")?, &step.test) {
+            matches.push(candidate);
+        }
+    }
+    for predicate in &step.predicates {
+        matches = match predicate {
+            Predicate::Position(position) => {
+                matches.into_iter().nth(position - 1).into_iter().collect()
+            }
+            Predicate::HasAttribute(name) => {
+                let mut kept = Vec::new();
+                for found in matches {
+                    if attribute(&found.1, name)?.is_some() {
+                        kept.push(found);
+                    }
+                }
+                kept
+            }
+            Predicate::AttributeEquals(name, value) => {
+                let mut kept = Vec::new();
+                for found in matches {
+                    if attribute(&found.1, name)?.as_deref() == Some(value.as_str()) {
+                        kept.push(found);
+                    }
+                }
+                kept
+            }
+        };
+    }
+    Ok(matches)
+}
+
+fn text(element: &Table, depth: usize) -> Result<String> {
+    if depth >= MAX_DEPTH {
+        return Err(rlua::Error::RuntimeError(
+            "elements are nested too deeply".to_string(),
+        ));
+    }
+    let mut joined = String::new();
+    if let Some(children) = element.get::<_, Option<Table>>("children")? {
+        for child in children.sequence_values::<Value>() {
+            match child? {
+                Value::String(string) => joined.push_str(string.to_str()?),
+                child @ (Value::Integer(_) | Value::Number(_)) => {
+                    joined.push_str(&child_text(child))
+                }
+                Value::Table(child) => joined.push_str(&text(&child, depth + 1)?),
+                _ => {}
+            }
+        }
+    }
+    Ok(joined)
+}
+
+fn query<'lua>(
+    lua_ctx: Context<'lua>,
+    element: Table<'lua>,
+    path: &str,
+    function: &str,
+) -> Result<Vec<Value<'lua>>> {
+    let query = parse_query(path, function)?;
+    let start = (Vec::new(), element);
+    let mut current = vec![start];
+    for (index, step) in query.steps.iter().enumerate() {
+        let mut found = Vec::new();
+        for context in &current {
+            // The first step of an absolute path can match the element it starts at
+            let mut candidates = match index == 0 && query.absolute {
+                true => vec![(context.0.clone(), context.1.clone())],
+                false => Vec::new(),
+            };
+            if step.descendants {
+                collect_descendants(context, &mut candidates, 0)?;
+            } else if !(index == 0 && query.absolute) {
+                for (position, child) in child_elements(&context.1)?.into_iter().enumerate() {
+                    let mut path = context.0.clone();
+                    path.push(position);
+                    candidates.push((path, child));
+                }
+            }
+            found.extend(apply_step(step, candidates)?);
+        }
+        // Contexts inside each other can find the same element through //
+        let mut seen = HashSet::new();
+        found.retain(|(path, _)| seen.insert(path.clone()));
+        found.sort_by(|(left, _), (right, _)| left.cmp(right));
+        current = found;
+    }
+    let mut results = Vec::new();
+    for (_, element) in current {
+        match &query.select {
+            Select::Elements => results.push(Value::Table(element)),
+            Select::Attribute(name) => {
+                if let Some(value) = attribute(&element, name)? {
+                    results.push(Value::String(lua_ctx.create_string(&value)?));
+                }
+            }
+            Select::Text => {
+                results.push(Value::String(lua_ctx.create_string(&text(&element, 0)?)?));
+            }
+        }
+    }
+    Ok(results)
+}
+
+fn query_function<'lua>(lua_ctx: Context<'lua>, function: &'static str) -> Result<Function<'lua>> {
+    lua_ctx.create_function(move |lua_ctx, (element, path): (Table, String)| {
+        let results = query(lua_ctx, element, &path, function)?;
+        match function {
+            "first" => Ok(results.into_iter().next().unwrap_or(Value::Nil)),
+            _ => Ok(Value::Table(lua_ctx.create_sequence_from(results)?)),
+        }
+    })
+}
+
+pub fn load_xml_library(lua: &Lua) -> Result<()> {
+    lua.context(|lua_ctx| {
+        let xml = lua_ctx.create_table()?;
+
+        xml.set(
+            "decode",
+            lua_ctx.create_function(|lua_ctx, text: String| decode(lua_ctx, text))?,
+        )?;
+
+        xml.set(
+            "encode",
+            lua_ctx.create_function(|_, (element, options): (Table, Option<Table>)| {
+                encode(element, options)
+            })?,
+        )?;
+
+        xml.set("query", query_function(lua_ctx, "query")?)?;
+        xml.set("first", query_function(lua_ctx, "first")?)?;
+        xml.set(
+            "text",
+            lua_ctx.create_function(|_, element: Table| text(&element, 0))?,
+        )?;
+
+        let methods = lua_ctx.create_table()?;
+        for name in ["query", "first", "text"] {
+            methods.set(name, xml.get::<_, Function>(name)?)?;
+        }
+        let element = lua_ctx.create_table()?;
+        element.set("__index", methods)?;
+        lua_ctx.set_named_registry_value(ELEMENT_REGISTRY_KEY, element)?;
+
+        help::register_module_docs(lua_ctx, "xml", "XML parsing and building", &xml, XML_DOCS)?;
+        lua_ctx.globals().set("xml", xml)?;
+
+        Ok(())
+    })
+}
@@ -0,0 +1,194 @@
+/*
+   Copyright (C) 2022  Kalka
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License, or (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use rlua::{Context, LightUserData, Lua, MultiValue, Result, Table, Value};
+use serde::Deserialize;
+
+use crate::help::{self, FunctionDoc};
+use crate::{error, json};
+
+const YAML_DOCS: &[FunctionDoc] = &[
+    FunctionDoc {
+        name: "decode",
+        signature: "yaml.decode(text) -> documents...",
+        description: "Parses YAML into Lua values, returning one value for each document in the text, so a file of several documents separated by --- gives several values. Mappings and sequences become tables, merge keys (<<) are applied, tags are dropped, and null becomes yaml.null, which is json.null. Raises an error saying where the YAML is invalid.",
+        example: "for _, manifest in ipairs({ yaml.decode(fs.read(\"deploy.yaml\")) }) do\n    print(manifest.kind, manifest.metadata.name)\nend",
+    },
+    FunctionDoc {
+        name: "encode",
+        signature: "yaml.encode(value[, options]) -> string",
+        description: "Turns a Lua value into YAML. Tables with nothing but the keys 1 to n become sequences and other tables mappings, and yaml.null becomes null. With options.documents, value is a list of documents written one after the other, separated by ---. options.sort_keys writes mapping keys in order instead of the order the table holds them in. Raises an error for functions and other values YAML can't hold, and for tables nested inside themselves.",
+        example: "fs.write(\"config.yaml\", yaml.encode(config, { sort_keys = true }))",
+    },
+];
+
+// Deeper tables are almost certainly cycles
+const MAX_DEPTH: usize = 100;
+
+fn encode_error(message: String) -> rlua::Error {
+    rlua::Error::RuntimeError(format!("cannot encode to YAML: {}", message))
+}
+
+fn to_lua<'lua>(lua_ctx: Context<'lua>, value: serde_yaml::Value) -> Result<Value<'lua>> {
+    Ok(match value {
+        serde_yaml::Value::Null => json::null(),
+        serde_yaml::Value::Bool(boolean) => Value::Boolean(boolean),
+        serde_yaml::Value::Number(number) => match number.as_i64() {
+            Some(integer) => Value::Integer(integer),
+            None => Value::Number(number.as_f64().unwrap_or(f64::NAN)),
+        },
+        serde_yaml::Value::String(string) => Value::String(lua_ctx.create_string(&string)?),
+        serde_yaml::Value::Sequence(sequence) => {
+            let table = lua_ctx.create_table()?;
+            for (index, value) in sequence.into_iter().enumerate() {
+                table.raw_set(index as i64 + 1, to_lua(lua_ctx, value)?)?;
+            }
+            Value::Table(table)
+        }
+        serde_yaml::Value::Mapping(mapping) => {
+            let table = lua_ctx.create_table()?;
+            for (key, value) in mapping {
+                table.raw_set(to_lua(lua_ctx, key)?, to_lua(lua_ctx, value)?)?;
+            }
+            Value::Table(table)
+        }
+        serde_yaml::Value::Tagged(tagged) => to_lua(lua_ctx, tagged.value)?,
+    })
+}
+
+fn decode<'lua>(lua_ctx: Context<'lua>, text: rlua::String) -> Result<MultiValue<'lua>> {
+    let yaml_error = |source| rlua::Error::external(error::Error::Yaml { source });
+    let mut documents = Vec::new();
+    for document in serde_yaml::Deserializer::from_slice(text.as_bytes()) {
+        let mut value = serde_yaml::Value::deserialize(document).map_err(yaml_error)?;
+        value.apply_merge().map_err(yaml_error)?;
+        documents.push(to_lua(lua_ctx, value)?);
+    }
+    Ok(MultiValue::from_vec(documents))
+}
+
+// Like json::to_json, but keeping number and boolean keys as they are
+fn to_yaml(value: Value, sort_keys: bool, depth: usize) -> Result<serde_yaml::Value> {
+    Ok(match value {
+        Value::Nil => serde_yaml::Value::Null,
+        Value::LightUserData(LightUserData(pointer)) if pointer.is_null() => {
+            serde_yaml::Value::Null
+        }
+        Value::Boolean(boolean) => serde_yaml::Value::Bool(boolean),
+        Value::Integer(integer) => serde_yaml::Value::from(integer),
+        Value::Number(number) => serde_yaml::Value::from(number),
+        Value::String(string) => serde_yaml::Value::String(string.to_str()?.to_string()),
+        Value::Table(table) => {
+            if depth >= MAX_DEPTH {
+                return Err(encode_error("tables are nested too deeply".to_string()));
+            }
+            let length = table.raw_len() as usize;
+            let pairs = table
+                .clone()
+                .pairs::<Value, Value>()
+                .collect::<Result<Vec<_>>>()?;
+            if length > 0 && pairs.len() == length {
+                let mut sequence = Vec::with_capacity(length);
+                for index in 1..=length {
+                    sequence.push(to_yaml(table.raw_get(index as i64)?, sort_keys, depth + 1)?);
+                }
+                serde_yaml::Value::Sequence(sequence)
+            } else {
+                let mut entries = Vec::with_capacity(pairs.len());
+                for (key, value) in pairs {
+                    let key = match key {
+                        Value::String(_)
+                        | Value::Integer(_)
+                        | Value::Number(_)
+                        | Value::Boolean(_) => to_yaml(key, sort_keys, depth + 1)?,
+                        key => {
+                            return Err(encode_error(format!("{} keys", key.type_name())));
+                        }
+                    };
+                    entries.push((key, to_yaml(value, sort_keys, depth + 1)?));
+                }
+                if sort_keys {
+                    entries.sort_by(|(a, _), (b, _)| {
+                        a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal)
+                    });
+                }
+                serde_yaml::Value::Mapping(entries.into_iter().collect())
+            }
+        }
+        value => return Err(encode_error(format!("{} values", value.type_name()))),
+    })
+}
+
+fn encode(value: Value, options: Option<Table>) -> Result<String> {
+    let (documents, sort_keys) = match options {
+        Some(options) => (
+            options
+                .get::<_, Option<bool>>("documents")?
+                .unwrap_or(false),
+            options
+                .get::<_, Option<bool>>("sort_keys")?
+                .unwrap_or(false),
+        ),
+        None => (false, false),
+    };
+    let documents = match value {
+        Value::Table(list) if documents => list
+            .sequence_values::<Value>()
+            .collect::<Result<Vec<_>>>()?,
+        value if documents => {
+            return Err(rlua::Error::RuntimeError(format!(
+                "bad argument #1 to 'encode' (table of documents expected, got {})",
+                value.type_name()
+            )))
+        }
+        value => vec![value],
+    };
+    let mut encoded = Vec::with_capacity(documents.len());
+    for document in documents {
+        let yaml = to_yaml(document, sort_keys, 0)?;
+        encoded
+            .push(serde_yaml::to_string(&yaml).map_err(|error| encode_error(error.to_string()))?);
+    }
+    Ok(encoded.join("---\n"))
+}
+
+pub fn load_yaml_library(lua: &Lua) -> Result<()> {
+    lua.context(|lua_ctx| {
+        let yaml = lua_ctx.create_table()?;
+
+        yaml.set("decode", lua_ctx.create_function(decode)?)?;
+
+        yaml.set(
+            "encode",
+            lua_ctx.create_function(|_, (value, options): (Value, Option<Table>)| {
+                encode(value, options)
+            })?,
+        )?;
+
+        yaml.set("null", json::null())?;
+
+        help::register_module_docs(
+            lua_ctx,
+            "yaml",
+            "YAML encoding and decoding",
+            &yaml,
+            YAML_DOCS,
+        )?;
+        lua_ctx.globals().set("yaml", yaml)?;
+
+        Ok(())
+    })
+}